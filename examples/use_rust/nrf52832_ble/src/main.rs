@@ -82,7 +82,7 @@ async fn main(spawner: Spawner) {
     // Initialize the matrix + keyboard
     let mut keyboard = Keyboard::new(&keymap, rmk_config.behavior_config.clone());
     let debouncer = DefaultDebouncer::<ROW, COL>::new();
-    let mut matrix = Matrix::<_, _, _, ROW, COL>::new(input_pins, output_pins, debouncer);
+    let mut matrix = Matrix::<_, _, _, ROW, COL>::new(input_pins, output_pins, debouncer, false);
     // let mut matrix = TestMatrix::<ROW, COL>::new();
 
     // Initialize the light controller
@@ -28,7 +28,7 @@ use rmk::{
         BleBatteryConfig, ControllerConfig, KeyboardUsbConfig, RmkConfig, StorageConfig, VialConfig,
     },
     debounce::default_debouncer::DefaultDebouncer,
-    event::Event,
+    event::{Event, EventSource},
     futures::future::join4,
     initialize_keymap_and_storage, initialize_nrf_sd_and_flash,
     input_device::{
@@ -140,7 +140,7 @@ async fn main(spawner: Spawner) {
     let mut keyboard: Keyboard<'_, ROW, COL, NUM_LAYER> =
         Keyboard::new(&keymap, rmk_config.behavior_config.clone());
     let debouncer = DefaultDebouncer::<ROW, COL>::new();
-    let mut matrix = Matrix::<_, _, _, ROW, COL>::new(input_pins, output_pins, debouncer);
+    let mut matrix = Matrix::<_, _, _, ROW, COL>::new(input_pins, output_pins, debouncer, false);
     // let mut matrix = TestMatrix::<ROW, COL>::new();
 
     // Initialize the light controller
@@ -195,7 +195,7 @@ struct MyDevice {}
 impl InputDevice for MyDevice {
     async fn read_event(&mut self) -> Event {
         embassy_time::Timer::after_secs(10).await;
-        Event::Eos
+        Event::Eos(EventSource::Local)
     }
 }
 
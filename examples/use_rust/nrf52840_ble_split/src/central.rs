@@ -142,7 +142,8 @@ async fn main(spawner: Spawner) {
 
     // Initialize the matrix + keyboard
     let debouncer = DefaultDebouncer::<4, 7>::new();
-    let mut matrix = CentralMatrix::<_, _, _, 0, 0, 4, 7>::new(input_pins, output_pins, debouncer);
+    let mut matrix =
+        CentralMatrix::<_, _, _, 0, 0, 4, 7>::new(input_pins, output_pins, debouncer, false);
     let mut keyboard = Keyboard::new(&keymap, rmk_config.behavior_config.clone());
 
     // Initialize the light controller
@@ -56,7 +56,7 @@ async fn main(_spawner: Spawner) {
 
     // Define the matrix
     let debouncer = DefaultDebouncer::<2, 2>::new();
-    let mut matrix = Matrix::<_, _, _, 2, 2>::new(input_pins, output_pins, debouncer);
+    let mut matrix = Matrix::<_, _, _, 2, 2>::new(input_pins, output_pins, debouncer, false);
 
     // Start
     join(
@@ -63,6 +63,13 @@ pub(crate) fn bind_interrupt_default(keyboard_config: &KeyboardConfig) -> TokenS
                     charge_led: _,
                     adc_divider_measured: _,
                     adc_divider_total: _,
+                    passkey_entry: _,
+                    adc_reference_mv: _,
+                    adc_gain: _,
+                    adc_resolution: _,
+                    discharge_curve: _,
+                    battery_filter_window: _,
+                    battery_hysteresis: _,
                 }) = keyboard_config.communication.get_ble_config()
                 {
                     Some(quote! {
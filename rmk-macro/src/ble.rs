@@ -1,11 +1,125 @@
-use proc_macro2::TokenStream as TokenStream2;
+use proc_macro2::{Ident, TokenStream as TokenStream2};
 use quote::{format_ident, quote};
 
 use crate::{
+    config::BleConfig,
     keyboard_config::{CommunicationConfig, KeyboardConfig},
     ChipSeries,
 };
 
+// Reference voltage of the ESP32 oneshot ADC in mV, at 11dB attenuation
+const ESP_ADC_REFERENCE_MV: u32 = 3100;
+
+// Gains supported by nRF52 SAADC, in (numerator, denominator)
+const ADC_GAINS: [(u32, u32); 8] = [
+    (1, 6),
+    (1, 5),
+    (1, 4),
+    (1, 3),
+    (1, 2),
+    (1, 1),
+    (2, 1),
+    (4, 1),
+];
+
+// Resolutions supported by nRF52 SAADC
+const ADC_RESOLUTIONS: [u8; 4] = [8, 10, 12, 14];
+
+// Internal reference voltage of nRF52 SAADC in mV, other references are VDD/4
+const ADC_INTERNAL_REFERENCE_MV: u32 = 600;
+
+// Parse ADC gain such as "1/6" or "2"
+fn parse_adc_gain(gain: &str) -> Option<(u32, u32)> {
+    let gain = match gain.split_once('/') {
+        Some((n, d)) => (n.trim().parse().ok()?, d.trim().parse().ok()?),
+        None => (gain.trim().parse().ok()?, 1),
+    };
+    ADC_GAINS.contains(&gain).then_some(gain)
+}
+
+// Check the battery config in `[ble]`
+pub(crate) fn check_battery_config(ble: &BleConfig) -> Result<(), String> {
+    if ble.adc_reference_mv == Some(0) {
+        return Err("keyboard.toml: adc_reference_mv should be greater than 0".to_string());
+    }
+    if let Some(gain) = &ble.adc_gain {
+        if parse_adc_gain(gain).is_none() {
+            return Err(format!(
+                "keyboard.toml: adc_gain \"{}\" is not supported, use one of 1/6, 1/5, 1/4, 1/3, 1/2, 1, 2, 4",
+                gain
+            ));
+        }
+    }
+    if let Some(resolution) = ble.adc_resolution {
+        if !ADC_RESOLUTIONS.contains(&resolution) {
+            return Err(format!(
+                "keyboard.toml: adc_resolution {} is not supported, use one of 8, 10, 12, 14",
+                resolution
+            ));
+        }
+    }
+    if let Some(curve) = &ble.discharge_curve {
+        if curve.is_empty() {
+            return Err("keyboard.toml: discharge_curve is empty".to_string());
+        }
+        if curve.windows(2).any(|p| p[0].0 <= p[1].0) {
+            return Err(
+                "keyboard.toml: discharge_curve should be sorted by voltage from high to low"
+                    .to_string(),
+            );
+        }
+        if curve.iter().any(|(_, percent)| *percent > 100) {
+            return Err(
+                "keyboard.toml: battery level in discharge_curve should be at most 100".to_string(),
+            );
+        }
+    }
+    Ok(())
+}
+
+// Tokens for setting the battery level config, which converts ADC values to battery levels.
+//
+// The fields are set on `target`, the ADC gain and reference default to `default_gain` and `default_reference_mv`.
+fn expand_battery_level_config(
+    ble: &BleConfig,
+    target: &Ident,
+    default_gain: (u32, u32),
+    default_reference_mv: u32,
+) -> TokenStream2 {
+    let (gain_numerator, gain_denominator) = ble
+        .adc_gain
+        .as_deref()
+        .and_then(parse_adc_gain)
+        .unwrap_or(default_gain);
+    let reference_mv = ble.adc_reference_mv.unwrap_or(default_reference_mv);
+    let resolution = ble.adc_resolution.unwrap_or(12);
+    let mut tokens = quote! {
+        #target.adc_config = ::rmk::ble::battery::AdcConfig {
+            reference_mv: #reference_mv,
+            gain_numerator: #gain_numerator,
+            gain_denominator: #gain_denominator,
+            resolution: #resolution,
+        };
+    };
+    if let Some(curve) = &ble.discharge_curve {
+        let points = curve.iter().map(|(mv, percent)| quote! { (#mv, #percent) });
+        tokens.extend(quote! {
+            #target.discharge_curve = &[#(#points),*];
+        });
+    }
+    if let Some(window) = ble.battery_filter_window {
+        tokens.extend(quote! {
+            #target.filter_window = #window;
+        });
+    }
+    if let Some(hysteresis) = ble.battery_hysteresis {
+        tokens.extend(quote! {
+            #target.hysteresis = #hysteresis;
+        });
+    }
+    tokens
+}
+
 // Default implementations of ble configuration.
 // Because ble configuration in `config` is enabled by a feature gate, so this function returns two TokenStreams.
 // One for initialization ble config, another one for filling this field into `RmkConfig`.
@@ -13,6 +127,13 @@ pub(crate) fn expand_ble_config(keyboard_config: &KeyboardConfig) -> (TokenStrea
     if !keyboard_config.communication.ble_enabled() {
         return (quote! {}, quote! {});
     }
+    // Passkey entry when pairing is opt-in
+    let passkey_entry = match &keyboard_config.communication {
+        CommunicationConfig::Ble(ble) | CommunicationConfig::Both(_, ble) => {
+            ble.passkey_entry.unwrap_or(false)
+        }
+        _ => false,
+    };
     // Support only nrf52 and esp32 (for now)
     if keyboard_config.chip.series != ChipSeries::Nrf52 {
         if keyboard_config.chip.series == ChipSeries::Esp32 {
@@ -22,6 +143,7 @@ pub(crate) fn expand_ble_config(keyboard_config: &KeyboardConfig) -> (TokenStrea
                 },
                 quote! {
                     ble_battery_config,
+                    ble_passkey_entry: #passkey_entry,
                 },
             );
         } else {
@@ -66,11 +188,32 @@ pub(crate) fn expand_ble_config(keyboard_config: &KeyboardConfig) -> (TokenStrea
                         }
                     };
 
+                    // Gain and resolution of the ADC
+                    let (gain_numerator, gain_denominator) = ble
+                        .adc_gain
+                        .as_deref()
+                        .and_then(parse_adc_gain)
+                        .unwrap_or((1, 6));
+                    let gain = if gain_denominator == 1 {
+                        format_ident!("GAIN{}", gain_numerator)
+                    } else {
+                        format_ident!("GAIN1_{}", gain_denominator)
+                    };
+                    let resolution = format_ident!("_{}BIT", ble.adc_resolution.unwrap_or(12));
+                    // Any reference other than the internal 600mV is VDD/4
+                    let reference = match ble.adc_reference_mv {
+                        None | Some(ADC_INTERNAL_REFERENCE_MV) => format_ident!("INTERNAL"),
+                        Some(_) => format_ident!("VDD1_4"),
+                    };
+
                     ble_config_tokens.extend(quote! {
                         use ::embassy_nrf::saadc::Input as _;
                         // Then we initialize the ADC. We are only using one channel in this example.
-                        let config = ::embassy_nrf::saadc::Config::default();
-                        let channel_cfg = ::embassy_nrf::saadc::ChannelConfig::single_ended(#adc_pin_def);
+                        let mut config = ::embassy_nrf::saadc::Config::default();
+                        config.resolution = ::embassy_nrf::saadc::Resolution::#resolution;
+                        let mut channel_cfg = ::embassy_nrf::saadc::ChannelConfig::single_ended(#adc_pin_def);
+                        channel_cfg.gain = ::embassy_nrf::saadc::Gain::#gain;
+                        channel_cfg.reference = ::embassy_nrf::saadc::Reference::#reference;
                         ::embassy_nrf::interrupt::SAADC.set_priority(::embassy_nrf::interrupt::Priority::P3);
                         let saadc = ::embassy_nrf::saadc::Saadc::new(p.SAADC, Irqs, config, [channel_cfg]);
                         // Wait for ADC calibration.
@@ -129,14 +272,21 @@ pub(crate) fn expand_ble_config(keyboard_config: &KeyboardConfig) -> (TokenStrea
 
                 ble_config_tokens.extend(
                     quote! {
-                        let ble_battery_config = ::rmk::config::BleBatteryConfig::new(is_charging_pin, charging_state_low_active, charge_led_pin, charge_led_low_active, saadc_option, adc_divider_measured, adc_divider_total);
+                        let mut ble_battery_config = ::rmk::config::BleBatteryConfig::new(is_charging_pin, charging_state_low_active, charge_led_pin, charge_led_low_active, saadc_option, adc_divider_measured, adc_divider_total);
                     }
                 );
+                ble_config_tokens.extend(expand_battery_level_config(
+                    ble,
+                    &format_ident!("ble_battery_config"),
+                    (1, 6),
+                    ADC_INTERNAL_REFERENCE_MV,
+                ));
 
                 (
                     ble_config_tokens,
                     quote! {
                         ble_battery_config,
+                        ble_passkey_entry: #passkey_entry,
                     },
                 )
             } else {
@@ -146,6 +296,7 @@ pub(crate) fn expand_ble_config(keyboard_config: &KeyboardConfig) -> (TokenStrea
                     },
                     quote! {
                         ble_battery_config,
+                        ble_passkey_entry: #passkey_entry,
                     },
                 )
             }
@@ -153,3 +304,67 @@ pub(crate) fn expand_ble_config(keyboard_config: &KeyboardConfig) -> (TokenStrea
         _ => (quote! {}, quote! {}),
     }
 }
+
+// Sample the battery of a split peripheral by the battery config in `[ble]`, the halves share the same config.
+//
+// Returns the tokens initializing the battery sampler, and the sampling task.
+// Nothing is returned if there's no `battery_adc_pin`.
+pub(crate) fn expand_peripheral_battery_sampling(
+    keyboard_config: &KeyboardConfig,
+) -> Option<(TokenStream2, TokenStream2)> {
+    let ble = keyboard_config
+        .communication
+        .get_ble_config()
+        .filter(|ble| ble.enabled)?;
+    let adc_pin = ble.battery_adc_pin.clone()?;
+    match keyboard_config.chip.series {
+        ChipSeries::Nrf52 => {
+            // Same as the battery config of the central
+            let (ble_battery_config, _) = expand_ble_config(keyboard_config);
+            Some((
+                quote! {
+                    ::embassy_nrf::bind_interrupts!(struct Irqs {
+                        SAADC => ::embassy_nrf::saadc::InterruptHandler;
+                    });
+                    #ble_battery_config
+                },
+                quote! { ::rmk::split::battery::run_peripheral_battery_sampling(ble_battery_config) },
+            ))
+        }
+        ChipSeries::Esp32 => {
+            // The pin should be an ADC1 channel
+            let adc_pin = format_ident!("{}", adc_pin.to_lowercase());
+            let (measured, total) = match (ble.adc_divider_measured, ble.adc_divider_total) {
+                (Some(measured), Some(total)) => (measured, total),
+                // If any of measured or total is not provided, we set both to 1, aka no divider.
+                _ => (1, 1),
+            };
+            let sampler = format_ident!("battery_sampler");
+            let battery_level_config =
+                expand_battery_level_config(&ble, &sampler, (1, 1), ESP_ADC_REFERENCE_MV);
+            Some((
+                quote! {
+                    let battery_adc = ::esp_idf_svc::hal::adc::oneshot::AdcDriver::new(p.adc1).unwrap();
+                    let mut battery_adc_pin = ::esp_idf_svc::hal::adc::oneshot::AdcChannelDriver::new(
+                        &battery_adc,
+                        p.pins.#adc_pin,
+                        &::esp_idf_svc::hal::adc::oneshot::config::AdcChannelConfig {
+                            attenuation: ::esp_idf_svc::hal::adc::attenuation::DB_11,
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap();
+                    let mut #sampler = ::rmk::ble::battery::AdcBatterySampler::new(
+                        || battery_adc_pin.read_raw().ok(),
+                        ::rmk::ble::battery::AdcConfig::default(),
+                    );
+                    #sampler.adc_divider_measured = #measured;
+                    #sampler.adc_divider_total = #total;
+                    #battery_level_config
+                },
+                quote! { ::rmk::split::battery::run_peripheral_battery_sampling(#sampler) },
+            ))
+        }
+        _ => None,
+    }
+}
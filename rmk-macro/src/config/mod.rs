@@ -9,6 +9,8 @@ pub struct KeyboardTomlConfig {
     pub keyboard: KeyboardInfo,
     /// Matrix of the keyboard, only for non-split keyboards
     pub matrix: Option<MatrixConfig>,
+    /// Debounce config
+    pub debounce: Option<DebounceConfig>,
     /// Layout config.
     /// For split keyboard, the total row/col should be defined in this section
     pub layout: LayoutConfig,
@@ -57,6 +59,9 @@ pub enum MatrixType {
     #[default]
     normal,
     direct_pin,
+    shift_register,
+    duplex,
+    charlieplex,
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
@@ -70,6 +75,70 @@ pub struct MatrixConfig {
     pub direct_pin_low_active: bool,
     #[serde(default = "default_false")]
     pub row2col: bool,
+    #[serde(default = "default_false")]
+    pub low_active: bool,
+    /// Suppress ghost keys of a `normal` matrix without diodes
+    #[serde(default = "default_false")]
+    pub ghost_detection: bool,
+    /// Shift register config, required by `shift_register` matrix
+    pub shift_register: Option<ShiftRegisterConfig>,
+    /// Row pins of `duplex` matrix
+    pub row_pins: Option<Vec<String>>,
+    /// Col pins of `duplex` matrix
+    pub col_pins: Option<Vec<String>>,
+    /// Pins of `charlieplex` matrix
+    pub charlieplex_pins: Option<Vec<String>>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum ShiftRegisterChip {
+    /// 74HC595, drives the output side of the matrix
+    hc595,
+    /// 74HC165, reads the input side of the matrix
+    hc165,
+}
+
+/// Config for shift registers connected to SPI
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShiftRegisterConfig {
+    pub chip: ShiftRegisterChip,
+    /// Number of chained chips
+    #[serde(default = "default_shift_register_chips")]
+    pub chips: usize,
+    /// Number of shift register pins used by the matrix
+    pub pins: usize,
+    /// SPI instance
+    pub instance: String,
+    pub sck: String,
+    /// MOSI for 74HC595, MISO for 74HC165
+    pub data: String,
+    /// Connected to RCLK of 74HC595, or CE of 74HC165
+    pub cs: String,
+    /// Parallel load pin of 74HC165
+    pub load: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum DebounceAlgorithm {
+    #[default]
+    sym_defer_pk,
+    sym_eager_pk,
+    asym_eager_defer_pk,
+}
+
+/// Config for debouncing
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DebounceConfig {
+    #[serde(default)]
+    pub algorithm: DebounceAlgorithm,
+    /// Debounce time of key presses
+    pub press_time: Option<DurationMillis>,
+    /// Debounce time of key releases
+    pub release_time: Option<DurationMillis>,
 }
 
 /// Config for storage
@@ -96,6 +165,20 @@ pub struct BleConfig {
     pub charge_led: Option<PinConfig>,
     pub adc_divider_measured: Option<u32>,
     pub adc_divider_total: Option<u32>,
+    // Type the passkey displayed by the host on the keyboard when pairing
+    pub passkey_entry: Option<bool>,
+    // Reference voltage of the ADC in mV, 600 is the internal reference of nRF52, other values are VDD/4
+    pub adc_reference_mv: Option<u32>,
+    // Gain of the ADC, such as "1/6"
+    pub adc_gain: Option<String>,
+    // Resolution of the ADC in bits
+    pub adc_resolution: Option<u8>,
+    // Battery levels at voltages, in [millivolts, percent] sorted by voltage from high to low
+    pub discharge_curve: Option<Vec<(u16, u8)>>,
+    // Number of samples of the moving average of the battery voltage
+    pub battery_filter_window: Option<usize>,
+    // Hysteresis of the battery level in percent
+    pub battery_hysteresis: Option<u8>,
 }
 
 /// Config for lights
@@ -138,6 +221,10 @@ pub struct LayoutConfig {
     pub cols: u8,
     pub layers: u8,
     pub keymap: Vec<Vec<Vec<String>>>,
+    /// Matrix position `(row, col)` of each key in physical order.
+    ///
+    /// If it's set, each layer of `keymap` is written in physical order, and has the same shape as `matrix_map`.
+    pub matrix_map: Option<Vec<Vec<(u8, u8)>>>,
 }
 
 /// Configurations for actions behavior
@@ -199,11 +286,29 @@ pub struct SplitConfig {
     pub connection: String,
     pub central: SplitBoardConfig,
     pub peripheral: Vec<SplitBoardConfig>,
+    /// Run the same firmware on both halves
+    pub ee_hands: Option<EeHandsConfig>,
+}
+
+/// Config of running the same firmware on both halves of a split keyboard
+///
+/// `split.central` is the left half and the only `split.peripheral` is the right half.
+/// Both halves use the matrix and serial of `split.central`, the role and handedness are detected at boot.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EeHandsConfig {
+    /// Pin which is high when USB VBUS is present, the half powered by USB is the central
+    pub vbus_pin: String,
+    /// Strap pin of the handedness, the handedness is read from the storage if it's not set
+    pub handedness_pin: Option<String>,
+    /// The half is the left one if `handedness_pin` is high
+    #[serde(default = "default_true")]
+    pub left_level_high: bool,
 }
 
 /// Configurations for each split board
 ///
-/// Either ble_addr or serial must be set, but not both.
+/// One of ble_addr, serial or i2c must be set, according to the connection type.
 #[allow(unused)]
 #[derive(Clone, Debug, Default, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -220,6 +325,8 @@ pub struct SplitBoardConfig {
     pub ble_addr: Option<[u8; 6]>,
     /// Serial config, the vector length should be 1 for peripheral
     pub serial: Option<Vec<SerialConfig>>,
+    /// I2C config, the address is required for peripheral
+    pub i2c: Option<SplitI2cConfig>,
     /// Matrix config for the split
     pub matrix: MatrixConfig,
     /// Input device config for the split
@@ -234,6 +341,16 @@ pub struct SerialConfig {
     pub rx_pin: String,
 }
 
+/// I2C config of split
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SplitI2cConfig {
+    pub instance: String,
+    pub sda: String,
+    pub scl: String,
+    /// I2C address of the peripheral, it's not used by central
+    pub address: Option<u8>,
+}
+
 /// Duration in milliseconds
 #[derive(Clone, Debug, Deserialize)]
 pub struct DurationMillis(#[serde(deserialize_with = "parse_duration_millis")] pub u64);
@@ -246,6 +363,10 @@ const fn default_false() -> bool {
     false
 }
 
+const fn default_shift_register_chips() -> usize {
+    1
+}
+
 fn parse_duration_millis<'de, D: de::Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
     let input: String = de::Deserialize::deserialize(deserializer)?;
     let num = input.trim_end_matches(|c: char| !c.is_numeric());
@@ -298,6 +419,22 @@ pub struct EncoderConfig {
 #[serde(deny_unknown_fields)]
 pub struct PointingDeviceConfig {
     pub interface: Option<CommunicationProtocol>,
+    /// Motion counts per wheel unit in drag-scroll mode
+    pub scroll_divisor: Option<u8>,
+    /// Lock drag-scroll to the dominant axis
+    pub scroll_snap: Option<bool>,
+    /// Invert the drag-scroll direction
+    pub invert_scroll: Option<bool>,
+    /// Motion divisor in precision mode
+    pub precision_divisor: Option<u8>,
+    /// Motion divisor in sniping mode
+    pub sniping_divisor: Option<u8>,
+    /// Layer which enables drag-scroll mode
+    pub drag_scroll_layer: Option<u8>,
+    /// Layer which enables precision mode
+    pub precision_layer: Option<u8>,
+    /// Layer which enables sniping mode
+    pub sniping_layer: Option<u8>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
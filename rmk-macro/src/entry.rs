@@ -4,6 +4,7 @@ use quote::{format_ident, quote};
 use syn::{ItemFn, ItemMod};
 
 use crate::{
+    input_device::expand_processor_chain,
     keyboard::Overwritten,
     keyboard_config::{BoardConfig, CommunicationConfig, KeyboardConfig},
     ChipSeries,
@@ -59,35 +60,79 @@ pub(crate) fn rmk_entry_select(keyboard_config: &KeyboardConfig) -> TokenStream2
                         ::rmk::run_rmk(&keymap, driver, storage, light_controller, rmk_config),
                     };
                     let mut tasks = vec![matrix_task, rmk_task, keyboard_task];
-                    let central_serials = split_config
-                        .central
-                        .serial
-                        .clone()
-                        .expect("No serial defined for central");
-                    split_config
-                        .peripheral
-                        .iter()
-                        .enumerate()
-                        .for_each(|(idx, p)| {
+                    tasks.extend(expand_processor_chain(keyboard_config));
+                    if split_config.connection == "i2c" {
+                        split_config.peripheral.iter().enumerate().for_each(|(idx, p)| {
                             let row = p.rows;
                             let col = p.cols;
                             let row_offset = p.row_offset;
                             let col_offset = p.col_offset;
-                            let uart_instance = format_ident!("{}", central_serials.get(idx).expect("No or not enough serial defined for peripheral in central").instance.to_lowercase());
+                            let address = p
+                                .i2c
+                                .as_ref()
+                                .and_then(|i2c| i2c.address)
+                                .expect("No i2c address defined for peripheral");
                             tasks.push(quote! {
-                                ::rmk::split::central::run_peripheral_manager::<#row, #col, #row_offset, #col_offset, _>(
+                                ::rmk::split::central::run_i2c_peripheral_manager::<#row, #col, #row_offset, #col_offset, _>(
                                     #idx,
-                                    #uart_instance,
+                                    ::rmk::embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice::new(&split_i2c_bus),
+                                    #address,
                                 )
                             });
                         });
+                    } else {
+                        let central_serials = split_config
+                            .central
+                            .serial
+                            .clone()
+                            .expect("No serial defined for central");
+                        split_config
+                            .peripheral
+                            .iter()
+                            .enumerate()
+                            .for_each(|(idx, p)| {
+                                let row = p.rows;
+                                let col = p.cols;
+                                let row_offset = p.row_offset;
+                                let col_offset = p.col_offset;
+                                let uart_instance = format_ident!("{}", central_serials.get(idx).expect("No or not enough serial defined for peripheral in central").instance.to_lowercase());
+                                if split_config.ee_hands.is_some() {
+                                    // The peripheral is the opposite half, whose offsets are chosen at boot
+                                    tasks.push(quote! {
+                                        ::rmk::split::central::run_peripheral_manager_with_offset::<#row, #col, _>(
+                                            #idx,
+                                            ee_hands_layout.offset(handedness.opposite()),
+                                            #uart_instance,
+                                        )
+                                    });
+                                } else {
+                                    tasks.push(quote! {
+                                        ::rmk::split::central::run_peripheral_manager::<#row, #col, #row_offset, #col_offset, _>(
+                                            #idx,
+                                            #uart_instance,
+                                        )
+                                    });
+                                }
+                            });
+                    }
                     join_all_tasks(tasks)
                 }
-                ChipSeries::Nrf52 => {
-                    let rmk_task = quote! {
-                        ::rmk::run_rmk(&keymap, driver, storage, light_controller, rmk_config, sd),
+                ChipSeries::Nrf52 | ChipSeries::Esp32 => {
+                    let esp32 = keyboard_config.chip.series == ChipSeries::Esp32;
+                    if esp32 && split_config.connection != "ble" {
+                        panic!("Only BLE split is supported for esp32");
+                    }
+                    let rmk_task = if esp32 {
+                        quote! {
+                            ::rmk::run_rmk(&keymap, storage, light_controller, rmk_config),
+                        }
+                    } else {
+                        quote! {
+                            ::rmk::run_rmk(&keymap, driver, storage, light_controller, rmk_config, sd),
+                        }
                     };
                     let mut tasks = vec![matrix_task, rmk_task, keyboard_task];
+                    tasks.extend(expand_processor_chain(keyboard_config));
                     split_config.peripheral.iter().enumerate().for_each(|(idx, p)| {
                         let row = p.rows ;
                         let col = p.cols ;
@@ -101,9 +146,17 @@ pub(crate) fn rmk_entry_select(keyboard_config: &KeyboardConfig) -> TokenStream2
                             )
                         });
                     });
-                    join_all_tasks(tasks)
+                    let run_tasks = join_all_tasks(tasks);
+                    if esp32 {
+                        // Peripherals only accept the central's address
+                        quote! {
+                            ::rmk::split::esp::set_ble_addr(central_addr);
+                            ::esp_idf_svc::hal::task::block_on(async { #run_tasks });
+                        }
+                    } else {
+                        run_tasks
+                    }
                 }
-                ChipSeries::Esp32 => panic!("Split for esp32 isn't implemented yet"),
             }
         }
         _ => rmk_entry_default(keyboard_config),
@@ -115,58 +168,44 @@ pub(crate) fn rmk_entry_select(keyboard_config: &KeyboardConfig) -> TokenStream2
 }
 
 pub(crate) fn rmk_entry_default(keyboard_config: &KeyboardConfig) -> TokenStream2 {
-    match keyboard_config.chip.series {
+    let devices_task = quote! {
+        ::rmk::run_devices! (
+            (matrix) => ::rmk::channel::EVENT_CHANNEL,
+        )
+    };
+    let keyboard_task = quote! { keyboard.run() };
+    let rmk_task = match keyboard_config.chip.series {
         ChipSeries::Nrf52 => match keyboard_config.communication {
-            CommunicationConfig::Usb(_) => {
-                quote! {
-                    ::rmk::futures::future::join3(
-                        ::rmk::run_devices! (
-                            (matrix) => ::rmk::channel::EVENT_CHANNEL,
-                        ),
-                        keyboard.run(),
-                        ::rmk::run_rmk(&keymap, driver, storage, light_controller, rmk_config),
-                    ).await;
-                }
-            }
+            CommunicationConfig::Usb(_) => quote! {
+                ::rmk::run_rmk(&keymap, driver, storage, light_controller, rmk_config)
+            },
             CommunicationConfig::Both(_, _) => quote! {
-                ::rmk::futures::future::join3(
-                    ::rmk::run_devices! (
-                        (matrix) => ::rmk::channel::EVENT_CHANNEL,
-                    ),
-                    keyboard.run(),
-                    ::rmk::run_rmk(&keymap, driver, storage, light_controller, rmk_config, sd),
-                ).await;
+                ::rmk::run_rmk(&keymap, driver, storage, light_controller, rmk_config, sd)
             },
             CommunicationConfig::Ble(_) => quote! {
-                ::rmk::futures::future::join3(
-                    ::rmk::run_devices! (
-                        (matrix) => ::rmk::channel::EVENT_CHANNEL,
-                    ),
-                    keyboard.run(),
-                    ::rmk::run_rmk(&keymap, storage, light_controller, rmk_config, sd),
-                ).await;
+                ::rmk::run_rmk(&keymap, storage, light_controller, rmk_config, sd)
             },
-            CommunicationConfig::None => quote! {},
+            CommunicationConfig::None => return quote! {},
         },
+        ChipSeries::Esp32 => quote! {
+            ::rmk::run_rmk(&keymap, storage, light_controller, rmk_config)
+        },
+        _ => quote! {
+            ::rmk::run_rmk(&keymap, driver, storage, light_controller, rmk_config)
+        },
+    };
+
+    let mut tasks = vec![devices_task, keyboard_task, rmk_task];
+    tasks.extend(expand_processor_chain(keyboard_config));
+
+    match keyboard_config.chip.series {
         ChipSeries::Esp32 => quote! {
             ::esp_idf_svc::hal::task::block_on(
-                ::rmk::futures::future::join3(
-                    ::rmk::run_devices! (
-                        (matrix) => ::rmk::channel::EVENT_CHANNEL,
-                    ),
-                    keyboard.run(),
-                    ::rmk::run_rmk(&keymap, storage, light_controller, rmk_config),
-                )
+                ::rmk::join_all!(#(#tasks), *)
             );
         },
         _ => quote! {
-            ::rmk::futures::future::join3(
-                ::rmk::run_devices! (
-                    (matrix) => ::rmk::channel::EVENT_CHANNEL,
-                ),
-                keyboard.run(),
-                ::rmk::run_rmk(&keymap, driver, storage, light_controller, rmk_config),
-            ).await;
+            ::rmk::join_all!(#(#tasks), *).await;
         },
     }
 }
@@ -4,12 +4,18 @@ use quote::{format_ident, quote};
 pub(crate) fn convert_output_pins_to_initializers(
     chip: &ChipModel,
     pins: Vec<String>,
+    low_active: bool,
 ) -> proc_macro2::TokenStream {
     let mut initializers = proc_macro2::TokenStream::new();
     let mut idents = vec![];
     let pin_initializers = pins
         .into_iter()
-        .map(|p| (p.clone(), convert_gpio_str_to_output_pin(chip, p, false)))
+        .map(|p| {
+            (
+                p.clone(),
+                convert_gpio_str_to_output_pin(chip, p, low_active),
+            )
+        })
         .map(|(p, ts)| {
             let ident_name = format_ident!("{}", p.to_lowercase());
             idents.push(ident_name.clone());
@@ -25,6 +31,7 @@ pub(crate) fn convert_input_pins_to_initializers(
     chip: &ChipModel,
     pins: Vec<String>,
     async_matrix: bool,
+    low_active: bool,
 ) -> proc_macro2::TokenStream {
     let mut initializers = proc_macro2::TokenStream::new();
     let mut idents = vec![];
@@ -33,7 +40,7 @@ pub(crate) fn convert_input_pins_to_initializers(
         .map(|p| {
             (
                 p.clone(),
-                convert_gpio_str_to_input_pin(chip, p, async_matrix, false),
+                convert_gpio_str_to_input_pin(chip, p, async_matrix, low_active),
             )
         })
         .map(|(p, ts)| {
@@ -85,6 +92,97 @@ pub(crate) fn convert_direct_pins_to_initializers(
     initializers
 }
 
+/// Initialize flex pins as an array named `array_name`, the pins are wrapped by the type generated by [`expand_flex_pin_type`]
+pub(crate) fn convert_flex_pins_to_initializers(
+    chip: &ChipModel,
+    pins: Vec<String>,
+    array_name: &str,
+) -> proc_macro2::TokenStream {
+    let mut initializers = proc_macro2::TokenStream::new();
+    let mut idents = vec![];
+    let pin_initializers = pins.into_iter().map(|p| {
+        let gpio_ident = format_ident!("{}", p);
+        let ident_name = format_ident!("{}", p.to_lowercase());
+        idents.push(ident_name.clone());
+        let flex = match chip.series {
+            ChipSeries::Stm32 => quote! { ::embassy_stm32::gpio::Flex::new(p.#gpio_ident) },
+            ChipSeries::Nrf52 => quote! {
+                ::embassy_nrf::gpio::Flex::new(::embassy_nrf::gpio::AnyPin::from(p.#gpio_ident))
+            },
+            ChipSeries::Rp2040 => quote! {
+                ::embassy_rp::gpio::Flex::new(::embassy_rp::gpio::AnyPin::from(p.#gpio_ident))
+            },
+            ChipSeries::Esp32 => quote! {
+                compile_error!("keyboard.toml: duplex and charlieplex matrices are not supported on esp32 yet")
+            },
+        };
+        quote! { let #ident_name = MatrixFlexPin(#flex); }
+    });
+    initializers.extend(pin_initializers);
+    let array_ident = format_ident!("{}", array_name);
+    initializers.extend(quote! {let #array_ident = [#(#idents), *];});
+    initializers
+}
+
+/// Generate `MatrixFlexPin`, which implements `::rmk::matrix::FlexPin` for the `Flex` pin of the chip
+pub(crate) fn expand_flex_pin_type(chip: &ChipModel) -> proc_macro2::TokenStream {
+    let (flex, set_as_input, set_as_output) = match chip.series {
+        ChipSeries::Stm32 => (
+            quote! { ::embassy_stm32::gpio::Flex<'static> },
+            quote! {
+                self.0.set_as_input(if pull_up { ::embassy_stm32::gpio::Pull::Up } else { ::embassy_stm32::gpio::Pull::Down });
+            },
+            quote! {
+                self.0.set_level(if high { ::embassy_stm32::gpio::Level::High } else { ::embassy_stm32::gpio::Level::Low });
+                self.0.set_as_output(::embassy_stm32::gpio::Speed::VeryHigh);
+            },
+        ),
+        ChipSeries::Nrf52 => (
+            quote! { ::embassy_nrf::gpio::Flex<'static> },
+            quote! {
+                self.0.set_as_input(if pull_up { ::embassy_nrf::gpio::Pull::Up } else { ::embassy_nrf::gpio::Pull::Down });
+            },
+            quote! {
+                self.0.set_level(if high { ::embassy_nrf::gpio::Level::High } else { ::embassy_nrf::gpio::Level::Low });
+                self.0.set_as_output(::embassy_nrf::gpio::OutputDrive::Standard);
+            },
+        ),
+        ChipSeries::Rp2040 => (
+            quote! { ::embassy_rp::gpio::Flex<'static> },
+            quote! {
+                self.0.set_as_input();
+                self.0.set_pull(if pull_up { ::embassy_rp::gpio::Pull::Up } else { ::embassy_rp::gpio::Pull::Down });
+            },
+            quote! {
+                self.0.set_level(if high { ::embassy_rp::gpio::Level::High } else { ::embassy_rp::gpio::Level::Low });
+                self.0.set_as_output();
+            },
+        ),
+        ChipSeries::Esp32 => {
+            return quote! {
+                compile_error!("keyboard.toml: duplex and charlieplex matrices are not supported on esp32 yet");
+            }
+        }
+    };
+    quote! {
+        struct MatrixFlexPin(#flex);
+
+        impl ::rmk::matrix::FlexPin for MatrixFlexPin {
+            fn set_as_input(&mut self, pull_up: bool) {
+                #set_as_input
+            }
+
+            fn set_as_output(&mut self, high: bool) {
+                #set_as_output
+            }
+
+            fn is_high(&mut self) -> bool {
+                self.0.is_high()
+            }
+        }
+    }
+}
+
 pub(crate) fn convert_gpio_str_to_output_pin(
     chip: &ChipModel,
     gpio_name: String,
@@ -140,7 +238,7 @@ pub(crate) fn convert_gpio_str_to_input_pin(
                     Some(pin_num) => {
                         let pin_num_ident = format_ident!("EXTI{}", pin_num);
                         quote! {
-                            ::embassy_stm32::exti::ExtiInput::new(p.#gpio_ident, p.#pin_num_ident, ::embassy_stm32::gpio::Pull::Down)
+                            ::embassy_stm32::exti::ExtiInput::new(p.#gpio_ident, p.#pin_num_ident, ::embassy_stm32::gpio::Pull::#default_pull_ident)
                         }
                     }
                     None => {
@@ -150,7 +248,7 @@ pub(crate) fn convert_gpio_str_to_input_pin(
                 }
             } else {
                 quote! {
-                    ::embassy_stm32::gpio::Input::new(p.#gpio_ident, ::embassy_stm32::gpio::Pull::Down)
+                    ::embassy_stm32::gpio::Input::new(p.#gpio_ident, ::embassy_stm32::gpio::Pull::#default_pull_ident)
                 }
             }
         }
@@ -0,0 +1,294 @@
+//! Initialize input devices and processors of RMK
+//!
+
+use proc_macro2::{Ident, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+
+use crate::config::{CommunicationProtocol, InputDeviceConfig, PointingDeviceConfig};
+use crate::gpio_config::{convert_gpio_str_to_input_pin, convert_gpio_str_to_output_pin};
+use crate::keyboard_config::{BoardConfig, KeyboardConfig};
+use crate::{ChipModel, ChipSeries};
+
+/// Get all pointing device configs of the keyboard, including the ones of split central
+fn get_pointing_configs(keyboard_config: &KeyboardConfig) -> Vec<PointingDeviceConfig> {
+    let mut pointing = keyboard_config
+        .input_device
+        .pointing
+        .clone()
+        .unwrap_or_default();
+    if let BoardConfig::Split(split_config) = &keyboard_config.board {
+        if let Some(input_device) = &split_config.central.input_device {
+            pointing.extend(input_device.pointing.clone().unwrap_or_default());
+        }
+    }
+    pointing
+}
+
+fn expand_option_u8(v: Option<u8>) -> TokenStream2 {
+    match v {
+        Some(v) => quote! { ::core::option::Option::Some(#v) },
+        None => quote! { ::core::option::Option::None },
+    }
+}
+
+fn expand_pointing_processor_config(pointing: &PointingDeviceConfig) -> TokenStream2 {
+    let mut fields = TokenStream2::new();
+    if let Some(v) = pointing.scroll_divisor {
+        fields.extend(quote! { scroll_divisor: #v, });
+    }
+    if let Some(v) = pointing.scroll_snap {
+        fields.extend(quote! { scroll_snap: #v, });
+    }
+    if let Some(v) = pointing.invert_scroll {
+        fields.extend(quote! { invert_scroll: #v, });
+    }
+    if let Some(v) = pointing.precision_divisor {
+        fields.extend(quote! { precision_divisor: #v, });
+    }
+    if let Some(v) = pointing.sniping_divisor {
+        fields.extend(quote! { sniping_divisor: #v, });
+    }
+    let drag_scroll_layer = expand_option_u8(pointing.drag_scroll_layer);
+    let precision_layer = expand_option_u8(pointing.precision_layer);
+    let sniping_layer = expand_option_u8(pointing.sniping_layer);
+    quote! {
+        ::rmk::input_device::pointing::PointingProcessorConfig {
+            #fields
+            drag_scroll_layer: #drag_scroll_layer,
+            precision_layer: #precision_layer,
+            sniping_layer: #sniping_layer,
+            ..Default::default()
+        }
+    }
+}
+
+/// Whether any split peripheral has encoders
+fn has_peripheral_encoder(keyboard_config: &KeyboardConfig) -> bool {
+    get_peripheral_input_devices(keyboard_config)
+        .iter()
+        .any(|(_, d)| d.encoder.as_ref().is_some_and(|e| !e.is_empty()))
+}
+
+/// Ids of split peripherals which have trackpads, aka pointing devices with `interface`
+fn get_peripheral_trackpads(keyboard_config: &KeyboardConfig) -> Vec<usize> {
+    get_peripheral_input_devices(keyboard_config)
+        .into_iter()
+        .filter(|(_, d)| {
+            d.pointing
+                .as_ref()
+                .is_some_and(|p| p.iter().any(|p| p.interface.is_some()))
+        })
+        .map(|(id, _)| id)
+        .collect()
+}
+
+/// Names of all input processors which should be run in the processor chain
+fn get_processor_names(keyboard_config: &KeyboardConfig) -> Vec<TokenStream2> {
+    let mut processors = Vec::new();
+    if !get_pointing_configs(keyboard_config).is_empty() {
+        processors.push(quote! { pointing_processor });
+    }
+    for id in get_peripheral_trackpads(keyboard_config) {
+        let touchpad_processor = format_ident!("peripheral{}_touchpad_processor", id);
+        processors.push(quote! { #touchpad_processor });
+    }
+    if has_peripheral_encoder(keyboard_config) {
+        processors.push(quote! { encoder_processor });
+    }
+    processors
+}
+
+/// Input device configs of split peripherals which have input devices, as `(peripheral id, config)`
+fn get_peripheral_input_devices(
+    keyboard_config: &KeyboardConfig,
+) -> Vec<(usize, InputDeviceConfig)> {
+    match &keyboard_config.board {
+        BoardConfig::Split(split_config) => split_config
+            .peripheral
+            .iter()
+            .enumerate()
+            .filter_map(|(id, p)| p.input_device.clone().map(|d| (id, d)))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Expand the initialization of input devices and processors
+pub(crate) fn expand_input_device_config(keyboard_config: &KeyboardConfig) -> TokenStream2 {
+    let mut initializers = TokenStream2::new();
+
+    // All pointing events are sent to `EVENT_CHANNEL`, so only one pointing processor is needed.
+    // The first `[[input_device.pointing]]` decides the config of the processor.
+    if let Some(pointing) = get_pointing_configs(keyboard_config).first() {
+        let config = expand_pointing_processor_config(pointing);
+        initializers.extend(quote! {
+            let mut pointing_processor = ::rmk::input_device::pointing::PointingProcessor::new(&keymap, #config);
+        });
+    }
+
+    // Trackpads of each peripheral have their own gesture state, so they're processed by their own processors
+    for id in get_peripheral_trackpads(keyboard_config) {
+        let touchpad_processor = format_ident!("peripheral{}_touchpad_processor", id);
+        let id = id as u8;
+        initializers.extend(quote! {
+            let mut #touchpad_processor = ::rmk::input_device::touchpad::TouchpadProcessor::new(
+                &keymap,
+                ::rmk::input_device::touchpad::TouchpadConfig {
+                    source: ::core::option::Option::Some(::rmk::event::EventSource::Peripheral(#id)),
+                    ..::core::default::Default::default()
+                },
+            );
+        });
+    }
+
+    // Encoders are told apart by the source and the id of events
+    if has_peripheral_encoder(keyboard_config) {
+        initializers.extend(quote! {
+            let mut encoder_processor = ::rmk::input_device::rotary_encoder::RotaryEncoderProcessor::new(&keymap);
+        });
+    }
+
+    initializers
+}
+
+/// Expand the processor chain task, returns `None` if there's no processor
+pub(crate) fn expand_processor_chain(keyboard_config: &KeyboardConfig) -> Option<TokenStream2> {
+    let processors = get_processor_names(keyboard_config);
+    if processors.is_empty() {
+        return None;
+    }
+    // Events of split peripherals are sent to `EVENT_CHANNEL` as well
+    Some(quote! {
+        ::rmk::run_processor_chain! {
+            ::rmk::channel::EVENT_CHANNEL => [#(#processors), *],
+        }
+    })
+}
+
+/// Initialize input devices of a split peripheral, returns the initializers and the names of devices,
+/// or a compile error if the devices can't be initialized.
+///
+/// Pointing devices of peripherals are Cirque Pinnacle trackpads, which work in relative mode,
+/// their events are processed by the central.
+pub(crate) fn expand_peripheral_input_devices(
+    chip: &ChipModel,
+    input_device: &Option<InputDeviceConfig>,
+    async_matrix: bool,
+) -> Result<(TokenStream2, Vec<Ident>), TokenStream2> {
+    let mut initializers = TokenStream2::new();
+    let mut devices = Vec::new();
+    let Some(input_device) = input_device else {
+        return Ok((initializers, devices));
+    };
+
+    for (idx, encoder) in input_device
+        .encoder
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+    {
+        let name = format_ident!("encoder{}", idx);
+        let id = idx as u8;
+        // Encoder pins are connected to the ground when active
+        let pin_a = convert_gpio_str_to_input_pin(chip, encoder.pin_a, async_matrix, true);
+        let pin_b = convert_gpio_str_to_input_pin(chip, encoder.pin_b, async_matrix, true);
+        initializers.extend(quote! {
+            let mut #name = ::rmk::input_device::rotary_encoder::RotaryEncoder::new(#pin_a, #pin_b, #id);
+        });
+        devices.push(name);
+    }
+
+    let mut spi_used = false;
+    for (idx, pointing) in input_device
+        .pointing
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+    {
+        let Some(interface) = pointing.interface else {
+            let message = format!(
+                "keyboard.toml: `interface` of the pointing device {} of the split peripheral is required",
+                idx
+            );
+            return Err(quote! { compile_error!(#message); });
+        };
+        let name = format_ident!("pointing{}", idx);
+        let bus = match chip.series {
+            ChipSeries::Rp2040 => match interface {
+                CommunicationProtocol::I2C(i2c) => {
+                    let instance = format_ident!("{}", i2c.instance);
+                    let irq = format_ident!("{}_IRQ", i2c.instance);
+                    let irq_name = format_ident!("IrqsPointing{}", idx);
+                    let sda = format_ident!("{}", i2c.sda);
+                    let scl = format_ident!("{}", i2c.scl);
+                    let address = i2c.address;
+                    quote! {
+                        ::embassy_rp::bind_interrupts!(struct #irq_name {
+                            #irq => ::embassy_rp::i2c::InterruptHandler<::embassy_rp::peripherals::#instance>;
+                        });
+                        let pointing_bus = ::rmk::input_device::pinnacle::PinnacleI2c::new(
+                            ::embassy_rp::i2c::I2c::new_async(p.#instance, p.#scl, p.#sda, #irq_name, ::embassy_rp::i2c::Config::default()),
+                            #address,
+                        );
+                    }
+                }
+                CommunicationProtocol::SPI(spi) => {
+                    // DMA channels are fixed, so only one SPI pointing device is supported
+                    if spi_used {
+                        return Err(quote! {
+                            compile_error!("keyboard.toml: only one SPI pointing device is supported on a split peripheral");
+                        });
+                    }
+                    spi_used = true;
+                    let instance = format_ident!("{}", spi.instance);
+                    let sck = format_ident!("{}", spi.sck);
+                    let mosi = format_ident!("{}", spi.mosi);
+                    let miso = format_ident!("{}", spi.miso);
+                    let spi_bus = format_ident!("pointing_spi_bus{}", idx);
+                    // CS pin is idle at high level
+                    let Some(cs) = spi.cs else {
+                        return Err(quote! {
+                            compile_error!("keyboard.toml: `cs` of the SPI pointing device is required");
+                        });
+                    };
+                    let cs = convert_gpio_str_to_output_pin(chip, cs, true);
+                    quote! {
+                        // Pinnacle uses SPI mode 1
+                        let mut spi_config = ::embassy_rp::spi::Config::default();
+                        spi_config.phase = ::embassy_rp::spi::Phase::CaptureOnSecondTransition;
+                        let #spi_bus = ::rmk::embassy_sync::mutex::Mutex::<::rmk::embassy_sync::blocking_mutex::raw::NoopRawMutex, _>::new(
+                            ::embassy_rp::spi::Spi::new(p.#instance, p.#sck, p.#mosi, p.#miso, p.DMA_CH3, p.DMA_CH4, spi_config),
+                        );
+                        let pointing_bus = ::rmk::input_device::pinnacle::PinnacleSpi::new(
+                            ::rmk::embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice::new(&#spi_bus, #cs),
+                        );
+                    }
+                }
+            },
+            _ => {
+                let message = format!(
+                    "keyboard.toml: pointing devices of split peripherals on {:?} aren't supported yet",
+                    chip.series
+                );
+                return Err(quote! { compile_error!(#message); });
+            }
+        };
+        initializers.extend(quote! {
+            let mut #name = {
+                #bus
+                ::rmk::input_device::pinnacle::Pinnacle::new(
+                    pointing_bus,
+                    ::rmk::input_device::pinnacle::PinnacleConfig {
+                        mode: ::rmk::input_device::pinnacle::PinnacleMode::Relative,
+                        ..::core::default::Default::default()
+                    },
+                )
+            };
+        });
+        devices.push(name);
+    }
+
+    Ok((initializers, devices))
+}
@@ -11,17 +11,23 @@ use crate::{
     comm::expand_usb_init,
     config::MatrixType,
     entry::expand_rmk_entry,
-    feature::{get_rmk_features, is_feature_enabled},
+    feature::get_rmk_features,
     flash::expand_flash_init,
     import::expand_imports,
+    input_device::expand_input_device_config,
     keyboard_config::{
         expand_keyboard_info, expand_vial_config, read_keyboard_toml_config, BoardConfig,
         KeyboardConfig,
     },
     layout::expand_default_keymap,
     light::expand_light_config,
-    matrix::expand_matrix_config,
-    split::central::expand_split_central_config,
+    matrix::{
+        expand_debouncer, expand_flex_matrix, expand_matrix_config, shift_register_input_output_num,
+    },
+    split::{
+        central::{expand_split_central_config, expand_split_rmk_config},
+        ee_hands::expand_ee_hands_init,
+    },
     ChipSeries,
 };
 
@@ -42,12 +48,6 @@ pub(crate) fn parse_keyboard_mod(item_mod: ItemMod) -> TokenStream2 {
         Err(e) => return e,
     };
 
-    if let Some(m) = toml_config.clone().matrix {
-        if m.row2col {
-            eprintln!("row2col is enabled, please ensure that you have updated your Cargo.toml, disabled default features(col2row is enabled as default feature)");
-        }
-    }
-
     let keyboard_config = match KeyboardConfig::new(toml_config) {
         Ok(c) => c,
         Err(e) => return e,
@@ -122,14 +122,17 @@ fn expand_main(
     let chip_init = expand_chip_init(keyboard_config, &item_mod);
     let usb_init = expand_usb_init(keyboard_config, &item_mod);
     let flash_init = expand_flash_init(keyboard_config);
+    let ee_hands_init = expand_ee_hands_init(keyboard_config, rmk_features);
     let light_config = expand_light_config(keyboard_config);
     let behavior_config = expand_behavior_config(keyboard_config);
     let split_central_config = expand_split_central_config(keyboard_config);
+    let set_split_config = expand_split_rmk_config(keyboard_config);
     let matrix_config = expand_matrix_config(keyboard_config, rmk_features);
     let (ble_config, set_ble_config) = expand_ble_config(keyboard_config);
     let keymap_and_storage = expand_keymap_and_storage(keyboard_config);
     let matrix_and_keyboard = expand_matrix_and_keyboard_init(keyboard_config, rmk_features);
     let controller = expand_controller_init(keyboard_config);
+    let input_device_config = expand_input_device_config(keyboard_config);
     let run_rmk = expand_rmk_entry(keyboard_config, &item_mod);
 
     let main_function_sig = if keyboard_config.chip.series == ChipSeries::Esp32 {
@@ -174,6 +177,9 @@ fn expand_main(
             // Initialize flash driver as `flash` and storage config as `storage_config`
             #flash_init
 
+            // Detect the role and handedness of the half(if needed), as `handedness` and `ee_hands_layout`
+            #ee_hands_init
+
             // Initialize ble config as `ble_battery_config`
             #ble_config
 
@@ -184,6 +190,7 @@ fn expand_main(
                 storage_config,
                 behavior_config,
                 #set_ble_config
+                #set_split_config
                 ..Default::default()
             };
 
@@ -195,7 +202,8 @@ fn expand_main(
             // Initialize the matrix + keyboard, as `matrix` and `keyboard`
             #matrix_and_keyboard
 
-            // TODO: Initialize other devices and processors
+            // Initialize other devices and processors
+            #input_device_config
 
             // Start
             #run_rmk
@@ -230,59 +238,69 @@ pub(crate) fn expand_matrix_and_keyboard_init(
     keyboard_config: &KeyboardConfig,
     rmk_features: &Option<Vec<String>>,
 ) -> TokenStream2 {
-    let rapid_debouncer_enabled = is_feature_enabled(rmk_features, "rapid_debouncer");
-    let col2row_enabled = is_feature_enabled(rmk_features, "col2row");
-    let input_output_num = if col2row_enabled {
-        quote! { ROW, COL }
-    } else {
-        quote! { COL, ROW }
-    };
-
-    let debouncer_type = if rapid_debouncer_enabled {
-        quote! { ::rmk::debounce::fast_debouncer::RapidDebouncer }
-    } else {
-        quote! { ::rmk::debounce::default_debouncer::DefaultDebouncer }
-    };
-
     let matrix = match &keyboard_config.board {
-        BoardConfig::Normal(_matrix_config) => quote! {
-            let debouncer = #debouncer_type::<#input_output_num>::new();
-            let mut matrix = ::rmk::matrix::Matrix::<_, _, _, #input_output_num>::new(input_pins, output_pins, debouncer);
-        },
+        BoardConfig::Normal(matrix_config) => {
+            let col2row = !matrix_config.row2col;
+            let low_active = matrix_config.low_active;
+            let ghost_detection = matrix_config.ghost_detection;
+            match matrix_config.matrix_type {
+                MatrixType::shift_register => {
+                    let (input_num, output_num) = shift_register_input_output_num(matrix_config);
+                    let debouncer = expand_debouncer(
+                        keyboard_config,
+                        rmk_features,
+                        quote! { #input_num, #output_num },
+                    );
+                    quote! {
+                        #debouncer
+                        let mut matrix = ::rmk::shift_register::ShiftRegisterMatrix::<_, _, _, 0, 0, #input_num, #output_num, #col2row>::new(input_pins, output_pins, debouncer, #low_active);
+                    }
+                }
+                MatrixType::duplex | MatrixType::charlieplex => {
+                    expand_flex_matrix(keyboard_config, rmk_features, matrix_config, 0, 0)
+                }
+                _ => {
+                    let input_output_num = if col2row {
+                        quote! { ROW, COL }
+                    } else {
+                        quote! { COL, ROW }
+                    };
+                    let debouncer =
+                        expand_debouncer(keyboard_config, rmk_features, input_output_num.clone());
+                    quote! {
+                        #debouncer
+                        let mut matrix = ::rmk::matrix::Matrix::<_, _, _, #input_output_num, #col2row>::new(input_pins, output_pins, debouncer, #low_active).with_ghost_detection(#ghost_detection);
+                    }
+                }
+            }
+        }
         BoardConfig::DirectPin(matrix_config) => {
             let low_active = matrix_config.direct_pin_low_active;
+            let debouncer = expand_debouncer(keyboard_config, rmk_features, quote! { COL, ROW });
             quote! {
-                let debouncer = #debouncer_type::<COL, ROW>::new();
-                let mut matrix = ::rmk::direct_pin::DirectPinMatrix::<_, _, #input_output_num, SIZE>::new(direct_pins, debouncer, #low_active);
+                #debouncer
+                let mut matrix = ::rmk::direct_pin::DirectPinMatrix::<_, _, ROW, COL, SIZE>::new(direct_pins, debouncer, #low_active);
             }
         }
-        BoardConfig::Split(split_config) => {
-            // Matrix config for split central
-            let central_row = split_config.central.rows;
-            let central_row_offset = split_config.central.row_offset;
-            let central_col = split_config.central.cols;
-            let central_col_offset = split_config.central.col_offset;
-            let input_output_pin_num = if split_config.central.matrix.row2col {
-                quote! { #central_row_offset, #central_col_offset, #central_col, #central_row }
-            } else {
-                quote! { #central_row_offset, #central_col_offset, #central_row, #central_col }
-            };
-            match split_config.central.matrix.matrix_type {
-                MatrixType::normal => quote! {
-                    let debouncer = #debouncer_type::<#input_output_num>::new();
-                    let mut matrix = ::rmk::split::central::CentralMatrix::<_, _, _, #input_output_pin_num>::new(input_pins, output_pins, debouncer);
-                },
-                MatrixType::direct_pin => {
-                    let low_active = split_config.central.matrix.direct_pin_low_active;
-                    let size =
-                        split_config.central.rows as usize * split_config.central.cols as usize;
-                    quote! {
-                        let debouncer = #debouncer_type::<COL, ROW>::new();
-                        let mut matrix = ::rmk::split::central::CentralDirectPinMatrix::<_, _, #central_row_offset, #central_col_offset, #central_row, #central_col, #size>::new(direct_pins, debouncer, #low_active);
-                    }
+        BoardConfig::Split(split_config) => match &split_config.ee_hands {
+            // The offsets of the half are chosen at boot, see `expand_ee_hands_init`
+            Some(_) => {
+                let matrix = expand_split_central_matrix(keyboard_config, rmk_features, 0, 0);
+                quote! {
+                    #[allow(unused_mut)]
+                    let mut matrix = {
+                        #matrix
+                        ::rmk::split::ee_hands::OffsetMatrix::new(matrix, ee_hands_layout.offset(handedness))
+                    };
                 }
             }
-        }
+            None => expand_split_central_matrix(
+                keyboard_config,
+                rmk_features,
+                split_config.central.row_offset,
+                split_config.central.col_offset,
+            ),
+        },
     };
     quote! {
         let mut keyboard = ::rmk::keyboard::Keyboard::new(&keymap, rmk_config.behavior_config.clone());
@@ -290,6 +308,68 @@ pub(crate) fn expand_matrix_and_keyboard_init(
     }
 }
 
+/// Expand the matrix of the split central as `matrix`, with the given offsets
+pub(crate) fn expand_split_central_matrix(
+    keyboard_config: &KeyboardConfig,
+    rmk_features: &Option<Vec<String>>,
+    central_row_offset: usize,
+    central_col_offset: usize,
+) -> TokenStream2 {
+    let BoardConfig::Split(split_config) = &keyboard_config.board else {
+        unreachable!("the board isn't a split keyboard");
+    };
+    // Matrix config for split central
+    let central_row = split_config.central.rows;
+    let central_col = split_config.central.cols;
+    let col2row = !split_config.central.matrix.row2col;
+    let low_active = split_config.central.matrix.low_active;
+    let ghost_detection = split_config.central.matrix.ghost_detection;
+    let input_output_num = if col2row {
+        quote! { #central_row, #central_col }
+    } else {
+        quote! { #central_col, #central_row }
+    };
+    match split_config.central.matrix.matrix_type {
+        MatrixType::normal => {
+            let debouncer =
+                expand_debouncer(keyboard_config, rmk_features, input_output_num.clone());
+            quote! {
+                #debouncer
+                let mut matrix = ::rmk::split::central::CentralMatrix::<_, _, _, #central_row_offset, #central_col_offset, #input_output_num, #col2row>::new(input_pins, output_pins, debouncer, #low_active).with_ghost_detection(#ghost_detection);
+            }
+        }
+        MatrixType::direct_pin => {
+            let low_active = split_config.central.matrix.direct_pin_low_active;
+            let size = split_config.central.rows as usize * split_config.central.cols as usize;
+            let debouncer = expand_debouncer(keyboard_config, rmk_features, quote! { COL, ROW });
+            quote! {
+                #debouncer
+                let mut matrix = ::rmk::split::central::CentralDirectPinMatrix::<_, _, #central_row_offset, #central_col_offset, #central_row, #central_col, #size>::new(direct_pins, debouncer, #low_active);
+            }
+        }
+        MatrixType::shift_register => {
+            let (input_num, output_num) =
+                shift_register_input_output_num(&split_config.central.matrix);
+            let debouncer = expand_debouncer(
+                keyboard_config,
+                rmk_features,
+                quote! { #input_num, #output_num },
+            );
+            quote! {
+                #debouncer
+                let mut matrix = ::rmk::shift_register::ShiftRegisterMatrix::<_, _, _, #central_row_offset, #central_col_offset, #input_num, #output_num, #col2row>::new(input_pins, output_pins, debouncer, #low_active);
+            }
+        }
+        MatrixType::duplex | MatrixType::charlieplex => expand_flex_matrix(
+            keyboard_config,
+            rmk_features,
+            &split_config.central.matrix,
+            central_row_offset,
+            central_col_offset,
+        ),
+    }
+}
+
 fn expand_controller_init(keyboard_config: &KeyboardConfig) -> TokenStream2 {
     // TODO: Initialization for other controllers
     let output_pin_type = match keyboard_config.chip.series {
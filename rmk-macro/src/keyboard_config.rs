@@ -4,10 +4,12 @@ use serde::Deserialize;
 use std::fs;
 
 use crate::config::{
-    BehaviorConfig, BleConfig, DependencyConfig, KeyboardInfo, KeyboardTomlConfig, LayoutConfig,
-    LightConfig, MatrixConfig, MatrixType, SplitConfig, StorageConfig,
+    BehaviorConfig, BleConfig, DebounceConfig, DependencyConfig, InputDeviceConfig, KeyboardInfo,
+    KeyboardTomlConfig, LayoutConfig, LightConfig, MatrixConfig, MatrixType, ShiftRegisterChip,
+    SplitConfig, StorageConfig,
 };
 use crate::{
+    ble::check_battery_config,
     default_config::{
         esp32::default_esp32, nrf52810::default_nrf52810, nrf52832::default_nrf52832,
         nrf52840::default_nrf52840, rp2040::default_rp2040, stm32::default_stm32,
@@ -75,6 +77,8 @@ pub(crate) struct KeyboardConfig {
     pub(crate) chip: ChipModel,
     // Board config, normal or split
     pub(crate) board: BoardConfig,
+    // Debounce config, `None` if `[debounce]` is not set
+    pub(crate) debounce: Option<DebounceConfig>,
     // Layout config
     pub(crate) layout: LayoutConfig,
     // Behavior Config
@@ -85,9 +89,12 @@ pub(crate) struct KeyboardConfig {
     pub(crate) storage: StorageConfig,
     // Dependency config
     pub(crate) dependency: DependencyConfig,
+    // Input device config
+    pub(crate) input_device: InputDeviceConfig,
 }
 
 #[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub(crate) enum BoardConfig {
     Normal(MatrixConfig),
     Split(SplitConfig),
@@ -168,9 +175,23 @@ impl KeyboardConfig {
 
         // Board config
         config.board = Self::get_board_config(toml_config.matrix, toml_config.split)?;
+        if let Err(message) = Self::check_split_connection(&config.board, &config.chip) {
+            return rmk_compile_error!(message);
+        }
+        if let Err(message) = Self::check_shift_register_support(&config.board, &config.chip) {
+            return rmk_compile_error!(message);
+        }
+
+        // Debounce config
+        config.debounce = toml_config.debounce;
 
         // Layout config
         config.layout = Self::get_layout_from_toml(toml_config.layout)?;
+        if let Err(message) =
+            Self::check_flex_pin_matrix(&config.board, &config.layout, &config.chip)
+        {
+            return rmk_compile_error!(message);
+        }
 
         // Behavior config
         config.behavior =
@@ -181,10 +202,16 @@ impl KeyboardConfig {
 
         // Storage config
         config.storage = Self::get_storage_from_toml(config.storage, toml_config.storage);
+        if let Err(message) = Self::check_ee_hands(&config.board, &config.chip, &config.storage) {
+            return rmk_compile_error!(message);
+        }
 
         // Dependency config
         config.dependency = toml_config.dependency.unwrap_or_default();
 
+        // Input device config
+        config.input_device = toml_config.input_device.unwrap_or_default();
+
         Ok(config)
     }
 
@@ -315,11 +342,27 @@ impl KeyboardConfig {
                 config.adc_divider_measured =
                     config.adc_divider_measured.or(default.adc_divider_measured);
                 config.adc_divider_total = config.adc_divider_total.or(default.adc_divider_total);
+                config.passkey_entry = config.passkey_entry.or(default.passkey_entry);
+                config.adc_reference_mv = config.adc_reference_mv.or(default.adc_reference_mv);
+                config.adc_gain = config.adc_gain.or(default.adc_gain);
+                config.adc_resolution = config.adc_resolution.or(default.adc_resolution);
+                config.discharge_curve = config.discharge_curve.or(default.discharge_curve);
+                config.battery_filter_window = config
+                    .battery_filter_window
+                    .or(default.battery_filter_window);
+                config.battery_hysteresis =
+                    config.battery_hysteresis.or(default.battery_hysteresis);
                 Some(config)
             }
             (_, c) => c,
         };
 
+        if let Some(ref config) = ble_config {
+            if let Err(message) = check_battery_config(config) {
+                return rmk_compile_error!(message);
+            }
+        }
+
         match (usb_info, ble_config) {
             (Some(usb_info), None) => Ok(CommunicationConfig::Usb(usb_info)),
             (Some(usb_info), Some(ble_config)) => {
@@ -366,6 +409,28 @@ impl KeyboardConfig {
                             Ok(BoardConfig::DirectPin(m))
                         }
                     },
+                    MatrixType::shift_register => {
+                        match Self::check_shift_register_matrix(&m) {
+                            Ok(()) => Ok(BoardConfig::Normal(m)),
+                            Err(message) => rmk_compile_error!(message),
+                        }
+                    },
+                    MatrixType::duplex => {
+                        if m.row_pins.is_none() || m.col_pins.is_none() {
+                            rmk_compile_error!("`row_pins` and `col_pins` is required for duplex matrix".to_string())
+                        }
+                        else {
+                            Ok(BoardConfig::Normal(m))
+                        }
+                    },
+                    MatrixType::charlieplex => {
+                        if !matches!(&m.charlieplex_pins, Some(pins) if pins.len() >= 2) {
+                            rmk_compile_error!("`charlieplex_pins` with at least 2 pins is required for charlieplex matrix".to_string())
+                        }
+                        else {
+                            Ok(BoardConfig::Normal(m))
+                        }
+                    },
                 }
             },
             (None, None) => rmk_compile_error!("[matrix] section in keyboard.toml is required for non-split keyboard".to_string()),
@@ -373,8 +438,166 @@ impl KeyboardConfig {
         }
     }
 
+    /// Check the shift register matrix config, returns the error message if the config is invalid
+    pub(crate) fn check_shift_register_matrix(m: &MatrixConfig) -> Result<(), String> {
+        let Some(s) = &m.shift_register else {
+            return Err(
+                "`[matrix.shift_register]` is required for shift register matrix".to_string(),
+            );
+        };
+        if s.chips == 0 || s.chips > 4 {
+            return Err(
+                "keyboard.toml: number of chained shift registers should be 1~4".to_string(),
+            );
+        }
+        if s.pins == 0 || s.pins > s.chips * 8 {
+            return Err(
+                "keyboard.toml: `pins` of shift register should be 1~`chips * 8`".to_string(),
+            );
+        }
+        match s.chip {
+            ShiftRegisterChip::hc595 if m.input_pins.is_none() => {
+                Err("`input_pins` is required for 74HC595 shift register matrix".to_string())
+            }
+            ShiftRegisterChip::hc165 if m.output_pins.is_none() || s.load.is_none() => Err(
+                "`output_pins` and `load` are required for 74HC165 shift register matrix"
+                    .to_string(),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check every shift register matrix of the board, including the split ones, against the chip, returns the error message if it isn't supported
+    pub(crate) fn check_shift_register_support(
+        board: &BoardConfig,
+        chip: &ChipModel,
+    ) -> Result<(), String> {
+        let matrices: Vec<&MatrixConfig> = match board {
+            BoardConfig::Split(s) => core::iter::once(&s.central)
+                .chain(s.peripheral.iter())
+                .map(|b| &b.matrix)
+                .collect(),
+            BoardConfig::Normal(m) | BoardConfig::DirectPin(m) => vec![m],
+        };
+        for m in matrices
+            .into_iter()
+            .filter(|m| matches!(m.matrix_type, MatrixType::shift_register))
+        {
+            Self::check_shift_register_matrix(m)?;
+            if chip.series != ChipSeries::Rp2040 {
+                return Err(format!(
+                    "keyboard.toml: shift register matrix is only supported on rp2040 now, found {:?}",
+                    chip.series
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the config of running the same firmware on both halves, returns the error message if it's invalid
+    pub(crate) fn check_ee_hands(
+        board: &BoardConfig,
+        chip: &ChipModel,
+        storage: &StorageConfig,
+    ) -> Result<(), String> {
+        let BoardConfig::Split(s) = board else {
+            return Ok(());
+        };
+        let Some(ee_hands) = &s.ee_hands else {
+            return Ok(());
+        };
+        if s.connection != "serial"
+            || !matches!(chip.series, ChipSeries::Rp2040 | ChipSeries::Stm32)
+        {
+            return Err(
+                "keyboard.toml: `split.ee_hands` is only supported for serial split on rp2040 and stm32 now"
+                    .to_string(),
+            );
+        }
+        let [peripheral] = &s.peripheral[..] else {
+            return Err(
+                "keyboard.toml: `split.ee_hands` requires exactly one `split.peripheral`"
+                    .to_string(),
+            );
+        };
+        if peripheral.rows != s.central.rows || peripheral.cols != s.central.cols {
+            return Err("keyboard.toml: both halves should have the same `rows` and `cols` when `split.ee_hands` is set".to_string());
+        }
+        if s.central.serial.as_ref().map_or(0, |serial| serial.len()) != 1 {
+            return Err("keyboard.toml: `split.central.serial` should have exactly one serial instance when `split.ee_hands` is set".to_string());
+        }
+        if ee_hands.handedness_pin.is_none() && !storage.enabled {
+            return Err("keyboard.toml: the handedness is read from the storage if `handedness_pin` of `split.ee_hands` isn't set, enable the storage or set `handedness_pin`".to_string());
+        }
+        Ok(())
+    }
+
+    /// Check whether the split connection is supported by the chip, returns the error message if it isn't
+    pub(crate) fn check_split_connection(
+        board: &BoardConfig,
+        chip: &ChipModel,
+    ) -> Result<(), String> {
+        match board {
+            BoardConfig::Split(s) if s.connection == "i2c" && chip.series != ChipSeries::Rp2040 => {
+                Err("keyboard.toml: I2C split is only supported on rp2040 now".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the duplex and charlieplex matrix config against the layout, returns the error message if the config is invalid
+    pub(crate) fn check_flex_pin_matrix(
+        board: &BoardConfig,
+        layout: &LayoutConfig,
+        chip: &ChipModel,
+    ) -> Result<(), String> {
+        let BoardConfig::Normal(m) = board else {
+            return Ok(());
+        };
+        let (rows, cols) = match m.matrix_type {
+            MatrixType::duplex => (
+                m.row_pins.as_ref().map_or(0, |p| p.len()),
+                2 * m.col_pins.as_ref().map_or(0, |p| p.len()),
+            ),
+            MatrixType::charlieplex => {
+                let n = m.charlieplex_pins.as_ref().map_or(0, |p| p.len());
+                (n, n.saturating_sub(1))
+            }
+            _ => return Ok(()),
+        };
+        if chip.series == ChipSeries::Esp32 {
+            return Err(
+                "keyboard.toml: duplex and charlieplex matrices are not supported on esp32 yet"
+                    .to_string(),
+            );
+        }
+        if layout.rows as usize != rows || layout.cols as usize != cols {
+            return Err(format!(
+                "keyboard.toml: {:?} matrix with the given pins requires `rows = {}` and `cols = {}` in [layout], found rows = {}, cols = {}",
+                m.matrix_type, rows, cols, layout.rows, layout.cols
+            ));
+        }
+        Ok(())
+    }
+
     // Layout is a mandatory field in toml, so we mainly check the sizes
     fn get_layout_from_toml(mut layout: LayoutConfig) -> Result<LayoutConfig, TokenStream2> {
+        // Reorder the keymap from physical order to matrix order
+        if let Some(matrix_map) = &layout.matrix_map {
+            if let Err(e) = Self::check_matrix_map(matrix_map, layout.rows, layout.cols) {
+                return rmk_compile_error!(e);
+            }
+            match Self::reorder_keymap_by_matrix_map(
+                &layout.keymap,
+                matrix_map,
+                layout.rows,
+                layout.cols,
+            ) {
+                Ok(keymap) => layout.keymap = keymap,
+                Err(e) => return rmk_compile_error!(e),
+            }
+        }
+
         if layout.keymap.len() <= layout.layers as usize {
             // The required number of layers is less than what's set in keymap
             // Fill the rest with empty keys
@@ -418,6 +641,59 @@ impl KeyboardConfig {
         Ok(layout)
     }
 
+    /// Check that every matrix position is covered exactly once by the matrix map, returns the error message if not
+    fn check_matrix_map(matrix_map: &[Vec<(u8, u8)>], rows: u8, cols: u8) -> Result<(), String> {
+        let mut covered = vec![vec![false; cols as usize]; rows as usize];
+        for &(row, col) in matrix_map.iter().flatten() {
+            if row >= rows || col >= cols {
+                return Err(format!(
+                    "keyboard.toml: ({row}, {col}) in [layout.matrix_map] is out of the {rows}x{cols} matrix"
+                ));
+            }
+            if covered[row as usize][col as usize] {
+                return Err(format!(
+                    "keyboard.toml: ({row}, {col}) appears more than once in [layout.matrix_map]"
+                ));
+            }
+            covered[row as usize][col as usize] = true;
+        }
+        for (row, r) in covered.iter().enumerate() {
+            if let Some(col) = r.iter().position(|c| !c) {
+                return Err(format!(
+                    "keyboard.toml: ({row}, {col}) is missing in [layout.matrix_map]"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reorder each layer of the keymap from physical order to `[row][col]` order by the matrix map
+    fn reorder_keymap_by_matrix_map(
+        keymap: &[Vec<Vec<String>>],
+        matrix_map: &[Vec<(u8, u8)>],
+        rows: u8,
+        cols: u8,
+    ) -> Result<Vec<Vec<Vec<String>>>, String> {
+        keymap
+            .iter()
+            .enumerate()
+            .map(|(layer_idx, layer)| {
+                let same_shape = layer.len() == matrix_map.len()
+                    && layer.iter().zip(matrix_map).all(|(l, m)| l.len() == m.len());
+                if !same_shape {
+                    return Err(format!(
+                        "keyboard.toml: layer {layer_idx} in keymap doesn't match the shape of [layout.matrix_map]"
+                    ));
+                }
+                let mut reordered = vec![vec!["_".to_string(); cols as usize]; rows as usize];
+                for (key, &(row, col)) in layer.iter().flatten().zip(matrix_map.iter().flatten()) {
+                    reordered[row as usize][col as usize] = key.clone();
+                }
+                Ok(reordered)
+            })
+            .collect()
+    }
+
     fn get_behavior_from_toml(
         default: BehaviorConfig,
         toml: Option<BehaviorConfig>,
@@ -1,13 +1,14 @@
 //! Initialize matrix initialization boilerplate of RMK
 //!
-use quote::quote;
+use quote::{format_ident, quote};
 
 use crate::{
-    config::MatrixType,
+    config::{DebounceAlgorithm, MatrixConfig, MatrixType, ShiftRegisterChip},
     feature::is_feature_enabled,
     gpio_config::{
-        convert_direct_pins_to_initializers, convert_input_pins_to_initializers,
-        convert_output_pins_to_initializers,
+        convert_direct_pins_to_initializers, convert_flex_pins_to_initializers,
+        convert_gpio_str_to_output_pin, convert_input_pins_to_initializers,
+        convert_output_pins_to_initializers, expand_flex_pin_type,
     },
     keyboard_config::{BoardConfig, KeyboardConfig},
     ChipModel, ChipSeries,
@@ -20,14 +21,23 @@ pub(crate) fn expand_matrix_config(
     let async_matrix = is_feature_enabled(rmk_features, "async_matrix");
     let mut matrix_config = proc_macro2::TokenStream::new();
     match &keyboard_config.board {
-        BoardConfig::Normal(matrix) => {
-            matrix_config.extend(expand_matrix_input_output_pins(
+        BoardConfig::Normal(matrix) => match matrix.matrix_type {
+            MatrixType::shift_register => matrix_config.extend(expand_shift_register_pins(
+                &keyboard_config.chip,
+                matrix,
+                async_matrix,
+            )),
+            MatrixType::duplex | MatrixType::charlieplex => {
+                matrix_config.extend(expand_flex_pins(&keyboard_config.chip, matrix))
+            }
+            _ => matrix_config.extend(expand_matrix_input_output_pins(
                 &keyboard_config.chip,
                 matrix.input_pins.clone().unwrap(),
                 matrix.output_pins.clone().unwrap(),
                 async_matrix,
-            ));
-        }
+                matrix.low_active,
+            )),
+        },
         BoardConfig::DirectPin(matrix) => {
             matrix_config.extend(expand_matrix_direct_pins(
                 &keyboard_config.chip,
@@ -58,6 +68,7 @@ pub(crate) fn expand_matrix_config(
                     split_config.central.matrix.input_pins.clone().unwrap(),
                     split_config.central.matrix.output_pins.clone().unwrap(),
                     async_matrix,
+                    split_config.central.matrix.low_active,
                 )),
                 MatrixType::direct_pin => matrix_config.extend(expand_matrix_direct_pins(
                     &keyboard_config.chip,
@@ -65,6 +76,14 @@ pub(crate) fn expand_matrix_config(
                     async_matrix,
                     split_config.central.matrix.direct_pin_low_active,
                 )),
+                MatrixType::shift_register => matrix_config.extend(expand_shift_register_pins(
+                    &keyboard_config.chip,
+                    &split_config.central.matrix,
+                    async_matrix,
+                )),
+                MatrixType::duplex | MatrixType::charlieplex => matrix_config.extend(
+                    expand_flex_pins(&keyboard_config.chip, &split_config.central.matrix),
+                ),
             }
         }
     };
@@ -99,6 +118,7 @@ pub(crate) fn expand_matrix_input_output_pins(
     input_pins: Vec<String>,
     output_pins: Vec<String>,
     async_matrix: bool,
+    low_active: bool,
 ) -> proc_macro2::TokenStream {
     let mut pin_initialization = proc_macro2::TokenStream::new();
     // Extra import when using `ExtiInput`
@@ -114,9 +134,14 @@ pub(crate) fn expand_matrix_input_output_pins(
         &chip,
         input_pins,
         async_matrix,
+        low_active,
     ));
     // Initialize output pins
-    pin_initialization.extend(convert_output_pins_to_initializers(&chip, output_pins));
+    pin_initialization.extend(convert_output_pins_to_initializers(
+        &chip,
+        output_pins,
+        low_active,
+    ));
 
     // Generate a macro that does pin matrix config
     quote! {
@@ -127,3 +152,218 @@ pub(crate) fn expand_matrix_input_output_pins(
         };
     }
 }
+
+/// Expand the debouncer of a matrix with `input_output_num` input and output pins.
+///
+/// The per-key debouncer is used if `[debounce]` is set in `keyboard.toml`,
+/// otherwise the debouncer is chosen by the `rapid_debouncer` feature.
+pub(crate) fn expand_debouncer(
+    keyboard_config: &KeyboardConfig,
+    rmk_features: &Option<Vec<String>>,
+    input_output_num: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    match &keyboard_config.debounce {
+        Some(debounce) => {
+            let algorithm = match debounce.algorithm {
+                DebounceAlgorithm::sym_defer_pk => quote! { SymDeferPk },
+                DebounceAlgorithm::sym_eager_pk => quote! { SymEagerPk },
+                DebounceAlgorithm::asym_eager_defer_pk => quote! { AsymEagerDeferPk },
+            };
+            let press_time = debounce.press_time.clone().map(|t| t.0).unwrap_or(20);
+            let release_time = debounce.release_time.clone().map(|t| t.0).unwrap_or(20);
+            quote! {
+                let debouncer = ::rmk::debounce::per_key_debouncer::PerKeyDebouncer::<#input_output_num>::new(
+                    ::rmk::config::DebounceConfig {
+                        algorithm: ::rmk::config::DebounceAlgorithm::#algorithm,
+                        press_time: ::embassy_time::Duration::from_millis(#press_time),
+                        release_time: ::embassy_time::Duration::from_millis(#release_time),
+                    }
+                );
+            }
+        }
+        None => {
+            if is_feature_enabled(rmk_features, "rapid_debouncer") {
+                quote! { let debouncer = ::rmk::debounce::fast_debouncer::RapidDebouncer::<#input_output_num>::new(); }
+            } else {
+                quote! { let debouncer = ::rmk::debounce::default_debouncer::DefaultDebouncer::<#input_output_num>::new(); }
+            }
+        }
+    }
+}
+
+/// Initialize the pins of a duplex matrix as `row_pins` and `col_pins`, or the pins of a charlieplex matrix as `charlieplex_pins`
+pub(crate) fn expand_flex_pins(
+    chip: &ChipModel,
+    matrix: &MatrixConfig,
+) -> proc_macro2::TokenStream {
+    let flex_pin_type = expand_flex_pin_type(chip);
+    let pin_initialization = match matrix.matrix_type {
+        MatrixType::duplex => {
+            let row_pins = convert_flex_pins_to_initializers(
+                chip,
+                matrix
+                    .row_pins
+                    .clone()
+                    .expect("matrix.row_pins is required for duplex matrix"),
+                "row_pins",
+            );
+            let col_pins = convert_flex_pins_to_initializers(
+                chip,
+                matrix
+                    .col_pins
+                    .clone()
+                    .expect("matrix.col_pins is required for duplex matrix"),
+                "col_pins",
+            );
+            quote! {
+                #row_pins
+                #col_pins
+            }
+        }
+        MatrixType::charlieplex => convert_flex_pins_to_initializers(
+            chip,
+            matrix
+                .charlieplex_pins
+                .clone()
+                .expect("matrix.charlieplex_pins is required for charlieplex matrix"),
+            "charlieplex_pins",
+        ),
+        _ => unreachable!("{:?} matrix doesn't use flex pins", matrix.matrix_type),
+    };
+    quote! {
+        #flex_pin_type
+        #pin_initialization
+    }
+}
+
+/// Create a duplex or charlieplex matrix as `matrix`, whose pins are initialized by [`expand_flex_pins`]
+pub(crate) fn expand_flex_matrix(
+    keyboard_config: &KeyboardConfig,
+    rmk_features: &Option<Vec<String>>,
+    matrix: &MatrixConfig,
+    row_offset: usize,
+    col_offset: usize,
+) -> proc_macro2::TokenStream {
+    let low_active = matrix.low_active;
+    match matrix.matrix_type {
+        MatrixType::duplex => {
+            let row_pin_num = matrix.row_pins.as_ref().map_or(0, |p| p.len());
+            let col_pin_num = matrix.col_pins.as_ref().map_or(0, |p| p.len());
+            // Keys found by row2col scanning are at the second half of cols
+            let col_num = col_pin_num * 2;
+            let debouncer = expand_debouncer(
+                keyboard_config,
+                rmk_features,
+                quote! { #row_pin_num, #col_num },
+            );
+            quote! {
+                #debouncer
+                let mut matrix = ::rmk::duplex_matrix::DuplexMatrix::<_, _, #row_offset, #col_offset, #row_pin_num, #col_pin_num>::new(row_pins, col_pins, debouncer, #low_active);
+            }
+        }
+        MatrixType::charlieplex => {
+            let pin_num = matrix.charlieplex_pins.as_ref().map_or(0, |p| p.len());
+            let debouncer =
+                expand_debouncer(keyboard_config, rmk_features, quote! { #pin_num, #pin_num });
+            quote! {
+                #debouncer
+                let mut matrix = ::rmk::charlieplex::CharlieplexMatrix::<_, _, #row_offset, #col_offset, #pin_num>::new(charlieplex_pins, debouncer, #low_active);
+            }
+        }
+        _ => unreachable!("{:?} matrix doesn't use flex pins", matrix.matrix_type),
+    }
+}
+
+/// Number of (inputs, outputs) of a shift register matrix
+pub(crate) fn shift_register_input_output_num(matrix: &MatrixConfig) -> (usize, usize) {
+    let shift_register = matrix
+        .shift_register
+        .as_ref()
+        .expect("matrix.shift_register is required for shift register matrix");
+    match shift_register.chip {
+        ShiftRegisterChip::hc595 => (
+            matrix.input_pins.as_ref().map_or(0, |p| p.len()),
+            shift_register.pins,
+        ),
+        ShiftRegisterChip::hc165 => (
+            shift_register.pins,
+            matrix.output_pins.as_ref().map_or(0, |p| p.len()),
+        ),
+    }
+}
+
+/// Initialize the native pins and the shift registers of a shift register matrix, as `input_pins` and `output_pins`
+pub(crate) fn expand_shift_register_pins(
+    chip: &ChipModel,
+    matrix: &MatrixConfig,
+    async_matrix: bool,
+) -> proc_macro2::TokenStream {
+    let shift_register = matrix
+        .shift_register
+        .clone()
+        .expect("matrix.shift_register is required for shift register matrix");
+    let chips = shift_register.chips;
+    let instance = format_ident!("{}", shift_register.instance);
+    let sck = format_ident!("{}", shift_register.sck);
+    let data = format_ident!("{}", shift_register.data);
+    let spi = match chip.series {
+        ChipSeries::Rp2040 => match shift_register.chip {
+            ShiftRegisterChip::hc595 => quote! {
+                ::embassy_rp::spi::Spi::new_txonly(p.#instance, p.#sck, p.#data, p.DMA_CH1, ::embassy_rp::spi::Config::default())
+            },
+            ShiftRegisterChip::hc165 => quote! {
+                ::embassy_rp::spi::Spi::new_rxonly(p.#instance, p.#sck, p.#data, p.DMA_CH1, p.DMA_CH2, ::embassy_rp::spi::Config::default())
+            },
+        },
+        _ => unreachable!(
+            "shift register matrix on {:?} is rejected by the config check",
+            chip.series
+        ),
+    };
+    // CS and load pins are idle at high level
+    let cs = convert_gpio_str_to_output_pin(chip, shift_register.cs, true);
+    let spi_device_init = quote! {
+        let shift_register_spi_bus = ::rmk::embassy_sync::mutex::Mutex::<::rmk::embassy_sync::blocking_mutex::raw::NoopRawMutex, _>::new(#spi);
+        let shift_register_spi = ::rmk::embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice::new(&shift_register_spi_bus, #cs);
+    };
+    match shift_register.chip {
+        ShiftRegisterChip::hc595 => {
+            let input_pins = convert_input_pins_to_initializers(
+                chip,
+                matrix.input_pins.clone().unwrap(),
+                async_matrix,
+                matrix.low_active,
+            );
+            quote! {
+                #spi_device_init
+                let output_pins = ::rmk::shift_register::Hc595::<_, #chips>::new(shift_register_spi);
+                let input_pins = {
+                    #input_pins
+                    input_pins
+                };
+            }
+        }
+        ShiftRegisterChip::hc165 => {
+            let output_pins = convert_output_pins_to_initializers(
+                chip,
+                matrix.output_pins.clone().unwrap(),
+                matrix.low_active,
+            );
+            let load = convert_gpio_str_to_output_pin(
+                chip,
+                shift_register
+                    .load
+                    .expect("`load` pin is required by 74HC165"),
+                true,
+            );
+            quote! {
+                #spi_device_init
+                let input_pins = ::rmk::shift_register::Hc165::<_, _, #chips>::new(shift_register_spi, #load);
+                let output_pins = {
+                    #output_pins
+                    output_pins
+                };
+            }
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use core::panic;
 
 use crate::{
-    config::{SerialConfig, SplitConfig},
+    config::{SerialConfig, SplitConfig, SplitI2cConfig},
     keyboard_config::{BoardConfig, KeyboardConfig},
     ChipModel, ChipSeries,
 };
@@ -16,6 +16,22 @@ pub(crate) fn expand_split_central_config(config: &KeyboardConfig) -> proc_macro
     }
 }
 
+/// Expand the split fields of `RmkConfig`
+pub(crate) fn expand_split_rmk_config(config: &KeyboardConfig) -> TokenStream2 {
+    match &config.board {
+        // The battery level of each BLE peripheral is reported to the host by the nRF central
+        BoardConfig::Split(split_config)
+            if split_config.connection == "ble" && config.chip.series == ChipSeries::Nrf52 =>
+        {
+            let peripheral_num = split_config.peripheral.len();
+            quote! {
+                split_peripheral_num: #peripheral_num,
+            }
+        }
+        _ => quote! {},
+    }
+}
+
 fn expand_split_communication_config(chip: &ChipModel, split_config: &SplitConfig) -> TokenStream2 {
     match &split_config.connection[..] {
         "ble" => {
@@ -49,6 +65,19 @@ fn expand_split_communication_config(chip: &ChipModel, split_config: &SplitConfi
                 .expect("central.serial is required");
             expand_serial_init(chip, serial_config)
         }
+        "i2c" => {
+            // The central is the controller of the I2C bus, the bus is shared by all peripherals
+            let i2c_config = split_config
+                .central
+                .i2c
+                .clone()
+                .expect("central.i2c is required");
+            let i2c_init = expand_i2c_init(chip, &i2c_config, false);
+            quote! {
+                #i2c_init
+                let split_i2c_bus = ::rmk::embassy_sync::mutex::Mutex::<::rmk::embassy_sync::blocking_mutex::raw::NoopRawMutex, _>::new(split_i2c);
+            }
+        }
         _ => panic!("Invalid connection type for split"),
     }
 }
@@ -133,3 +162,55 @@ pub(crate) fn expand_serial_init(chip: &ChipModel, serial: Vec<SerialConfig>) ->
     });
     uart_initializers
 }
+
+/// Initialize the I2C bus of split as `split_i2c`, the central is the I2C controller and peripherals are I2C targets
+pub(crate) fn expand_i2c_init(
+    chip: &ChipModel,
+    i2c: &SplitI2cConfig,
+    target: bool,
+) -> TokenStream2 {
+    match chip.series {
+        ChipSeries::Rp2040 => {
+            let i2c_instance = format_ident!("{}", i2c.instance);
+            let i2c_irq = format_ident!("{}_IRQ", i2c.instance);
+            let sda_pin = format_ident!("{}", i2c.sda);
+            let scl_pin = format_ident!("{}", i2c.scl);
+            let i2c_new = if target {
+                let address = i2c
+                    .address
+                    .expect("address of peripheral's i2c is required")
+                    as u16;
+                quote! {
+                    let mut i2c_config = ::embassy_rp::i2c_slave::Config::default();
+                    i2c_config.addr = #address;
+                    let split_i2c = ::embassy_rp::i2c_slave::I2cSlave::new(
+                        p.#i2c_instance,
+                        p.#scl_pin,
+                        p.#sda_pin,
+                        IrqsI2c,
+                        i2c_config,
+                    );
+                }
+            } else {
+                quote! {
+                    let split_i2c = ::embassy_rp::i2c::I2c::new_async(
+                        p.#i2c_instance,
+                        p.#scl_pin,
+                        p.#sda_pin,
+                        IrqsI2c,
+                        ::embassy_rp::i2c::Config::default(),
+                    );
+                }
+            };
+            quote! {
+                ::embassy_rp::bind_interrupts!(struct IrqsI2c {
+                    #i2c_irq => ::embassy_rp::i2c::InterruptHandler<::embassy_rp::peripherals::#i2c_instance>;
+                });
+                #i2c_new
+            }
+        }
+        _ => quote! {
+            compile_error!("keyboard.toml: I2C split is only supported on rp2040 now");
+        },
+    }
+}
@@ -0,0 +1,88 @@
+//! Initialize the role and handedness of the half, when the same firmware runs on both halves
+//!
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+
+use crate::{
+    entry::join_all_tasks,
+    gpio_config::convert_gpio_str_to_input_pin,
+    keyboard::expand_split_central_matrix,
+    keyboard_config::{BoardConfig, KeyboardConfig},
+};
+
+/// Detect the handedness as `handedness` and the offsets of both halves as `ee_hands_layout`,
+/// then run the split peripheral here if the half isn't powered by USB.
+///
+/// It should be expanded after the matrix pins, the split serial and the flash are initialized.
+pub(crate) fn expand_ee_hands_init(
+    keyboard_config: &KeyboardConfig,
+    rmk_features: &Option<Vec<String>>,
+) -> TokenStream2 {
+    let BoardConfig::Split(split_config) = &keyboard_config.board else {
+        return quote! {};
+    };
+    let Some(ee_hands) = &split_config.ee_hands else {
+        return quote! {};
+    };
+    let chip = &keyboard_config.chip;
+
+    // The strap pin is pulled to the left level, VBUS is pulled down
+    let handedness = match &ee_hands.handedness_pin {
+        Some(pin) => {
+            let left_level_high = ee_hands.left_level_high;
+            let pin = convert_gpio_str_to_input_pin(chip, pin.clone(), false, left_level_high);
+            quote! {
+                let handedness = ::rmk::split::ee_hands::Handedness::from_pin(&mut #pin, #left_level_high);
+            }
+        }
+        None => quote! {
+            let mut flash = flash;
+            let handedness = ::rmk::split::ee_hands::read_handedness(&mut flash, &storage_config)
+                .await
+                .unwrap_or(::rmk::split::ee_hands::Handedness::Left);
+        },
+    };
+    let vbus_pin = convert_gpio_str_to_input_pin(chip, ee_hands.vbus_pin.clone(), false, false);
+
+    // `split.central` is the left half, and the only peripheral is the right half
+    let left = (
+        split_config.central.row_offset,
+        split_config.central.col_offset,
+    );
+    let right = split_config
+        .peripheral
+        .first()
+        .map_or((0, 0), |p| (p.row_offset, p.col_offset));
+    let (left_row, left_col) = left;
+    let (right_row, right_col) = right;
+
+    // Both halves use the serial of the central
+    let uart_instance = format_ident!(
+        "{}",
+        split_config
+            .central
+            .serial
+            .as_ref()
+            .and_then(|serial| serial.first())
+            .expect("No serial defined for central")
+            .instance
+            .to_lowercase()
+    );
+    let matrix = expand_split_central_matrix(keyboard_config, rmk_features, 0, 0);
+    let run_peripheral = join_all_tasks(vec![
+        quote! { ::rmk::run_devices!((matrix) => ::rmk::channel::EVENT_CHANNEL) },
+        quote! { ::rmk::split::peripheral::run_rmk_split_peripheral(#uart_instance) },
+    ]);
+
+    quote! {
+        #handedness
+        let ee_hands_layout = ::rmk::split::ee_hands::SplitLayout::new((#left_row, #left_col), (#right_row, #right_col));
+        if ::rmk::split::ee_hands::SplitRole::from_vbus(&mut #vbus_pin) == ::rmk::split::ee_hands::SplitRole::Peripheral {
+            ::defmt::info!("Running as split peripheral");
+            #matrix
+            #run_peripheral
+            return;
+        }
+        ::defmt::info!("Running as split central");
+    }
+}
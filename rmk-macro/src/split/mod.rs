@@ -1,2 +1,3 @@
 pub(crate) mod central;
+pub(crate) mod ee_hands;
 pub(crate) mod peripheral;
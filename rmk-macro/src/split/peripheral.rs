@@ -1,16 +1,22 @@
-use proc_macro2::TokenStream as TokenStream2;
+use proc_macro2::{Ident, TokenStream as TokenStream2};
 use quote::{format_ident, quote};
 use syn::ItemMod;
 
 use crate::{
+    ble::expand_peripheral_battery_sampling,
     chip_init::expand_chip_init,
     config::{MatrixType, SplitBoardConfig},
     entry::join_all_tasks,
     feature::{get_rmk_features, is_feature_enabled},
     import::expand_imports,
+    input_device::expand_peripheral_input_devices,
     keyboard_config::{read_keyboard_toml_config, BoardConfig, KeyboardConfig},
-    matrix::{expand_matrix_direct_pins, expand_matrix_input_output_pins},
-    split::central::expand_serial_init,
+    matrix::{
+        expand_debouncer, expand_flex_matrix, expand_flex_pins, expand_matrix_direct_pins,
+        expand_matrix_input_output_pins, expand_shift_register_pins,
+        shift_register_input_output_num,
+    },
+    split::central::{expand_i2c_init, expand_serial_init},
     ChipModel, ChipSeries,
 };
 
@@ -91,21 +97,18 @@ fn expand_split_peripheral(
     let chip_init = expand_chip_init(keyboard_config, &item_mod);
 
     // Debouncer config
-    let rapid_debouncer_enabled = is_feature_enabled(rmk_features, "rapid_debouncer");
-    let col2row_enabled = is_feature_enabled(rmk_features, "col2row");
     let col = peripheral_config.cols;
     let row = peripheral_config.rows;
-    let input_output_num = if col2row_enabled {
+    let col2row = !peripheral_config.matrix.row2col;
+    let low_active = peripheral_config.matrix.low_active;
+    let ghost_detection = peripheral_config.matrix.ghost_detection;
+    let input_output_num = if col2row {
         quote! { #row, #col }
     } else {
         quote! { #col, #row }
     };
 
-    let debouncer_type = if rapid_debouncer_enabled {
-        quote! { ::rmk::debounce::fast_debouncer::RapidDebouncer }
-    } else {
-        quote! { ::rmk::debounce::default_debouncer::DefaultDebouncer }
-    };
+    let debouncer = expand_debouncer(keyboard_config, rmk_features, input_output_num.clone());
 
     // Matrix config
     let async_matrix = is_feature_enabled(rmk_features, "async_matrix");
@@ -125,11 +128,12 @@ fn expand_split_peripheral(
                     .clone()
                     .expect("split.peripheral.matrix.output_pins is required"),
                 async_matrix,
+                low_active,
             ));
 
             matrix_config.extend(quote! {
-                let debouncer = #debouncer_type::<#input_output_num>::new();
-                let mut matrix = ::rmk::matrix::Matrix::<_, _, _, #input_output_num>::new(input_pins, output_pins, debouncer);
+                #debouncer
+                let mut matrix = ::rmk::matrix::Matrix::<_, _, _, #input_output_num, #col2row>::new(input_pins, output_pins, debouncer, #low_active).with_ghost_detection(#ghost_detection);
             });
         }
         MatrixType::direct_pin => {
@@ -148,39 +152,124 @@ fn expand_split_peripheral(
             let size = row * col;
             let low_active = peripheral_config.matrix.direct_pin_low_active;
 
+            let debouncer = expand_debouncer(keyboard_config, rmk_features, quote! { #col, #row });
             matrix_config.extend(quote! {
-                let debouncer = #debouncer_type::<#col, #row>::new();
+                #debouncer
                 let mut matrix = ::rmk::direct_pin::DirectPinMatrix::<_, _, #row, #col, #size>::new(direct_pins, debouncer, #low_active);
             });
         }
+        MatrixType::shift_register => {
+            matrix_config.extend(expand_shift_register_pins(
+                &keyboard_config.chip,
+                &peripheral_config.matrix,
+                async_matrix,
+            ));
+            let (input_num, output_num) =
+                shift_register_input_output_num(&peripheral_config.matrix);
+            let debouncer = expand_debouncer(
+                keyboard_config,
+                rmk_features,
+                quote! { #input_num, #output_num },
+            );
+            matrix_config.extend(quote! {
+                #debouncer
+                let mut matrix = ::rmk::shift_register::ShiftRegisterMatrix::<_, _, _, 0, 0, #input_num, #output_num, #col2row>::new(input_pins, output_pins, debouncer, #low_active);
+            });
+        }
+        MatrixType::duplex | MatrixType::charlieplex => {
+            matrix_config.extend(expand_flex_pins(
+                &keyboard_config.chip,
+                &peripheral_config.matrix,
+            ));
+            matrix_config.extend(expand_flex_matrix(
+                keyboard_config,
+                rmk_features,
+                &peripheral_config.matrix,
+                0,
+                0,
+            ));
+        }
     }
 
-    let run_rmk_peripheral =
-        expand_split_peripheral_entry(&keyboard_config.chip, peripheral_config, &central_config);
+    if split_config.connection == "i2c"
+        && keyboard_config.chip.series == ChipSeries::Rp2040
+        && !is_feature_enabled(rmk_features, "rp2040_i2c")
+    {
+        return quote! {
+            compile_error!("\"rp2040_i2c\" feature of RMK should be enabled for I2C split");
+        };
+    }
+
+    // Events of input devices are sent to the central
+    let (input_device_config, devices) = match expand_peripheral_input_devices(
+        &keyboard_config.chip,
+        &peripheral_config.input_device,
+        async_matrix,
+    ) {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+
+    // The battery is sampled together with the peripheral
+    let (battery_config, battery_task) = match expand_peripheral_battery_sampling(keyboard_config) {
+        Some((config, task)) => (config, Some(task)),
+        None => (quote! {}, None),
+    };
+
+    let run_rmk_peripheral = expand_split_peripheral_entry(
+        &keyboard_config.chip,
+        &split_config.connection,
+        peripheral_config,
+        &central_config,
+        &devices,
+        battery_task,
+    );
 
     quote! {
         #imports
         #chip_init
         #matrix_config
+        #input_device_config
+        #battery_config
         #run_rmk_peripheral
     }
 }
 fn expand_split_peripheral_entry(
     chip: &ChipModel,
+    connection: &str,
     peripheral_config: &SplitBoardConfig,
     central_config: &SplitBoardConfig,
+    devices: &[Ident],
+    battery_task: Option<TokenStream2>,
 ) -> TokenStream2 {
     let peripheral_matrix_task = quote! {
-        ::rmk::run_devices!((matrix) => ::rmk::channel::EVENT_CHANNEL)
+        ::rmk::run_devices!((matrix #(, #devices)*) => ::rmk::channel::EVENT_CHANNEL)
     };
     match chip.series {
-        ChipSeries::Nrf52 => {
+        ChipSeries::Nrf52 | ChipSeries::Esp32 => {
             let central_addr = central_config
                 .ble_addr
                 .expect("Missing central ble address");
             let peripheral_addr = peripheral_config.ble_addr.expect(
                 "Peripheral should have a ble address, please check the `ble_addr` field in `keyboard.toml`",
             );
+            if chip.series == ChipSeries::Esp32 {
+                if connection != "ble" {
+                    panic!("Only BLE split is supported for esp32");
+                }
+                let peripheral_run = quote! {
+                    ::rmk::split::peripheral::run_rmk_split_peripheral(
+                        [#(#central_addr), *],
+                        [#(#peripheral_addr), *],
+                    )
+                };
+                let mut tasks = vec![peripheral_matrix_task, peripheral_run];
+                tasks.extend(battery_task);
+                let run_rmk_peripheral = join_all_tasks(tasks);
+                return quote! {
+                    ::esp_idf_svc::hal::task::block_on(async { #run_rmk_peripheral });
+                };
+            }
             let peripheral_run = quote! {
                 ::rmk::split::peripheral::run_rmk_split_peripheral(
                     [#(#central_addr), *],
@@ -188,7 +277,24 @@ fn expand_split_peripheral_entry(
                     spawner,
                 )
             };
-            join_all_tasks(vec![peripheral_matrix_task, peripheral_run])
+            let mut tasks = vec![peripheral_matrix_task, peripheral_run];
+            tasks.extend(battery_task);
+            join_all_tasks(tasks)
+        }
+        ChipSeries::Rp2040 | ChipSeries::Stm32 if connection == "i2c" => {
+            let i2c_config = peripheral_config
+                .i2c
+                .clone()
+                .expect("Missing peripheral i2c config");
+            let i2c_init = expand_i2c_init(chip, &i2c_config, true);
+            let peripheral_run = quote! {
+                ::rmk::split::peripheral::run_rmk_split_i2c_peripheral(split_i2c)
+            };
+            let run_rmk_peripheral = join_all_tasks(vec![peripheral_matrix_task, peripheral_run]);
+            quote! {
+                #i2c_init
+                #run_rmk_peripheral
+            }
         }
         ChipSeries::Rp2040 | ChipSeries::Stm32 => {
             let peripheral_serial = peripheral_config
@@ -220,6 +326,5 @@ fn expand_split_peripheral_entry(
                 #run_rmk_peripheral
             }
         }
-        ChipSeries::Esp32 => todo!("esp32 split keyboard is not supported yet"),
     }
 }
@@ -0,0 +1,588 @@
+//! Analog(hall-effect) key matrix
+//!
+//! [`AnalogMatrix`] reads the raw sensor value of each key through [`AnalogKeyReader`], converts it to key travel
+//! by per-key calibration, and generates standard [`KeyEvent`]s. It supports:
+//!
+//! - Configurable actuation point of each key
+//! - Rapid trigger: the key is released as soon as it moves up by `release_sensitivity`,
+//!   and pressed again as soon as it moves down by `press_sensitivity`, even if it's above the actuation point.
+//!   The actuation point applies again once the key moves up past the release point(`actuation_point - hysteresis`)
+//! - Dual actuation: a second key position is triggered when the key is pressed deeper
+//!
+//! The rest position of keys is sampled at startup. The bottom-out position is learned while typing,
+//! and saved to the storage so that it's available after reboot.
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::OutputPin;
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Deque;
+
+use crate::channel::FLASH_CHANNEL;
+use crate::event::{Event, KeyEvent};
+use crate::input_device::joystick::AnalogRead;
+use crate::input_device::InputDevice;
+use crate::matrix::MatrixTrait;
+use crate::storage::{AnalogCalibrationData, FlashOperationMessage, Storage};
+
+/// Key travel when the key is bottomed out. Travel of keys are in range of `0..=FULL_TRAVEL`
+pub const FULL_TRAVEL: u8 = 255;
+
+/// Max number of key events which are generated in one scan
+const PENDING_EVENTS: usize = 16;
+
+/// Trait for reading raw sensor values of analog keys
+pub trait AnalogKeyReader<const ROW: usize, const COL: usize> {
+    /// Read raw sensor values of all keys, the value of positions without a sensor are left untouched
+    async fn read(&mut self, values: &mut [[Option<u16>; COL]; ROW]);
+}
+
+/// Analog multiplexers(such as 74HC4067) whose outputs are connected to ADC channels.
+///
+/// All multiplexers share the same select pins. `positions[ch][mux]` is the key position(row, col)
+/// connected to channel `ch` of the `mux`th multiplexer.
+pub struct AnalogMultiplexer<
+    A: AnalogRead,
+    Out: OutputPin,
+    const SELECT_PIN_NUM: usize,
+    const CHANNEL_NUM: usize,
+    const MUX_NUM: usize,
+> {
+    adc: A,
+    select_pins: [Out; SELECT_PIN_NUM],
+    /// ADC channel of each multiplexer's output
+    adc_channels: [u8; MUX_NUM],
+    positions: [[Option<(u8, u8)>; MUX_NUM]; CHANNEL_NUM],
+    /// Time to wait for the output to settle after switching the channel
+    settle_time: Duration,
+}
+
+impl<
+        A: AnalogRead,
+        Out: OutputPin,
+        const SELECT_PIN_NUM: usize,
+        const CHANNEL_NUM: usize,
+        const MUX_NUM: usize,
+    > AnalogMultiplexer<A, Out, SELECT_PIN_NUM, CHANNEL_NUM, MUX_NUM>
+{
+    pub fn new(
+        adc: A,
+        select_pins: [Out; SELECT_PIN_NUM],
+        adc_channels: [u8; MUX_NUM],
+        positions: [[Option<(u8, u8)>; MUX_NUM]; CHANNEL_NUM],
+        settle_time: Duration,
+    ) -> Self {
+        assert!(
+            CHANNEL_NUM <= 1 << SELECT_PIN_NUM,
+            "Not enough select pins for the multiplexer channels"
+        );
+        Self {
+            adc,
+            select_pins,
+            adc_channels,
+            positions,
+            settle_time,
+        }
+    }
+}
+
+impl<
+        A: AnalogRead,
+        Out: OutputPin,
+        const SELECT_PIN_NUM: usize,
+        const CHANNEL_NUM: usize,
+        const MUX_NUM: usize,
+        const ROW: usize,
+        const COL: usize,
+    > AnalogKeyReader<ROW, COL>
+    for AnalogMultiplexer<A, Out, SELECT_PIN_NUM, CHANNEL_NUM, MUX_NUM>
+{
+    async fn read(&mut self, values: &mut [[Option<u16>; COL]; ROW]) {
+        for ch in 0..CHANNEL_NUM {
+            for (bit, pin) in self.select_pins.iter_mut().enumerate() {
+                if (ch >> bit) & 1 == 1 {
+                    pin.set_high().ok();
+                } else {
+                    pin.set_low().ok();
+                }
+            }
+            Timer::after(self.settle_time).await;
+            for mux in 0..MUX_NUM {
+                if let Some((row, col)) = self.positions[ch][mux] {
+                    let (row, col) = (row as usize, col as usize);
+                    if row < ROW && col < COL {
+                        values[row][col] = Some(self.adc.read(self.adc_channels[mux]).await);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Calibration of an analog key, in raw sensor values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyCalibration {
+    /// Raw value when the key is released
+    pub rest: u16,
+    /// Raw value when the key is bottomed out, it can be either larger or smaller than `rest`
+    pub bottom: u16,
+}
+
+impl KeyCalibration {
+    /// Convert the raw value to key travel
+    fn travel(&self, raw: u16) -> u8 {
+        let range = self.bottom as i32 - self.rest as i32;
+        if range == 0 {
+            return 0;
+        }
+        ((raw as i32 - self.rest as i32) * FULL_TRAVEL as i32 / range).clamp(0, FULL_TRAVEL as i32)
+            as u8
+    }
+
+    /// Extend the bottom position if the raw value goes beyond it
+    fn update_bottom(&mut self, raw: u16) {
+        let beyond = if self.bottom >= self.rest {
+            raw > self.bottom
+        } else {
+            raw < self.bottom
+        };
+        if beyond {
+            self.bottom = raw;
+        }
+    }
+}
+
+/// Rapid trigger config, sensitivities are in the unit of key travel
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RapidTrigger {
+    /// The key is pressed again when it moves down by `press_sensitivity` after released,
+    /// unless it has moved up past the release point
+    pub press_sensitivity: u8,
+    /// The key is released when it moves up by `release_sensitivity` after pressed
+    pub release_sensitivity: u8,
+}
+
+/// Dual actuation config
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DualActuation {
+    /// Key travel of the second actuation point, should be deeper than the actuation point
+    pub point: u8,
+    /// The key position which is triggered at the second actuation point
+    pub row: u8,
+    pub col: u8,
+}
+
+/// Config of an analog key
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AnalogKeyConfig {
+    /// Key travel where the key is pressed
+    pub actuation_point: u8,
+    /// The key is released when the travel is below `actuation_point - hysteresis`
+    pub hysteresis: u8,
+    /// Rapid trigger, disabled if `None`
+    pub rapid_trigger: Option<RapidTrigger>,
+    /// Dual actuation, disabled if `None`
+    pub dual_actuation: Option<DualActuation>,
+}
+
+impl Default for AnalogKeyConfig {
+    fn default() -> Self {
+        Self {
+            actuation_point: 128,
+            hysteresis: 12,
+            rapid_trigger: None,
+            dual_actuation: None,
+        }
+    }
+}
+
+/// Config of the analog matrix
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AnalogMatrixConfig {
+    /// Default config of all keys, can be overridden by [`AnalogMatrix::set_key_config`]
+    pub key: AnalogKeyConfig,
+    /// Expected change of raw value from rest to bottom-out, negative if the value decreases when the key is pressed.
+    ///
+    /// It's used before the key is bottomed out for the first time, so a conservative(smaller) value should be used.
+    pub default_range: i16,
+    /// Number of samples averaged to find the rest position at startup
+    pub calibration_samples: u8,
+    /// The learned bottom-out position is saved to storage when it changes more than `save_threshold`
+    pub save_threshold: u16,
+    /// Interval between scans
+    pub scan_interval: Duration,
+}
+
+impl Default for AnalogMatrixConfig {
+    fn default() -> Self {
+        Self {
+            key: AnalogKeyConfig::default(),
+            default_range: 600,
+            calibration_samples: 32,
+            save_threshold: 16,
+            scan_interval: Duration::from_micros(500),
+        }
+    }
+}
+
+/// State of an analog key
+#[derive(Clone, Copy, Debug, Default)]
+struct AnalogKeyState {
+    pressed: bool,
+    second_pressed: bool,
+    /// Peak travel since the key is pressed, or the lowest travel since the key is released. Used by rapid trigger
+    extremum: u8,
+    /// Bottom-out position which is saved in storage
+    saved_bottom: u16,
+}
+
+impl AnalogKeyState {
+    /// Update the state by current key travel, returns whether the key and the second actuation are changed
+    fn update(&mut self, travel: u8, config: &AnalogKeyConfig) -> (bool, bool) {
+        let was_pressed = self.pressed;
+        let release_point = config.actuation_point.saturating_sub(config.hysteresis);
+        if self.pressed {
+            self.extremum = self.extremum.max(travel);
+            let rapid_release = config
+                .rapid_trigger
+                .is_some_and(|rt| travel.saturating_add(rt.release_sensitivity) <= self.extremum);
+            if travel < release_point || rapid_release {
+                self.pressed = false;
+                self.extremum = travel;
+            }
+        } else {
+            self.extremum = self.extremum.min(travel);
+            let press = match config.rapid_trigger {
+                // The actuation point is ignored until the key moves up past the release point
+                Some(rt) => {
+                    (travel >= config.actuation_point || self.extremum >= release_point)
+                        && travel >= self.extremum.saturating_add(rt.press_sensitivity)
+                }
+                None => travel >= config.actuation_point,
+            };
+            if press {
+                self.pressed = true;
+                self.extremum = travel;
+            }
+        }
+
+        let was_second_pressed = self.second_pressed;
+        if let Some(dual) = config.dual_actuation {
+            if self.second_pressed {
+                self.second_pressed = travel >= dual.point.saturating_sub(config.hysteresis);
+            } else {
+                self.second_pressed = travel >= dual.point;
+            }
+        }
+
+        (
+            was_pressed != self.pressed,
+            was_second_pressed != self.second_pressed,
+        )
+    }
+}
+
+/// Matrix of analog(hall-effect) keys
+pub struct AnalogMatrix<R: AnalogKeyReader<ROW, COL>, const ROW: usize, const COL: usize> {
+    reader: R,
+    config: AnalogMatrixConfig,
+    key_configs: [[AnalogKeyConfig; COL]; ROW],
+    /// Calibration of each key, `None` if there's no sensor at the position
+    calibration: [[Option<KeyCalibration>; COL]; ROW],
+    /// Calibration loaded from storage
+    saved_calibration: [[Option<KeyCalibration>; COL]; ROW],
+    key_states: [[AnalogKeyState; COL]; ROW],
+    /// Raw sensor values of the last scan
+    values: [[Option<u16>; COL]; ROW],
+    calibrated: bool,
+    /// Key events generated by the last scan
+    pending: Deque<KeyEvent, PENDING_EVENTS>,
+}
+
+impl<R: AnalogKeyReader<ROW, COL>, const ROW: usize, const COL: usize> AnalogMatrix<R, ROW, COL> {
+    pub fn new(reader: R, config: AnalogMatrixConfig) -> Self {
+        Self {
+            reader,
+            config,
+            key_configs: [[config.key; COL]; ROW],
+            calibration: [[None; COL]; ROW],
+            saved_calibration: [[None; COL]; ROW],
+            key_states: [[AnalogKeyState::default(); COL]; ROW],
+            values: [[None; COL]; ROW],
+            calibrated: false,
+            pending: Deque::new(),
+        }
+    }
+
+    /// Override the config of the key at (row, col)
+    pub fn set_key_config(&mut self, row: usize, col: usize, config: AnalogKeyConfig) {
+        if row < ROW && col < COL {
+            self.key_configs[row][col] = config;
+        }
+    }
+
+    /// Load the calibration saved in storage, it should be called before running the matrix
+    pub async fn load_calibration<F: NorFlash, const NUM_LAYER: usize>(
+        &mut self,
+        storage: &mut Storage<F, ROW, COL, NUM_LAYER>,
+    ) {
+        if storage
+            .read_analog_calibration(&mut self.saved_calibration)
+            .await
+            .is_err()
+        {
+            error!("Failed to read analog key calibration from storage");
+        }
+    }
+
+    /// Sample the rest position of all keys, the keys should be released during calibration
+    async fn calibrate(&mut self) {
+        let samples = self.config.calibration_samples.max(1) as u32;
+        let mut sum = [[0u32; COL]; ROW];
+        for _ in 0..samples {
+            self.reader.read(&mut self.values).await;
+            for (row, values) in self.values.iter().enumerate() {
+                for (col, v) in values.iter().enumerate() {
+                    if let Some(v) = v {
+                        sum[row][col] += *v as u32;
+                    }
+                }
+            }
+            Timer::after(self.config.scan_interval).await;
+        }
+
+        for (row, sum) in sum.iter().enumerate() {
+            for (col, sum) in sum.iter().enumerate() {
+                if self.values[row][col].is_none() {
+                    continue;
+                }
+                let rest = (sum / samples) as u16;
+                // Keep the learned travel range, the rest position might drift a bit
+                let range = match self.saved_calibration[row][col] {
+                    Some(saved) => saved.bottom as i32 - saved.rest as i32,
+                    None => self.config.default_range as i32,
+                };
+                let bottom = (rest as i32 + range).clamp(0, u16::MAX as i32) as u16;
+                self.calibration[row][col] = Some(KeyCalibration { rest, bottom });
+                self.key_states[row][col].saved_bottom = bottom;
+            }
+        }
+        self.calibrated = true;
+        info!("Analog matrix calibrated");
+    }
+
+    /// Save the learned bottom-out position if it changes a lot
+    fn save_calibration(&mut self, row: usize, col: usize) {
+        let (Some(calibration), state) =
+            (self.calibration[row][col], &mut self.key_states[row][col])
+        else {
+            return;
+        };
+        if calibration.bottom.abs_diff(state.saved_bottom) <= self.config.save_threshold {
+            return;
+        }
+        let message = FlashOperationMessage::AnalogCalibration(AnalogCalibrationData {
+            row: row as u8,
+            col: col as u8,
+            rest: calibration.rest,
+            bottom: calibration.bottom,
+        });
+        // Don't block scanning, retry at next release if the flash channel is busy
+        if FLASH_CHANNEL.try_send(message).is_ok() {
+            state.saved_bottom = calibration.bottom;
+        }
+    }
+
+    /// Scan all keys, generated key events are pushed to `self.pending`
+    async fn scan(&mut self) {
+        self.reader.read(&mut self.values).await;
+        for row in 0..ROW {
+            for col in 0..COL {
+                // Leave the remaining keys to the next scan
+                if self.pending.capacity() - self.pending.len() < 2 {
+                    return;
+                }
+                let (Some(raw), Some(calibration)) =
+                    (self.values[row][col], self.calibration[row][col].as_mut())
+                else {
+                    continue;
+                };
+                calibration.update_bottom(raw);
+                let travel = calibration.travel(raw);
+                let config = self.key_configs[row][col];
+                let (changed, second_changed) = self.key_states[row][col].update(travel, &config);
+                let state = self.key_states[row][col];
+                if changed {
+                    let _ = self.pending.push_back(KeyEvent {
+                        row: row as u8,
+                        col: col as u8,
+                        pressed: state.pressed,
+                    });
+                    if !state.pressed {
+                        self.save_calibration(row, col);
+                    }
+                }
+                if let (true, Some(dual)) = (second_changed, config.dual_actuation) {
+                    let _ = self.pending.push_back(KeyEvent {
+                        row: dual.row,
+                        col: dual.col,
+                        pressed: state.second_pressed,
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl<R: AnalogKeyReader<ROW, COL>, const ROW: usize, const COL: usize> InputDevice
+    for AnalogMatrix<R, ROW, COL>
+{
+    async fn read_event(&mut self) -> Event {
+        if !self.calibrated {
+            self.calibrate().await;
+        }
+        loop {
+            if let Some(key_event) = self.pending.pop_front() {
+                return Event::Key(key_event);
+            }
+            Timer::after(self.config.scan_interval).await;
+            self.scan().await;
+        }
+    }
+}
+
+impl<R: AnalogKeyReader<ROW, COL>, const ROW: usize, const COL: usize> MatrixTrait
+    for AnalogMatrix<R, ROW, COL>
+{
+    const ROW: usize = ROW;
+    const COL: usize = COL;
+
+    // Analog keys have to be scanned all the time, there's no interrupt
+    #[cfg(feature = "async_matrix")]
+    async fn wait_for_key(&mut self) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Feed the travels to the key, returns the changes of the key and the second actuation
+    fn feed(
+        state: &mut AnalogKeyState,
+        config: &AnalogKeyConfig,
+        travels: &[u8],
+    ) -> std::vec::Vec<(bool, bool)> {
+        travels.iter().map(|t| state.update(*t, config)).collect()
+    }
+
+    #[test]
+    fn test_actuation_and_hysteresis() {
+        let config = AnalogKeyConfig::default();
+        let mut state = AnalogKeyState::default();
+        assert_eq!(state.update(127, &config), (false, false));
+        assert_eq!(state.update(128, &config), (true, false));
+        assert!(state.pressed);
+        // The key is released below `actuation_point - hysteresis`
+        assert_eq!(state.update(117, &config), (false, false));
+        assert_eq!(state.update(116, &config), (false, false));
+        assert_eq!(state.update(115, &config), (true, false));
+        assert!(!state.pressed);
+        assert_eq!(state.update(127, &config), (false, false));
+    }
+
+    #[test]
+    fn test_rapid_trigger() {
+        let config = AnalogKeyConfig {
+            actuation_point: 128,
+            hysteresis: 60,
+            rapid_trigger: Some(RapidTrigger {
+                press_sensitivity: 10,
+                release_sensitivity: 10,
+            }),
+            dual_actuation: None,
+        };
+        let mut state = AnalogKeyState::default();
+        assert_eq!(state.update(130, &config), (true, false));
+        // Released as soon as moving up by `release_sensitivity` from the peak
+        assert_eq!(
+            feed(&mut state, &config, &[200, 191, 190]),
+            [(false, false), (false, false), (true, false)]
+        );
+        assert!(!state.pressed);
+        // Pressed again as soon as moving down by `press_sensitivity`, even above the actuation point
+        assert_eq!(
+            feed(&mut state, &config, &[100, 109, 110]),
+            [(false, false), (false, false), (true, false)]
+        );
+        assert!(state.pressed);
+        // Moving up past the release point requires the actuation point again
+        assert_eq!(state.update(60, &config), (true, false));
+        assert_eq!(
+            feed(&mut state, &config, &[75, 127, 128]),
+            [(false, false), (false, false), (true, false)]
+        );
+    }
+
+    #[test]
+    fn test_dual_actuation() {
+        let config = AnalogKeyConfig {
+            dual_actuation: Some(DualActuation {
+                point: 200,
+                row: 0,
+                col: 1,
+            }),
+            ..Default::default()
+        };
+        let mut state = AnalogKeyState::default();
+        assert_eq!(state.update(150, &config), (true, false));
+        assert_eq!(state.update(210, &config), (false, true));
+        assert!(state.second_pressed);
+        // Hysteresis applies to the second actuation point too
+        assert_eq!(state.update(188, &config), (false, false));
+        assert_eq!(state.update(187, &config), (false, true));
+        assert!(state.pressed);
+        // Both are released at once
+        assert_eq!(state.update(210, &config), (false, true));
+        assert_eq!(state.update(0, &config), (true, true));
+    }
+
+    #[test]
+    fn test_calibration_scaling() {
+        let mut calibration = KeyCalibration {
+            rest: 2000,
+            bottom: 2600,
+        };
+        assert_eq!(calibration.travel(2000), 0);
+        assert_eq!(calibration.travel(2300), 127);
+        assert_eq!(calibration.travel(2600), FULL_TRAVEL);
+        // Values out of the range are clamped
+        assert_eq!(calibration.travel(1900), 0);
+        assert_eq!(calibration.travel(2700), FULL_TRAVEL);
+        // The bottom position is extended
+        calibration.update_bottom(2500);
+        assert_eq!(calibration.bottom, 2600);
+        calibration.update_bottom(2900);
+        assert_eq!(calibration.travel(2600), 170);
+
+        // The value decreases when the key is pressed
+        let mut calibration = KeyCalibration {
+            rest: 2000,
+            bottom: 1400,
+        };
+        assert_eq!(calibration.travel(1700), 127);
+        assert_eq!(calibration.travel(2100), 0);
+        calibration.update_bottom(1500);
+        assert_eq!(calibration.bottom, 1400);
+        calibration.update_bottom(1100);
+        assert_eq!(calibration.bottom, 1100);
+
+        let calibration = KeyCalibration {
+            rest: 2000,
+            bottom: 2000,
+        };
+        assert_eq!(calibration.travel(2600), 0);
+    }
+}
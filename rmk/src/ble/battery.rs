@@ -0,0 +1,481 @@
+//! Battery level of BLE keyboards
+//!
+//! ADC values are converted to the battery voltage by [`AdcConfig`] and the voltage divider, then the voltage is
+//! converted to the battery level by a discharge curve. LiPo batteries discharge nonlinearly, so the curve is a
+//! lookup table of voltages and levels, the level between two points is interpolated.
+//!
+//! Readings are noisy and the voltage rises while charging, so [`BatteryLevelFilter`] smooths the voltage by a
+//! moving average, and keeps the reported level from going backwards within the hysteresis.
+//!
+//! The battery is sampled by the same loop on the keyboard and on split peripherals, the board-specific parts are
+//! provided by a [`BatterySampler`]: `BleBatteryConfig` on nRF, or [`AdcBatterySampler`] for other ADCs.
+use embassy_time::Timer;
+
+/// Default discharge curve of LiPo batteries, in `(millivolts, percent)`
+pub const LIPO_DISCHARGE_CURVE: [(u16, u8); 21] = [
+    (4200, 100),
+    (4150, 95),
+    (4110, 90),
+    (4080, 85),
+    (4020, 80),
+    (3980, 75),
+    (3950, 70),
+    (3910, 65),
+    (3870, 60),
+    (3850, 55),
+    (3840, 50),
+    (3820, 45),
+    (3800, 40),
+    (3790, 35),
+    (3770, 30),
+    (3750, 25),
+    (3730, 20),
+    (3710, 15),
+    (3690, 10),
+    (3610, 5),
+    (3270, 0),
+];
+
+/// Maximum number of samples of the moving average
+pub const MAX_FILTER_WINDOW: usize = 16;
+
+/// ADC parameters for converting ADC values to voltages.
+///
+/// The ADC value of a single-ended input is `v_adc * gain / reference * 2^resolution`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdcConfig {
+    /// Reference voltage of the ADC in mV
+    pub reference_mv: u32,
+    /// Numerator of the ADC gain
+    pub gain_numerator: u32,
+    /// Denominator of the ADC gain
+    pub gain_denominator: u32,
+    /// Resolution of the ADC in bits
+    pub resolution: u8,
+}
+
+impl Default for AdcConfig {
+    /// nRF52 SAADC with default settings: 0.6V internal reference, 1/6 gain and 12-bit resolution
+    fn default() -> Self {
+        Self {
+            reference_mv: 600,
+            gain_numerator: 1,
+            gain_denominator: 6,
+            resolution: 12,
+        }
+    }
+}
+
+impl AdcConfig {
+    /// Convert the ADC value to the battery voltage in mV.
+    ///
+    /// `divider_measured / divider_total` is the ratio of the voltage divider between the battery and the ADC input.
+    pub fn battery_mv(&self, val: i16, divider_measured: u32, divider_total: u32) -> u32 {
+        // Negative values are noise around 0V
+        let val = val.max(0) as u64;
+        let numerator =
+            val * self.reference_mv as u64 * self.gain_denominator as u64 * divider_total as u64;
+        let denominator = (self.gain_numerator as u64 * divider_measured as u64) << self.resolution;
+        if denominator == 0 {
+            return 0;
+        }
+        (numerator / denominator) as u32
+    }
+}
+
+/// Convert the battery voltage to the battery level in percent.
+///
+/// `curve` is a list of `(millivolts, percent)` sorted by voltage from high to low, the level between two points is
+/// linearly interpolated. Voltages out of the curve are clamped to the first or the last point.
+pub fn battery_percent(mv: u32, curve: &[(u16, u8)]) -> u8 {
+    let (Some(first), Some(last)) = (curve.first(), curve.last()) else {
+        return 0;
+    };
+    if mv >= first.0 as u32 {
+        return first.1;
+    }
+    for pair in curve.windows(2) {
+        let (high_mv, high) = (pair[0].0 as u32, pair[0].1 as u32);
+        let (low_mv, low) = (pair[1].0 as u32, pair[1].1 as u32);
+        if mv >= low_mv {
+            if high_mv <= low_mv {
+                return low as u8;
+            }
+            // Round to the nearest percent
+            let span = high_mv - low_mv;
+            let delta = high.abs_diff(low) * (mv - low_mv);
+            let delta = (delta + span / 2) / span;
+            let percent = if high >= low {
+                low + delta
+            } else {
+                low - delta
+            };
+            return percent as u8;
+        }
+    }
+    last.1
+}
+
+/// Moving average and hysteresis of battery levels
+#[derive(Clone, Debug)]
+pub struct BatteryLevelFilter {
+    /// Voltage samples in mV
+    samples: [u32; MAX_FILTER_WINDOW],
+    /// Number of samples in the moving average
+    window: usize,
+    /// Number of valid samples
+    len: usize,
+    /// Index of the next sample
+    next: usize,
+    /// Hysteresis of the reported level in percent
+    hysteresis: u8,
+    /// Charging state of the last update
+    charging: Option<bool>,
+    /// Last reported level
+    level: Option<u8>,
+}
+
+impl BatteryLevelFilter {
+    /// Create a filter which averages `window` samples, the window is clamped to `1..=MAX_FILTER_WINDOW`
+    pub fn new(window: usize, hysteresis: u8) -> Self {
+        Self {
+            samples: [0; MAX_FILTER_WINDOW],
+            window: window.clamp(1, MAX_FILTER_WINDOW),
+            len: 0,
+            next: 0,
+            hysteresis,
+            charging: None,
+            level: None,
+        }
+    }
+
+    /// Add a voltage sample, and get the battery level to report.
+    ///
+    /// `charging` is the charging state, or `None` if it's unknown. While charging, the level doesn't decrease,
+    /// and while discharging, it doesn't increase, unless the change is larger than the hysteresis. When the charging
+    /// state is unknown, changes within the hysteresis are ignored.
+    pub fn update(&mut self, mv: u32, charging: Option<bool>, curve: &[(u16, u8)]) -> u8 {
+        if charging != self.charging {
+            // The voltage jumps when the charging state changes, drop samples of the previous state
+            self.len = 0;
+            self.next = 0;
+            self.charging = charging;
+        }
+        self.samples[self.next] = mv;
+        self.next = (self.next + 1) % self.window;
+        self.len = (self.len + 1).min(self.window);
+        let average = self.samples[..self.len].iter().sum::<u32>() / self.len as u32;
+
+        let percent = battery_percent(average, curve);
+        let level = match self.level {
+            Some(last) if percent.abs_diff(last) <= self.hysteresis => match charging {
+                Some(true) => percent.max(last),
+                Some(false) => percent.min(last),
+                None => last,
+            },
+            _ => percent,
+        };
+        self.level = Some(level);
+        level
+    }
+}
+
+/// Board-specific parts of sampling the battery, see [`run_battery_sampling`]
+pub trait BatterySampler {
+    /// Sample the battery voltage in mV, `None` if the battery can't be sampled
+    async fn sample_mv(&mut self) -> Option<u32>;
+
+    /// Charging state, `None` if it's unknown
+    fn charging_state(&self) -> Option<bool> {
+        None
+    }
+
+    /// Turn the charge LED on or off, or toggle it if `on` is `None`
+    fn set_charge_led(&mut self, _on: Option<bool>) {}
+
+    /// Discharge curve of the battery, see [`battery_percent`]
+    fn discharge_curve(&self) -> &'static [(u16, u8)] {
+        &LIPO_DISCHARGE_CURVE
+    }
+
+    /// Filter of the sampled voltages
+    fn level_filter(&self) -> BatteryLevelFilter {
+        BatteryLevelFilter::new(4, 2)
+    }
+}
+
+/// Battery sampled by reading the raw value of an ADC, such as the oneshot ADC driver of ESP-IDF
+pub struct AdcBatterySampler<F: FnMut() -> Option<u16>> {
+    /// Read the raw ADC value, `None` if the read fails
+    read: F,
+    pub adc_divider_measured: u32,
+    pub adc_divider_total: u32,
+    /// Reference, gain and resolution of the ADC
+    pub adc_config: AdcConfig,
+    /// Battery levels at voltages, in `(millivolts, percent)` sorted by voltage from high to low
+    pub discharge_curve: &'static [(u16, u8)],
+    /// Number of samples of the moving average of the battery voltage
+    pub filter_window: usize,
+    /// Changes of the battery level within the hysteresis(in percent) are ignored
+    pub hysteresis: u8,
+}
+
+impl<F: FnMut() -> Option<u16>> AdcBatterySampler<F> {
+    /// Create a sampler which reads the ADC by `read`, without the voltage divider
+    pub fn new(read: F, adc_config: AdcConfig) -> Self {
+        Self {
+            read,
+            adc_divider_measured: 1,
+            adc_divider_total: 1,
+            adc_config,
+            discharge_curve: &LIPO_DISCHARGE_CURVE,
+            filter_window: 4,
+            hysteresis: 2,
+        }
+    }
+}
+
+impl<F: FnMut() -> Option<u16>> BatterySampler for AdcBatterySampler<F> {
+    async fn sample_mv(&mut self) -> Option<u32> {
+        let val = (self.read)()?;
+        debug!("Detected adc value: {:?}", val);
+        Some(self.adc_config.battery_mv(
+            val.min(i16::MAX as u16) as i16,
+            self.adc_divider_measured,
+            self.adc_divider_total,
+        ))
+    }
+
+    fn discharge_curve(&self) -> &'static [(u16, u8)] {
+        self.discharge_curve
+    }
+
+    fn level_filter(&self) -> BatteryLevelFilter {
+        BatteryLevelFilter::new(self.filter_window, self.hysteresis)
+    }
+}
+
+/// Sample the battery every 120s, the battery level is passed to `report`.
+///
+/// The charge LED is on while charging, and it blinks slowly when the battery is low.
+/// If the battery can't be sampled, it waits forever.
+pub(crate) async fn run_battery_sampling(
+    sampler: &mut impl BatterySampler,
+    mut report: impl FnMut(u8),
+) -> ! {
+    check_charging_state(sampler);
+    let mut filter = sampler.level_filter();
+    loop {
+        let Some(mv) = sampler.sample_mv().await else {
+            // No ADC, skip battery check
+            loop {
+                core::future::pending::<()>().await;
+            }
+        };
+        debug!("Battery voltage: {}mV", mv);
+        let level = filter.update(mv, sampler.charging_state(), sampler.discharge_curve());
+        report(level);
+        if level < 10 {
+            // The battery is low, blink the led!
+            sampler.set_charge_led(None);
+            Timer::after_secs(200).await;
+            continue;
+        }
+        // Turn off the led
+        sampler.set_charge_led(Some(false));
+
+        check_charging_state(sampler);
+
+        // Sample every 120s
+        Timer::after_secs(120).await
+    }
+}
+
+/// Show the charging state on the charge LED
+fn check_charging_state(sampler: &mut impl BatterySampler) {
+    if let Some(charging) = sampler.charging_state() {
+        if charging {
+            info!("Charging!");
+        } else {
+            info!("Not charging!");
+        }
+        // The LED is on while charging
+        sampler.set_charge_led(Some(charging));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use embassy_futures::block_on;
+    use embassy_time::{with_timeout, Duration};
+
+    use super::*;
+
+    #[test]
+    fn test_battery_mv() {
+        let adc = AdcConfig::default();
+        // 4.2V battery, measured directly: 4200 / 6 / 600 * 4096
+        assert_eq!(adc.battery_mv(4778, 1, 1), 4199);
+        // VDDH input of nRF52, which is VDDH / 5
+        assert_eq!(adc.battery_mv(956, 1, 5), 4201);
+        // nice!nano's divider, 806K + 2M
+        assert_eq!(adc.battery_mv(3405, 2000, 2806), 4198);
+        assert_eq!(adc.battery_mv(-3, 1, 1), 0);
+
+        // 3.3V reference, no gain, 10-bit resolution
+        let adc = AdcConfig {
+            reference_mv: 3300,
+            gain_numerator: 1,
+            gain_denominator: 1,
+            resolution: 10,
+        };
+        assert_eq!(adc.battery_mv(651, 1, 2), 4195);
+        assert_eq!(adc.battery_mv(651, 0, 2), 0);
+    }
+
+    #[test]
+    fn test_battery_percent() {
+        let curve = &LIPO_DISCHARGE_CURVE;
+        assert_eq!(battery_percent(4300, curve), 100);
+        assert_eq!(battery_percent(4200, curve), 100);
+        assert_eq!(battery_percent(3840, curve), 50);
+        // Interpolated between (3980, 75) and (3950, 70)
+        assert_eq!(battery_percent(3965, curve), 73);
+        assert_eq!(battery_percent(3970, curve), 73);
+        // Level drops faster at the end of the curve
+        assert_eq!(battery_percent(3650, curve), 8);
+        assert_eq!(battery_percent(3270, curve), 0);
+        assert_eq!(battery_percent(3000, curve), 0);
+        assert_eq!(battery_percent(3900, &[]), 0);
+        assert_eq!(battery_percent(3900, &[(4000, 100)]), 100);
+
+        // Linear curve between 3.6V and 4.2V
+        let linear = &[(4200, 100), (3600, 0)];
+        assert_eq!(battery_percent(3900, linear), 50);
+        assert_eq!(battery_percent(3603, linear), 1);
+    }
+
+    #[test]
+    fn test_moving_average() {
+        let mut filter = BatteryLevelFilter::new(4, 0);
+        let curve = &[(4200, 100), (3200, 0)];
+        assert_eq!(filter.update(4000, None, curve), 80);
+        // Average of 4000 and 3800
+        assert_eq!(filter.update(3800, None, curve), 70);
+        assert_eq!(filter.update(3800, None, curve), 67);
+        assert_eq!(filter.update(3800, None, curve), 65);
+        // The first sample is dropped
+        assert_eq!(filter.update(3800, None, curve), 60);
+
+        // Samples are dropped when the charging state changes
+        assert_eq!(filter.update(4100, Some(true), curve), 90);
+
+        // Window of 1 disables the moving average
+        let mut filter = BatteryLevelFilter::new(0, 0);
+        assert_eq!(filter.update(4000, None, curve), 80);
+        assert_eq!(filter.update(3800, None, curve), 60);
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let mut filter = BatteryLevelFilter::new(1, 3);
+        let curve = &[(4200, 100), (3200, 0)];
+        // Small changes are ignored when the charging state is unknown
+        assert_eq!(filter.update(3700, None, curve), 50);
+        assert_eq!(filter.update(3720, None, curve), 50);
+        assert_eq!(filter.update(3680, None, curve), 50);
+        assert_eq!(filter.update(3660, None, curve), 46);
+
+        // Level doesn't go up while discharging
+        assert_eq!(filter.update(3650, Some(false), curve), 45);
+        assert_eq!(filter.update(3670, Some(false), curve), 45);
+        assert_eq!(filter.update(3640, Some(false), curve), 44);
+        // Unless it's changed a lot
+        assert_eq!(filter.update(3700, Some(false), curve), 50);
+
+        // Level doesn't go down while charging
+        assert_eq!(filter.update(3720, Some(true), curve), 52);
+        assert_eq!(filter.update(3700, Some(true), curve), 52);
+        assert_eq!(filter.update(3740, Some(true), curve), 54);
+    }
+
+    /// Battery whose voltages are sampled from a list, the charge LED is recorded
+    struct FakeSampler<'a> {
+        voltages: &'a [u32],
+        next: usize,
+        charging: Option<bool>,
+        led: Cell<Option<bool>>,
+    }
+
+    impl BatterySampler for FakeSampler<'_> {
+        async fn sample_mv(&mut self) -> Option<u32> {
+            let mv = self.voltages.get(self.next).copied();
+            self.next += 1;
+            mv
+        }
+
+        fn charging_state(&self) -> Option<bool> {
+            self.charging
+        }
+
+        fn set_charge_led(&mut self, on: Option<bool>) {
+            let led = self.led.get().unwrap_or_default();
+            self.led.set(Some(on.unwrap_or(!led)));
+        }
+    }
+
+    /// Run the sampling loop until it waits for the next sample, returns the reported levels
+    fn sample(sampler: &mut FakeSampler) -> Vec<u8> {
+        let mut levels = Vec::new();
+        let _ = block_on(with_timeout(
+            Duration::from_millis(10),
+            run_battery_sampling(sampler, |level| levels.push(level)),
+        ));
+        levels
+    }
+
+    #[test]
+    fn test_battery_sampling() {
+        let mut sampler = FakeSampler {
+            voltages: &[3840],
+            next: 0,
+            charging: Some(true),
+            led: Cell::new(None),
+        };
+        assert_eq!(sample(&mut sampler), [50]);
+        // The LED shows the charging state after sampling
+        assert_eq!(sampler.led.get(), Some(true));
+
+        // The LED blinks when the battery is low
+        let mut sampler = FakeSampler {
+            voltages: &[3650],
+            next: 0,
+            charging: None,
+            led: Cell::new(None),
+        };
+        assert_eq!(sample(&mut sampler), [8]);
+        assert_eq!(sampler.led.get(), Some(true));
+
+        // Nothing is reported if the battery can't be sampled
+        let mut sampler = FakeSampler {
+            voltages: &[],
+            next: 0,
+            charging: None,
+            led: Cell::new(None),
+        };
+        assert!(sample(&mut sampler).is_empty());
+    }
+
+    #[test]
+    fn test_adc_battery_sampler() {
+        let mut reads = [Some(3405), None].into_iter();
+        let mut sampler = AdcBatterySampler::new(|| reads.next().flatten(), AdcConfig::default());
+        // nice!nano's divider, 806K + 2M
+        sampler.adc_divider_measured = 2000;
+        sampler.adc_divider_total = 2806;
+        assert_eq!(block_on(sampler.sample_mv()), Some(4198));
+        assert_eq!(block_on(sampler.sample_mv()), None);
+    }
+}
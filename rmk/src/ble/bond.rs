@@ -0,0 +1,96 @@
+//! Bond info of the ESP32 BLE backend which is saved in the storage
+//!
+//! The keys are stored by NimBLE, RMK saves which host is bonded to each profile.
+//! It's kept out of `ble::esp` so that the storage encoding can be tested on the host.
+
+// Bond info which will be stored in flash.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) struct BondInfo {
+    pub(crate) slot_num: u8,
+    // Identity address type of the host
+    pub(crate) addr_type: u8,
+    // Identity address of the host
+    pub(crate) addr: [u8; 6],
+    pub(crate) removed: bool,
+}
+
+impl BondInfo {
+    /// Size of the bond info saved in the storage
+    pub(crate) const SIZE: usize = 9;
+
+    /// Encode the bond info which is saved in the storage
+    pub(crate) fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut buf = [0; Self::SIZE];
+        buf[0] = self.slot_num;
+        buf[1] = self.addr_type;
+        buf[2..8].copy_from_slice(&self.addr);
+        buf[8] = self.removed as u8;
+        buf
+    }
+
+    /// Decode the bond info saved in the storage, any non-zero flag byte is read as `removed`
+    pub(crate) fn from_bytes(buf: &[u8; Self::SIZE]) -> Self {
+        let mut addr = [0; 6];
+        addr.copy_from_slice(&buf[2..8]);
+        Self {
+            slot_num: buf[0],
+            addr_type: buf[1],
+            addr,
+            removed: buf[8] != 0,
+        }
+    }
+
+    /// Whether the host with the identity address is bonded to this profile
+    pub(crate) fn is_host(&self, addr_type: u8, addr: &[u8; 6]) -> bool {
+        !self.removed && self.addr_type == addr_type && self.addr == *addr
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bond_info_round_trip() {
+        let info = BondInfo {
+            slot_num: 3,
+            addr_type: 1,
+            addr: [0x18, 0xe2, 0x21, 0x80, 0xc0, 0xc7],
+            removed: false,
+        };
+        let buf = info.to_bytes();
+        assert_eq!(buf, [3, 1, 0x18, 0xe2, 0x21, 0x80, 0xc0, 0xc7, 0]);
+        assert_eq!(BondInfo::from_bytes(&buf), info);
+
+        let removed = BondInfo {
+            removed: true,
+            ..info
+        };
+        assert_eq!(BondInfo::from_bytes(&removed.to_bytes()), removed);
+    }
+
+    #[test]
+    fn test_bond_info_removed_flag() {
+        // Any non-zero flag byte, e.g. erased flash, is read as removed
+        let mut buf = [0; BondInfo::SIZE];
+        buf[8] = 0xFF;
+        let info = BondInfo::from_bytes(&buf);
+        assert!(info.removed);
+        assert!(!info.is_host(0, &[0; 6]));
+    }
+
+    #[test]
+    fn test_bond_info_is_host() {
+        let info = BondInfo {
+            slot_num: 0,
+            addr_type: 1,
+            addr: [1, 2, 3, 4, 5, 6],
+            removed: false,
+        };
+        assert!(info.is_host(1, &[1, 2, 3, 4, 5, 6]));
+        assert!(!info.is_host(0, &[1, 2, 3, 4, 5, 6]));
+        assert!(!info.is_host(1, &[1, 2, 3, 4, 5, 7]));
+    }
+}
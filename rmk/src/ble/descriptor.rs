@@ -12,6 +12,7 @@ pub(crate) enum BleCompositeReportType {
     Media = 0x03,
     System = 0x04,
     Vial = 0x05,
+    Joystick = 0x06,
 }
 
 /// KeyboardReport describes a report and its companion descriptor that can be
@@ -72,6 +73,24 @@ pub(crate) enum BleCompositeReportType {
                 #[item_settings data,array,absolute,not_null] system_usage_id=input;
             };
         };
+    },
+    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = 0x05) = {
+        (report_id = 0x06,) = {
+            (usage_page = BUTTON, usage_min = BUTTON_1, usage_max = 0x20) = {
+                #[packed_bits 32] #[item_settings data,variable,absolute] joystick_buttons=input;
+            };
+            (usage_page = GENERIC_DESKTOP,) = {
+                (usage = X,) = {
+                    #[item_settings data,variable,absolute] joystick_x=input;
+                };
+                (usage = Y,) = {
+                    #[item_settings data,variable,absolute] joystick_y=input;
+                };
+                (usage = Z,) = {
+                    #[item_settings data,variable,absolute] joystick_z=input;
+                };
+            };
+        };
     }
 )]
 #[allow(dead_code)]
@@ -87,4 +106,8 @@ pub(crate) struct BleKeyboardReport {
     pub(crate) pan: i8,   // Scroll left (negative) or right (positive) this many units
     pub(crate) media_usage_id: u16,
     pub(crate) system_usage_id: u8,
+    pub(crate) joystick_buttons: u32,
+    pub(crate) joystick_x: i8,
+    pub(crate) joystick_y: i8,
+    pub(crate) joystick_z: i8,
 }
@@ -0,0 +1,135 @@
+use core::cell::RefCell;
+use core::sync::atomic::Ordering;
+
+use embassy_sync::blocking_mutex::Mutex;
+use esp_idf_svc::sys::{
+    ble_addr_t, ble_gap_conn_desc, ble_gap_conn_find, ble_store_util_delete_peer,
+};
+use heapless::FnvIndexMap;
+
+use crate::{
+    ble::{
+        bond::BondInfo,
+        profile::{ProfileBonder, ACTIVE_PROFILE, BONDED_DEVICE_NUM},
+    },
+    channel::FLASH_CHANNEL,
+    storage::FlashOperationMessage,
+    RawMutex,
+};
+
+// Bonder that manages multiple profiles.
+//
+// It's shared with NimBLE callbacks which run in the NimBLE host task, so the bond info is protected by a mutex.
+pub(crate) struct MultiBonder {
+    // Info of all bonded devices, `slot_num` is used as the key
+    bond_info: Mutex<RawMutex, RefCell<FnvIndexMap<u8, BondInfo, BONDED_DEVICE_NUM>>>,
+}
+
+impl MultiBonder {
+    pub(crate) fn new(bond_info: FnvIndexMap<u8, BondInfo, BONDED_DEVICE_NUM>) -> Self {
+        Self {
+            bond_info: Mutex::new(RefCell::new(bond_info)),
+        }
+    }
+
+    /// Identity address of the host bonded to the active profile
+    pub(crate) fn active_host(&self) -> Option<ble_addr_t> {
+        let profile = ACTIVE_PROFILE.load(Ordering::Acquire);
+        self.bond_info.lock(|b| {
+            b.borrow()
+                .get(&profile)
+                .filter(|info| !info.removed)
+                .map(ble_addr)
+        })
+    }
+
+    /// Whether the connected host is bonded to a profile other than the active one
+    pub(crate) fn is_other_profile_host(&self, conn_handle: u16) -> bool {
+        let Some(addr) = peer_id_addr(conn_handle) else {
+            return false;
+        };
+        let profile = ACTIVE_PROFILE.load(Ordering::Acquire);
+        self.bond_info.lock(|b| {
+            b.borrow()
+                .values()
+                .any(|info| info.slot_num != profile && info.is_host(addr.type_, &addr.val))
+        })
+    }
+
+    /// Save the host bonded on the connection to the active profile
+    pub(crate) fn on_bonded(&self, conn_handle: u16) {
+        let Some(addr) = peer_id_addr(conn_handle) else {
+            return;
+        };
+        let profile = ACTIVE_PROFILE.load(Ordering::Acquire);
+        let new_bond_info = BondInfo {
+            slot_num: profile,
+            addr_type: addr.type_,
+            addr: addr.val,
+            removed: false,
+        };
+        let replaced = self
+            .bond_info
+            .lock(|b| b.borrow_mut().insert(profile, new_bond_info).ok().flatten());
+        if let Some(old) = replaced {
+            if old.is_host(addr.type_, &addr.val) {
+                // Same host is bonded again, nothing changed
+                return;
+            }
+            if !old.removed {
+                // The previous host of the profile is replaced
+                delete_bond(&ble_addr(&old));
+            }
+        }
+
+        info!("Saving bond info of profile {}", profile);
+        if FLASH_CHANNEL
+            .try_send(FlashOperationMessage::BondInfo(new_bond_info))
+            .is_err()
+        {
+            error!("Send bond info to flash channel error");
+        }
+    }
+}
+
+impl ProfileBonder for MultiBonder {
+    fn clear_bonded(&self, profile: u8) {
+        let cleared = self.bond_info.lock(|b| {
+            b.borrow_mut().get_mut(&profile).and_then(|info| {
+                let bonded = !info.removed;
+                info.removed = true;
+                bonded.then(|| ble_addr(info))
+            })
+        });
+        if let Some(addr) = cleared {
+            delete_bond(&addr);
+        }
+    }
+}
+
+/// Identity address of the host bonded to the profile
+fn ble_addr(info: &BondInfo) -> ble_addr_t {
+    ble_addr_t {
+        type_: info.addr_type,
+        val: info.addr,
+    }
+}
+
+/// Identity address of the peer, which doesn't change even if the peer uses resolvable private addresses
+fn peer_id_addr(conn_handle: u16) -> Option<ble_addr_t> {
+    let mut desc: ble_gap_conn_desc = unsafe { core::mem::zeroed() };
+    let rc = unsafe { ble_gap_conn_find(conn_handle, &mut desc) };
+    if rc != 0 {
+        error!("Find BLE connection error, error code: {}", rc);
+        return None;
+    }
+    Some(desc.peer_id_addr)
+}
+
+/// Delete the keys of the host which are stored by NimBLE
+fn delete_bond(addr: &ble_addr_t) {
+    let rc = unsafe { ble_store_util_delete_peer(addr) };
+    if rc != 0 {
+        error!("Delete BLE bond error, error code: {}", rc);
+    }
+}
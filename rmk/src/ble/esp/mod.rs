@@ -1,15 +1,19 @@
+pub(crate) mod bonder;
 pub(crate) mod server;
 
-use self::server::BleServer;
-use crate::channel::VIAL_READ_CHANNEL;
+use self::bonder::MultiBonder;
+use self::server::{BleServer, EspAdvertiser, EspBatteryService};
+use crate::ble::profile::{load_bond_info, load_keyboard_states};
+use crate::ble::service::{BleKeyboard, BleKeyboardEvents, NoUsb};
+use crate::ble::stack::run_ble_stack;
 use crate::config::RmkConfig;
 use crate::keymap::KeyMap;
 use crate::light::LightController;
 use crate::storage::Storage;
-use crate::{run_keyboard, CONNECTION_STATE};
 use core::cell::RefCell;
 use embedded_hal::digital::OutputPin;
 use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+use static_cell::StaticCell;
 
 /// Initialize and run the BLE keyboard service, with given keyboard usb config.
 /// Can only be used on nrf52 series microcontrollers with `nrf-softdevice` crate.
@@ -35,41 +39,30 @@ pub(crate) async fn run_esp_ble_keyboard<
     light_controller: &mut LightController<Out>,
     rmk_config: RmkConfig<'static>,
 ) -> ! {
-    // esp32c3 doesn't have USB device, so there is no usb here
-    loop {
-        CONNECTION_STATE.store(false, core::sync::atomic::Ordering::Release);
-        info!("Advertising..");
-        let mut ble_server = BleServer::new(rmk_config.usb_config);
-        ble_server.output_keyboard.lock().on_write(|args| {
-            let data: &[u8] = args.recv_data();
-            debug!("output_keyboard {}, {}", data.len(), data[0]);
-        });
-
-        info!("Waitting for connection..");
-        ble_server.wait_for_connection().await;
-
-        info!("BLE connected!");
-        CONNECTION_STATE.store(true, core::sync::atomic::Ordering::Release);
+    // Load the active profile and the bonded hosts of profiles
+    load_keyboard_states(storage).await;
+    let bond_info = load_bond_info(storage).await;
+    info!("Loaded {} saved bond info", bond_info.len());
+    static BONDER: StaticCell<MultiBonder> = StaticCell::new();
+    let bonder = BONDER.init(MultiBonder::new(bond_info));
 
-        // Create BLE HID writers
-        let keyboard_writer = ble_server.get_keyboard_writer();
-        let vial_reader_writer = ble_server.get_vial_reader_writer();
-        let led_reader = ble_server.get_led_reader();
+    let ble_server = BleServer::new(rmk_config.usb_config, rmk_config.ble_passkey_entry, bonder);
 
-        let disconnect = BleServer::wait_for_disconnection(ble_server.server);
-
-        run_keyboard(
-            keymap,
-            storage,
-            disconnect,
-            light_controller,
-            led_reader,
-            vial_reader_writer,
-            keyboard_writer,
-            rmk_config.vial_config,
-        )
-        .await;
-
-        warn!("BLE disconnected!")
-    }
+    // esp32c3 doesn't have USB device, so there is no usb here
+    let mut advertiser = EspAdvertiser { bonder };
+    let mut keyboard = BleKeyboard {
+        keymap,
+        storage,
+        light_controller,
+        vial_config: rmk_config.vial_config,
+        hid_service: &ble_server,
+        battery_service: EspBatteryService,
+        usb: NoUsb,
+    };
+    run_ble_stack(
+        &mut advertiser,
+        &mut keyboard,
+        &BleKeyboardEvents { bonder },
+    )
+    .await
 }
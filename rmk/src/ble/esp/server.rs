@@ -1,11 +1,18 @@
 extern crate alloc;
 use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU16, Ordering};
 use embassy_futures::block_on;
-use embassy_time::Timer;
+use embassy_futures::select::select;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Timer};
 use esp32_nimble::{
-    enums::{AuthReq, SecurityIOCap},
+    enums::{AdvFilterPolicy, AuthReq, SecurityIOCap},
     utilities::{mutex::Mutex, BleUuid},
-    BLEAdvertisementData, BLECharacteristic, BLEDevice, BLEHIDDevice, BLEServer, NimbleProperties,
+    BLEAdvertisementData, BLECharacteristic, BLEDevice, BLEHIDDevice, NimbleProperties,
+};
+use esp_idf_svc::sys::{
+    ble_gap_terminate, ble_gap_wl_set, ble_sm_inject_io, ble_sm_io, ble_sm_io__bindgen_ty_1,
+    BLE_SM_IOACT_INPUT,
 };
 use ssmarshal::serialize;
 use usbd_hid::descriptor::SerializedDescriptor as _;
@@ -15,22 +22,42 @@ use crate::{
         descriptor::{BleCompositeReportType, BleKeyboardReport},
         device_info::VidSource,
         led::BleLedReader,
+        passkey::{cancel_passkey_entry, start_passkey_entry, PASSKEY_ENTERED},
+        service::{BleBatteryService, BleHidService},
+        stack::{BleAdvertiser, BleConnection, BleStackError},
     },
-    channel::{KEYBOARD_REPORT_CHANNEL, LED_SIGNAL},
+    channel::{KEYBOARD_REPORT_CHANNEL, LED_SIGNAL, VIAL_READ_CHANNEL},
     config::KeyboardUsbConfig,
     hid::{HidError, HidReaderTrait, HidWriterTrait, Report, RunnableHidWriter},
     light::LedIndicator,
     usb::descriptor::ViaReport,
-    CONNECTION_STATE,
+    RawMutex, CONNECTION_STATE,
 };
 
-use super::VIAL_READ_CHANNEL;
+use super::bonder::MultiBonder;
+
+/// Handle of the current connection
+static CONNECTION_HANDLE: AtomicU16 = AtomicU16::new(0);
+
+/// Pairing is failed if the passkey isn't typed in time, same as the timeout of the security manager protocol
+const PASSKEY_ENTRY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Passkey returned to the passkey request callback, which is rejected by NimBLE as it has 7 digits.
+/// The security procedure keeps waiting then, the typed passkey is injected later by [`reply_passkey`].
+const DEFERRED_PASSKEY: u32 = 1_000_000;
+
+/// HCI reason "Authentication Failure", which terminates the connection when the pairing is rejected
+const AUTHENTICATION_FAILURE: u8 = 0x05;
+
+/// Handle of the connection which requests a passkey
+static PASSKEY_REQUESTED: Signal<RawMutex, u16> = Signal::new();
 
 pub(crate) struct BleKeyboardWriter {
     pub(crate) keyboard_handle: Arc<Mutex<BLECharacteristic>>,
     pub(crate) media_handle: Arc<Mutex<BLECharacteristic>>,
     pub(crate) system_control_handle: Arc<Mutex<BLECharacteristic>>,
     pub(crate) mouse_handle: Arc<Mutex<BLECharacteristic>>,
+    pub(crate) joystick_handle: Arc<Mutex<BLECharacteristic>>,
 }
 
 impl RunnableHidWriter for BleKeyboardWriter {
@@ -73,6 +100,13 @@ impl HidWriterTrait for BleKeyboardWriter {
                 self.write(&self.system_control_handle, &buf).await?;
                 Ok(n)
             }
+            Report::JoystickReport(joystick_report) => {
+                let mut buf = [0u8; 7];
+                let n = serialize(&mut buf, &joystick_report)
+                    .map_err(|_| HidError::ReportSerializeError)?;
+                self.write(&self.joystick_handle, &buf).await?;
+                Ok(n)
+            }
         }
     }
 }
@@ -132,33 +166,68 @@ impl BleVialReaderWriter {
 
 // BLE HID keyboard server
 pub(crate) struct BleServer {
-    pub(crate) server: &'static mut BLEServer,
     pub(crate) input_keyboard: Arc<Mutex<BLECharacteristic>>,
     pub(crate) output_keyboard: Arc<Mutex<BLECharacteristic>>,
     pub(crate) input_media_keys: Arc<Mutex<BLECharacteristic>>,
     pub(crate) input_system_keys: Arc<Mutex<BLECharacteristic>>,
     pub(crate) input_mouse_keys: Arc<Mutex<BLECharacteristic>>,
+    pub(crate) input_joystick: Arc<Mutex<BLECharacteristic>>,
     pub(crate) input_vial: Arc<Mutex<BLECharacteristic>>,
     pub(crate) output_vial: Arc<Mutex<BLECharacteristic>>,
 }
 
 impl BleServer {
-    pub(crate) fn new(usb_config: KeyboardUsbConfig) -> Self {
+    pub(crate) fn new(
+        usb_config: KeyboardUsbConfig,
+        passkey_entry: bool,
+        bonder: &'static MultiBonder,
+    ) -> Self {
         let keyboard_name = usb_config.product_name;
         let device = BLEDevice::take();
         BLEDevice::set_device_name(keyboard_name).ok();
         device
             .security()
             .set_auth(AuthReq::all())
-            .set_io_cap(SecurityIOCap::NoInputNoOutput)
+            .set_io_cap(if passkey_entry {
+                SecurityIOCap::KeyboardOnly
+            } else {
+                SecurityIOCap::NoInputNoOutput
+            })
             .resolve_rpa();
         let server = device.get_server();
+        // Advertising is restarted by the BLE stack, with the white list of the active profile
+        server.advertise_on_disconnect(false);
+        // Hosts bonded to other profiles are rejected
+        server.on_connect(move |server, desc| {
+            CONNECTION_HANDLE.store(desc.conn_handle(), Ordering::Release);
+            if bonder.is_other_profile_host(desc.conn_handle()) {
+                warn!("Rejected the host of another BLE profile");
+                if let Err(e) = server.disconnect(desc.conn_handle()) {
+                    error!("BLE disconnect error, error code: {}", e.code());
+                }
+            }
+        });
+        // Save the bonded host to the active profile
+        server.on_authentication_complete(move |desc, r| match r {
+            Ok(()) if desc.bonded() => bonder.on_bonded(desc.conn_handle()),
+            Ok(()) => (),
+            Err(e) => warn!("BLE pairing failed, error code: {}", e.code()),
+        });
         // Set disconnected callback
         server.on_disconnect(|_, r| {
             if let Err(e) = r {
                 warn!("BLE disconnected, error code: {}", e.code());
             }
             info!("Disconnected!");
+            cancel_passkey_entry();
+        });
+        // The callback is called in the NimBLE host task, which must not be blocked,
+        // so the passkey is replied by `reply_passkey` after it's typed
+        server.on_passkey_request(|| {
+            info!("BLE passkey requested");
+            start_passkey_entry();
+            PASSKEY_REQUESTED.signal(CONNECTION_HANDLE.load(Ordering::Acquire));
+            DEFERRED_PASSKEY
         });
         let mut hid = BLEHIDDevice::new(server);
         hid.manufacturer(usb_config.manufacturer);
@@ -174,6 +243,7 @@ impl BleServer {
         let input_media_keys = hid.input_report(BleCompositeReportType::Media as u8);
         let input_system_keys = hid.input_report(BleCompositeReportType::System as u8);
         let input_mouse_keys = hid.input_report(BleCompositeReportType::Mouse as u8);
+        let input_joystick = hid.input_report(BleCompositeReportType::Joystick as u8);
 
         hid.pnp(
             VidSource::UsbIF as u8,
@@ -216,17 +286,13 @@ impl BleServer {
             error!("BLE advertising error, error code: {}", e.code());
         }
 
-        if let Err(e) = ble_advertising.lock().start() {
-            error!("BLE advertising start error: {}", e.code());
-        }
-
         Self {
-            server,
             input_keyboard,
             output_keyboard,
             input_media_keys,
             input_system_keys,
             input_mouse_keys,
+            input_joystick,
             input_vial,
             output_vial,
         }
@@ -251,6 +317,7 @@ impl BleServer {
             media_handle: self.input_media_keys.clone(),
             system_control_handle: self.input_system_keys.clone(),
             mouse_handle: self.input_mouse_keys.clone(),
+            joystick_handle: self.input_joystick.clone(),
         }
     }
 
@@ -267,25 +334,139 @@ impl BleServer {
             vial_input_handle: self.input_vial.clone(),
         }
     }
+}
+
+impl BleHidService<EspConnection> for BleServer {
+    async fn run(&self, _conn: &EspConnection) {
+        // GATT server runs in the NimBLE host task
+        core::future::pending().await
+    }
+
+    fn led_reader(&self) -> impl HidReaderTrait<ReportType = LedIndicator> {
+        self.get_led_reader()
+    }
+
+    fn keyboard_writer<'s>(&'s self, _conn: &'s EspConnection) -> impl RunnableHidWriter + 's {
+        self.get_keyboard_writer()
+    }
+
+    fn vial_reader_writer<'s>(
+        &'s self,
+        _conn: &'s EspConnection,
+    ) -> impl HidReaderTrait<ReportType = ViaReport> + HidWriterTrait<ReportType = ViaReport> + 's
+    {
+        self.get_vial_reader_writer()
+    }
+}
 
-    pub(crate) async fn wait_for_connection(&mut self) {
-        loop {
-            // Check connection status every 100 ms
+/// Advertiser of the BLE keyboard, only the bonded host of the active profile can connect
+pub(crate) struct EspAdvertiser {
+    pub(crate) bonder: &'static MultiBonder,
+}
+
+impl BleAdvertiser for EspAdvertiser {
+    type Connection = EspConnection;
+
+    async fn advertise(&mut self) -> Result<Self::Connection, BleStackError> {
+        let ble_advertising = BLEDevice::take().get_advertising();
+        // Advertising might be running for the previous profile, stop it before updating the white list
+        ble_advertising.lock().stop().ok();
+        // Any host can pair if the active profile is empty
+        let filter_policy = match self.bonder.active_host() {
+            Some(addr) => {
+                let rc = unsafe { ble_gap_wl_set(&addr, 1) };
+                if rc != 0 {
+                    error!("Set BLE white list error, error code: {}", rc);
+                }
+                AdvFilterPolicy::Both
+            }
+            None => AdvFilterPolicy::None,
+        };
+        if let Err(e) = ble_advertising.lock().filter_policy(filter_policy).start() {
+            error!("BLE advertising start error: {}", e.code());
+            return Err(BleStackError::AdvertiseError);
+        }
+
+        let server = BLEDevice::take().get_server();
+        // Check connection status every 100 ms
+        while server.connected_count() == 0 {
             Timer::after_millis(100).await;
-            if self.server.connected_count() > 0 {
-                break;
+        }
+        Ok(EspConnection {
+            conn_handle: CONNECTION_HANDLE.load(Ordering::Acquire),
+        })
+    }
+}
+
+/// Connection to a host
+pub(crate) struct EspConnection {
+    conn_handle: u16,
+}
+
+/// Reply the passkey requests of the host with the passkeys typed on the keyboard.
+///
+/// The pairing is rejected if the passkey entry is cancelled or isn't finished in time.
+async fn reply_passkey() -> ! {
+    loop {
+        let conn_handle = PASSKEY_REQUESTED.wait().await;
+        let passkey = with_timeout(PASSKEY_ENTRY_TIMEOUT, PASSKEY_ENTERED.wait()).await;
+        cancel_passkey_entry();
+        match passkey {
+            Ok(Some(passkey)) => {
+                let mut io = ble_sm_io {
+                    action: BLE_SM_IOACT_INPUT as u8,
+                    __bindgen_anon_1: ble_sm_io__bindgen_ty_1 { passkey },
+                };
+                let rc = unsafe { ble_sm_inject_io(conn_handle, &mut io) };
+                if rc != 0 {
+                    error!("Reply BLE passkey error, error code: {}", rc);
+                }
+            }
+            _ => {
+                warn!("BLE passkey isn't typed, the pairing is rejected");
+                let rc = unsafe { ble_gap_terminate(conn_handle, AUTHENTICATION_FAILURE) };
+                if rc != 0 {
+                    error!("BLE disconnect error, error code: {}", rc);
+                }
             }
         }
     }
+}
 
-    pub(crate) async fn wait_for_disconnection(server: &'static mut BLEServer) {
-        CONNECTION_STATE.store(true, core::sync::atomic::Ordering::Release);
-        loop {
+impl BleConnection for EspConnection {
+    async fn run(&self) {
+        CONNECTION_STATE.store(true, Ordering::Release);
+        PASSKEY_REQUESTED.reset();
+        let server = BLEDevice::take().get_server();
+        let check_connection = async {
             // Check connection status every 500 ms
-            Timer::after_millis(500).await;
-            if server.connected_count() == 0 {
-                break;
+            while server.connected_count() > 0 {
+                Timer::after_millis(500).await;
+            }
+        };
+        select(check_connection, reply_passkey()).await;
+    }
+
+    async fn disconnect(self) {
+        cancel_passkey_entry();
+        let server = BLEDevice::take().get_server();
+        if server.connected_count() > 0 {
+            if let Err(e) = server.disconnect(self.conn_handle) {
+                error!("BLE disconnect error, error code: {}", e.code());
             }
         }
+        // Wait until the host is disconnected
+        while server.connected_count() > 0 {
+            Timer::after_millis(100).await;
+        }
+    }
+}
+
+/// Battery service of the keyboard, the battery level isn't sampled on ESP32 yet
+pub(crate) struct EspBatteryService;
+
+impl BleBatteryService<EspConnection> for EspBatteryService {
+    async fn run(&mut self, _conn: &EspConnection) {
+        core::future::pending().await
     }
 }
@@ -1,6 +1,19 @@
+pub mod battery;
+#[cfg(any(feature = "_esp_ble", test))]
+pub(crate) mod bond;
+#[cfg(feature = "_ble")]
 pub(crate) mod descriptor;
+#[cfg(feature = "_ble")]
 pub(crate) mod device_info;
+#[cfg(feature = "_ble")]
 pub(crate) mod led;
+#[cfg(any(feature = "_ble", test))]
+pub(crate) mod passkey;
+#[cfg(feature = "_ble")]
+pub mod profile;
+#[cfg(feature = "_ble")]
+pub(crate) mod service;
+pub(crate) mod stack;
 
 #[cfg(feature = "_esp_ble")]
 pub mod esp;
@@ -9,3 +22,32 @@ pub mod nrf;
 
 #[cfg(any(feature = "nrf52840_ble", feature = "nrf52833_ble"))]
 pub use nrf::SOFTWARE_VBUS;
+
+#[cfg(feature = "_ble")]
+use {
+    crate::hid::{DummyWriter, RunnableHidWriter},
+    crate::storage::Storage,
+    crate::CONNECTION_STATE,
+    core::sync::atomic::Ordering,
+    embassy_futures::select::select,
+    embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash,
+};
+
+// Dummy keyboard service is used to monitoring keys when there's no actual connection.
+// It's useful for functions like switching active profiles when there's no connection.
+#[cfg(feature = "_ble")]
+pub(crate) async fn run_dummy_keyboard<
+    'a,
+    'b,
+    F: AsyncNorFlash,
+    const ROW: usize,
+    const COL: usize,
+    const NUM_LAYER: usize,
+>(
+    storage: &mut Storage<F, ROW, COL, NUM_LAYER>,
+) {
+    CONNECTION_STATE.store(false, Ordering::Release);
+    let storage_fut = storage.run();
+    let mut dummy_writer = DummyWriter {};
+    select(storage_fut, dummy_writer.run_writer()).await;
+}
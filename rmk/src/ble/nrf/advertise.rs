@@ -2,6 +2,13 @@ use nrf_softdevice::ble::advertisement_builder::{
     AdvertisementDataType, Error, Flag, LegacyAdvertisementBuilder, LegacyAdvertisementPayload,
     ServiceList, ServiceUuid16,
 };
+use nrf_softdevice::ble::peripheral::{self, ConnectableAdvertisement};
+use nrf_softdevice::ble::TxPower;
+use nrf_softdevice::Softdevice;
+
+use super::bonder::MultiBonder;
+use super::connection::NrfConnection;
+use crate::ble::stack::{BleAdvertiser, BleStackError};
 
 pub(crate) fn create_advertisement_data(keyboard_name: &str) -> LegacyAdvertisementPayload {
     LegacyAdvertisementBuilder::new()
@@ -35,3 +42,48 @@ pub(crate) static SCAN_DATA: LegacyAdvertisementPayload = LegacyAdvertisementBui
         ],
     )
     .build();
+
+/// Advertiser of the BLE keyboard, hosts are bonded by the bonder
+pub(crate) struct NrfAdvertiser<'d> {
+    sd: &'d Softdevice,
+    bonder: &'static MultiBonder,
+    adv_data: LegacyAdvertisementPayload,
+    config: peripheral::Config,
+}
+
+impl<'d> NrfAdvertiser<'d> {
+    pub(crate) fn new(
+        sd: &'d Softdevice,
+        bonder: &'static MultiBonder,
+        keyboard_name: &str,
+    ) -> Self {
+        let mut config = peripheral::Config::default();
+        // Interval: 500ms
+        config.interval = 800;
+        config.tx_power = TxPower::Plus4dBm;
+        Self {
+            sd,
+            bonder,
+            adv_data: create_advertisement_data(keyboard_name),
+            config,
+        }
+    }
+}
+
+impl BleAdvertiser for NrfAdvertiser<'_> {
+    type Connection = NrfConnection;
+
+    async fn advertise(&mut self) -> Result<Self::Connection, BleStackError> {
+        let adv = ConnectableAdvertisement::ScannableUndirected {
+            adv_data: &self.adv_data,
+            scan_data: &SCAN_DATA,
+        };
+        match peripheral::advertise_pairable(self.sd, adv, &self.config, self.bonder).await {
+            Ok(conn) => Ok(NrfConnection::new(conn, self.bonder)),
+            Err(e) => {
+                error!("Advertise error: {}", e);
+                Err(BleStackError::AdvertiseError)
+            }
+        }
+    }
+}
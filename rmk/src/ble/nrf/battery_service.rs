@@ -1,6 +1,25 @@
+use super::{connection::NrfConnection, server::BleServer};
+use crate::ble::battery::{run_battery_sampling, BatteryLevelFilter, BatterySampler};
+use crate::ble::service::BleBatteryService;
 use crate::config::BleBatteryConfig;
+#[cfg(feature = "split")]
+use embassy_futures::join::join;
 use embassy_time::Timer;
 use nrf_softdevice::ble::Connection;
+#[cfg(feature = "split")]
+use {
+    super::spec::{BleCharacteristics, BleDescriptor, BleSpecification},
+    crate::split::battery::{peripheral_battery_level, PERIPHERAL_BATTERY_CHANGED},
+    nrf_softdevice::{
+        ble::gatt_server::{
+            self,
+            builder::ServiceBuilder,
+            characteristic::{Attribute, Metadata, Properties},
+            RegisterError,
+        },
+        Softdevice,
+    },
+};
 
 #[nrf_softdevice::gatt_service(uuid = "180f")]
 #[derive(Debug, Clone, Copy)]
@@ -10,30 +29,6 @@ pub(crate) struct BatteryService {
 }
 
 impl<'a> BatteryService {
-    fn check_charging_state(battery_config: &mut BleBatteryConfig<'a>) {
-        if let Some(ref is_charging_pin) = battery_config.charge_state_pin {
-            if is_charging_pin.is_low() == battery_config.charge_state_low_active {
-                info!("Charging!");
-                if let Some(ref mut charge_led) = battery_config.charge_led_pin {
-                    if battery_config.charge_led_low_active {
-                        charge_led.set_low()
-                    } else {
-                        charge_led.set_high()
-                    }
-                }
-            } else {
-                info!("Not charging!");
-                if let Some(ref mut charge_led) = battery_config.charge_led_pin {
-                    if battery_config.charge_led_low_active {
-                        charge_led.set_high()
-                    } else {
-                        charge_led.set_low()
-                    }
-                }
-            }
-        }
-    }
-
     pub(crate) async fn run(
         &mut self,
         battery_config: &mut BleBatteryConfig<'a>,
@@ -41,84 +36,168 @@ impl<'a> BatteryService {
     ) {
         // Wait 1 seconds, ensure that gatt server has been started
         Timer::after_secs(1).await;
-        BatteryService::check_charging_state(battery_config);
-
-        loop {
-            if let Some(ref mut saadc) = battery_config.saadc {
-                let mut buf = [0i16; 1];
-                saadc.sample(&mut buf).await;
-                // We only sampled one ADC channel.
-                let val: u8 = self.get_battery_percent(buf[0], battery_config);
-                match self.battery_level_notify(conn, &val) {
-                    Ok(_) => info!("Battery value: {}", val),
-                    Err(e) => match self.battery_level_set(&val) {
-                        Ok(_) => info!("Battery value set: {}", val),
-                        Err(e2) => error!("Battery value notify error: {}, set error: {}", e, e2),
-                    },
-                }
-                if val < 10 {
-                    // The battery is low, blink the led!
-                    if let Some(ref mut charge_led) = battery_config.charge_led_pin {
-                        charge_led.toggle();
-                    }
-                    Timer::after_secs(200).await;
-                    continue;
-                } else {
-                    // Turn off the led
-                    if let Some(ref mut charge_led) = battery_config.charge_led_pin {
-                        if battery_config.charge_led_low_active {
-                            charge_led.set_high();
-                        } else {
-                            charge_led.set_low();
-                        }
-                    }
-                }
-            } else {
-                // No SAADC, skip battery check
-                Timer::after_secs(u32::MAX as u64).await;
+        run_battery_sampling(battery_config, |val| {
+            match self.battery_level_notify(conn, &val) {
+                Ok(_) => info!("Battery value: {}", val),
+                Err(e) => match self.battery_level_set(&val) {
+                    Ok(_) => info!("Battery value set: {}", val),
+                    Err(e2) => error!("Battery value notify error: {}, set error: {}", e, e2),
+                },
             }
+        })
+        .await
+    }
+}
+
+impl BatterySampler for BleBatteryConfig<'_> {
+    async fn sample_mv(&mut self) -> Option<u32> {
+        let saadc = self.saadc.as_mut()?;
+        let mut buf = [0i16; 1];
+        saadc.sample(&mut buf).await;
+        // We only sampled one ADC channel.
+        info!("Detected adc value: {:?}", buf[0]);
+        Some(
+            self.adc_config
+                .battery_mv(buf[0], self.adc_divider_measured, self.adc_divider_total),
+        )
+    }
 
-            // Check charging state
-            BatteryService::check_charging_state(battery_config);
+    fn charging_state(&self) -> Option<bool> {
+        let is_charging_pin = self.charge_state_pin.as_ref()?;
+        Some(is_charging_pin.is_low() == self.charge_state_low_active)
+    }
 
-            // Sample every 120s
-            Timer::after_secs(120).await
+    fn set_charge_led(&mut self, on: Option<bool>) {
+        if let Some(ref mut charge_led) = self.charge_led_pin {
+            match on {
+                Some(on) if on == self.charge_led_low_active => charge_led.set_low(),
+                Some(_) => charge_led.set_high(),
+                None => charge_led.toggle(),
+            }
         }
     }
 
-    fn get_battery_percent(&self, val: i16, battery_config: &BleBatteryConfig<'a>) -> u8 {
-        info!("Detected adc value: {:?}", val);
-        // Avoid overflow
-        let val = val as i32;
-
-        // According to nRF52840's datasheet, for single_ended saadc:
-        // val = v_adc * (gain / reference) * 2^(resolution)
-        //
-        // When using default setting, gain = 1/6, reference = 0.6v, resolution = 12bits, so:
-        // val = v_adc * 1137.8
-        //
-        // For example, rmk-ble-keyboard uses two resistors 820K and 2M adjusting the v_adc, then,
-        // v_adc = v_bat * measured / total => val = v_bat * 1137.8 * measured / total
-        //
-        // If the battery voltage range is 3.6v ~ 4.2v, the adc val range should be (4096 ~ 4755) * measured / total
-        let mut measured = battery_config.adc_divider_measured as i32;
-        let mut total = battery_config.adc_divider_total as i32;
-        if 500 < val && val < 1000 {
-            // Thing becomes different when using vddh as reference
-            // The adc value for vddh pin is actually vddh/5,
-            // so we use this rough range to detect vddh
-            measured = 1;
-            total = 5;
+    fn discharge_curve(&self) -> &'static [(u16, u8)] {
+        self.discharge_curve
+    }
+
+    fn level_filter(&self) -> BatteryLevelFilter {
+        BatteryLevelFilter::new(self.filter_window, self.hysteresis)
+    }
+}
+
+/// Battery service of a split peripheral, registered on the central.
+///
+/// It's distinguished from the central's battery service by its descriptors:
+/// the presentation format's description is the peripheral id plus 1, and the user description is "Peripheral".
+#[cfg(feature = "split")]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PeripheralBatteryService {
+    id: usize,
+    battery_level: u16,
+    battery_level_cccd: u16,
+}
+
+#[cfg(feature = "split")]
+impl PeripheralBatteryService {
+    pub(crate) fn new(sd: &mut Softdevice, id: usize) -> Result<Self, RegisterError> {
+        let mut service_builder = ServiceBuilder::new(sd, BleSpecification::BatteryService.uuid())?;
+
+        let mut battery_level = service_builder.add_characteristic(
+            BleCharacteristics::BatteryLevel.uuid(),
+            Attribute::new([0u8]),
+            Metadata::new(Properties::new().read().notify()),
+        )?;
+        let description = (id as u16 + 1).to_le_bytes();
+        battery_level.add_descriptor(
+            BleDescriptor::CharacteristicPresentationFormat.uuid(),
+            Attribute::new([
+                0x04u8, // Format: uint8
+                0x00u8, // Exponent
+                0xADu8, // Unit: percentage(0x27AD)
+                0x27u8,
+                0x01u8, // Namespace: Bluetooth SIG
+                description[0],
+                description[1],
+            ]),
+        )?;
+        battery_level.add_descriptor(
+            BleDescriptor::CharacteristicUserDescription.uuid(),
+            Attribute::new("Peripheral"),
+        )?;
+        let battery_level_handle = battery_level.build();
+
+        let _service_handle = service_builder.build();
+
+        Ok(Self {
+            id,
+            battery_level: battery_level_handle.value_handle,
+            battery_level_cccd: battery_level_handle.cccd_handle,
+        })
+    }
+
+    /// Whether the write is a subscription change of this service
+    pub(crate) fn is_cccd_write(&self, handle: u16) -> bool {
+        handle == self.battery_level_cccd
+    }
+
+    /// Notify the latest battery level of the peripheral, or set it if the host doesn't subscribe it.
+    ///
+    /// Nothing is reported until the peripheral reports its level, and the last level is kept after it's disconnected.
+    pub(crate) fn update(&self, sd: &Softdevice, conn: &Connection) {
+        let Some(val) = peripheral_battery_level(self.id) else {
+            return;
+        };
+        if let Err(e) = gatt_server::notify_value(conn, self.battery_level, &[val]) {
+            if let Err(e2) = gatt_server::set_value(sd, self.battery_level, &[val]) {
+                error!(
+                    "Peripheral {} battery value notify error: {}, set error: {}",
+                    self.id, e, e2
+                );
+            }
         }
-        if val > 4755_i32 * measured / total {
-            // 4755 ~= 4.2v * 1137.8
-            100_u8
-        } else if val < 4055_i32 * measured / total {
-            // 4096 ~= 3.6v * 1137.8
-            // To simplify the calculation, we use 4055 here
-            0_u8
-        } else {
-            ((val * total / measured - 4055) / 7) as u8
+    }
+}
+
+/// Report the battery levels of split peripherals to the host, when they're changed
+#[cfg(feature = "split")]
+pub(crate) async fn run_peripheral_battery_services(
+    sd: &Softdevice,
+    services: &[PeripheralBatteryService],
+    conn: &Connection,
+) {
+    // Wait 1 seconds, ensure that gatt server has been started
+    Timer::after_secs(1).await;
+    loop {
+        for service in services {
+            service.update(sd, conn);
         }
+        PERIPHERAL_BATTERY_CHANGED.wait().await;
+    }
+}
+
+/// Battery service of the keyboard, and the battery services of split peripherals
+pub(crate) struct NrfBatteryService<'s> {
+    #[cfg(feature = "split")]
+    pub(crate) sd: &'s Softdevice,
+    pub(crate) server: &'s BleServer,
+    pub(crate) config: BleBatteryConfig<'static>,
+}
+
+impl BleBatteryService<NrfConnection> for NrfBatteryService<'_> {
+    async fn run(&mut self, conn: &NrfConnection) {
+        #[cfg(feature = "split")]
+        join(
+            self.server.bas.clone().run(&mut self.config, &conn.conn),
+            run_peripheral_battery_services(self.sd, &self.server.peripheral_bas, &conn.conn),
+        )
+        .await;
+
+        #[cfg(not(feature = "split"))]
+        self.server
+            .bas
+            .clone()
+            .run(&mut self.config, &conn.conn)
+            .await;
     }
 }
@@ -1,11 +1,12 @@
-use super::BONDED_DEVICE_NUM;
+use crate::ble::passkey::{start_passkey_entry, PASSKEY_DIGITS, PASSKEY_ENTERED};
+use crate::ble::profile::{ProfileBonder, ACTIVE_PROFILE, BONDED_DEVICE_NUM};
 use crate::channel::FLASH_CHANNEL;
-use crate::{ble::nrf::ACTIVE_PROFILE, storage::FlashOperationMessage, CONNECTION_STATE};
+use crate::{storage::FlashOperationMessage, CONNECTION_STATE};
 use core::{cell::RefCell, sync::atomic::Ordering};
 use heapless::FnvIndexMap;
 use nrf_softdevice::ble::{
     gatt_server::{get_sys_attrs, set_sys_attrs},
-    security::{IoCapabilities, SecurityHandler},
+    security::{IoCapabilities, PasskeyReply, SecurityHandler},
     Address, AddressType, Connection, EncryptionInfo, IdentityKey, IdentityResolutionKey, MasterId,
     SecurityMode,
 };
@@ -64,16 +65,51 @@ pub(crate) struct MultiBonder {
     // Info of all bonded devices
     // `slot_num` is used as the key, because using peer as key will bring a lot more complexity
     bond_info: RefCell<FnvIndexMap<u8, BondInfo, BONDED_DEVICE_NUM>>,
+    // Whether the passkey displayed by the host is typed on the keyboard when pairing
+    passkey_entry: bool,
+    // Reply of the passkey request, which is replied after the passkey is typed
+    passkey_reply: RefCell<Option<PasskeyReply>>,
 }
 
 impl MultiBonder {
-    pub(crate) fn new(bond_info: RefCell<FnvIndexMap<u8, BondInfo, BONDED_DEVICE_NUM>>) -> Self {
-        Self { bond_info }
+    pub(crate) fn new(
+        bond_info: RefCell<FnvIndexMap<u8, BondInfo, BONDED_DEVICE_NUM>>,
+        passkey_entry: bool,
+    ) -> Self {
+        Self {
+            bond_info,
+            passkey_entry,
+            passkey_reply: RefCell::new(None),
+        }
+    }
+
+    /// Reply the passkey requests of the host with the passkeys typed on the keyboard
+    pub(crate) async fn reply_passkey(&self) {
+        loop {
+            let passkey = PASSKEY_ENTERED.wait().await;
+            let Some(reply) = self.passkey_reply.borrow_mut().take() else {
+                continue;
+            };
+            // Passkey is replied in ASCII digits
+            let passkey = passkey.map(|mut p| {
+                let mut digits = [b'0'; PASSKEY_DIGITS];
+                for d in digits.iter_mut().rev() {
+                    *d += (p % 10) as u8;
+                    p /= 10;
+                }
+                digits
+            });
+            if let Err(e) = reply.reply(passkey.as_ref()) {
+                error!("Reply BLE passkey error: {:?}", e);
+            }
+        }
     }
+}
 
-    pub(crate) fn clear_bonded(&self, slot_num: u8) {
+impl ProfileBonder for MultiBonder {
+    fn clear_bonded(&self, profile: u8) {
         let mut bond_info = self.bond_info.borrow_mut();
-        if let Some(info) = bond_info.get_mut(&slot_num) {
+        if let Some(info) = bond_info.get_mut(&profile) {
             info.removed = true;
         }
     }
@@ -81,17 +117,32 @@ impl MultiBonder {
 
 impl SecurityHandler for MultiBonder {
     fn io_capabilities(&self) -> IoCapabilities {
-        IoCapabilities::None
+        if self.passkey_entry {
+            IoCapabilities::KeyboardOnly
+        } else {
+            IoCapabilities::None
+        }
     }
 
     fn can_bond(&self, _conn: &Connection) -> bool {
         true
     }
 
+    fn request_mitm(&self, _conn: &Connection) -> bool {
+        self.passkey_entry
+    }
+
     fn display_passkey(&self, passkey: &[u8; 6]) {
         info!("BLE passkey: {:?}", passkey);
     }
 
+    fn enter_passkey(&self, reply: PasskeyReply) {
+        info!("BLE passkey requested");
+        // The previous reply, if any, is rejected when dropped
+        self.passkey_reply.borrow_mut().replace(reply);
+        start_passkey_entry();
+    }
+
     fn on_security_update(&self, _conn: &Connection, security_mode: SecurityMode) {
         info!("on_security_update, new security mode: {:?}", security_mode);
         // Security updated, indicating that the connection is established?
@@ -0,0 +1,74 @@
+use embassy_futures::join::join;
+use nrf_softdevice::ble::{security::SecurityHandler as _, Connection, PhySet, PhyUpdateError};
+use nrf_softdevice::raw::{self, sd_ble_gap_conn_param_update};
+
+use super::bonder::MultiBonder;
+use crate::ble::passkey::cancel_passkey_entry;
+use crate::ble::stack::BleConnection;
+
+/// Connection to a host, with the bonder which manages its security
+pub(crate) struct NrfConnection {
+    pub(crate) conn: Connection,
+    bonder: &'static MultiBonder,
+}
+
+impl NrfConnection {
+    pub(crate) fn new(mut conn: Connection, bonder: &'static MultiBonder) -> Self {
+        bonder.load_sys_attrs(&conn);
+        if let Err(e) = conn.phy_update(PhySet::M2, PhySet::M2) {
+            error!("Failed to update PHY");
+            if let PhyUpdateError::Raw(re) = e {
+                error!("Raw error code: {:?}", re);
+            }
+        }
+        Self { conn, bonder }
+    }
+}
+
+impl BleConnection for NrfConnection {
+    async fn run(&self) {
+        join(set_conn_params(&self.conn), self.bonder.reply_passkey()).await;
+    }
+
+    async fn disconnect(self) {
+        cancel_passkey_entry();
+        self.bonder.save_sys_attrs(&self.conn);
+        // The host might be disconnected already
+        self.conn.disconnect().ok();
+    }
+}
+
+async fn set_conn_params(conn: &Connection) {
+    // Wait for 5 seconds before setting connection parameters to avoid connection drop
+    embassy_time::Timer::after_secs(5).await;
+    if let Some(conn_handle) = conn.handle() {
+        // Update connection parameters
+        unsafe {
+            // For macOS/iOS(aka Apple devices), both interval should be set to 12
+            let re = sd_ble_gap_conn_param_update(
+                conn_handle,
+                &raw::ble_gap_conn_params_t {
+                    min_conn_interval: 12,
+                    max_conn_interval: 12,
+                    slave_latency: 99,
+                    conn_sup_timeout: 500, // timeout: 5s
+                },
+            );
+            debug!("Set conn params result: {:?}", re);
+
+            embassy_time::Timer::after_millis(5000).await;
+
+            // Setting the conn param the second time ensures that we have best performance on all platforms
+            let re = sd_ble_gap_conn_param_update(
+                conn_handle,
+                &raw::ble_gap_conn_params_t {
+                    min_conn_interval: 6,
+                    max_conn_interval: 6,
+                    slave_latency: 99,
+                    conn_sup_timeout: 500, // timeout: 5s
+                },
+            );
+            debug!("Set conn params result: {:?}", re);
+        }
+    }
+}
@@ -41,6 +41,9 @@ pub(crate) struct HidService {
     pub(crate) input_system_keys: u16,
     input_system_keys_cccd: u16,
     input_system_keys_descriptor: u16,
+    pub(crate) input_joystick: u16,
+    input_joystick_cccd: u16,
+    input_joystick_descriptor: u16,
 }
 
 impl HidService {
@@ -136,6 +139,18 @@ impl HidService {
         )?;
         let input_mouse_handle = input_mouse.build();
 
+        let mut input_joystick = service_builder.add_characteristic(
+            BleCharacteristics::HidReport.uuid(),
+            Attribute::new([0u8; 7]).security(SecurityMode::JustWorks),
+            Metadata::new(Properties::new().read().notify()),
+        )?;
+        let input_joystick_desc = input_joystick.add_descriptor(
+            BleDescriptor::ReportReference.uuid(),
+            Attribute::new([BleCompositeReportType::Joystick as u8, 1u8])
+                .security(SecurityMode::JustWorks),
+        )?;
+        let input_joystick_handle = input_joystick.build();
+
         let _service_handle = service_builder.build();
 
         Ok(HidService {
@@ -156,6 +171,9 @@ impl HidService {
             input_mouse_keys: input_mouse_handle.value_handle,
             input_mouse_keys_cccd: input_mouse_handle.cccd_handle,
             input_mouse_keys_descriptor: input_mouse_desc.handle(),
+            input_joystick: input_joystick_handle.value_handle,
+            input_joystick_cccd: input_joystick_handle.cccd_handle,
+            input_joystick_descriptor: input_joystick_desc.handle(),
         })
     }
 
@@ -184,6 +202,8 @@ impl gatt_server::Service for HidService {
             Some(HidServiceEvent::InputMouseKeyCccdWrite)
         } else if handle == self.input_system_keys_cccd {
             Some(HidServiceEvent::InputSystemKeyCccdWrite)
+        } else if handle == self.input_joystick_cccd {
+            Some(HidServiceEvent::InputJoystickCccdWrite)
         } else if handle == self.output_keyboard {
             // Fires if a keyboard output is changed - e.g. the caps lock LED
             let led_indicator = LedIndicator::from_bits(data[0]);
@@ -204,6 +224,7 @@ pub(crate) enum HidServiceEvent {
     InputMediaKeyCccdWrite,
     InputMouseKeyCccdWrite,
     InputSystemKeyCccdWrite,
+    InputJoystickCccdWrite,
     OutputKeyboard,
 }
 
@@ -213,6 +234,7 @@ pub(crate) struct BleKeyboardWriter<'a> {
     media_handle: u16,
     system_control_handle: u16,
     mouse_handle: u16,
+    joystick_handle: u16,
 }
 
 impl<'a> BleKeyboardWriter<'a> {
@@ -222,6 +244,7 @@ impl<'a> BleKeyboardWriter<'a> {
         media_handle: u16,
         system_control_handle: u16,
         mouse_handle: u16,
+        joystick_handle: u16,
     ) -> Self {
         Self {
             conn,
@@ -229,6 +252,7 @@ impl<'a> BleKeyboardWriter<'a> {
             media_handle,
             system_control_handle,
             mouse_handle,
+            joystick_handle,
         }
     }
     async fn write(&mut self, handle: u16, report: &[u8]) -> Result<(), HidError> {
@@ -282,6 +306,13 @@ impl HidWriterTrait for BleKeyboardWriter<'_> {
                 self.write(self.system_control_handle, &buf).await?;
                 Ok(n)
             }
+            Report::JoystickReport(joystick_report) => {
+                let mut buf = [0u8; 7];
+                let n = serialize(&mut buf, &joystick_report)
+                    .map_err(|_| HidError::ReportSerializeError)?;
+                self.write(self.joystick_handle, &buf).await?;
+                Ok(n)
+            }
         }
     }
 }
@@ -1,70 +1,40 @@
 pub(crate) mod advertise;
-mod battery_service;
+pub(crate) mod battery_service;
 pub(crate) mod bonder;
+pub(crate) mod connection;
 mod device_information_service;
 mod hid_service;
-pub(crate) mod profile;
 pub(crate) mod server;
 pub(crate) mod spec;
 mod vial_service;
 
 use self::server::BleServer;
-use crate::ble::led::BleLedReader;
-use crate::ble::nrf::hid_service::BleKeyboardWriter;
-use crate::config::{BleBatteryConfig, RmkConfig, VialConfig};
-use crate::hid::{DummyWriter, RunnableHidWriter};
+use crate::ble::profile::{load_bond_info, load_keyboard_states};
+#[cfg(feature = "_no_usb")]
+use crate::ble::service::NoUsb;
+use crate::ble::service::{BleKeyboard, BleKeyboardEvents};
+use crate::ble::stack::run_ble_stack;
+use crate::config::RmkConfig;
 use crate::keymap::KeyMap;
 use crate::light::LightController;
-use crate::run_keyboard;
-use crate::storage::StorageKeys;
-use crate::{
-    ble::nrf::bonder::BondInfo,
-    storage::{get_bond_info_key, Storage, StorageData},
-    CONNECTION_STATE, CONNECTION_TYPE,
-};
-use advertise::{create_advertisement_data, SCAN_DATA};
+use crate::storage::Storage;
+use advertise::NrfAdvertiser;
+use battery_service::NrfBatteryService;
 use bonder::MultiBonder;
-use core::sync::atomic::{AtomicU8, Ordering};
 use core::{cell::RefCell, mem};
 use embassy_executor::Spawner;
-use embassy_futures::join::join;
-use embassy_futures::select::{select, select4, Either4};
-use embassy_time::Timer;
 use embedded_hal::digital::OutputPin;
 use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
-use heapless::FnvIndexMap;
-use nrf_softdevice::ble::peripheral::ConnectableAdvertisement;
-use nrf_softdevice::ble::{PhySet, PhyUpdateError, TxPower};
-use nrf_softdevice::raw::sd_ble_gap_conn_param_update;
-use nrf_softdevice::{
-    ble::{gatt_server, peripheral, security::SecurityHandler as _, Connection},
-    raw, Config, Flash, Softdevice,
-};
-use profile::update_profile;
-use sequential_storage::{cache::NoCache, map::fetch_item};
+use nrf_softdevice::{raw, Config, Flash, Softdevice};
 use static_cell::StaticCell;
-use vial_service::BleVialReaderWriter;
 #[cfg(not(feature = "_no_usb"))]
 use {
-    crate::light::UsbLedReader,
-    crate::register_usb_writer,
-    crate::usb::{
-        descriptor::{CompositeReport, KeyboardReport, ViaReport},
-        new_usb_builder, wait_for_usb_enabled, wait_for_usb_suspend, UsbKeyboardWriter, UsbState,
-        USB_STATE,
-    },
-    crate::via::UsbVialReaderWriter,
-    crate::{add_usb_reader_writer, run_usb_device},
-    embassy_futures::select::{select3, Either3},
-    embassy_nrf::usb::vbus_detect::SoftwareVbusDetect,
-    embassy_usb::driver::Driver,
+    crate::ble::service::UsbDeviceTransport, core::sync::atomic::Ordering,
+    embassy_nrf::usb::vbus_detect::SoftwareVbusDetect, embassy_usb::driver::Driver,
     once_cell::sync::OnceCell,
 };
 
-/// Maximum number of bonded devices
-pub const BONDED_DEVICE_NUM: usize = 8;
-/// The number of the active profile
-pub static ACTIVE_PROFILE: AtomicU8 = AtomicU8::new(0);
+pub use crate::ble::profile::{ACTIVE_PROFILE, BONDED_DEVICE_NUM};
 
 #[cfg(not(feature = "_no_usb"))]
 /// Software Vbus detect when using BLE + USB
@@ -135,20 +105,6 @@ pub(crate) async fn softdevice_task(sd: &'static nrf_softdevice::Softdevice) ->
     sd.run().await
 }
 
-/// Helper macro for reading storage config
-macro_rules! read_storage {
-    ($storage: ident, $key: expr, $buf: expr) => {
-        fetch_item::<u32, StorageData, _>(
-            &mut $storage.flash,
-            $storage.storage_range.clone(),
-            &mut NoCache::new(),
-            &mut $buf,
-            $key,
-        )
-        .await
-    };
-}
-
 /// Create default nrf ble config
 pub(crate) fn nrf_ble_config(keyboard_name: &str) -> Config {
     Config {
@@ -238,354 +194,52 @@ pub(crate) async fn run_nrf_ble_keyboard<
     storage: &mut Storage<F, ROW, COL, NUM_LAYER>,
     #[cfg(not(feature = "_no_usb"))] usb_driver: D,
     light_controller: &mut LightController<Out>,
-    mut rmk_config: RmkConfig<'static>,
+    rmk_config: RmkConfig<'static>,
     sd: &mut Softdevice,
 ) -> ! {
-    // Initialize usb device, ble service, etc
-    #[cfg(not(feature = "_no_usb"))]
     // Initialize usb device and usb hid reader/writer
-    let (
-        mut usb_device,
-        mut keyboard_reader,
-        mut keyboard_writer,
-        mut other_writer,
-        mut vial_reader_writer,
-    ) = {
-        let mut usb_builder: embassy_usb::Builder<'_, D> =
-            new_usb_builder(usb_driver, rmk_config.usb_config);
-        let keyboard_reader_writer = add_usb_reader_writer!(&mut usb_builder, KeyboardReport, 1, 8);
-        let other_writer = register_usb_writer!(&mut usb_builder, CompositeReport, 9);
-        let vial_reader_writer = add_usb_reader_writer!(&mut usb_builder, ViaReport, 32, 32);
-        let (keyboard_reader, keyboard_writer) = keyboard_reader_writer.split();
-        let usb_device = usb_builder.build();
-        (
-            usb_device,
-            keyboard_reader,
-            keyboard_writer,
-            other_writer,
-            vial_reader_writer,
-        )
-    };
+    #[cfg(not(feature = "_no_usb"))]
+    let usb = UsbDeviceTransport::new(usb_driver, rmk_config.usb_config);
+    #[cfg(feature = "_no_usb")]
+    let usb = NoUsb;
 
     // Initialize ble service
     load_keyboard_states(storage).await;
     let bond_info = load_bond_info(storage).await;
     info!("Loaded {} saved bond info", bond_info.len());
     static BONDER: StaticCell<MultiBonder> = StaticCell::new();
-    let bonder = BONDER.init(MultiBonder::new(RefCell::new(bond_info)));
-    let ble_server: BleServer =
-        BleServer::new(sd, rmk_config.usb_config, bonder).expect("Failed to start ble server");
-
-    // Main loop
-    loop {
-        // Init BLE advertising data
-        let mut config = peripheral::Config::default();
-        // Interval: 500ms
-        config.interval = 800;
-        config.tx_power = TxPower::Plus4dBm;
-        let adv = ConnectableAdvertisement::ScannableUndirected {
-            adv_data: &create_advertisement_data(rmk_config.usb_config.product_name),
-            scan_data: &SCAN_DATA,
-        };
-        // If there is a USB device, things become a little bit complex because we need to enable switching between USB and BLE.
-        // Remember that USB ALWAYS has higher priority than BLE.
-        #[cfg(not(feature = "_no_usb"))]
-        {
-            debug!(
-                "usb state: {}, connection type: {}",
-                USB_STATE.load(Ordering::SeqCst),
-                CONNECTION_TYPE.load(Ordering::Relaxed)
-            );
-            // Check whether the USB is connected
-            if USB_STATE.load(Ordering::SeqCst) != UsbState::Disabled as u8 {
-                let usb_fut = run_keyboard(
-                    keymap,
-                    #[cfg(any(feature = "_nrf_ble", not(feature = "_no_external_storage")))]
-                    storage,
-                    run_usb_device(&mut usb_device),
-                    light_controller,
-                    UsbLedReader::new(&mut keyboard_reader),
-                    UsbVialReaderWriter::new(&mut vial_reader_writer),
-                    UsbKeyboardWriter::new(&mut keyboard_writer, &mut other_writer),
-                    rmk_config.vial_config,
-                );
-                if CONNECTION_TYPE.load(Ordering::Relaxed) == 0 {
-                    info!("Running USB keyboard");
-                    // USB is connected, connection_type is USB, then run USB keyboard
-                    match select3(usb_fut, wait_for_usb_suspend(), update_profile(bonder)).await {
-                        Either3::Third(_) => {
-                            Timer::after_millis(10).await;
-                            continue;
-                        }
-                        _ => (),
-                    }
-                } else {
-                    // USB is connected, but connection type is BLE, try BLE while running USB keyboard
-                    info!("Running USB keyboard, while advertising");
-                    let adv_fut = peripheral::advertise_pairable(sd, adv, &config, bonder);
-                    match select3(adv_fut, usb_fut, update_profile(bonder)).await {
-                        Either3::First(Ok(conn)) => {
-                            run_ble_keyboard(
-                                keymap,
-                                storage,
-                                light_controller,
-                                rmk_config.vial_config,
-                                &mut rmk_config.ble_battery_config,
-                                &ble_server,
-                                bonder,
-                                conn,
-                            )
-                            .await
-                        }
-                        _ => {
-                            // Wait 10ms
-                            Timer::after_millis(10).await;
-                            continue;
-                        }
-                    }
-                }
-            } else {
-                // USB isn't connected, wait for any of BLE/USB connection
-                let dummy_task = run_dummy_keyboard(storage);
-                let adv_fut = peripheral::advertise_pairable(sd, adv, &config, bonder);
-
-                info!("BLE advertising");
-                // Wait for BLE or USB connection
-                match select3(adv_fut, wait_for_status_change(bonder), dummy_task).await {
-                    Either3::First(Ok(conn)) => {
-                        run_ble_keyboard(
-                            keymap,
-                            storage,
-                            light_controller,
-                            rmk_config.vial_config,
-                            &mut rmk_config.ble_battery_config,
-                            &ble_server,
-                            bonder,
-                            conn,
-                        )
-                        .await
-                    }
-                    _ => {
-                        // Wait 10ms for usb resuming/switching profile/advertising error
-                        Timer::after_millis(10).await;
-                    }
-                }
-            }
-        }
-
-        #[cfg(feature = "_no_usb")]
-        match peripheral::advertise_pairable(sd, adv, &config, bonder).await {
-            Ok(conn) => {
-                run_ble_keyboard(
-                    keymap,
-                    storage,
-                    light_controller,
-                    rmk_config.vial_config,
-                    &mut rmk_config.ble_battery_config,
-                    &ble_server,
-                    bonder,
-                    conn,
-                )
-                .await;
-            }
-            Err(e) => error!("Advertise error: {}", e),
-        }
-
-        // Retry after 200 ms
-        Timer::after_millis(200).await;
-    }
-}
-
-pub(crate) async fn load_keyboard_states<
-    F: AsyncNorFlash,
-    const ROW: usize,
-    const COL: usize,
-    const NUM_LAYER: usize,
->(
-    storage: &mut Storage<F, ROW, COL, NUM_LAYER>,
-) {
-    let mut buf: [u8; 128] = [0; 128];
-
-    // Load current active profile
-    if let Ok(Some(StorageData::ActiveBleProfile(profile))) =
-        read_storage!(storage, &(StorageKeys::ActiveBleProfile as u32), buf)
-    {
-        debug!("Loaded active profile: {}", profile);
-        ACTIVE_PROFILE.store(profile, Ordering::SeqCst);
-    } else {
-        // If no saved active profile, use 0 as default
-        debug!("Loaded default active profile",);
-        ACTIVE_PROFILE.store(0, Ordering::SeqCst);
-    };
-
-    // Load current connection type
-    if let Ok(Some(StorageData::ConnectionType(conn_type))) =
-        read_storage!(storage, &(StorageKeys::ConnectionType as u32), buf)
-    {
-        CONNECTION_TYPE.store(conn_type, Ordering::Relaxed);
-    } else {
-        // If no saved connection type, use 0 as default
-        CONNECTION_TYPE.store(0, Ordering::Relaxed);
+    let bonder = BONDER.init(MultiBonder::new(
+        RefCell::new(bond_info),
+        rmk_config.ble_passkey_entry,
+    ));
+    let ble_server: BleServer = BleServer::new(
+        sd,
+        rmk_config.usb_config,
+        bonder,
+        #[cfg(feature = "split")]
+        rmk_config.split_peripheral_num,
+    )
+    .expect("Failed to start ble server");
+
+    let mut advertiser = NrfAdvertiser::new(sd, bonder, rmk_config.usb_config.product_name);
+    let mut keyboard = BleKeyboard {
+        keymap,
+        storage,
+        light_controller,
+        vial_config: rmk_config.vial_config,
+        hid_service: &ble_server,
+        battery_service: NrfBatteryService {
+            #[cfg(feature = "split")]
+            sd,
+            server: &ble_server,
+            config: rmk_config.ble_battery_config,
+        },
+        usb,
     };
-
-    #[cfg(feature = "_no_usb")]
-    CONNECTION_TYPE.store(0, Ordering::Relaxed);
-}
-
-pub(crate) async fn load_bond_info<
-    F: AsyncNorFlash,
-    const ROW: usize,
-    const COL: usize,
-    const NUM_LAYER: usize,
->(
-    storage: &mut Storage<F, ROW, COL, NUM_LAYER>,
-) -> FnvIndexMap<u8, BondInfo, BONDED_DEVICE_NUM> {
-    let mut buf: [u8; 128] = [0; 128];
-    // Get all saved bond info, config BLE bonder
-    let mut bond_info: FnvIndexMap<u8, BondInfo, BONDED_DEVICE_NUM> = FnvIndexMap::new();
-    for key in 0..BONDED_DEVICE_NUM {
-        if let Ok(Some(StorageData::BondInfo(info))) =
-            read_storage!(storage, &get_bond_info_key(key as u8), buf)
-        {
-            bond_info.insert(key as u8, info).ok();
-        }
-    }
-    bond_info
-}
-
-async fn run_ble_keyboard<
-    'a,
-    F: AsyncNorFlash,
-    Out: OutputPin,
-    const ROW: usize,
-    const COL: usize,
-    const NUM_LAYER: usize,
->(
-    keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
-    storage: &mut Storage<F, ROW, COL, NUM_LAYER>,
-    light_controller: &mut LightController<Out>,
-    vial_config: VialConfig<'static>,
-    ble_battery_config: &mut BleBatteryConfig<'static>,
-    ble_server: &BleServer,
-    bonder: &'static MultiBonder,
-    mut conn: Connection,
-) {
-    info!("Connected to BLE");
-    bonder.load_sys_attrs(&conn);
-    if let Err(e) = conn.phy_update(PhySet::M2, PhySet::M2) {
-        error!("Failed to update PHY");
-        if let PhyUpdateError::Raw(re) = e {
-            error!("Raw error code: {:?}", re);
-        }
-    }
-    match select4(
-        run_keyboard(
-            keymap,
-            storage,
-            run_ble_server(&conn, ble_server),
-            light_controller,
-            BleLedReader {},
-            BleVialReaderWriter::new(ble_server.vial, &conn),
-            BleKeyboardWriter::new(
-                &conn,
-                ble_server.hid.input_keyboard,
-                ble_server.hid.input_media_keys,
-                ble_server.hid.input_system_keys,
-                ble_server.hid.input_mouse_keys,
-            ),
-            vial_config,
-        ),
-        ble_server.bas.clone().run(ble_battery_config, &conn),
-        wait_for_usb_enabled(),
-        update_profile(bonder),
+    run_ble_stack(
+        &mut advertiser,
+        &mut keyboard,
+        &BleKeyboardEvents { bonder },
     )
     .await
-    {
-        Either4::First(_) => info!("BLE disconnected"),
-        Either4::Second(_) => info!("Bas service error"),
-        Either4::Third(_) => info!("Detected USB configured, quit BLE"),
-        Either4::Fourth(_) => info!("Switch profile"),
-    }
-    bonder.save_sys_attrs(&conn);
-}
-
-pub(crate) async fn set_conn_params(conn: &Connection) {
-    // Wait for 5 seconds before setting connection parameters to avoid connection drop
-    embassy_time::Timer::after_secs(5).await;
-    if let Some(conn_handle) = conn.handle() {
-        // Update connection parameters
-        unsafe {
-            // For macOS/iOS(aka Apple devices), both interval should be set to 12
-            let re = sd_ble_gap_conn_param_update(
-                conn_handle,
-                &raw::ble_gap_conn_params_t {
-                    min_conn_interval: 12,
-                    max_conn_interval: 12,
-                    slave_latency: 99,
-                    conn_sup_timeout: 500, // timeout: 5s
-                },
-            );
-            debug!("Set conn params result: {:?}", re);
-
-            embassy_time::Timer::after_millis(5000).await;
-
-            // Setting the conn param the second time ensures that we have best performance on all platforms
-            let re = sd_ble_gap_conn_param_update(
-                conn_handle,
-                &raw::ble_gap_conn_params_t {
-                    min_conn_interval: 6,
-                    max_conn_interval: 6,
-                    slave_latency: 99,
-                    conn_sup_timeout: 500, // timeout: 5s
-                },
-            );
-            debug!("Set conn params result: {:?}", re);
-        }
-    }
-}
-
-// Dummy keyboard service is used to monitoring keys when there's no actual connection.
-// It's useful for functions like switching active profiles when there's no connection.
-pub(crate) async fn run_dummy_keyboard<
-    'a,
-    'b,
-    F: AsyncNorFlash,
-    const ROW: usize,
-    const COL: usize,
-    const NUM_LAYER: usize,
->(
-    storage: &mut Storage<F, ROW, COL, NUM_LAYER>,
-) {
-    CONNECTION_STATE.store(false, Ordering::Release);
-    let storage_fut = storage.run();
-    let mut dummy_writer = DummyWriter {};
-    select(storage_fut, dummy_writer.run_writer()).await;
-}
-
-#[cfg(not(feature = "_no_usb"))]
-// Wait for USB enabled or BLE state changed
-pub(crate) async fn wait_for_status_change(bonder: &MultiBonder) {
-    if CONNECTION_TYPE.load(Ordering::Relaxed) == 0 {
-        // Connection type is USB, USB has higher priority
-        select(wait_for_usb_enabled(), update_profile(bonder)).await;
-    } else {
-        // Connection type is BLE, so we don't consider USB
-        update_profile(bonder).await;
-    }
-}
-
-async fn run_ble_server(conn: &Connection, ble_server: &BleServer) {
-    let err = join(
-        set_conn_params(&conn),
-        gatt_server::run(conn, ble_server, |_| {}),
-    )
-    .await;
-    error!("BLE server exited with error: {:?}", err.1);
-}
-
-#[cfg(feature = "_no_usb")]
-async fn wait_for_usb_enabled() {
-    loop {
-        core::future::pending::<()>().await;
-    }
 }
@@ -1,11 +1,16 @@
 use super::{
     battery_service::{BatteryService, BatteryServiceEvent},
+    connection::NrfConnection,
     device_information_service::DeviceInformationService,
-    hid_service::{HidService, HidServiceEvent},
-    vial_service::{BleVialService, VialServiceEvent},
+    hid_service::{BleKeyboardWriter, HidService, HidServiceEvent},
+    vial_service::{BleVialReaderWriter, BleVialService, VialServiceEvent},
 };
 use crate::ble::device_info::{DeviceInformation, PnPID, VidSource};
+use crate::ble::{led::BleLedReader, service::BleHidService};
 use crate::config::KeyboardUsbConfig;
+use crate::hid::{HidReaderTrait, HidWriterTrait, RunnableHidWriter};
+use crate::light::LedIndicator;
+use crate::usb::descriptor::ViaReport;
 use nrf_softdevice::{
     ble::{
         gatt_server::{self, RegisterError, Service, WriteOp},
@@ -14,6 +19,11 @@ use nrf_softdevice::{
     },
     Softdevice,
 };
+#[cfg(feature = "split")]
+use {
+    super::battery_service::PeripheralBatteryService,
+    crate::split::battery::PERIPHERAL_BATTERY_NUM, heapless::Vec,
+};
 
 /// Wrapper struct for writing via BLE
 pub(crate) struct BleHidWriter<'a, const N: usize> {
@@ -25,6 +35,9 @@ pub(crate) struct BleHidWriter<'a, const N: usize> {
 pub(crate) struct BleServer {
     _dis: DeviceInformationService,
     pub(crate) bas: BatteryService,
+    /// Battery services of split peripherals
+    #[cfg(feature = "split")]
+    pub(crate) peripheral_bas: Vec<PeripheralBatteryService, PERIPHERAL_BATTERY_NUM>,
     pub(crate) hid: HidService,
     pub(crate) vial: BleVialService,
     bonder: &'static dyn SecurityHandler,
@@ -35,6 +48,7 @@ impl BleServer {
         sd: &mut Softdevice,
         usb_config: KeyboardUsbConfig<'static>,
         bonder: &'static dyn SecurityHandler,
+        #[cfg(feature = "split")] peripheral_num: usize,
     ) -> Result<Self, RegisterError> {
         let dis = DeviceInformationService::new(
             sd,
@@ -54,6 +68,15 @@ impl BleServer {
 
         let bas = BatteryService::new(sd)?;
 
+        #[cfg(feature = "split")]
+        let mut peripheral_bas = Vec::new();
+        #[cfg(feature = "split")]
+        for id in 0..peripheral_num.min(PERIPHERAL_BATTERY_NUM) {
+            peripheral_bas
+                .push(PeripheralBatteryService::new(sd, id)?)
+                .ok();
+        }
+
         let hid = HidService::new(sd)?;
 
         let vial = BleVialService::new(sd)?;
@@ -61,6 +84,8 @@ impl BleServer {
         Ok(Self {
             _dis: dis,
             bas,
+            #[cfg(feature = "split")]
+            peripheral_bas,
             hid,
             vial,
             bonder,
@@ -84,7 +109,8 @@ impl gatt_server::Server for BleServer {
                 HidServiceEvent::InputKeyboardCccdWrite
                 | HidServiceEvent::InputMediaKeyCccdWrite
                 | HidServiceEvent::InputMouseKeyCccdWrite
-                | HidServiceEvent::InputSystemKeyCccdWrite => {
+                | HidServiceEvent::InputSystemKeyCccdWrite
+                | HidServiceEvent::InputJoystickCccdWrite => {
                     info!("{:?}, handle: {}, data: {:?}", event, handle, data);
                     self.bonder.save_sys_attrs(conn)
                 }
@@ -102,6 +128,18 @@ impl gatt_server::Server for BleServer {
                 }
             }
         }
+        #[cfg(feature = "split")]
+        if self
+            .peripheral_bas
+            .iter()
+            .any(|bas| bas.is_cccd_write(handle))
+        {
+            info!(
+                "Peripheral BatteryLevelCccdWrite, handle: {}, data: {:?}",
+                handle, data
+            );
+            self.bonder.save_sys_attrs(conn)
+        }
         if let Some(event) = self.vial.on_write(handle, data) {
             match event {
                 VialServiceEvent::InputVialKeyCccdWrite => {
@@ -115,3 +153,33 @@ impl gatt_server::Server for BleServer {
         None
     }
 }
+
+impl BleHidService<NrfConnection> for BleServer {
+    async fn run(&self, conn: &NrfConnection) {
+        let err = gatt_server::run(&conn.conn, self, |_| {}).await;
+        error!("BLE server exited with error: {:?}", err);
+    }
+
+    fn led_reader(&self) -> impl HidReaderTrait<ReportType = LedIndicator> {
+        BleLedReader {}
+    }
+
+    fn keyboard_writer<'s>(&'s self, conn: &'s NrfConnection) -> impl RunnableHidWriter + 's {
+        BleKeyboardWriter::new(
+            &conn.conn,
+            self.hid.input_keyboard,
+            self.hid.input_media_keys,
+            self.hid.input_system_keys,
+            self.hid.input_mouse_keys,
+            self.hid.input_joystick,
+        )
+    }
+
+    fn vial_reader_writer<'s>(
+        &'s self,
+        conn: &'s NrfConnection,
+    ) -> impl HidReaderTrait<ReportType = ViaReport> + HidWriterTrait<ReportType = ViaReport> + 's
+    {
+        BleVialReaderWriter::new(self.vial, &conn.conn)
+    }
+}
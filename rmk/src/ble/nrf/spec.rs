@@ -33,6 +33,8 @@ pub(crate) enum BleCharacteristics {
 }
 
 pub(crate) enum BleDescriptor {
+    CharacteristicUserDescription = 0x2901,
+    CharacteristicPresentationFormat = 0x2904,
     ReportReference = 0x2908,
 }
 
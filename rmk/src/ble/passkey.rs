@@ -0,0 +1,218 @@
+//! Passkey entry of BLE pairing.
+//!
+//! When passkey entry is enabled, the keyboard is a `KeyboardOnly` device during pairing: the host displays a
+//! 6-digit passkey, and the user types it on the keyboard. While the passkey is being entered, key events are
+//! consumed here instead of being sent to the host:
+//!
+//! - number keys and keypad number keys type a digit
+//! - `Backspace` deletes the last digit
+//! - `Enter` or `KpEnter` submits the passkey once 6 digits are typed
+//! - `Escape` cancels the pairing
+use core::cell::RefCell;
+
+use embassy_sync::{blocking_mutex::Mutex, signal::Signal};
+use heapless::Vec;
+
+use crate::{
+    action::{Action, KeyAction},
+    event::KeyEvent,
+    keycode::KeyCode,
+    RawMutex,
+};
+
+/// Number of digits of a BLE passkey
+pub(crate) const PASSKEY_DIGITS: usize = 6;
+
+/// Passkey typed by the user, `None` if the entry is cancelled
+pub(crate) static PASSKEY_ENTERED: Signal<RawMutex, Option<u32>> = Signal::new();
+
+/// State of the passkey entry
+struct PasskeyInput {
+    /// Whether the passkey is being entered
+    entering: bool,
+    /// Digits typed so far
+    digits: Vec<u8, PASSKEY_DIGITS>,
+    /// Positions of the pressed keys consumed by the passkey entry, their releases are consumed too
+    pressed: Vec<(u8, u8), 8>,
+}
+
+static PASSKEY_INPUT: Mutex<RawMutex, RefCell<PasskeyInput>> =
+    Mutex::new(RefCell::new(PasskeyInput {
+        entering: false,
+        digits: Vec::new(),
+        pressed: Vec::new(),
+    }));
+
+/// Start the passkey entry, called by the BLE stack when the host requests a passkey
+pub(crate) fn start_passkey_entry() {
+    info!("Type the passkey displayed on the host, then press Enter");
+    PASSKEY_ENTERED.reset();
+    PASSKEY_INPUT.lock(|input| {
+        let mut input = input.borrow_mut();
+        input.entering = true;
+        input.digits.clear();
+    });
+}
+
+/// Stop the passkey entry without a passkey, e.g. when the pairing failed or the host is disconnected
+#[cfg_attr(not(feature = "_ble"), allow(dead_code))]
+pub(crate) fn cancel_passkey_entry() {
+    PASSKEY_INPUT.lock(|input| input.borrow_mut().entering = false);
+}
+
+/// Process a key event for the passkey entry.
+///
+/// Returns `true` if the key event is consumed by the passkey entry.
+pub(crate) fn process_passkey_key(key_event: KeyEvent, key_action: KeyAction) -> bool {
+    let pos = (key_event.row, key_event.col);
+    PASSKEY_INPUT.lock(|input| {
+        let mut input = input.borrow_mut();
+        if !key_event.pressed {
+            // Consume the release of the keys whose press is consumed
+            return match input.pressed.iter().position(|p| *p == pos) {
+                Some(i) => {
+                    input.pressed.swap_remove(i);
+                    true
+                }
+                None => false,
+            };
+        }
+        if !input.entering {
+            return false;
+        }
+        input.pressed.push(pos).ok();
+
+        let Some(keycode) = passkey_keycode(key_action) else {
+            return true;
+        };
+        match keycode {
+            KeyCode::Backspace => {
+                input.digits.pop();
+            }
+            KeyCode::Enter | KeyCode::KpEnter => {
+                if input.digits.len() == PASSKEY_DIGITS {
+                    let passkey = input
+                        .digits
+                        .iter()
+                        .fold(0, |passkey, d| passkey * 10 + *d as u32);
+                    input.entering = false;
+                    PASSKEY_ENTERED.signal(Some(passkey));
+                } else {
+                    warn!("Passkey should have {} digits", PASSKEY_DIGITS);
+                }
+            }
+            KeyCode::Escape => {
+                info!("Passkey entry cancelled");
+                input.entering = false;
+                PASSKEY_ENTERED.signal(None);
+            }
+            k => {
+                if let Some(digit) = passkey_digit(k) {
+                    input.digits.push(digit).ok();
+                }
+            }
+        }
+        true
+    })
+}
+
+/// Whether the passkey is being entered
+pub(crate) fn is_entering_passkey() -> bool {
+    PASSKEY_INPUT.lock(|input| input.borrow().entering)
+}
+
+/// Get the keycode of the key action, the tap action is used for tap-hold keys
+fn passkey_keycode(key_action: KeyAction) -> Option<KeyCode> {
+    match key_action {
+        KeyAction::Single(Action::Key(k))
+        | KeyAction::Tap(Action::Key(k))
+        | KeyAction::TapHold(Action::Key(k), _)
+        | KeyAction::LayerTapHold(Action::Key(k), _)
+        | KeyAction::ModifierTapHold(Action::Key(k), _) => Some(k),
+        _ => None,
+    }
+}
+
+/// Convert number keys and keypad number keys to digits
+fn passkey_digit(keycode: KeyCode) -> Option<u8> {
+    match keycode {
+        KeyCode::Kc0 | KeyCode::Kp0 => Some(0),
+        k if (KeyCode::Kc1..=KeyCode::Kc9).contains(&k) => {
+            Some((k as u16 - KeyCode::Kc1 as u16) as u8 + 1)
+        }
+        k if (KeyCode::Kp1..=KeyCode::Kp9).contains(&k) => {
+            Some((k as u16 - KeyCode::Kp1 as u16) as u8 + 1)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use embassy_futures::block_on;
+
+    fn tap(row: u8, col: u8, keycode: KeyCode) -> (bool, bool) {
+        let action = KeyAction::Single(Action::Key(keycode));
+        let press = process_passkey_key(
+            KeyEvent {
+                row,
+                col,
+                pressed: true,
+            },
+            action,
+        );
+        let release = process_passkey_key(
+            KeyEvent {
+                row,
+                col,
+                pressed: false,
+            },
+            action,
+        );
+        (press, release)
+    }
+
+    #[test]
+    fn test_passkey_entry() {
+        // Keys aren't consumed when the passkey isn't requested
+        assert_eq!(tap(0, 0, KeyCode::Kc1), (false, false));
+
+        start_passkey_entry();
+        assert!(is_entering_passkey());
+        for k in [KeyCode::Kc0, KeyCode::Kp4, KeyCode::Kc9, KeyCode::Kc5] {
+            assert_eq!(tap(0, 1, k), (true, true));
+        }
+        // Wrong digit is deleted
+        tap(0, 2, KeyCode::Kc7);
+        tap(0, 3, KeyCode::Backspace);
+        // Passkey isn't submitted before 6 digits are typed
+        tap(0, 4, KeyCode::Enter);
+        assert!(is_entering_passkey());
+        tap(0, 5, KeyCode::Kc1);
+        // Other keys are consumed but ignored
+        assert_eq!(tap(0, 6, KeyCode::A), (true, true));
+        tap(0, 7, KeyCode::Kp3);
+        tap(0, 4, KeyCode::KpEnter);
+        assert!(!is_entering_passkey());
+        assert_eq!(block_on(PASSKEY_ENTERED.wait()), Some(49513));
+
+        // Release of a key pressed during the entry is consumed
+        start_passkey_entry();
+        let action = KeyAction::Single(Action::Key(KeyCode::Escape));
+        let press = KeyEvent {
+            row: 1,
+            col: 0,
+            pressed: true,
+        };
+        assert!(process_passkey_key(press, action));
+        assert!(!is_entering_passkey());
+        assert_eq!(block_on(PASSKEY_ENTERED.wait()), None);
+        let release = KeyEvent {
+            pressed: false,
+            ..press
+        };
+        assert!(process_passkey_key(release, action));
+        assert!(!process_passkey_key(release, action));
+    }
+}
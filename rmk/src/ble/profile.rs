@@ -0,0 +1,182 @@
+//! Manage BLE profiles
+//!
+//! A BLE keyboard can be bonded with [`BONDED_DEVICE_NUM`] hosts, each host is saved in a profile.
+//! Profiles are switched by `User0`~`User11` keys, which are shared by all BLE backends,
+//! the bonded hosts are managed by the backend's bonder which implements [`ProfileBonder`].
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embassy_futures::yield_now;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+use heapless::FnvIndexMap;
+use sequential_storage::{cache::NoCache, map::fetch_item};
+
+#[cfg(feature = "_esp_ble")]
+pub(crate) use crate::ble::bond::BondInfo;
+#[cfg(feature = "_nrf_ble")]
+pub(crate) use crate::ble::nrf::bonder::BondInfo;
+use crate::{
+    channel::{BLE_PROFILE_CHANNEL, FLASH_CHANNEL},
+    storage::{get_bond_info_key, FlashOperationMessage, Storage, StorageData, StorageKeys},
+    CONNECTION_TYPE,
+};
+
+/// Maximum number of bonded devices
+pub const BONDED_DEVICE_NUM: usize = 8;
+/// The number of the active profile
+pub static ACTIVE_PROFILE: AtomicU8 = AtomicU8::new(0);
+
+/// BLE profile switch action
+pub(crate) enum BleProfileAction {
+    SwitchProfile(u8),
+    PreviousProfile,
+    NextProfile,
+    ClearProfile,
+    ToggleConnection,
+}
+
+/// Bonded hosts of the profiles, implemented by each BLE backend
+pub(crate) trait ProfileBonder {
+    /// Remove the bonded host of the profile, so that a new host can be bonded to it
+    fn clear_bonded(&self, profile: u8);
+}
+
+// Wait for profile switch action and update the active profile
+pub(crate) async fn update_profile(bonder: &impl ProfileBonder) {
+    // Wait until there's a profile switch action
+    loop {
+        match BLE_PROFILE_CHANNEL.receive().await {
+            BleProfileAction::SwitchProfile(profile) => {
+                let current = ACTIVE_PROFILE.load(Ordering::SeqCst);
+                if profile == current {
+                    // No need to switch to the same profile, just continue waiting
+                    continue;
+                }
+                ACTIVE_PROFILE.store(profile, Ordering::SeqCst);
+                FLASH_CHANNEL
+                    .send(FlashOperationMessage::ActiveBleProfile(profile))
+                    .await;
+                info!("Switch to BLE profile: {}", profile);
+            }
+            BleProfileAction::PreviousProfile => {
+                // Get current profile number and minus 1
+                let mut profile = ACTIVE_PROFILE.load(Ordering::SeqCst);
+                profile = if profile == 0 {
+                    BONDED_DEVICE_NUM as u8 - 1
+                } else {
+                    profile - 1
+                };
+                ACTIVE_PROFILE.store(profile, Ordering::SeqCst);
+                FLASH_CHANNEL
+                    .send(FlashOperationMessage::ActiveBleProfile(profile))
+                    .await;
+                info!("Switch to previous BLE profile");
+            }
+            BleProfileAction::NextProfile => {
+                let mut profile = ACTIVE_PROFILE.load(Ordering::SeqCst) + 1;
+                profile = profile % BONDED_DEVICE_NUM as u8;
+                ACTIVE_PROFILE.store(profile, Ordering::SeqCst);
+                FLASH_CHANNEL
+                    .send(FlashOperationMessage::ActiveBleProfile(profile))
+                    .await;
+                info!("Switch to next BLE profile");
+            }
+            BleProfileAction::ClearProfile => {
+                let profile = ACTIVE_PROFILE.load(Ordering::SeqCst);
+                bonder.clear_bonded(profile);
+                FLASH_CHANNEL
+                    .send(FlashOperationMessage::ClearSlot(profile))
+                    .await;
+                info!("Clear profile");
+            }
+            BleProfileAction::ToggleConnection => {
+                let current = CONNECTION_TYPE.load(Ordering::SeqCst);
+                let updated = 1 - current;
+                CONNECTION_TYPE.store(updated, Ordering::SeqCst);
+                FLASH_CHANNEL
+                    .send(FlashOperationMessage::ConnectionType(updated))
+                    .await;
+            }
+        }
+        // Sync the new output to split peripherals
+        #[cfg(feature = "split")]
+        crate::split::state::update_output_state();
+        break;
+    }
+    yield_now().await;
+    // Wait for the flash operation to complete
+    // A signal could be used here, but for simplicity, just waiting for 1s
+    embassy_time::Timer::after_secs(1).await
+}
+
+macro_rules! read_storage {
+    ($storage: ident, $key: expr, $buf: expr) => {
+        fetch_item::<u32, StorageData, _>(
+            &mut $storage.flash,
+            $storage.storage_range.clone(),
+            &mut NoCache::new(),
+            &mut $buf,
+            $key,
+        )
+        .await
+    };
+}
+
+/// Load the active profile and the connection type from the storage
+pub(crate) async fn load_keyboard_states<
+    F: AsyncNorFlash,
+    const ROW: usize,
+    const COL: usize,
+    const NUM_LAYER: usize,
+>(
+    storage: &mut Storage<F, ROW, COL, NUM_LAYER>,
+) {
+    let mut buf: [u8; 128] = [0; 128];
+
+    // Load current active profile
+    if let Ok(Some(StorageData::ActiveBleProfile(profile))) =
+        read_storage!(storage, &(StorageKeys::ActiveBleProfile as u32), buf)
+    {
+        debug!("Loaded active profile: {}", profile);
+        ACTIVE_PROFILE.store(profile, Ordering::SeqCst);
+    } else {
+        // If no saved active profile, use 0 as default
+        debug!("Loaded default active profile",);
+        ACTIVE_PROFILE.store(0, Ordering::SeqCst);
+    };
+
+    // Load current connection type
+    if let Ok(Some(StorageData::ConnectionType(conn_type))) =
+        read_storage!(storage, &(StorageKeys::ConnectionType as u32), buf)
+    {
+        CONNECTION_TYPE.store(conn_type, Ordering::Relaxed);
+    } else {
+        // If no saved connection type, use 0 as default
+        CONNECTION_TYPE.store(0, Ordering::Relaxed);
+    };
+
+    #[cfg(feature = "_no_usb")]
+    CONNECTION_TYPE.store(0, Ordering::Relaxed);
+}
+
+/// Load the bond info of all profiles from the storage
+pub(crate) async fn load_bond_info<
+    F: AsyncNorFlash,
+    const ROW: usize,
+    const COL: usize,
+    const NUM_LAYER: usize,
+>(
+    storage: &mut Storage<F, ROW, COL, NUM_LAYER>,
+) -> FnvIndexMap<u8, BondInfo, BONDED_DEVICE_NUM> {
+    let mut buf: [u8; 128] = [0; 128];
+    // Get all saved bond info, config BLE bonder
+    let mut bond_info: FnvIndexMap<u8, BondInfo, BONDED_DEVICE_NUM> = FnvIndexMap::new();
+    for key in 0..BONDED_DEVICE_NUM {
+        if let Ok(Some(StorageData::BondInfo(info))) =
+            read_storage!(storage, &get_bond_info_key(key as u8), buf)
+        {
+            bond_info.insert(key as u8, info).ok();
+        }
+    }
+    bond_info
+}
@@ -0,0 +1,276 @@
+//! Services of BLE keyboards, shared by all BLE backends
+//!
+//! A backend provides the HID service and the battery service of a connection, then [`BleKeyboard`] runs the
+//! keyboard over them, or over USB by a [`UsbTransport`].
+use core::cell::RefCell;
+use core::sync::atomic::Ordering;
+
+use embassy_futures::select::select;
+use embedded_hal::digital::OutputPin;
+use embedded_storage_async::nor_flash::NorFlash as AsyncNorFlash;
+
+use crate::ble::profile::{update_profile, ProfileBonder};
+use crate::ble::run_dummy_keyboard;
+use crate::ble::stack::{BleConnection, BleStackEvents, KeyboardRunner};
+use crate::config::VialConfig;
+use crate::hid::{HidReaderTrait, HidWriterTrait, RunnableHidWriter};
+use crate::keymap::KeyMap;
+use crate::light::{LedIndicator, LightController};
+use crate::run_keyboard;
+use crate::storage::Storage;
+use crate::usb::descriptor::ViaReport;
+use crate::CONNECTION_TYPE;
+#[cfg(not(feature = "_no_usb"))]
+use {
+    crate::config::KeyboardUsbConfig,
+    crate::light::UsbLedReader,
+    crate::register_usb_writer,
+    crate::usb::{
+        descriptor::{CompositeReport, KeyboardReport},
+        new_usb_builder, wait_for_usb_enabled, wait_for_usb_suspend, UsbKeyboardWriter, UsbState,
+        USB_STATE,
+    },
+    crate::via::UsbVialReaderWriter,
+    crate::{add_usb_reader_writer, run_usb_device},
+    embassy_usb::{
+        class::hid::{HidReader, HidReaderWriter, HidWriter},
+        driver::Driver,
+        UsbDevice,
+    },
+};
+
+/// HID services of a BLE connection, including the keyboard and the vial service
+pub(crate) trait BleHidService<C> {
+    /// Run the GATT server of the services, returns when the host is disconnected.
+    ///
+    /// Backends whose GATT server runs in the BLE host task never return here.
+    async fn run(&self, conn: &C);
+
+    /// Reader of the LED indicator written by the host
+    fn led_reader(&self) -> impl HidReaderTrait<ReportType = LedIndicator>;
+
+    /// Writer of keyboard reports
+    fn keyboard_writer<'s>(&'s self, conn: &'s C) -> impl RunnableHidWriter + 's;
+
+    /// Reader and writer of vial reports
+    fn vial_reader_writer<'s>(
+        &'s self,
+        conn: &'s C,
+    ) -> impl HidReaderTrait<ReportType = ViaReport> + HidWriterTrait<ReportType = ViaReport> + 's;
+}
+
+/// Battery service of a BLE connection
+pub(crate) trait BleBatteryService<C> {
+    /// Report the battery level to the host, returns only if the battery service fails
+    async fn run(&mut self, conn: &C);
+}
+
+/// USB transport of BLE keyboards, which is used when USB is connected
+pub(crate) trait UsbTransport {
+    /// Run the keyboard over USB
+    async fn run_keyboard<
+        'a,
+        F: AsyncNorFlash,
+        Out: OutputPin,
+        const ROW: usize,
+        const COL: usize,
+        const NUM_LAYER: usize,
+    >(
+        &mut self,
+        keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+        storage: &mut Storage<F, ROW, COL, NUM_LAYER>,
+        light_controller: &mut LightController<Out>,
+        vial_config: VialConfig<'static>,
+    );
+}
+
+/// No USB transport, for chips without USB
+pub(crate) struct NoUsb;
+
+impl UsbTransport for NoUsb {
+    async fn run_keyboard<
+        'a,
+        F: AsyncNorFlash,
+        Out: OutputPin,
+        const ROW: usize,
+        const COL: usize,
+        const NUM_LAYER: usize,
+    >(
+        &mut self,
+        _keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+        _storage: &mut Storage<F, ROW, COL, NUM_LAYER>,
+        _light_controller: &mut LightController<Out>,
+        _vial_config: VialConfig<'static>,
+    ) {
+        core::future::pending().await
+    }
+}
+
+/// USB device and USB HID reader/writers of BLE keyboards
+#[cfg(not(feature = "_no_usb"))]
+pub(crate) struct UsbDeviceTransport<D: Driver<'static>> {
+    device: UsbDevice<'static, D>,
+    keyboard_reader: HidReader<'static, D, 1>,
+    keyboard_writer: HidWriter<'static, D, 8>,
+    other_writer: HidWriter<'static, D, 9>,
+    vial_reader_writer: HidReaderWriter<'static, D, 32, 32>,
+}
+
+#[cfg(not(feature = "_no_usb"))]
+impl<D: Driver<'static>> UsbDeviceTransport<D> {
+    pub(crate) fn new(usb_driver: D, usb_config: KeyboardUsbConfig<'static>) -> Self {
+        let mut usb_builder: embassy_usb::Builder<'_, D> = new_usb_builder(usb_driver, usb_config);
+        let keyboard_reader_writer = add_usb_reader_writer!(&mut usb_builder, KeyboardReport, 1, 8);
+        let other_writer = register_usb_writer!(&mut usb_builder, CompositeReport, 9);
+        let vial_reader_writer = add_usb_reader_writer!(&mut usb_builder, ViaReport, 32, 32);
+        let (keyboard_reader, keyboard_writer) = keyboard_reader_writer.split();
+        Self {
+            device: usb_builder.build(),
+            keyboard_reader,
+            keyboard_writer,
+            other_writer,
+            vial_reader_writer,
+        }
+    }
+}
+
+#[cfg(not(feature = "_no_usb"))]
+impl<D: Driver<'static>> UsbTransport for UsbDeviceTransport<D> {
+    async fn run_keyboard<
+        'a,
+        F: AsyncNorFlash,
+        Out: OutputPin,
+        const ROW: usize,
+        const COL: usize,
+        const NUM_LAYER: usize,
+    >(
+        &mut self,
+        keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+        storage: &mut Storage<F, ROW, COL, NUM_LAYER>,
+        light_controller: &mut LightController<Out>,
+        vial_config: VialConfig<'static>,
+    ) {
+        run_keyboard(
+            keymap,
+            storage,
+            run_usb_device(&mut self.device),
+            light_controller,
+            UsbLedReader::new(&mut self.keyboard_reader),
+            UsbVialReaderWriter::new(&mut self.vial_reader_writer),
+            UsbKeyboardWriter::new(&mut self.keyboard_writer, &mut self.other_writer),
+            vial_config,
+        )
+        .await
+    }
+}
+
+/// BLE keyboard, which runs over the services of a BLE backend or USB
+pub(crate) struct BleKeyboard<
+    'a,
+    'b,
+    F: AsyncNorFlash,
+    Out: OutputPin,
+    H,
+    B,
+    U: UsbTransport,
+    const ROW: usize,
+    const COL: usize,
+    const NUM_LAYER: usize,
+> {
+    pub(crate) keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+    pub(crate) storage: &'b mut Storage<F, ROW, COL, NUM_LAYER>,
+    pub(crate) light_controller: &'b mut LightController<Out>,
+    pub(crate) vial_config: VialConfig<'static>,
+    pub(crate) hid_service: &'b H,
+    pub(crate) battery_service: B,
+    pub(crate) usb: U,
+}
+
+impl<
+        'a,
+        'b,
+        C: BleConnection,
+        F: AsyncNorFlash,
+        Out: OutputPin,
+        H: BleHidService<C>,
+        B: BleBatteryService<C>,
+        U: UsbTransport,
+        const ROW: usize,
+        const COL: usize,
+        const NUM_LAYER: usize,
+    > KeyboardRunner<C> for BleKeyboard<'a, 'b, F, Out, H, B, U, ROW, COL, NUM_LAYER>
+{
+    async fn run_ble(&mut self, conn: &C) {
+        let hid_service = self.hid_service;
+        // Tasks of the connection and the GATT server, one of them returns when the host is disconnected
+        let communication_task = async {
+            select(conn.run(), hid_service.run(conn)).await;
+        };
+        select(
+            run_keyboard(
+                self.keymap,
+                self.storage,
+                communication_task,
+                self.light_controller,
+                hid_service.led_reader(),
+                hid_service.vial_reader_writer(conn),
+                hid_service.keyboard_writer(conn),
+                self.vial_config,
+            ),
+            self.battery_service.run(conn),
+        )
+        .await;
+    }
+
+    async fn run_usb(&mut self) {
+        self.usb
+            .run_keyboard(
+                self.keymap,
+                self.storage,
+                self.light_controller,
+                self.vial_config,
+            )
+            .await
+    }
+
+    async fn run_dummy(&mut self) {
+        run_dummy_keyboard(self.storage).await
+    }
+}
+
+/// Events of BLE keyboards: USB state changes, and profile switch actions handled by the bonder
+pub(crate) struct BleKeyboardEvents<'b, P: ProfileBonder> {
+    pub(crate) bonder: &'b P,
+}
+
+impl<P: ProfileBonder> BleStackEvents for BleKeyboardEvents<'_, P> {
+    fn usb_enabled(&self) -> bool {
+        #[cfg(not(feature = "_no_usb"))]
+        let enabled = USB_STATE.load(Ordering::SeqCst) != UsbState::Disabled as u8;
+        #[cfg(feature = "_no_usb")]
+        let enabled = false;
+        enabled
+    }
+
+    fn usb_output(&self) -> bool {
+        CONNECTION_TYPE.load(Ordering::Relaxed) == 0
+    }
+
+    async fn wait_for_usb_enabled(&self) {
+        #[cfg(not(feature = "_no_usb"))]
+        wait_for_usb_enabled().await;
+        #[cfg(feature = "_no_usb")]
+        core::future::pending::<()>().await;
+    }
+
+    async fn wait_for_usb_suspended(&self) {
+        #[cfg(not(feature = "_no_usb"))]
+        wait_for_usb_suspend().await;
+        #[cfg(feature = "_no_usb")]
+        core::future::pending::<()>().await;
+    }
+
+    async fn wait_for_profile_update(&self) {
+        update_profile(self.bonder).await
+    }
+}
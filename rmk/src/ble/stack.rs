@@ -0,0 +1,452 @@
+//! Backend-neutral BLE stack
+//!
+//! The connection loop of BLE keyboards is shared by all BLE backends. A backend implements:
+//!
+//! - [`BleAdvertiser`]: advertising of the active profile, which returns a connection
+//! - [`BleConnection`]: the tasks of a connection, such as pairing and connection parameters
+//! - `BleHidService` and `BleBatteryService` in `ble::service`: the GATT services used on a connection
+//!
+//! then [`run_ble_stack`] runs the keyboard over BLE or USB. USB always has higher priority than BLE:
+//! if USB is connected and the USB output is selected, the USB keyboard runs, otherwise the keyboard advertises
+//! and switches to BLE once a host is connected.
+//!
+//! The storage of bond info and the links of split peripherals are still implemented by each backend.
+use embassy_futures::select::{select, select3, Either3};
+use embassy_time::Timer;
+
+/// Errors of BLE backends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum BleStackError {
+    /// Advertising failed, the error of the backend is logged by the backend
+    AdvertiseError,
+}
+
+/// Advertising of a BLE backend
+pub(crate) trait BleAdvertiser {
+    /// Connection to a host
+    type Connection: BleConnection;
+
+    /// Advertise for the active profile, returns when a host is connected
+    async fn advertise(&mut self) -> Result<Self::Connection, BleStackError>;
+}
+
+/// Connection to a host
+pub(crate) trait BleConnection {
+    /// Run the tasks of the connection, returns when the host is disconnected
+    async fn run(&self);
+
+    /// Disconnect the host if it's still connected, and clean up the connection
+    async fn disconnect(self);
+}
+
+/// The keyboard that runs on the output chosen by the BLE stack
+pub(crate) trait KeyboardRunner<C> {
+    /// Run the keyboard over BLE, returns when the host is disconnected
+    async fn run_ble(&mut self, conn: &C);
+
+    /// Run the keyboard over USB
+    async fn run_usb(&mut self);
+
+    /// Run the keyboard without output, keys are still processed so that profiles can be switched
+    async fn run_dummy(&mut self);
+}
+
+/// Events that change the output of the BLE stack
+pub(crate) trait BleStackEvents {
+    /// Whether USB is connected
+    fn usb_enabled(&self) -> bool;
+
+    /// Whether USB is the selected output
+    fn usb_output(&self) -> bool;
+
+    /// Wait until USB is connected but not configured yet, e.g. when the cable is plugged in
+    async fn wait_for_usb_enabled(&self);
+
+    /// Wait until USB is suspended
+    async fn wait_for_usb_suspended(&self);
+
+    /// Wait until the active profile or the selected output is changed
+    async fn wait_for_profile_update(&self);
+}
+
+/// Run the connection loop of the BLE stack, switching between BLE and USB.
+/// This function never returns.
+pub(crate) async fn run_ble_stack<
+    A: BleAdvertiser,
+    K: KeyboardRunner<A::Connection>,
+    E: BleStackEvents,
+>(
+    advertiser: &mut A,
+    keyboard: &mut K,
+    events: &E,
+) -> ! {
+    loop {
+        debug!(
+            "usb enabled: {}, usb output: {}",
+            events.usb_enabled(),
+            events.usb_output()
+        );
+        if events.usb_enabled() && events.usb_output() {
+            info!("Running USB keyboard");
+            select3(
+                keyboard.run_usb(),
+                events.wait_for_usb_suspended(),
+                events.wait_for_profile_update(),
+            )
+            .await;
+        } else {
+            let connected = if events.usb_enabled() {
+                // USB is connected, but the output is BLE, try BLE while running USB keyboard
+                info!("Running USB keyboard, while advertising");
+                select3(
+                    advertiser.advertise(),
+                    keyboard.run_usb(),
+                    events.wait_for_profile_update(),
+                )
+                .await
+            } else {
+                // USB isn't connected, wait for any of BLE/USB connection
+                info!("BLE advertising");
+                select3(
+                    advertiser.advertise(),
+                    keyboard.run_dummy(),
+                    wait_for_status_change(events),
+                )
+                .await
+            };
+            match connected {
+                Either3::First(Ok(conn)) => run_connection(keyboard, events, conn).await,
+                Either3::First(Err(e)) => {
+                    error!("BLE advertise error: {:?}", e);
+                    // Retry after 200 ms
+                    Timer::after_millis(200).await;
+                }
+                _ => (),
+            }
+        }
+        // Wait 10ms for usb resuming/switching profile
+        Timer::after_millis(10).await;
+    }
+}
+
+/// Run the keyboard on the connection, until the host is disconnected or the output is changed
+async fn run_connection<C: BleConnection, K: KeyboardRunner<C>, E: BleStackEvents>(
+    keyboard: &mut K,
+    events: &E,
+    conn: C,
+) {
+    info!("Connected to BLE");
+    match select3(
+        keyboard.run_ble(&conn),
+        events.wait_for_usb_enabled(),
+        events.wait_for_profile_update(),
+    )
+    .await
+    {
+        Either3::First(_) => info!("BLE disconnected"),
+        Either3::Second(_) => info!("Detected USB configured, quit BLE"),
+        Either3::Third(_) => info!("Switch profile"),
+    }
+    conn.disconnect().await;
+}
+
+// Wait for USB enabled or BLE state changed
+async fn wait_for_status_change(events: &impl BleStackEvents) {
+    if events.usb_output() {
+        // Connection type is USB, USB has higher priority
+        select(
+            events.wait_for_usb_enabled(),
+            events.wait_for_profile_update(),
+        )
+        .await;
+    } else {
+        // Connection type is BLE, so we don't consider USB
+        events.wait_for_profile_update().await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::cell::{Cell, RefCell};
+    use embassy_futures::{block_on, yield_now};
+    use embassy_sync::{blocking_mutex::raw::NoopRawMutex, signal::Signal};
+    use heapless::Vec;
+
+    /// Steps of the BLE stack recorded by the mock backend
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Step {
+        Advertise,
+        AdvertiseFailed,
+        Connected(u8),
+        RunBle(u8),
+        RunUsb,
+        RunDummy,
+        Disconnect(u8),
+    }
+
+    /// Mock backend, the test script drives it by signals and checks the recorded steps
+    #[derive(Default)]
+    struct MockBackend {
+        steps: RefCell<Vec<Step, 32>>,
+        usb_enabled: Cell<bool>,
+        usb_output: Cell<bool>,
+        next_host: Cell<u8>,
+        connect: Signal<NoopRawMutex, Result<(), BleStackError>>,
+        disconnect: Signal<NoopRawMutex, ()>,
+        usb_changed: Signal<NoopRawMutex, ()>,
+        profile_updated: Signal<NoopRawMutex, ()>,
+    }
+
+    impl MockBackend {
+        fn record(&self, step: Step) {
+            self.steps.borrow_mut().push(step).unwrap();
+        }
+
+        fn take_steps(&self) -> Vec<Step, 32> {
+            core::mem::take(&mut *self.steps.borrow_mut())
+        }
+
+        fn set_usb(&self, enabled: bool) {
+            self.usb_enabled.set(enabled);
+            self.usb_changed.signal(());
+        }
+    }
+
+    struct MockAdvertiser<'a>(&'a MockBackend);
+
+    struct MockConnection<'a> {
+        host: u8,
+        backend: &'a MockBackend,
+    }
+
+    struct MockKeyboard<'a>(&'a MockBackend);
+
+    impl<'a> BleAdvertiser for MockAdvertiser<'a> {
+        type Connection = MockConnection<'a>;
+
+        async fn advertise(&mut self) -> Result<Self::Connection, BleStackError> {
+            self.0.record(Step::Advertise);
+            if let Err(e) = self.0.connect.wait().await {
+                self.0.record(Step::AdvertiseFailed);
+                return Err(e);
+            }
+            let host = self.0.next_host.get();
+            self.0.next_host.set(host + 1);
+            self.0.record(Step::Connected(host));
+            Ok(MockConnection {
+                host,
+                backend: self.0,
+            })
+        }
+    }
+
+    impl BleConnection for MockConnection<'_> {
+        async fn run(&self) {
+            self.backend.disconnect.wait().await;
+        }
+
+        async fn disconnect(self) {
+            self.backend.record(Step::Disconnect(self.host));
+        }
+    }
+
+    impl<'a> KeyboardRunner<MockConnection<'a>> for MockKeyboard<'a> {
+        async fn run_ble(&mut self, conn: &MockConnection<'a>) {
+            self.0.record(Step::RunBle(conn.host));
+            conn.run().await;
+        }
+
+        async fn run_usb(&mut self) {
+            self.0.record(Step::RunUsb);
+            core::future::pending().await
+        }
+
+        async fn run_dummy(&mut self) {
+            self.0.record(Step::RunDummy);
+            core::future::pending().await
+        }
+    }
+
+    impl BleStackEvents for MockBackend {
+        fn usb_enabled(&self) -> bool {
+            self.usb_enabled.get()
+        }
+
+        fn usb_output(&self) -> bool {
+            self.usb_output.get()
+        }
+
+        async fn wait_for_usb_enabled(&self) {
+            loop {
+                self.usb_changed.wait().await;
+                if self.usb_enabled.get() {
+                    break;
+                }
+            }
+        }
+
+        async fn wait_for_usb_suspended(&self) {
+            while self.usb_enabled.get() {
+                self.usb_changed.wait().await;
+            }
+        }
+
+        async fn wait_for_profile_update(&self) {
+            self.profile_updated.wait().await;
+        }
+    }
+
+    /// Run the BLE stack with the mock backend until the script finishes
+    fn run_script(backend: &MockBackend, script: impl core::future::Future<Output = ()>) {
+        let mut advertiser = MockAdvertiser(backend);
+        let mut keyboard = MockKeyboard(backend);
+        block_on(select(
+            run_ble_stack(&mut advertiser, &mut keyboard, backend),
+            script,
+        ));
+    }
+
+    /// Maximum number of yields to wait for the steps.
+    ///
+    /// The stack waits for timers of up to 200ms between steps, which takes about 1M yields in debug builds.
+    const MAX_YIELDS: usize = 10_000_000;
+
+    /// Let the BLE stack run until it records `count` steps, then take the steps.
+    ///
+    /// The stack runs whenever the script yields, so the steps are taken as soon as they're recorded,
+    /// an unexpected step recorded meanwhile fails the assertion of the taken steps.
+    /// Panics if the steps aren't recorded within [`MAX_YIELDS`] yields, e.g. when the stack is stuck.
+    async fn wait_for_steps(backend: &MockBackend, count: usize) -> Vec<Step, 32> {
+        for _ in 0..MAX_YIELDS {
+            if backend.steps.borrow().len() >= count {
+                return backend.take_steps();
+            }
+            yield_now().await;
+        }
+        panic!(
+            "Expected {} steps, got {:?}",
+            count,
+            backend.steps.borrow().as_slice()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected 3 steps")]
+    fn test_wait_for_steps_bounded() {
+        let backend = MockBackend::default();
+        run_script(&backend, async {
+            // Only advertising and the dummy keyboard run without a host
+            wait_for_steps(&backend, 3).await;
+        });
+    }
+
+    #[test]
+    fn test_ble_connection() {
+        let backend = MockBackend::default();
+        backend.usb_output.set(true);
+        run_script(&backend, async {
+            assert_eq!(
+                wait_for_steps(&backend, 2).await,
+                [Step::Advertise, Step::RunDummy]
+            );
+
+            // A host is connected, then disconnected
+            backend.connect.signal(Ok(()));
+            assert_eq!(
+                wait_for_steps(&backend, 2).await,
+                [Step::Connected(0), Step::RunBle(0)]
+            );
+            backend.disconnect.signal(());
+            assert_eq!(
+                wait_for_steps(&backend, 3).await,
+                [Step::Disconnect(0), Step::Advertise, Step::RunDummy]
+            );
+
+            // Advertising is retried after an error
+            backend.connect.signal(Err(BleStackError::AdvertiseError));
+            assert_eq!(
+                wait_for_steps(&backend, 3).await,
+                [Step::AdvertiseFailed, Step::Advertise, Step::RunDummy]
+            );
+
+            // Switching profile disconnects the current host and advertises again
+            backend.connect.signal(Ok(()));
+            assert_eq!(
+                wait_for_steps(&backend, 2).await,
+                [Step::Connected(1), Step::RunBle(1)]
+            );
+            backend.profile_updated.signal(());
+            assert_eq!(
+                wait_for_steps(&backend, 3).await,
+                [Step::Disconnect(1), Step::Advertise, Step::RunDummy]
+            );
+
+            // Switching profile while advertising restarts advertising
+            backend.profile_updated.signal(());
+            assert_eq!(
+                wait_for_steps(&backend, 2).await,
+                [Step::Advertise, Step::RunDummy]
+            );
+        });
+    }
+
+    #[test]
+    fn test_usb_priority() {
+        let backend = MockBackend::default();
+        backend.usb_output.set(true);
+        run_script(&backend, async {
+            backend.connect.signal(Ok(()));
+            assert_eq!(
+                wait_for_steps(&backend, 4).await,
+                [
+                    Step::Advertise,
+                    Step::RunDummy,
+                    Step::Connected(0),
+                    Step::RunBle(0)
+                ]
+            );
+
+            // USB has higher priority when the USB output is selected
+            backend.set_usb(true);
+            assert_eq!(
+                wait_for_steps(&backend, 2).await,
+                [Step::Disconnect(0), Step::RunUsb]
+            );
+
+            // Keyboard goes back to BLE once USB is suspended
+            backend.set_usb(false);
+            assert_eq!(
+                wait_for_steps(&backend, 2).await,
+                [Step::Advertise, Step::RunDummy]
+            );
+        });
+    }
+
+    #[test]
+    fn test_ble_output_with_usb() {
+        let backend = MockBackend::default();
+        backend.usb_enabled.set(true);
+        run_script(&backend, async {
+            // USB keyboard runs while advertising
+            assert_eq!(
+                wait_for_steps(&backend, 2).await,
+                [Step::Advertise, Step::RunUsb]
+            );
+            backend.connect.signal(Ok(()));
+            assert_eq!(
+                wait_for_steps(&backend, 2).await,
+                [Step::Connected(0), Step::RunBle(0)]
+            );
+
+            // Select the USB output
+            backend.usb_output.set(true);
+            backend.profile_updated.signal(());
+            assert_eq!(
+                wait_for_steps(&backend, 2).await,
+                [Step::Disconnect(0), Step::RunUsb]
+            );
+        });
+    }
+}
@@ -0,0 +1,211 @@
+//! Charlieplexed matrix
+//!
+//! A charlieplexed matrix uses `N` pins for up to `N * (N - 1)` keys.
+//! Every pin is driven in turn while other pins are read, each ordered pair of pins has a key with a diode.
+//!
+//! The key found by driving pin `i` and reading pin `j` is at row `i` in the keymap,
+//! and at col `j` if `j < i`, or at col `j - 1` if `j > i`.
+//! So the keymap has `N` rows and `N - 1` cols.
+use embassy_time::{Instant, Timer};
+
+use crate::debounce::{DebounceState, DebouncerTrait};
+use crate::event::{Event, KeyEvent};
+use crate::input_device::InputDevice;
+use crate::matrix::{
+    drive_flex_pin, is_flex_pin_active, release_flex_pin, FlexPin, KeyState, MatrixTrait,
+};
+
+/// Charlieplexed matrix, see the [module level docs](self) for the key positions.
+///
+/// The debouncer should have `PIN_NUM` inputs and `PIN_NUM` outputs, it's indexed by (read pin, driven pin).
+/// `ROW_OFFSET` and `COL_OFFSET` are used when the matrix is on the split central, set them to 0 otherwise.
+///
+/// Flex pins have no interrupt, so when the `async_matrix` feature is enabled,
+/// the matrix is scanned every 1ms if no key is pressed.
+pub struct CharlieplexMatrix<
+    P: FlexPin,
+    D: DebouncerTrait,
+    const ROW_OFFSET: usize,
+    const COL_OFFSET: usize,
+    const PIN_NUM: usize,
+> {
+    pins: [P; PIN_NUM],
+    /// Debouncer
+    debouncer: D,
+    /// Key states, indexed by `[driven pin][read pin]`
+    key_states: [[KeyState; PIN_NUM]; PIN_NUM],
+    /// Start scanning
+    scan_start: Option<Instant>,
+    /// Current scan pos: (out_idx, in_idx)
+    scan_pos: (usize, usize),
+    /// Pin active level
+    low_active: bool,
+}
+
+impl<
+        P: FlexPin,
+        D: DebouncerTrait,
+        const ROW_OFFSET: usize,
+        const COL_OFFSET: usize,
+        const PIN_NUM: usize,
+    > CharlieplexMatrix<P, D, ROW_OFFSET, COL_OFFSET, PIN_NUM>
+{
+    /// Create a charlieplexed matrix from pins.
+    pub fn new(mut pins: [P; PIN_NUM], debouncer: D, low_active: bool) -> Self {
+        assert!(PIN_NUM >= 2, "Charlieplexed matrix needs at least 2 pins");
+        // All pins are inputs at the idle level when not scanning
+        for pin in pins.iter_mut() {
+            release_flex_pin(pin, low_active);
+        }
+        Self {
+            pins,
+            debouncer,
+            key_states: [[KeyState::new(); PIN_NUM]; PIN_NUM],
+            scan_start: None,
+            scan_pos: (0, 0),
+            low_active,
+        }
+    }
+}
+
+impl<
+        P: FlexPin,
+        D: DebouncerTrait,
+        const ROW_OFFSET: usize,
+        const COL_OFFSET: usize,
+        const PIN_NUM: usize,
+    > InputDevice for CharlieplexMatrix<P, D, ROW_OFFSET, COL_OFFSET, PIN_NUM>
+{
+    async fn read_event(&mut self) -> Event {
+        loop {
+            let (out_idx_start, in_idx_start) = self.scan_pos;
+            #[cfg(feature = "async_matrix")]
+            self.wait_for_key().await;
+
+            for out_idx in out_idx_start..PIN_NUM {
+                // Drive current pin, wait 1us ensuring the change comes into effect
+                drive_flex_pin(&mut self.pins[out_idx], self.low_active);
+                Timer::after_micros(1).await;
+
+                let in_idx_start = if out_idx == out_idx_start {
+                    in_idx_start
+                } else {
+                    0
+                };
+                for in_idx in (in_idx_start..PIN_NUM).filter(|i| *i != out_idx) {
+                    let pin_state = is_flex_pin_active(&mut self.pins[in_idx], self.low_active);
+                    let key_state = &mut self.key_states[out_idx][in_idx];
+                    let debounce_state = self
+                        .debouncer
+                        .detect_change_with_debounce(in_idx, out_idx, pin_state, key_state);
+
+                    if let DebounceState::Debounced = debounce_state {
+                        key_state.toggle_pressed();
+                        let pressed = key_state.pressed;
+                        release_flex_pin(&mut self.pins[out_idx], self.low_active);
+                        let col = if in_idx < out_idx { in_idx } else { in_idx - 1 };
+                        self.scan_pos = (out_idx, in_idx);
+                        return Event::Key(KeyEvent {
+                            row: (out_idx + ROW_OFFSET) as u8,
+                            col: (col + COL_OFFSET) as u8,
+                            pressed,
+                        });
+                    }
+
+                    // If there's key still pressed, always refresh the self.scan_start
+                    #[cfg(feature = "async_matrix")]
+                    if key_state.pressed {
+                        self.scan_start = Some(Instant::now());
+                    }
+                }
+
+                // Release it back to idle level
+                release_flex_pin(&mut self.pins[out_idx], self.low_active);
+            }
+            self.scan_pos = (0, 0);
+        }
+    }
+}
+
+impl<
+        P: FlexPin,
+        D: DebouncerTrait,
+        const ROW_OFFSET: usize,
+        const COL_OFFSET: usize,
+        const PIN_NUM: usize,
+    > MatrixTrait for CharlieplexMatrix<P, D, ROW_OFFSET, COL_OFFSET, PIN_NUM>
+{
+    const ROW: usize = PIN_NUM;
+    const COL: usize = PIN_NUM - 1;
+
+    #[cfg(feature = "async_matrix")]
+    async fn wait_for_key(&mut self) {
+        if let Some(start_time) = self.scan_start {
+            // If no key press over 1ms, slow down scanning
+            if start_time.elapsed().as_millis() <= 1 {
+                return;
+            } else {
+                self.scan_start = None;
+            }
+        }
+        Timer::after_millis(1).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use embassy_time::Duration;
+
+    use super::*;
+    use crate::config::{DebounceAlgorithm, DebounceConfig};
+    use crate::debounce::per_key_debouncer::PerKeyDebouncer;
+    use crate::matrix::fake_flex_pin::{FakeFlexPin, FakeNet};
+    use crate::matrix::test_utils::read_key;
+
+    type TestMatrix = CharlieplexMatrix<FakeFlexPin, PerKeyDebouncer<4, 4>, 0, 0, 4>;
+
+    fn matrix(net: &Rc<RefCell<FakeNet>>, low_active: bool) -> TestMatrix {
+        let debouncer = PerKeyDebouncer::new(DebounceConfig {
+            algorithm: DebounceAlgorithm::SymDeferPk,
+            press_time: Duration::from_millis(0),
+            release_time: Duration::from_millis(0),
+        });
+        CharlieplexMatrix::new(FakeNet::pins::<4>(net), debouncer, low_active)
+    }
+
+    #[test]
+    fn test_charlieplex_size() {
+        assert_eq!(TestMatrix::ROW, 4);
+        assert_eq!(TestMatrix::COL, 3);
+    }
+
+    #[test]
+    fn test_charlieplex_key_positions() {
+        for low_active in [false, true] {
+            let net = Rc::new(RefCell::new(FakeNet::default()));
+            let mut matrix = matrix(&net, low_active);
+
+            // Drive pin 0, read pin 1
+            net.borrow_mut().pressed.push((0, 1));
+            assert_eq!(read_key(&mut matrix), (0, 0, true));
+            // Drive pin 2, read pin 1 and pin 3
+            net.borrow_mut().pressed.push((2, 1));
+            assert_eq!(read_key(&mut matrix), (2, 1, true));
+            net.borrow_mut().pressed.push((2, 3));
+            assert_eq!(read_key(&mut matrix), (2, 2, true));
+            // Drive pin 3, read pin 0
+            net.borrow_mut().pressed.push((3, 0));
+            assert_eq!(read_key(&mut matrix), (3, 0, true));
+
+            // Scanning continues from the last reported key
+            net.borrow_mut().pressed.clear();
+            assert_eq!(read_key(&mut matrix), (3, 0, false));
+            assert_eq!(read_key(&mut matrix), (0, 0, false));
+            assert_eq!(read_key(&mut matrix), (2, 1, false));
+            assert_eq!(read_key(&mut matrix), (2, 2, false));
+        }
+    }
+}
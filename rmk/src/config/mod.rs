@@ -114,6 +114,12 @@ pub struct RmkConfig<'a> {
     pub ble_battery_config: BleBatteryConfig<'a>,
     #[cfg(feature = "_esp_ble")]
     pub ble_battery_config: BleBatteryConfig,
+    /// Type the passkey displayed by the host on the keyboard when pairing, instead of "Just Works" pairing
+    #[cfg(feature = "_ble")]
+    pub ble_passkey_entry: bool,
+    /// Number of split peripherals, the battery level of each peripheral is reported to the host
+    #[cfg(all(feature = "split", feature = "_nrf_ble"))]
+    pub split_peripheral_num: usize,
 }
 
 /// Config for configurable action behavior
@@ -236,10 +242,38 @@ impl<'a> VialConfig<'a> {
     }
 }
 
+/// Debounce algorithms, see [`crate::debounce::per_key_debouncer::PerKeyDebouncer`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DebounceAlgorithm {
+    /// Symmetric, deferred, per-key
+    #[default]
+    SymDeferPk,
+    /// Symmetric, eager, per-key
+    SymEagerPk,
+    /// Eager press, deferred release, per-key
+    AsymEagerDeferPk,
+}
+
 /// Configuration for debouncing
+#[derive(Clone, Copy, Debug)]
 pub struct DebounceConfig {
-    /// Debounce time in ms
-    pub debounce_time: u32,
+    /// Debounce algorithm
+    pub algorithm: DebounceAlgorithm,
+    /// Debounce time of key presses
+    pub press_time: Duration,
+    /// Debounce time of key releases
+    pub release_time: Duration,
+}
+
+impl Default for DebounceConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: DebounceAlgorithm::SymDeferPk,
+            press_time: Duration::from_millis(20),
+            release_time: Duration::from_millis(20),
+        }
+    }
 }
 
 /// Configurations for mouse functionalities
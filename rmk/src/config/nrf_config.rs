@@ -3,6 +3,8 @@ use embassy_nrf::{
     saadc::Saadc,
 };
 
+use crate::ble::battery::{AdcConfig, LIPO_DISCHARGE_CURVE};
+
 pub struct BleBatteryConfig<'a> {
     pub charge_state_pin: Option<Input<'a>>,
     pub charge_led_pin: Option<Output<'a>>,
@@ -11,6 +13,14 @@ pub struct BleBatteryConfig<'a> {
     pub saadc: Option<Saadc<'a, 1>>,
     pub adc_divider_measured: u32,
     pub adc_divider_total: u32,
+    /// Reference, gain and resolution of the ADC, which should be the same as the config of `saadc`
+    pub adc_config: AdcConfig,
+    /// Battery levels at voltages, in `(millivolts, percent)` sorted by voltage from high to low
+    pub discharge_curve: &'static [(u16, u8)],
+    /// Number of samples of the moving average of the battery voltage
+    pub filter_window: usize,
+    /// Changes of the battery level within the hysteresis(in percent) are ignored, if they're against the charging state
+    pub hysteresis: u8,
 }
 
 impl<'a> Default for BleBatteryConfig<'a> {
@@ -23,6 +33,10 @@ impl<'a> Default for BleBatteryConfig<'a> {
             saadc: None,
             adc_divider_measured: 1,
             adc_divider_total: 1,
+            adc_config: AdcConfig::default(),
+            discharge_curve: &LIPO_DISCHARGE_CURVE,
+            filter_window: 4,
+            hysteresis: 2,
         }
     }
 }
@@ -45,6 +59,7 @@ impl<'a> BleBatteryConfig<'a> {
             saadc,
             adc_divider_measured,
             adc_divider_total,
+            ..Default::default()
         }
     }
 }
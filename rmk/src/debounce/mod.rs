@@ -2,6 +2,7 @@ use crate::matrix::KeyState;
 
 pub mod default_debouncer;
 pub mod fast_debouncer;
+pub mod per_key_debouncer;
 
 /// Default DEBOUNCE_THRESHOLD in ms.
 static DEBOUNCE_THRESHOLD: u16 = 20;
@@ -0,0 +1,148 @@
+use embassy_time::Instant;
+
+use crate::config::{DebounceAlgorithm, DebounceConfig};
+use crate::matrix::KeyState;
+
+use super::{DebounceState, DebouncerTrait};
+
+/// Debouncing phase of a single key
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Phase {
+    /// The pin state matches the key state
+    Idle,
+    /// A change is detected and is waiting to be stable, for defer algorithms
+    Deferring,
+    /// A change is reported, further changes are ignored for a while, for eager algorithms
+    Cooldown,
+}
+
+/// Debounce info for each key.
+#[derive(Copy, Clone, Debug)]
+struct KeyDebounce {
+    phase: Phase,
+    /// Start time of current phase, in ms
+    start_ms: u32,
+}
+
+/// Per-key debouncer whose algorithm and debounce times are set at runtime by [`DebounceConfig`].
+///
+/// The algorithms are same as QMK's [per-key debounce algorithms](https://docs.qmk.fm/feature_debounce_type):
+///
+/// - `SymDeferPk`: a change is reported after the pin is stable for the debounce time
+/// - `SymEagerPk`: a change is reported immediately, then the key is ignored for the debounce time
+/// - `AsymEagerDeferPk`: press is eager, release is deferred
+///
+/// `press_time` is used for debouncing presses, and `release_time` is used for debouncing releases.
+pub struct PerKeyDebouncer<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> {
+    algorithm: DebounceAlgorithm,
+    press_ms: u32,
+    release_ms: u32,
+    keys: [[KeyDebounce; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+}
+
+impl<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> Default
+    for PerKeyDebouncer<INPUT_PIN_NUM, OUTPUT_PIN_NUM>
+{
+    fn default() -> Self {
+        Self::new(DebounceConfig::default())
+    }
+}
+
+impl<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize>
+    PerKeyDebouncer<INPUT_PIN_NUM, OUTPUT_PIN_NUM>
+{
+    /// Create a per-key debouncer with the given config
+    pub fn new(config: DebounceConfig) -> Self {
+        PerKeyDebouncer {
+            algorithm: config.algorithm,
+            press_ms: config.press_time.as_millis() as u32,
+            release_ms: config.release_time.as_millis() as u32,
+            keys: [[KeyDebounce {
+                phase: Phase::Idle,
+                start_ms: 0,
+            }; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+        }
+    }
+
+    /// Debounce time of a press or a release
+    fn debounce_ms(&self, pressed: bool) -> u32 {
+        if pressed {
+            self.press_ms
+        } else {
+            self.release_ms
+        }
+    }
+
+    /// Whether a change to `pressed` is reported eagerly
+    fn is_eager(&self, pressed: bool) -> bool {
+        match self.algorithm {
+            DebounceAlgorithm::SymDeferPk => false,
+            DebounceAlgorithm::SymEagerPk => true,
+            DebounceAlgorithm::AsymEagerDeferPk => pressed,
+        }
+    }
+
+    /// Debounce the key at the given time in ms
+    fn detect_change_at(
+        &mut self,
+        now_ms: u32,
+        in_idx: usize,
+        out_idx: usize,
+        pin_state: bool,
+        key_state: &KeyState,
+    ) -> DebounceState {
+        let mut key = self.keys[out_idx][in_idx];
+
+        if key.phase == Phase::Cooldown {
+            // The key state is already updated to the reported change
+            if now_ms.wrapping_sub(key.start_ms) < self.debounce_ms(key_state.pressed) {
+                return DebounceState::InProgress;
+            }
+            key.phase = Phase::Idle;
+        }
+
+        let result = if key_state.pressed == pin_state {
+            if key.phase == Phase::Deferring {
+                // The pin bounced back before it's stable
+                key.phase = Phase::Idle;
+                DebounceState::InProgress
+            } else {
+                DebounceState::Ignored
+            }
+        } else if self.is_eager(pin_state) {
+            // Report the change immediately, and ignore following bounces
+            key.phase = Phase::Cooldown;
+            key.start_ms = now_ms;
+            DebounceState::Debounced
+        } else {
+            if key.phase == Phase::Idle {
+                key.phase = Phase::Deferring;
+                key.start_ms = now_ms;
+            }
+            if now_ms.wrapping_sub(key.start_ms) >= self.debounce_ms(pin_state) {
+                key.phase = Phase::Idle;
+                DebounceState::Debounced
+            } else {
+                DebounceState::InProgress
+            }
+        };
+
+        self.keys[out_idx][in_idx] = key;
+        result
+    }
+}
+
+impl<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize> DebouncerTrait
+    for PerKeyDebouncer<INPUT_PIN_NUM, OUTPUT_PIN_NUM>
+{
+    fn detect_change_with_debounce(
+        &mut self,
+        in_idx: usize,
+        out_idx: usize,
+        pin_state: bool,
+        key_state: &KeyState,
+    ) -> DebounceState {
+        let now_ms = Instant::now().as_millis() as u32;
+        self.detect_change_at(now_ms, in_idx, out_idx, pin_state, key_state)
+    }
+}
@@ -0,0 +1,262 @@
+//! Japanese duplex matrix
+//!
+//! A duplex matrix has two keys at each intersection of a row pin and a col pin, whose diodes are in opposite directions.
+//! Both row pins and col pins are switched between input and output, so it scans the matrix twice:
+//!
+//! - col2row: drive each col pin, read row pins
+//! - row2col: drive each row pin, read col pins
+//!
+//! The key at row pin `r` and col pin `c` is at `(r, c)` in the keymap if it's found by col2row scanning,
+//! or at `(r, COL_PIN_NUM + c)` if it's found by row2col scanning.
+//! So the keymap has `ROW_PIN_NUM` rows and `COL_PIN_NUM * 2` cols.
+use embassy_time::{Instant, Timer};
+
+use crate::debounce::{DebounceState, DebouncerTrait};
+use crate::event::{Event, KeyEvent};
+use crate::input_device::InputDevice;
+use crate::matrix::{
+    drive_flex_pin, is_flex_pin_active, release_flex_pin, FlexPin, KeyState, MatrixTrait,
+};
+
+/// Index of the col2row scanning direction, the index of row2col direction is 1
+const COL2ROW: usize = 0;
+
+/// Duplex matrix, see the [module level docs](self) for the key positions.
+///
+/// The debouncer should have `ROW_PIN_NUM` inputs and `COL_PIN_NUM * 2` outputs, it's indexed by the key position.
+/// `ROW_OFFSET` and `COL_OFFSET` are used when the matrix is on the split central, set them to 0 otherwise.
+///
+/// Flex pins have no interrupt, so when the `async_matrix` feature is enabled,
+/// the matrix is scanned every 1ms if no key is pressed.
+pub struct DuplexMatrix<
+    P: FlexPin,
+    D: DebouncerTrait,
+    const ROW_OFFSET: usize,
+    const COL_OFFSET: usize,
+    const ROW_PIN_NUM: usize,
+    const COL_PIN_NUM: usize,
+> {
+    row_pins: [P; ROW_PIN_NUM],
+    col_pins: [P; COL_PIN_NUM],
+    /// Debouncer
+    debouncer: D,
+    /// Key states of each direction, indexed by `[direction][col pin][row pin]`
+    key_states: [[[KeyState; ROW_PIN_NUM]; COL_PIN_NUM]; 2],
+    /// Start scanning
+    scan_start: Option<Instant>,
+    /// Current scan pos: (direction, out_idx, in_idx)
+    scan_pos: (usize, usize, usize),
+    /// Pin active level
+    low_active: bool,
+}
+
+impl<
+        P: FlexPin,
+        D: DebouncerTrait,
+        const ROW_OFFSET: usize,
+        const COL_OFFSET: usize,
+        const ROW_PIN_NUM: usize,
+        const COL_PIN_NUM: usize,
+    > DuplexMatrix<P, D, ROW_OFFSET, COL_OFFSET, ROW_PIN_NUM, COL_PIN_NUM>
+{
+    /// Create a duplex matrix from row pins and col pins.
+    pub fn new(
+        mut row_pins: [P; ROW_PIN_NUM],
+        mut col_pins: [P; COL_PIN_NUM],
+        debouncer: D,
+        low_active: bool,
+    ) -> Self {
+        // All pins are inputs at the idle level when not scanning
+        for pin in row_pins.iter_mut().chain(col_pins.iter_mut()) {
+            release_flex_pin(pin, low_active);
+        }
+        Self {
+            row_pins,
+            col_pins,
+            debouncer,
+            key_states: [[[KeyState::new(); ROW_PIN_NUM]; COL_PIN_NUM]; 2],
+            scan_start: None,
+            scan_pos: (0, 0, 0),
+            low_active,
+        }
+    }
+
+    /// Drive or release the output pin of current direction
+    fn set_output(&mut self, direction: usize, out_idx: usize, active: bool) {
+        let pin = if direction == COL2ROW {
+            &mut self.col_pins[out_idx]
+        } else {
+            &mut self.row_pins[out_idx]
+        };
+        if active {
+            drive_flex_pin(pin, self.low_active);
+        } else {
+            release_flex_pin(pin, self.low_active);
+        }
+    }
+}
+
+impl<
+        P: FlexPin,
+        D: DebouncerTrait,
+        const ROW_OFFSET: usize,
+        const COL_OFFSET: usize,
+        const ROW_PIN_NUM: usize,
+        const COL_PIN_NUM: usize,
+    > InputDevice for DuplexMatrix<P, D, ROW_OFFSET, COL_OFFSET, ROW_PIN_NUM, COL_PIN_NUM>
+{
+    async fn read_event(&mut self) -> Event {
+        loop {
+            let (direction_start, out_idx_start, in_idx_start) = self.scan_pos;
+            #[cfg(feature = "async_matrix")]
+            self.wait_for_key().await;
+
+            for direction in direction_start..2 {
+                let (out_num, in_num) = if direction == COL2ROW {
+                    (COL_PIN_NUM, ROW_PIN_NUM)
+                } else {
+                    (ROW_PIN_NUM, COL_PIN_NUM)
+                };
+                let out_idx_start = if direction == direction_start {
+                    out_idx_start
+                } else {
+                    0
+                };
+                for out_idx in out_idx_start..out_num {
+                    // Drive current output pin, wait 1us ensuring the change comes into effect
+                    self.set_output(direction, out_idx, true);
+                    Timer::after_micros(1).await;
+
+                    let in_idx_start = if direction == direction_start && out_idx == out_idx_start {
+                        in_idx_start
+                    } else {
+                        0
+                    };
+                    for in_idx in in_idx_start..in_num {
+                        let (row_pin, col_pin, pin_state) = if direction == COL2ROW {
+                            let active =
+                                is_flex_pin_active(&mut self.row_pins[in_idx], self.low_active);
+                            (in_idx, out_idx, active)
+                        } else {
+                            let active =
+                                is_flex_pin_active(&mut self.col_pins[in_idx], self.low_active);
+                            (out_idx, in_idx, active)
+                        };
+                        let col = col_pin + direction * COL_PIN_NUM;
+                        let key_state = &mut self.key_states[direction][col_pin][row_pin];
+
+                        let debounce_state = self
+                            .debouncer
+                            .detect_change_with_debounce(row_pin, col, pin_state, key_state);
+
+                        if let DebounceState::Debounced = debounce_state {
+                            key_state.toggle_pressed();
+                            let pressed = key_state.pressed;
+                            self.set_output(direction, out_idx, false);
+                            self.scan_pos = (direction, out_idx, in_idx);
+                            return Event::Key(KeyEvent {
+                                row: (row_pin + ROW_OFFSET) as u8,
+                                col: (col + COL_OFFSET) as u8,
+                                pressed,
+                            });
+                        }
+
+                        // If there's key still pressed, always refresh the self.scan_start
+                        #[cfg(feature = "async_matrix")]
+                        if key_state.pressed {
+                            self.scan_start = Some(Instant::now());
+                        }
+                    }
+
+                    // Release it back to idle level
+                    self.set_output(direction, out_idx, false);
+                }
+            }
+            self.scan_pos = (0, 0, 0);
+        }
+    }
+}
+
+impl<
+        P: FlexPin,
+        D: DebouncerTrait,
+        const ROW_OFFSET: usize,
+        const COL_OFFSET: usize,
+        const ROW_PIN_NUM: usize,
+        const COL_PIN_NUM: usize,
+    > MatrixTrait for DuplexMatrix<P, D, ROW_OFFSET, COL_OFFSET, ROW_PIN_NUM, COL_PIN_NUM>
+{
+    const ROW: usize = ROW_PIN_NUM;
+    const COL: usize = COL_PIN_NUM * 2;
+
+    #[cfg(feature = "async_matrix")]
+    async fn wait_for_key(&mut self) {
+        if let Some(start_time) = self.scan_start {
+            // If no key press over 1ms, slow down scanning
+            if start_time.elapsed().as_millis() <= 1 {
+                return;
+            } else {
+                self.scan_start = None;
+            }
+        }
+        Timer::after_millis(1).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use embassy_time::Duration;
+
+    use super::*;
+    use crate::config::{DebounceAlgorithm, DebounceConfig};
+    use crate::debounce::per_key_debouncer::PerKeyDebouncer;
+    use crate::matrix::fake_flex_pin::{FakeFlexPin, FakeNet};
+    use crate::matrix::test_utils::read_key;
+
+    type TestMatrix = DuplexMatrix<FakeFlexPin, PerKeyDebouncer<2, 6>, 0, 0, 2, 3>;
+
+    fn matrix(net: &Rc<RefCell<FakeNet>>, low_active: bool) -> TestMatrix {
+        // Pin 0~1 are rows, pin 2~4 are cols
+        let row_pins = FakeNet::pins::<2>(net);
+        let col_pins = FakeNet::pins::<3>(net);
+        let debouncer = PerKeyDebouncer::new(DebounceConfig {
+            algorithm: DebounceAlgorithm::SymDeferPk,
+            press_time: Duration::from_millis(0),
+            release_time: Duration::from_millis(0),
+        });
+        DuplexMatrix::new(row_pins, col_pins, debouncer, low_active)
+    }
+
+    #[test]
+    fn test_duplex_size() {
+        assert_eq!(TestMatrix::ROW, 2);
+        assert_eq!(TestMatrix::COL, 6);
+    }
+
+    #[test]
+    fn test_duplex_scan_both_directions() {
+        for low_active in [false, true] {
+            let net = Rc::new(RefCell::new(FakeNet::default()));
+            let mut matrix = matrix(&net, low_active);
+
+            // col2row key at row pin 1, col pin 2
+            net.borrow_mut().pressed.push((4, 1));
+            assert_eq!(read_key(&mut matrix), (1, 2, true));
+            // row2col key at the same intersection
+            net.borrow_mut().pressed.push((1, 4));
+            assert_eq!(read_key(&mut matrix), (1, 5, true));
+            // row2col key at row pin 0, col pin 0
+            net.borrow_mut().pressed.push((0, 2));
+            assert_eq!(read_key(&mut matrix), (0, 3, true));
+
+            net.borrow_mut().pressed.retain(|k| *k != (4, 1));
+            assert_eq!(read_key(&mut matrix), (1, 2, false));
+            net.borrow_mut().pressed.clear();
+            assert_eq!(read_key(&mut matrix), (0, 3, false));
+            assert_eq!(read_key(&mut matrix), (1, 5, false));
+        }
+    }
+}
@@ -22,12 +22,53 @@ pub enum Event {
     Joystick([AxisEvent; 3]),
     /// An AxisEvent in a stream of events. The receiver should keep receiving events until it receives [`Event::Eos`] event.
     AxisEventStream(AxisEvent),
-    /// End of the event sequence
+    /// End of the event sequence of the board
     ///
     /// This is used with [`Event::AxisEventStream`] to indicate the end of the event sequence.
-    Eos,
-    /// Custom event
-    Custom([u8; 16]),
+    Eos(EventSource),
+    /// Custom event, and the board which it comes from
+    Custom([u8; 16], EventSource),
+}
+
+impl Event {
+    /// The board which the event comes from.
+    ///
+    /// Key events of peripherals are mapped to the keyboard matrix by the central, so they're always [`EventSource::Local`].
+    pub fn source(&self) -> EventSource {
+        match self {
+            Event::Key(_) => EventSource::Local,
+            Event::RotaryEncoder(e) => e.source,
+            Event::Touchpad(e) => e.source,
+            Event::Joystick([e, ..]) | Event::AxisEventStream(e) => e.source,
+            Event::Eos(source) | Event::Custom(_, source) => *source,
+        }
+    }
+
+    /// Set the source of the event, key events are not changed
+    pub(crate) fn set_source(&mut self, source: EventSource) {
+        match self {
+            Event::Key(_) => {}
+            Event::RotaryEncoder(e) => e.source = source,
+            Event::Touchpad(e) => {
+                e.source = source;
+                e.axis.iter_mut().for_each(|a| a.source = source);
+            }
+            Event::Joystick(axes) => axes.iter_mut().for_each(|a| a.source = source),
+            Event::AxisEventStream(e) => e.source = source,
+            Event::Eos(s) | Event::Custom(_, s) => *s = source,
+        }
+    }
+}
+
+/// The board which an input device is attached to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, MaxSize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EventSource {
+    /// The device is attached to this board
+    #[default]
+    Local,
+    /// The device is attached to the split peripheral with the id, set by the central when the event is received
+    Peripheral(u8),
 }
 
 /// Event for rotary encoder
@@ -38,6 +79,8 @@ pub struct RotaryEncoderEvent {
     pub id: u8,
     /// The direction of the rotary encoder
     pub direction: Direction,
+    /// The board which the rotary encoder is attached to
+    pub source: EventSource,
 }
 
 /// Event for multi-touch touchpad
@@ -48,6 +91,8 @@ pub struct TouchpadEvent {
     pub finger: u8,
     /// X, Y, Z axes for touchpad
     pub axis: [AxisEvent; 3],
+    /// The board which the touchpad is attached to
+    pub source: EventSource,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Copy, MaxSize)]
@@ -59,6 +104,8 @@ pub struct AxisEvent {
     pub axis: Axis,
     /// Value of the axis event
     pub value: i16,
+    /// The board which the device is attached to
+    pub source: EventSource,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Copy, MaxSize)]
@@ -89,3 +136,54 @@ pub struct KeyEvent {
     pub col: u8,
     pub pressed: bool,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set_source() {
+        let axis = AxisEvent {
+            typ: AxisValType::Rel,
+            axis: Axis::X,
+            value: 1,
+            source: EventSource::Local,
+        };
+        let source = EventSource::Peripheral(1);
+        let events = [
+            Event::RotaryEncoder(RotaryEncoderEvent {
+                id: 0,
+                direction: Direction::Clockwise,
+                source: EventSource::Local,
+            }),
+            Event::Touchpad(TouchpadEvent {
+                finger: 0,
+                axis: [axis; 3],
+                source: EventSource::Local,
+            }),
+            Event::Joystick([axis; 3]),
+            Event::AxisEventStream(axis),
+            Event::Eos(EventSource::Local),
+            Event::Custom([0; 16], EventSource::Local),
+        ];
+        for mut event in events {
+            assert_eq!(event.source(), EventSource::Local);
+            event.set_source(source);
+            assert_eq!(event.source(), source);
+            match event {
+                Event::Touchpad(e) => assert!(e.axis.iter().all(|a| a.source == source)),
+                Event::Joystick(axes) => assert!(axes.iter().all(|a| a.source == source)),
+                _ => (),
+            }
+        }
+
+        // Key events of peripherals are mapped to the matrix of the central
+        let mut key = Event::Key(KeyEvent {
+            row: 0,
+            col: 0,
+            pressed: true,
+        });
+        key.set_source(source);
+        assert_eq!(key.source(), EventSource::Local);
+    }
+}
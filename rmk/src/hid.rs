@@ -1,7 +1,11 @@
 /// Traits and types for HID message reporting and listening.
 use core::{future::Future, sync::atomic::Ordering};
 
-use crate::{channel::KEYBOARD_REPORT_CHANNEL, usb::descriptor::KeyboardReport, CONNECTION_STATE};
+use crate::{
+    channel::KEYBOARD_REPORT_CHANNEL,
+    usb::descriptor::{JoystickReport, KeyboardReport},
+    CONNECTION_STATE,
+};
 use embassy_usb::{class::hid::ReadError, driver::EndpointError};
 use serde::Serialize;
 use usbd_hid::descriptor::{AsInputReport, MediaKeyboardReport, MouseReport, SystemControlReport};
@@ -16,6 +20,8 @@ pub enum Report {
     MediaKeyboardReport(MediaKeyboardReport),
     /// System control report
     SystemControlReport(SystemControlReport),
+    /// Joystick/gamepad report
+    JoystickReport(JoystickReport),
 }
 
 impl AsInputReport for Report {}
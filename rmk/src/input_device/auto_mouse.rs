@@ -0,0 +1,260 @@
+//! Automatic mouse layer
+//!
+//! Activates a layer automatically when the pointing device moves, and deactivates it after a timeout
+//! or when a non-mouse key is pressed. It's the equivalent of QMK's auto mouse layer.
+use core::cell::{Cell, RefCell};
+
+use embassy_futures::select::select;
+use embassy_sync::{blocking_mutex::Mutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::action::{Action, KeyAction};
+use crate::event::{Axis, AxisEvent, AxisValType, Event, TouchpadEvent};
+use crate::keymap::KeyMap;
+use crate::RawMutex;
+
+use super::{InputProcessor, ProcessResult};
+
+/// The currently activated auto mouse layer and the instant when it expires.
+static AUTO_MOUSE_LAYER: Mutex<RawMutex, Cell<Option<(u8, Instant)>>> = Mutex::new(Cell::new(None));
+/// Signal which is fired when the auto mouse layer is activated or its deadline is extended.
+static AUTO_MOUSE_SIGNAL: Signal<RawMutex, ()> = Signal::new();
+
+/// Config for the automatic mouse layer
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AutoMouseConfig {
+    /// The layer which is activated when the pointing device moves
+    pub layer: u8,
+    /// The layer is deactivated after the pointing device stops moving for `timeout`
+    pub timeout: Duration,
+    /// Accumulated motion(sum of absolute x/y deltas) required to activate the layer
+    pub threshold: u16,
+}
+
+impl Default for AutoMouseConfig {
+    fn default() -> Self {
+        Self {
+            layer: 1,
+            timeout: Duration::from_millis(650),
+            threshold: 10,
+        }
+    }
+}
+
+/// Input processor which activates the auto mouse layer on pointer motion.
+///
+/// The processor only observes axis events, all events are passed to the next processor in the chain.
+/// Deactivating the layer is done in [`crate::keyboard::Keyboard`], either when the timeout is reached
+/// or when a non-mouse key is pressed.
+pub struct AutoMouseLayerProcessor<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+    config: AutoMouseConfig,
+    trigger: MotionTrigger,
+}
+
+/// Accumulates motions and decides when the auto mouse layer is activated
+struct MotionTrigger {
+    threshold: u16,
+    timeout: Duration,
+    /// Accumulated motion while the layer is not active
+    accumulated: u16,
+    /// Timestamp of last motion
+    last_motion: Instant,
+}
+
+impl MotionTrigger {
+    fn new(config: &AutoMouseConfig) -> Self {
+        Self {
+            threshold: config.threshold,
+            timeout: config.timeout,
+            accumulated: 0,
+            last_motion: Instant::MIN,
+        }
+    }
+
+    /// Add a motion, returns whether the layer should be activated, or kept if it's `active`
+    fn update(&mut self, motion: u16, active: bool, now: Instant) -> bool {
+        let idle = now.saturating_duration_since(self.last_motion);
+        self.last_motion = now;
+        if active {
+            return true;
+        }
+        if idle > self.timeout {
+            // Movements long ago don't count
+            self.accumulated = 0;
+        }
+        self.accumulated = self.accumulated.saturating_add(motion);
+        if self.accumulated >= self.threshold {
+            // The layer might be deactivated by a key press at any time,
+            // motions before that shouldn't count towards the threshold again
+            self.accumulated = 0;
+            return true;
+        }
+        false
+    }
+}
+
+impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    AutoMouseLayerProcessor<'a, ROW, COL, NUM_LAYER>
+{
+    pub fn new(
+        keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+        config: AutoMouseConfig,
+    ) -> Self {
+        Self {
+            keymap,
+            config,
+            trigger: MotionTrigger::new(&config),
+        }
+    }
+
+    /// Get the magnitude of relative x/y motion in the event
+    fn motion_of(event: &Event) -> u16 {
+        fn axis_motion(axis: &AxisEvent) -> u16 {
+            match (axis.typ, axis.axis) {
+                (AxisValType::Rel, Axis::X) | (AxisValType::Rel, Axis::Y) => {
+                    axis.value.unsigned_abs()
+                }
+                _ => 0,
+            }
+        }
+
+        match event {
+            Event::Joystick(axes) | Event::Touchpad(TouchpadEvent { axis: axes, .. }) => axes
+                .iter()
+                .fold(0u16, |acc, a| acc.saturating_add(axis_motion(a))),
+            Event::AxisEventStream(axis) => axis_motion(axis),
+            _ => 0,
+        }
+    }
+
+    fn on_motion(&mut self, motion: u16) {
+        let now = Instant::now();
+        let active = AUTO_MOUSE_LAYER.lock(|s| s.get().is_some());
+        if self.trigger.update(motion, active, now) {
+            if !active {
+                debug!("Activate auto mouse layer {}", self.config.layer);
+                self.keymap.borrow_mut().activate_layer(self.config.layer);
+            }
+            let deadline = now + self.config.timeout;
+            AUTO_MOUSE_LAYER.lock(|s| s.set(Some((self.config.layer, deadline))));
+            AUTO_MOUSE_SIGNAL.signal(());
+        }
+    }
+}
+
+impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    InputProcessor<'a, ROW, COL, NUM_LAYER> for AutoMouseLayerProcessor<'a, ROW, COL, NUM_LAYER>
+{
+    async fn process(&mut self, event: Event) -> ProcessResult {
+        let motion = Self::motion_of(&event);
+        if motion > 0 {
+            self.on_motion(motion);
+        }
+        ProcessResult::Continue(event)
+    }
+
+    fn get_keymap(&self) -> &RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>> {
+        self.keymap
+    }
+}
+
+/// Wait until the auto mouse layer times out, returns the layer which should be deactivated.
+///
+/// If the auto mouse layer is never activated, this future never completes.
+pub(crate) async fn wait_for_auto_mouse_timeout() -> u8 {
+    loop {
+        match AUTO_MOUSE_LAYER.lock(|s| s.get()) {
+            None => AUTO_MOUSE_SIGNAL.wait().await,
+            Some((layer, deadline)) => {
+                if Instant::now() >= deadline {
+                    AUTO_MOUSE_LAYER.lock(|s| s.set(None));
+                    return layer;
+                }
+                // The deadline might be extended by new motions
+                select(Timer::at(deadline), AUTO_MOUSE_SIGNAL.wait()).await;
+            }
+        }
+    }
+}
+
+/// Check whether the auto mouse layer should be deactivated by the pressed key action.
+///
+/// Mouse keys, pointing mode keys and modifiers keep the auto mouse layer, so that clicking with modifiers works.
+/// Returns the layer which should be deactivated.
+pub(crate) fn auto_mouse_layer_to_deactivate(key_action: KeyAction) -> Option<u8> {
+    if keeps_auto_mouse_layer(key_action) {
+        return None;
+    }
+    AUTO_MOUSE_LAYER.lock(|s| s.take()).map(|(layer, _)| layer)
+}
+
+fn keeps_auto_mouse_layer(key_action: KeyAction) -> bool {
+    match key_action {
+        KeyAction::Single(a) | KeyAction::Tap(a) | KeyAction::WithModifier(a, _) => match a {
+            Action::Key(k) => k.is_mouse_key() || k.is_modifier() || k.is_pointing_mode(),
+            Action::Modifier(_) => true,
+            _ => false,
+        },
+        KeyAction::OneShot(Action::Modifier(_)) | KeyAction::ModifierTapHold(_, _) => true,
+        KeyAction::No | KeyAction::Transparent => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::k;
+
+    fn create_trigger() -> MotionTrigger {
+        MotionTrigger::new(&AutoMouseConfig {
+            layer: 1,
+            timeout: Duration::from_millis(500),
+            threshold: 10,
+        })
+    }
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn test_threshold() {
+        let mut trigger = create_trigger();
+        assert!(!trigger.update(3, false, at(0)));
+        assert!(!trigger.update(6, false, at(10)));
+        assert!(trigger.update(1, false, at(20)));
+        // Any motion keeps the active layer
+        assert!(trigger.update(1, true, at(30)));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut trigger = create_trigger();
+        assert!(!trigger.update(6, false, at(0)));
+        // The first motion is dropped after the timeout
+        assert!(!trigger.update(6, false, at(600)));
+        assert!(trigger.update(6, false, at(1000)));
+    }
+
+    #[test]
+    fn test_deactivated_by_key_press() {
+        let mut trigger = create_trigger();
+        assert!(trigger.update(20, false, at(0)));
+        // The layer is deactivated by a key press, small motions right after it don't activate it again
+        assert!(!trigger.update(3, false, at(100)));
+        assert!(!trigger.update(3, false, at(200)));
+        assert!(trigger.update(4, false, at(300)));
+    }
+
+    #[test]
+    fn test_keep_layer() {
+        assert!(keeps_auto_mouse_layer(k!(MouseBtn1)));
+        assert!(keeps_auto_mouse_layer(k!(LShift)));
+        assert!(keeps_auto_mouse_layer(KeyAction::No));
+        assert!(!keeps_auto_mouse_layer(k!(A)));
+        assert!(!keeps_auto_mouse_layer(k!(Kc1)));
+    }
+}
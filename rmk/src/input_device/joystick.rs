@@ -0,0 +1,424 @@
+//! Analog joystick
+//!
+//! [`AnalogJoystick`] samples up to 3 axes from an ADC, and converts the raw values to [`Event::Joystick`].
+//! The raw values are calibrated, filtered by a deadzone and smoothed before being reported.
+//!
+//! The joystick can be used in two ways, according to [`JoystickOutput`]:
+//!
+//! - As a game controller: absolute axis values are reported, which are converted to [`JoystickReport`]
+//!   by [`JoystickProcessor`]. Joystick buttons are `KeyCode::JoystickButton0..31` in the keymap.
+//! - As a mouse: the deflection of the stick is converted to relative motion,
+//!   which is processed by [`crate::input_device::pointing::PointingProcessor`].
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicI8, AtomicU32, Ordering};
+
+use embassy_time::{Duration, Timer};
+
+use crate::event::{Axis, AxisEvent, AxisValType, Event, EventSource};
+use crate::hid::Report;
+use crate::keymap::KeyMap;
+use crate::usb::descriptor::JoystickReport;
+
+use super::{InputDevice, InputProcessor, ProcessResult};
+
+/// Max value of normalized axis values
+const AXIS_MAX: i32 = i16::MAX as i32;
+
+/// Joystick buttons which are currently pressed, set by `KeyCode::JoystickButton0..31`
+pub(crate) static JOYSTICK_BUTTONS: AtomicU32 = AtomicU32::new(0);
+/// Latest x/y/z values of the joystick report
+static JOYSTICK_AXES: [AtomicI8; 3] = [AtomicI8::new(0), AtomicI8::new(0), AtomicI8::new(0)];
+
+/// Get the joystick report of current buttons and axes
+pub(crate) fn current_joystick_report() -> JoystickReport {
+    JoystickReport {
+        buttons: JOYSTICK_BUTTONS.load(Ordering::Relaxed),
+        x: JOYSTICK_AXES[0].load(Ordering::Relaxed),
+        y: JOYSTICK_AXES[1].load(Ordering::Relaxed),
+        z: JOYSTICK_AXES[2].load(Ordering::Relaxed),
+    }
+}
+
+/// Trait for reading raw values from ADC channels.
+///
+/// Implement it for the ADC of your chip, for example, the SAADC of nRF52 or the ADC of RP2040.
+pub trait AnalogRead {
+    /// Sample the raw value of the given channel
+    async fn read(&mut self, channel: u8) -> u16;
+}
+
+/// How the joystick reports its axes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum JoystickOutput {
+    /// Report absolute axis values, for game controllers
+    Absolute,
+    /// Report relative motion, for controlling the mouse cursor.
+    /// The deflection(-32767 ~ 32767) is divided by `speed_divisor` every poll.
+    Relative { speed_divisor: u16 },
+}
+
+/// Config for the analog joystick
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct JoystickConfig {
+    /// Range of raw ADC values, (min, max). The range is extended automatically if a value out of range is sampled.
+    pub adc_range: (u16, u16),
+    /// Invert x/y/z axes
+    pub invert: [bool; 3],
+    /// Deflection within `deadzone` is reported as 0, in normalized units(0 ~ 32767)
+    pub deadzone: u16,
+    /// Exponential smoothing, each sample moves the output by `1 / 2^smoothing` of the difference. 0 disables smoothing
+    pub smoothing: u8,
+    /// Number of samples averaged to find the center position at startup
+    pub calibration_samples: u8,
+    /// Interval of sampling the ADC
+    pub poll_interval: Duration,
+    /// Report absolute values or relative motion
+    pub output: JoystickOutput,
+}
+
+impl Default for JoystickConfig {
+    fn default() -> Self {
+        Self {
+            adc_range: (0, 4095),
+            invert: [false; 3],
+            deadzone: 2048,
+            smoothing: 2,
+            calibration_samples: 16,
+            poll_interval: Duration::from_millis(10),
+            output: JoystickOutput::Absolute,
+        }
+    }
+}
+
+/// Calibration of a single axis, in raw ADC values
+#[derive(Clone, Copy, Debug)]
+struct AxisCalibration {
+    min: u16,
+    center: u16,
+    max: u16,
+}
+
+impl AxisCalibration {
+    /// Normalize the raw value to -32767 ~ 32767, the center is 0.
+    ///
+    /// Two halves of the axis are scaled separately, because the center is rarely in the middle of the range.
+    fn normalize(&mut self, raw: u16) -> i32 {
+        self.min = self.min.min(raw);
+        self.max = self.max.max(raw);
+        let (raw, center) = (raw as i32, self.center as i32);
+        let half = if raw >= center {
+            self.max as i32 - center
+        } else {
+            center - self.min as i32
+        };
+        if half <= 0 {
+            return 0;
+        }
+        ((raw - center) * AXIS_MAX / half).clamp(-AXIS_MAX, AXIS_MAX)
+    }
+}
+
+/// Apply the deadzone, the remaining range is rescaled so that the output is still continuous.
+fn apply_deadzone(v: i32, deadzone: u16) -> i32 {
+    let deadzone = (deadzone as i32).min(AXIS_MAX - 1);
+    if v.abs() <= deadzone {
+        0
+    } else {
+        v.signum() * (v.abs() - deadzone) * AXIS_MAX / (AXIS_MAX - deadzone)
+    }
+}
+
+/// Analog joystick input device, which samples `N`(up to 3) ADC channels as x/y/z axes.
+pub struct AnalogJoystick<A: AnalogRead, const N: usize> {
+    adc: A,
+    /// ADC channels of x/y/z axes
+    channels: [u8; N],
+    config: JoystickConfig,
+    /// Calibration of each axis, `None` before calibrated
+    calibration: Option<[AxisCalibration; N]>,
+    /// Smoothed values
+    filtered: [i32; N],
+    /// Last reported absolute values
+    last: [i16; N],
+    /// Remainders of relative motion, so that small deflections are not lost
+    remainder: [i32; N],
+}
+
+impl<A: AnalogRead, const N: usize> AnalogJoystick<A, N> {
+    pub fn new(adc: A, channels: [u8; N], config: JoystickConfig) -> Self {
+        const { assert!(N <= 3, "Joystick supports at most 3 axes") };
+        Self {
+            adc,
+            channels,
+            config,
+            calibration: None,
+            filtered: [0; N],
+            last: [0; N],
+            remainder: [0; N],
+        }
+    }
+
+    /// Find the center position by averaging samples. The stick should be released during calibration.
+    async fn calibrate(&mut self) -> [AxisCalibration; N] {
+        let samples = self.config.calibration_samples.max(1) as u32;
+        let mut sum = [0u32; N];
+        for _ in 0..samples {
+            for (i, s) in sum.iter_mut().enumerate() {
+                *s += self.adc.read(self.channels[i]).await as u32;
+            }
+            Timer::after(self.config.poll_interval).await;
+        }
+        let (min, max) = self.config.adc_range;
+        let calibration = sum.map(|s| AxisCalibration {
+            min,
+            center: (s / samples) as u16,
+            max,
+        });
+        debug!(
+            "Joystick calibrated, center: {:?}",
+            calibration.map(|c| c.center)
+        );
+        calibration
+    }
+
+    /// Sample all axes, returns calibrated, smoothed and deadzone-filtered values
+    async fn sample(&mut self) -> [i32; N] {
+        let mut calibration = match self.calibration {
+            Some(c) => c,
+            None => self.calibrate().await,
+        };
+        let mut values = [0; N];
+        for i in 0..N {
+            let raw = self.adc.read(self.channels[i]).await;
+            let mut v = calibration[i].normalize(raw);
+            if self.config.invert[i] {
+                v = -v;
+            }
+            // Smoothing is done before the deadzone, so that the noise around the center is filtered too
+            self.filtered[i] += (v - self.filtered[i]) >> self.config.smoothing.min(8);
+            values[i] = apply_deadzone(self.filtered[i], self.config.deadzone);
+        }
+        self.calibration = Some(calibration);
+        values
+    }
+
+    fn axis_event(i: usize, typ: AxisValType, value: i16) -> AxisEvent {
+        AxisEvent {
+            typ,
+            axis: [Axis::X, Axis::Y, Axis::Z][i],
+            value,
+            source: EventSource::Local,
+        }
+    }
+}
+
+impl<A: AnalogRead, const N: usize> InputDevice for AnalogJoystick<A, N> {
+    async fn read_event(&mut self) -> Event {
+        loop {
+            Timer::after(self.config.poll_interval).await;
+            let values = self.sample().await;
+            match self.config.output {
+                JoystickOutput::Absolute => {
+                    let values = values.map(|v| v as i16);
+                    if values != self.last {
+                        self.last = values;
+                        let mut axes =
+                            core::array::from_fn(|i| Self::axis_event(i, AxisValType::Abs, 0));
+                        for (i, v) in values.into_iter().enumerate() {
+                            axes[i].value = v;
+                        }
+                        return Event::Joystick(axes);
+                    }
+                }
+                JoystickOutput::Relative { speed_divisor } => {
+                    let divisor = speed_divisor.max(1) as i32;
+                    let mut axes =
+                        core::array::from_fn(|i| Self::axis_event(i, AxisValType::Rel, 0));
+                    let mut moved = false;
+                    for (i, v) in values.into_iter().enumerate() {
+                        if v == 0 {
+                            self.remainder[i] = 0;
+                            continue;
+                        }
+                        let v = v + self.remainder[i];
+                        self.remainder[i] = v % divisor;
+                        let delta = (v / divisor) as i16;
+                        moved |= delta != 0;
+                        axes[i].value = delta;
+                    }
+                    if moved {
+                        return Event::Joystick(axes);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Input processor which converts absolute joystick events to [`JoystickReport`].
+///
+/// Relative joystick events are passed to the next processor, typically the pointing processor.
+pub struct JoystickProcessor<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+}
+
+impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    JoystickProcessor<'a, ROW, COL, NUM_LAYER>
+{
+    pub fn new(keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>) -> Self {
+        Self { keymap }
+    }
+}
+
+impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    InputProcessor<'a, ROW, COL, NUM_LAYER> for JoystickProcessor<'a, ROW, COL, NUM_LAYER>
+{
+    async fn process(&mut self, event: Event) -> ProcessResult {
+        match event {
+            Event::Joystick(axes) if axes.iter().all(|a| matches!(a.typ, AxisValType::Abs)) => {
+                for a in axes.iter() {
+                    let i = match a.axis {
+                        Axis::X => 0,
+                        Axis::Y => 1,
+                        Axis::Z => 2,
+                        _ => continue,
+                    };
+                    // -32767 ~ 32767 to -127 ~ 127
+                    let v = (a.value as i32 / 256).clamp(-127, 127) as i8;
+                    JOYSTICK_AXES[i].store(v, Ordering::Relaxed);
+                }
+                self.send_report(Report::JoystickReport(current_joystick_report()))
+                    .await;
+                ProcessResult::Stop
+            }
+            _ => ProcessResult::Continue(event),
+        }
+    }
+
+    fn get_keymap(&self) -> &RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>> {
+        self.keymap
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use embassy_futures::block_on;
+
+    /// ADC which returns `center` during calibration, and `deflected` after that
+    struct MockAdc {
+        reads: usize,
+        calibration_reads: usize,
+        center: [u16; 4],
+        deflected: [u16; 4],
+    }
+
+    impl AnalogRead for MockAdc {
+        async fn read(&mut self, channel: u8) -> u16 {
+            let values = if self.reads < self.calibration_reads {
+                self.center
+            } else {
+                self.deflected
+            };
+            self.reads += 1;
+            values[channel as usize]
+        }
+    }
+
+    fn create_joystick(output: JoystickOutput) -> AnalogJoystick<MockAdc, 2> {
+        let adc = MockAdc {
+            reads: 0,
+            calibration_reads: 2,
+            center: [0, 2048, 0, 2000],
+            deflected: [0, 1024, 0, 4095],
+        };
+        let config = JoystickConfig {
+            invert: [false, true, false],
+            deadzone: 0,
+            smoothing: 0,
+            calibration_samples: 1,
+            poll_interval: Duration::from_ticks(0),
+            output,
+            ..Default::default()
+        };
+        // Channel 3 is x axis, channel 1 is y axis
+        AnalogJoystick::new(adc, [3, 1], config)
+    }
+
+    fn axis_values(event: Event) -> [(u8, u8, i16); 3] {
+        match event {
+            Event::Joystick(axes) => axes.map(|a| (a.typ as u8, a.axis as u8, a.value)),
+            _ => panic!("Not a joystick event"),
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        let mut calibration = AxisCalibration {
+            min: 0,
+            center: 2000,
+            max: 4095,
+        };
+        assert_eq!(calibration.normalize(2000), 0);
+        assert_eq!(calibration.normalize(4095), AXIS_MAX);
+        assert_eq!(calibration.normalize(0), -AXIS_MAX);
+        // Two halves are scaled separately
+        assert_eq!(calibration.normalize(1000), -16383);
+        assert_eq!(calibration.normalize(3047), 16375);
+        // The range is extended by out of range values
+        assert_eq!(calibration.normalize(5000), AXIS_MAX);
+        assert_eq!(calibration.normalize(3500), 16383);
+    }
+
+    #[test]
+    fn test_deadzone() {
+        assert_eq!(apply_deadzone(0, 2048), 0);
+        assert_eq!(apply_deadzone(2048, 2048), 0);
+        assert_eq!(apply_deadzone(-2048, 2048), 0);
+        assert_eq!(apply_deadzone(2049, 2048), 1);
+        assert_eq!(apply_deadzone(-2049, 2048), -1);
+        // The output reaches the max value at the end of the axis
+        assert_eq!(apply_deadzone(AXIS_MAX, 2048), AXIS_MAX);
+        assert_eq!(apply_deadzone(-AXIS_MAX, 2048), -AXIS_MAX);
+        assert_eq!(apply_deadzone(1000, 0), 1000);
+    }
+
+    #[test]
+    fn test_absolute_axes() {
+        let mut joystick = create_joystick(JoystickOutput::Absolute);
+        let event = block_on(joystick.read_event());
+        assert_eq!(
+            axis_values(event),
+            [
+                (AxisValType::Abs as u8, Axis::X as u8, 32767),
+                // Inverted
+                (AxisValType::Abs as u8, Axis::Y as u8, 16383),
+                (AxisValType::Abs as u8, Axis::Z as u8, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_relative_axes() {
+        let mut joystick = create_joystick(JoystickOutput::Relative {
+            speed_divisor: 1000,
+        });
+        let event = block_on(joystick.read_event());
+        assert_eq!(
+            axis_values(event),
+            [
+                (AxisValType::Rel as u8, Axis::X as u8, 32),
+                (AxisValType::Rel as u8, Axis::Y as u8, 16),
+                (AxisValType::Rel as u8, Axis::Z as u8, 0),
+            ]
+        );
+        // Remainders are added to the next motion
+        let event = block_on(joystick.read_event());
+        assert_eq!(
+            axis_values(event)[0],
+            (AxisValType::Rel as u8, Axis::X as u8, 33)
+        );
+    }
+}
@@ -6,7 +6,12 @@ use core::cell::RefCell;
 
 use crate::{channel::KEYBOARD_REPORT_CHANNEL, event::Event, hid::Report, keymap::KeyMap};
 
+pub mod auto_mouse;
+pub mod joystick;
+pub mod pinnacle;
+pub mod pointing;
 pub mod rotary_encoder;
+pub mod touchpad;
 
 /// The trait for runnable input devices and processors.
 ///
@@ -214,7 +219,8 @@ macro_rules! join_all {
 
 /// Macro for binding input processor chain to event channel and running them.
 ///
-/// FIXME: For split keyboard, `EVENT_CHANNEL` is REQUIRED as it's the default channel for receiving events from peripherals.
+/// For split keyboard, `EVENT_CHANNEL` is REQUIRED as the central sends events from peripherals to it,
+/// the source of those events is [`crate::event::EventSource::Peripheral`].
 ///
 /// This macro creates tasks that receive events from channels and process them using specified processor chains.
 /// It calls processors in order and decides whether to continue the chain based on the result of each processor.
@@ -0,0 +1,526 @@
+//! Driver for Cirque Pinnacle(GlidePoint) trackpads
+//!
+//! The trackpad can be connected via I2C or SPI, and works in either relative or absolute mode.
+//!
+//! - Relative mode: the trackpad reports x/y deltas, and the two-finger scroll is done by the trackpad.
+//! - Absolute mode: the trackpad reports finger position and pressure, gestures are done by
+//!   [`crate::input_device::touchpad::TouchpadProcessor`]. Pinnacle tracks a single finger in this mode, so
+//!   only finger 0 is reported and two-finger gestures of the processor aren't available.
+//!
+//! Reference: <https://github.com/cirque-corp/Cirque_Pinnacle_1CA027>
+use core::sync::atomic::Ordering;
+
+use embassy_time::{Duration, Timer};
+use embedded_hal::spi::Operation;
+use embedded_hal_async::{i2c::I2c, spi::SpiDevice};
+
+use crate::event::{Axis, AxisEvent, AxisValType, Event, EventSource, TouchpadEvent};
+
+use super::touchpad::GLIDING;
+use super::InputDevice;
+
+/// Default I2C address of Pinnacle
+pub const PINNACLE_I2C_ADDRESS: u8 = 0x2A;
+
+/// Range of the absolute x coordinate reported by Pinnacle
+pub const PINNACLE_X_RANGE: (i16, i16) = (128, 1920);
+/// Range of the absolute y coordinate reported by Pinnacle
+pub const PINNACLE_Y_RANGE: (i16, i16) = (64, 1472);
+
+// Register access protocol masks
+const WRITE_MASK: u8 = 0x80;
+const READ_MASK: u8 = 0xA0;
+// SPI filler byte
+const FILLER: u8 = 0xFC;
+
+// Registers
+const REG_FIRMWARE_ID: u8 = 0x00;
+const REG_STATUS1: u8 = 0x02;
+const REG_SYS_CONFIG1: u8 = 0x03;
+const REG_FEED_CONFIG1: u8 = 0x04;
+const REG_FEED_CONFIG2: u8 = 0x05;
+const REG_Z_IDLE: u8 = 0x0A;
+const REG_PACKET_BYTE0: u8 = 0x12;
+
+const FIRMWARE_ID: u8 = 0x07;
+
+// Status1 flags
+const STATUS1_SW_DR: u8 = 1 << 2;
+
+// FeedConfig1 flags
+const FEED_CONFIG1_FEED_ENABLE: u8 = 1 << 0;
+const FEED_CONFIG1_DATA_TYPE_ABS: u8 = 1 << 1;
+
+// FeedConfig2 flags, relative mode only
+const FEED_CONFIG2_INTELLIMOUSE: u8 = 1 << 0;
+const FEED_CONFIG2_ALL_TAP_DISABLE: u8 = 1 << 1;
+const FEED_CONFIG2_SECONDARY_TAP_DISABLE: u8 = 1 << 2;
+const FEED_CONFIG2_SCROLL_DISABLE: u8 = 1 << 3;
+const FEED_CONFIG2_GLIDE_EXTEND_DISABLE: u8 = 1 << 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PinnacleError {
+    /// Error of the I2C/SPI bus
+    BusError,
+    /// The firmware id read from the trackpad is not Pinnacle's
+    InvalidFirmwareId(u8),
+}
+
+/// Data mode of the trackpad
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PinnacleMode {
+    /// Report x/y deltas
+    Relative,
+    /// Report finger position and pressure
+    Absolute,
+}
+
+/// Config of Pinnacle trackpad
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PinnacleConfig {
+    /// Data mode
+    pub mode: PinnacleMode,
+    /// Invert x axis
+    pub invert_x: bool,
+    /// Invert y axis
+    pub invert_y: bool,
+    /// Swap x and y axes
+    pub swap_xy: bool,
+    /// Enable two-finger scroll of the trackpad, relative mode only
+    pub scroll: bool,
+    /// Enable glide extend of the trackpad, relative mode only
+    pub glide_extend: bool,
+    /// Interval of polling the data ready flag
+    pub poll_interval: Duration,
+}
+
+impl Default for PinnacleConfig {
+    fn default() -> Self {
+        Self {
+            mode: PinnacleMode::Absolute,
+            invert_x: false,
+            invert_y: false,
+            swap_xy: false,
+            scroll: true,
+            glide_extend: false,
+            poll_interval: Duration::from_millis(5),
+        }
+    }
+}
+
+/// Register access of the trackpad, implemented for I2C and SPI
+pub trait PinnacleBus {
+    /// Read consecutive registers starting from `reg`
+    async fn read_regs(&mut self, reg: u8, buf: &mut [u8]) -> Result<(), PinnacleError>;
+
+    /// Write a register
+    async fn write_reg(&mut self, reg: u8, value: u8) -> Result<(), PinnacleError>;
+}
+
+/// I2C bus of Pinnacle
+pub struct PinnacleI2c<I: I2c> {
+    i2c: I,
+    address: u8,
+}
+
+impl<I: I2c> PinnacleI2c<I> {
+    pub fn new(i2c: I, address: u8) -> Self {
+        Self { i2c, address }
+    }
+}
+
+impl<I: I2c> PinnacleBus for PinnacleI2c<I> {
+    async fn read_regs(&mut self, reg: u8, buf: &mut [u8]) -> Result<(), PinnacleError> {
+        self.i2c
+            .write_read(self.address, &[READ_MASK | reg], buf)
+            .await
+            .map_err(|_| PinnacleError::BusError)
+    }
+
+    async fn write_reg(&mut self, reg: u8, value: u8) -> Result<(), PinnacleError> {
+        self.i2c
+            .write(self.address, &[WRITE_MASK | reg, value])
+            .await
+            .map_err(|_| PinnacleError::BusError)
+    }
+}
+
+/// SPI bus of Pinnacle, the SPI should be configured to mode 1
+pub struct PinnacleSpi<S: SpiDevice> {
+    spi: S,
+}
+
+impl<S: SpiDevice> PinnacleSpi<S> {
+    pub fn new(spi: S) -> Self {
+        Self { spi }
+    }
+}
+
+impl<S: SpiDevice> PinnacleBus for PinnacleSpi<S> {
+    async fn read_regs(&mut self, reg: u8, buf: &mut [u8]) -> Result<(), PinnacleError> {
+        // The register data are clocked out after the command byte and two filler bytes
+        buf.fill(FILLER);
+        self.spi
+            .transaction(&mut [
+                Operation::Write(&[READ_MASK | reg, FILLER, FILLER]),
+                Operation::TransferInPlace(buf),
+            ])
+            .await
+            .map_err(|_| PinnacleError::BusError)
+    }
+
+    async fn write_reg(&mut self, reg: u8, value: u8) -> Result<(), PinnacleError> {
+        self.spi
+            .write(&[WRITE_MASK | reg, value])
+            .await
+            .map_err(|_| PinnacleError::BusError)
+    }
+}
+
+/// Cirque Pinnacle trackpad
+pub struct Pinnacle<B: PinnacleBus> {
+    bus: B,
+    config: PinnacleConfig,
+    initialized: bool,
+    /// Whether the finger is on the trackpad, absolute mode only
+    touching: bool,
+}
+
+impl<B: PinnacleBus> Pinnacle<B> {
+    pub fn new(bus: B, config: PinnacleConfig) -> Self {
+        Self {
+            bus,
+            config,
+            initialized: false,
+            touching: false,
+        }
+    }
+
+    /// Initialize the trackpad
+    pub async fn init(&mut self) -> Result<(), PinnacleError> {
+        let mut id = [0; 1];
+        self.bus.read_regs(REG_FIRMWARE_ID, &mut id).await?;
+        if id[0] != FIRMWARE_ID {
+            return Err(PinnacleError::InvalidFirmwareId(id[0]));
+        }
+
+        self.clear_flags().await?;
+        // Disable low power mode
+        self.bus.write_reg(REG_SYS_CONFIG1, 0x00).await?;
+
+        let feed_config1 = match self.config.mode {
+            PinnacleMode::Relative => {
+                // Hardware taps can't be represented by `TouchpadEvent`, use absolute mode for tap-to-click
+                let mut feed_config2 =
+                    FEED_CONFIG2_ALL_TAP_DISABLE | FEED_CONFIG2_SECONDARY_TAP_DISABLE;
+                if self.config.scroll {
+                    feed_config2 |= FEED_CONFIG2_INTELLIMOUSE;
+                } else {
+                    feed_config2 |= FEED_CONFIG2_SCROLL_DISABLE;
+                }
+                if !self.config.glide_extend {
+                    feed_config2 |= FEED_CONFIG2_GLIDE_EXTEND_DISABLE;
+                }
+                self.bus.write_reg(REG_FEED_CONFIG2, feed_config2).await?;
+                FEED_CONFIG1_FEED_ENABLE
+            }
+            PinnacleMode::Absolute => FEED_CONFIG1_FEED_ENABLE | FEED_CONFIG1_DATA_TYPE_ABS,
+        };
+        self.bus.write_reg(REG_FEED_CONFIG1, feed_config1).await?;
+        // Send 5 z-idle packets after the finger is lifted
+        self.bus.write_reg(REG_Z_IDLE, 5).await?;
+
+        self.initialized = true;
+        Ok(())
+    }
+
+    async fn clear_flags(&mut self) -> Result<(), PinnacleError> {
+        self.bus.write_reg(REG_STATUS1, 0x00).await?;
+        Timer::after_micros(50).await;
+        Ok(())
+    }
+
+    /// Read a data packet, returns `None` if the data isn't ready
+    async fn read_packet(&mut self) -> Result<Option<Event>, PinnacleError> {
+        let mut status = [0; 1];
+        self.bus.read_regs(REG_STATUS1, &mut status).await?;
+        if status[0] & STATUS1_SW_DR == 0 {
+            return Ok(self.lifted_event());
+        }
+
+        let event = match self.config.mode {
+            PinnacleMode::Relative => {
+                let mut packet = [0; 4];
+                self.bus.read_regs(REG_PACKET_BYTE0, &mut packet).await?;
+                self.clear_flags().await?;
+                self.parse_relative(packet)
+            }
+            PinnacleMode::Absolute => {
+                let mut packet = [0; 6];
+                self.bus.read_regs(REG_PACKET_BYTE0, &mut packet).await?;
+                self.clear_flags().await?;
+                self.parse_absolute(packet)
+            }
+        };
+        Ok(event)
+    }
+
+    fn parse_relative(&self, packet: [u8; 4]) -> Option<Event> {
+        // X/Y deltas are 9-bit two's complement numbers, the sign bits are in byte 0
+        let mut x = packet[1] as i16;
+        if packet[0] & 0x10 != 0 {
+            x -= 256;
+        }
+        let mut y = packet[2] as i16;
+        if packet[0] & 0x20 != 0 {
+            y -= 256;
+        }
+        let wheel = packet[3] as i8 as i16;
+        if x == 0 && y == 0 && wheel == 0 {
+            return None;
+        }
+        let (x, y) = self.transform(x, y);
+        Some(Event::Touchpad(TouchpadEvent {
+            finger: 0,
+            axis: [
+                AxisEvent {
+                    typ: AxisValType::Rel,
+                    axis: Axis::X,
+                    value: x,
+                    source: EventSource::Local,
+                },
+                AxisEvent {
+                    typ: AxisValType::Rel,
+                    axis: Axis::Y,
+                    value: y,
+                    source: EventSource::Local,
+                },
+                AxisEvent {
+                    typ: AxisValType::Rel,
+                    axis: Axis::V,
+                    // Scroll up when the fingers move up
+                    value: -wheel,
+                    source: EventSource::Local,
+                },
+            ],
+            source: EventSource::Local,
+        }))
+    }
+
+    fn parse_absolute(&mut self, packet: [u8; 6]) -> Option<Event> {
+        let x = packet[2] as i16 | ((packet[4] as i16 & 0x0F) << 8);
+        let y = packet[3] as i16 | ((packet[4] as i16 & 0xF0) << 4);
+        let z = (packet[5] & 0x3F) as i16;
+
+        if z == 0 {
+            // Report the lift only once, following z-idle packets are ignored unless the cursor is gliding
+            if !self.touching {
+                return self.lifted_event();
+            }
+            self.touching = false;
+        } else {
+            self.touching = true;
+        }
+
+        let (x, y) = if z == 0 { (0, 0) } else { self.transform(x, y) };
+        Some(Self::absolute_event(x, y, z))
+    }
+
+    /// Keep reporting the lifted finger in absolute mode while the cursor is gliding, which advances the glide
+    fn lifted_event(&self) -> Option<Event> {
+        (self.config.mode == PinnacleMode::Absolute
+            && !self.touching
+            && GLIDING.load(Ordering::Relaxed))
+        .then(|| Self::absolute_event(0, 0, 0))
+    }
+
+    fn absolute_event(x: i16, y: i16, z: i16) -> Event {
+        Event::Touchpad(TouchpadEvent {
+            finger: 0,
+            axis: [
+                AxisEvent {
+                    typ: AxisValType::Abs,
+                    axis: Axis::X,
+                    value: x,
+                    source: EventSource::Local,
+                },
+                AxisEvent {
+                    typ: AxisValType::Abs,
+                    axis: Axis::Y,
+                    value: y,
+                    source: EventSource::Local,
+                },
+                AxisEvent {
+                    typ: AxisValType::Abs,
+                    axis: Axis::Z,
+                    value: z,
+                    source: EventSource::Local,
+                },
+            ],
+            source: EventSource::Local,
+        })
+    }
+
+    /// Apply inversion and swapping of axes
+    fn transform(&self, x: i16, y: i16) -> (i16, i16) {
+        let (mut x, mut y) = if self.config.swap_xy { (y, x) } else { (x, y) };
+        match self.config.mode {
+            PinnacleMode::Relative => {
+                if self.config.invert_x {
+                    x = -x;
+                }
+                if self.config.invert_y {
+                    y = -y;
+                }
+            }
+            PinnacleMode::Absolute => {
+                let (x_range, y_range) = if self.config.swap_xy {
+                    (PINNACLE_Y_RANGE, PINNACLE_X_RANGE)
+                } else {
+                    (PINNACLE_X_RANGE, PINNACLE_Y_RANGE)
+                };
+                x = x.clamp(x_range.0, x_range.1);
+                y = y.clamp(y_range.0, y_range.1);
+                if self.config.invert_x {
+                    x = x_range.0 + x_range.1 - x;
+                }
+                if self.config.invert_y {
+                    y = y_range.0 + y_range.1 - y;
+                }
+            }
+        }
+        (x, y)
+    }
+}
+
+impl<B: PinnacleBus> InputDevice for Pinnacle<B> {
+    async fn read_event(&mut self) -> Event {
+        loop {
+            if !self.initialized {
+                if let Err(e) = self.init().await {
+                    error!("Initialize Pinnacle trackpad error: {:?}", e);
+                    Timer::after_secs(1).await;
+                    continue;
+                }
+            }
+
+            match self.read_packet().await {
+                Ok(Some(event)) => return event,
+                Ok(None) => (),
+                Err(e) => {
+                    error!("Read Pinnacle trackpad error: {:?}", e);
+                    self.initialized = false;
+                }
+            }
+            Timer::after(self.config.poll_interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct DummyBus;
+
+    impl PinnacleBus for DummyBus {
+        async fn read_regs(&mut self, _reg: u8, _buf: &mut [u8]) -> Result<(), PinnacleError> {
+            Ok(())
+        }
+
+        async fn write_reg(&mut self, _reg: u8, _value: u8) -> Result<(), PinnacleError> {
+            Ok(())
+        }
+    }
+
+    fn create_pinnacle(mode: PinnacleMode) -> Pinnacle<DummyBus> {
+        Pinnacle::new(
+            DummyBus,
+            PinnacleConfig {
+                mode,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn axis_values(event: Option<Event>) -> Option<[i16; 3]> {
+        match event {
+            Some(Event::Touchpad(TouchpadEvent {
+                finger: 0, axis, ..
+            })) => Some([axis[0].value, axis[1].value, axis[2].value]),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_parse_relative() {
+        let mut pinnacle = create_pinnacle(PinnacleMode::Relative);
+        assert_eq!(
+            axis_values(pinnacle.parse_relative([0x00, 5, 3, 0])),
+            Some([5, 3, 0])
+        );
+        // Sign bits of x and y, and a negative wheel
+        assert_eq!(
+            axis_values(pinnacle.parse_relative([0x30, 0xFB, 0xFE, 0xFF])),
+            Some([-5, -2, 1])
+        );
+        assert_eq!(axis_values(pinnacle.parse_relative([0x08, 0, 0, 0])), None);
+
+        pinnacle.config.invert_x = true;
+        pinnacle.config.swap_xy = true;
+        assert_eq!(
+            axis_values(pinnacle.parse_relative([0x00, 5, 3, 0])),
+            Some([-3, 5, 0])
+        );
+    }
+
+    #[test]
+    fn test_parse_absolute() {
+        let mut pinnacle = create_pinnacle(PinnacleMode::Absolute);
+        // Idle packets before touching are ignored
+        assert_eq!(axis_values(pinnacle.parse_absolute([0; 6])), None);
+        // x = 0x234, y = 0x156, z = 20
+        let packet = [0, 0, 0x34, 0x56, 0x12, 20];
+        assert_eq!(
+            axis_values(pinnacle.parse_absolute(packet)),
+            Some([564, 342, 20])
+        );
+        // Reserved bits of z are ignored
+        let packet = [0, 0, 0x34, 0x56, 0x12, 0xC0 | 21];
+        assert_eq!(
+            axis_values(pinnacle.parse_absolute(packet)),
+            Some([564, 342, 21])
+        );
+        // The lift is reported once
+        assert_eq!(
+            axis_values(pinnacle.parse_absolute([0; 6])),
+            Some([0, 0, 0])
+        );
+        assert_eq!(axis_values(pinnacle.parse_absolute([0; 6])), None);
+        // The lifted finger is reported while the cursor is gliding
+        GLIDING.store(true, Ordering::Relaxed);
+        assert_eq!(
+            axis_values(pinnacle.parse_absolute([0; 6])),
+            Some([0, 0, 0])
+        );
+        GLIDING.store(false, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_transform_absolute() {
+        let mut pinnacle = create_pinnacle(PinnacleMode::Absolute);
+        // Positions are clamped to the range
+        assert_eq!(pinnacle.transform(100, 2000), (128, 1472));
+        pinnacle.config.invert_x = true;
+        pinnacle.config.invert_y = true;
+        assert_eq!(pinnacle.transform(128, 1000), (1920, 536));
+        // Ranges are swapped with axes
+        pinnacle.config.invert_x = false;
+        pinnacle.config.invert_y = false;
+        pinnacle.config.swap_xy = true;
+        assert_eq!(pinnacle.transform(100, 2000), (1472, 128));
+    }
+}
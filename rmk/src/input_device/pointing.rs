@@ -0,0 +1,408 @@
+//! Pointing device processor
+//!
+//! Converts relative x/y motion from pointing devices(trackball, trackpoint, etc.) to mouse reports.
+//! The processor supports several modes, which can be switched by keycodes or layers:
+//!
+//! - Drag-scroll: axis motion is converted to wheel/pan
+//! - Precision: motion is scaled down
+//! - Sniping: motion is scaled down even more, for momentary fine-grained aiming
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use usbd_hid::descriptor::MouseReport;
+
+use crate::event::{Axis, AxisEvent, AxisValType, Event};
+use crate::hid::Report;
+use crate::keycode::KeyCode;
+use crate::keymap::KeyMap;
+
+use super::{InputProcessor, ProcessResult};
+
+/// Mouse buttons which are currently pressed by mouse keys.
+///
+/// The pointing processor has to report them too, otherwise the buttons are released while dragging.
+pub(crate) static MOUSE_BUTTONS: AtomicU8 = AtomicU8::new(0);
+
+/// Pointing modes which are activated by holding a mode keycode
+static MOMENTARY_MODES: AtomicU8 = AtomicU8::new(0);
+/// Pointing modes which are toggled by a mode keycode
+static TOGGLED_MODES: AtomicU8 = AtomicU8::new(0);
+
+/// Mode of the pointing processor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum PointingMode {
+    /// Motion is reported as is
+    Normal = 0,
+    /// Motion is converted to wheel/pan
+    DragScroll = 1 << 0,
+    /// Motion is scaled down by `precision_divisor`
+    Precision = 1 << 1,
+    /// Motion is scaled down by `sniping_divisor`
+    Sniping = 1 << 2,
+}
+
+/// Update the pointing mode by the mode keycodes, called by the keyboard processor
+pub(crate) fn update_pointing_mode(key: KeyCode, pressed: bool) {
+    let (mode, toggle) = match key {
+        KeyCode::DragScroll => (PointingMode::DragScroll, false),
+        KeyCode::DragScrollToggle => (PointingMode::DragScroll, true),
+        KeyCode::PrecisionMode => (PointingMode::Precision, false),
+        KeyCode::PrecisionModeToggle => (PointingMode::Precision, true),
+        KeyCode::SnipingMode => (PointingMode::Sniping, false),
+        KeyCode::SnipingModeToggle => (PointingMode::Sniping, true),
+        _ => return,
+    };
+    let bit = mode as u8;
+    if toggle {
+        if pressed {
+            TOGGLED_MODES.fetch_xor(bit, Ordering::Relaxed);
+        }
+    } else if pressed {
+        MOMENTARY_MODES.fetch_or(bit, Ordering::Relaxed);
+    } else {
+        MOMENTARY_MODES.fetch_and(!bit, Ordering::Relaxed);
+    }
+}
+
+/// Config for the pointing processor
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PointingProcessorConfig {
+    /// Motion is divided by `scroll_divisor` in drag-scroll mode, 1 wheel unit per `scroll_divisor` counts
+    pub scroll_divisor: u8,
+    /// Lock scrolling to the dominant axis in drag-scroll mode
+    pub scroll_snap: bool,
+    /// Invert the scrolling direction in drag-scroll mode
+    pub invert_scroll: bool,
+    /// Motion is divided by `precision_divisor` in precision mode
+    pub precision_divisor: u8,
+    /// Motion is divided by `sniping_divisor` in sniping mode
+    pub sniping_divisor: u8,
+    /// Enable drag-scroll mode when this layer is activated
+    pub drag_scroll_layer: Option<u8>,
+    /// Enable precision mode when this layer is activated
+    pub precision_layer: Option<u8>,
+    /// Enable sniping mode when this layer is activated
+    pub sniping_layer: Option<u8>,
+}
+
+impl Default for PointingProcessorConfig {
+    fn default() -> Self {
+        Self {
+            scroll_divisor: 8,
+            scroll_snap: true,
+            invert_scroll: false,
+            precision_divisor: 2,
+            sniping_divisor: 4,
+            drag_scroll_layer: None,
+            precision_layer: None,
+            sniping_layer: None,
+        }
+    }
+}
+
+/// Input processor which converts relative motion of pointing devices to mouse reports
+pub struct PointingProcessor<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+    config: PointingProcessorConfig,
+    /// Motion accumulated from `Event::AxisEventStream`, reported when `Event::Eos` is received
+    pending: Option<(i32, i32)>,
+    /// Scaler of the motion, keeps the remainders between reports
+    scaler: MotionScaler,
+}
+
+/// Scales the motion according to the pointing mode
+#[derive(Default)]
+struct MotionScaler {
+    /// Remainders of the scaled down motion, so that slow movements are not lost
+    remainder: (i32, i32),
+}
+
+impl MotionScaler {
+    /// Divide the motion, keep the remainder for the next motion
+    fn divide(&mut self, x: i32, y: i32, divisor: u8) -> (i32, i32) {
+        let divisor = divisor.max(1) as i32;
+        let x = self.remainder.0 + x;
+        let y = self.remainder.1 + y;
+        self.remainder = (x % divisor, y % divisor);
+        (x / divisor, y / divisor)
+    }
+
+    /// Convert the motion to a mouse report according to the mode
+    fn scale(
+        &mut self,
+        config: &PointingProcessorConfig,
+        mode: PointingMode,
+        x: i32,
+        y: i32,
+    ) -> Option<MouseReport> {
+        let mut report = MouseReport {
+            buttons: MOUSE_BUTTONS.load(Ordering::Relaxed),
+            x: 0,
+            y: 0,
+            wheel: 0,
+            pan: 0,
+        };
+        match mode {
+            PointingMode::DragScroll => {
+                let (mut x, mut y) = (x, y);
+                if config.scroll_snap {
+                    // Scroll along the dominant axis only
+                    if x.abs() > y.abs() {
+                        y = 0;
+                        self.remainder.1 = 0;
+                    } else {
+                        x = 0;
+                        self.remainder.0 = 0;
+                    }
+                }
+                let (pan, wheel) = self.divide(x, y, config.scroll_divisor);
+                // Moving up(negative y) scrolls up(positive wheel)
+                let (pan, wheel) = if config.invert_scroll {
+                    (-pan, wheel)
+                } else {
+                    (pan, -wheel)
+                };
+                report.pan = clamp_i8(pan);
+                report.wheel = clamp_i8(wheel);
+                if report.pan == 0 && report.wheel == 0 {
+                    return None;
+                }
+            }
+            _ => {
+                let (x, y) = match mode {
+                    PointingMode::Precision => self.divide(x, y, config.precision_divisor),
+                    PointingMode::Sniping => self.divide(x, y, config.sniping_divisor),
+                    _ => {
+                        self.remainder = (0, 0);
+                        (x, y)
+                    }
+                };
+                report.x = clamp_i8(x);
+                report.y = clamp_i8(y);
+                if report.x == 0 && report.y == 0 {
+                    return None;
+                }
+            }
+        }
+        Some(report)
+    }
+}
+
+impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    PointingProcessor<'a, ROW, COL, NUM_LAYER>
+{
+    pub fn new(
+        keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+        config: PointingProcessorConfig,
+    ) -> Self {
+        Self {
+            keymap,
+            config,
+            pending: None,
+            scaler: MotionScaler::default(),
+        }
+    }
+
+    /// Get current mode. If multiple modes are enabled, drag-scroll wins, then sniping, then precision.
+    pub fn mode(&self) -> PointingMode {
+        let mut modes =
+            MOMENTARY_MODES.load(Ordering::Relaxed) | TOGGLED_MODES.load(Ordering::Relaxed);
+        {
+            let keymap = self.keymap.borrow();
+            for (layer, mode) in [
+                (self.config.drag_scroll_layer, PointingMode::DragScroll),
+                (self.config.precision_layer, PointingMode::Precision),
+                (self.config.sniping_layer, PointingMode::Sniping),
+            ] {
+                if layer.is_some_and(|l| keymap.is_layer_active(l)) {
+                    modes |= mode as u8;
+                }
+            }
+        }
+
+        if modes & PointingMode::DragScroll as u8 != 0 {
+            PointingMode::DragScroll
+        } else if modes & PointingMode::Sniping as u8 != 0 {
+            PointingMode::Sniping
+        } else if modes & PointingMode::Precision as u8 != 0 {
+            PointingMode::Precision
+        } else {
+            PointingMode::Normal
+        }
+    }
+
+    async fn report_motion(&mut self, x: i32, y: i32) {
+        let mode = self.mode();
+        if let Some(report) = self.scaler.scale(&self.config, mode, x, y) {
+            self.send_report(Report::MouseReport(report)).await;
+        }
+    }
+}
+
+/// Whether the axis event is a relative x/y motion
+fn is_motion(axis: &AxisEvent) -> bool {
+    matches!(
+        (axis.typ, axis.axis),
+        (AxisValType::Rel, Axis::X) | (AxisValType::Rel, Axis::Y)
+    )
+}
+
+/// Get the relative x/y motion of the axis event
+fn axis_motion(axis: &AxisEvent) -> (i32, i32) {
+    match (axis.typ, axis.axis) {
+        (AxisValType::Rel, Axis::X) => (axis.value as i32, 0),
+        (AxisValType::Rel, Axis::Y) => (0, axis.value as i32),
+        _ => (0, 0),
+    }
+}
+
+pub(crate) fn clamp_i8(v: i32) -> i8 {
+    v.clamp(i8::MIN as i32, i8::MAX as i32) as i8
+}
+
+impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    InputProcessor<'a, ROW, COL, NUM_LAYER> for PointingProcessor<'a, ROW, COL, NUM_LAYER>
+{
+    async fn process(&mut self, event: Event) -> ProcessResult {
+        match event {
+            // Absolute axes, e.g. of analog joysticks and touchpads, are left to other processors
+            Event::Joystick(axes) if axes.iter().any(is_motion) => {
+                let (x, y) = axes.iter().fold((0, 0), |(x, y), a| {
+                    let (dx, dy) = axis_motion(a);
+                    (x + dx, y + dy)
+                });
+                self.report_motion(x, y).await;
+                ProcessResult::Stop
+            }
+            Event::AxisEventStream(axis) if is_motion(&axis) => {
+                let (dx, dy) = axis_motion(&axis);
+                let (x, y) = self.pending.unwrap_or_default();
+                self.pending = Some((x + dx, y + dy));
+                ProcessResult::Stop
+            }
+            // End of the stream of relative motion
+            Event::Eos(_) if self.pending.is_some() => {
+                let (x, y) = self.pending.take().unwrap_or_default();
+                self.report_motion(x, y).await;
+                ProcessResult::Stop
+            }
+            _ => ProcessResult::Continue(event),
+        }
+    }
+
+    fn get_keymap(&self) -> &RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>> {
+        self.keymap
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::EventSource;
+
+    fn motion(report: Option<MouseReport>) -> Option<(i8, i8, i8, i8)> {
+        report.map(|r| (r.x, r.y, r.wheel, r.pan))
+    }
+
+    fn rel(axis: Axis, value: i16) -> AxisEvent {
+        AxisEvent {
+            typ: AxisValType::Rel,
+            axis,
+            value,
+            source: EventSource::Local,
+        }
+    }
+
+    #[test]
+    fn test_motion_axes() {
+        assert!(is_motion(&rel(Axis::X, 1)));
+        assert!(is_motion(&rel(Axis::Y, 1)));
+        // Wheel axes and absolute positions aren't consumed by the pointing processor
+        assert!(!is_motion(&rel(Axis::Z, 1)));
+        let abs = AxisEvent {
+            typ: AxisValType::Abs,
+            axis: Axis::X,
+            value: 100,
+            source: EventSource::Local,
+        };
+        assert!(!is_motion(&abs));
+        assert_eq!(axis_motion(&abs), (0, 0));
+        assert_eq!(axis_motion(&rel(Axis::Y, -3)), (0, -3));
+    }
+
+    #[test]
+    fn test_normal_motion() {
+        let config = PointingProcessorConfig::default();
+        let mut scaler = MotionScaler::default();
+        let report = scaler.scale(&config, PointingMode::Normal, 3, -200);
+        assert_eq!(motion(report), Some((3, -128, 0, 0)));
+        assert_eq!(scaler.scale(&config, PointingMode::Normal, 0, 0), None);
+    }
+
+    #[test]
+    fn test_drag_scroll_divisor() {
+        let config = PointingProcessorConfig {
+            scroll_divisor: 8,
+            scroll_snap: false,
+            ..Default::default()
+        };
+        let mut scaler = MotionScaler::default();
+        // Less than a wheel unit is kept for the next motion
+        assert_eq!(scaler.scale(&config, PointingMode::DragScroll, 4, -5), None);
+        // Moving up scrolls up, moving right pans right
+        let report = scaler.scale(&config, PointingMode::DragScroll, 4, -11);
+        assert_eq!(motion(report), Some((0, 0, 2, 1)));
+        assert_eq!(scaler.remainder, (0, 0));
+
+        let config = PointingProcessorConfig {
+            invert_scroll: true,
+            ..config
+        };
+        let report = scaler.scale(&config, PointingMode::DragScroll, -8, -8);
+        assert_eq!(motion(report), Some((0, 0, -1, 1)));
+    }
+
+    #[test]
+    fn test_drag_scroll_snap() {
+        let config = PointingProcessorConfig {
+            scroll_divisor: 4,
+            ..Default::default()
+        };
+        let mut scaler = MotionScaler::default();
+        // Only the dominant axis scrolls, the remainder of the other axis is dropped
+        let report = scaler.scale(&config, PointingMode::DragScroll, 3, 9);
+        assert_eq!(motion(report), Some((0, 0, -2, 0)));
+        assert_eq!(scaler.remainder, (0, 1));
+        let report = scaler.scale(&config, PointingMode::DragScroll, -10, 2);
+        assert_eq!(motion(report), Some((0, 0, 0, -2)));
+        assert_eq!(scaler.remainder, (-2, 0));
+    }
+
+    #[test]
+    fn test_precision_and_sniping_scaling() {
+        let config = PointingProcessorConfig {
+            precision_divisor: 2,
+            sniping_divisor: 4,
+            ..Default::default()
+        };
+        let mut scaler = MotionScaler::default();
+        let report = scaler.scale(&config, PointingMode::Precision, 5, -3);
+        assert_eq!(motion(report), Some((2, -1, 0, 0)));
+        // Slow movements accumulate in the remainders
+        let report = scaler.scale(&config, PointingMode::Precision, 1, -1);
+        assert_eq!(motion(report), Some((1, -1, 0, 0)));
+
+        assert_eq!(scaler.scale(&config, PointingMode::Sniping, 3, 3), None);
+        let report = scaler.scale(&config, PointingMode::Sniping, 1, 6);
+        assert_eq!(motion(report), Some((1, 2, 0, 0)));
+        assert_eq!(scaler.remainder, (0, 1));
+
+        // Remainders are dropped in normal mode
+        let report = scaler.scale(&config, PointingMode::Normal, 1, 0);
+        assert_eq!(motion(report), Some((1, 0, 0, 0)));
+        assert_eq!(scaler.remainder, (0, 0));
+    }
+}
@@ -11,7 +11,7 @@ use serde::{Deserialize, Serialize};
 use usbd_hid::descriptor::{MediaKey, MediaKeyboardReport};
 
 use crate::channel::KEYBOARD_REPORT_CHANNEL;
-use crate::event::{Event, RotaryEncoderEvent};
+use crate::event::{Event, EventSource, RotaryEncoderEvent};
 use crate::hid::Report;
 use crate::keymap::KeyMap;
 
@@ -177,8 +177,13 @@ impl<
                 return Event::RotaryEncoder(RotaryEncoderEvent {
                     id: self.id,
                     direction,
+                    source: EventSource::Local,
                 });
             }
+
+            // Poll the pins periodically, otherwise the loop never yields
+            #[cfg(not(feature = "async_matrix"))]
+            embassy_time::Timer::after_micros(500).await;
         }
     }
 }
@@ -201,12 +206,16 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
 {
     async fn process(&mut self, event: Event) -> ProcessResult {
         match event {
-            Event::RotaryEncoder(RotaryEncoderEvent { id, direction }) => {
+            Event::RotaryEncoder(RotaryEncoderEvent {
+                id,
+                direction,
+                source,
+            }) => {
                 // TODO: Use Vial and shared keymap for encoders
                 // TODO: Merge the keyboard report sender, avoid KeyboardReport override each other
                 match direction {
                     Direction::Clockwise => {
-                        debug!("Encoder {} - Clockwise", id);
+                        debug!("Encoder {} of {:?} - Clockwise", id, source);
                         self.send_report(Report::MediaKeyboardReport(MediaKeyboardReport {
                             usage_id: MediaKey::VolumeIncrement as u16,
                         }))
@@ -218,7 +227,7 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                         .await;
                     }
                     Direction::CounterClockwise => {
-                        debug!("Encoder {} - CounterClockwise", id);
+                        debug!("Encoder {} of {:?} - CounterClockwise", id, source);
                         self.send_report(Report::MediaKeyboardReport(MediaKeyboardReport {
                             usage_id: MediaKey::VolumeDecrement as u16,
                         }))
@@ -0,0 +1,654 @@
+//! Touchpad gesture processor
+//!
+//! Converts [`Event::Touchpad`] to mouse reports, the following gestures are supported:
+//!
+//! - Tap-to-click: one-finger tap for left click, two-finger tap for right click
+//! - Two-finger scroll: move two fingers to scroll
+//! - Circular scroll: touch the edge of the touchpad and move along the edge to scroll
+//! - Inertial glide: the cursor keeps moving for a while after a fast swipe
+//!
+//! Gestures work with absolute touchpad events, for relative touchpad events, the motion and the wheel are reported directly.
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_time::{Duration, Instant, Timer};
+use usbd_hid::descriptor::MouseReport;
+
+use crate::event::{Axis, AxisValType, Event, EventSource, TouchpadEvent};
+use crate::hid::Report;
+use crate::keymap::KeyMap;
+
+use super::pointing::{clamp_i8, MOUSE_BUTTONS};
+use super::{InputProcessor, ProcessResult};
+
+/// Whether the cursor is gliding.
+///
+/// The glide is advanced by the events of the touchpad, so touchpads should keep reporting the lifted finger(z = 0)
+/// while it's set. Touching the touchpad again stops the glide.
+pub static GLIDING: AtomicBool = AtomicBool::new(false);
+
+/// Max number of tracked fingers
+const MAX_FINGERS: usize = 2;
+
+/// Config of touchpad gestures
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TouchpadConfig {
+    /// Range of absolute x coordinate
+    pub x_range: (i16, i16),
+    /// Range of absolute y coordinate
+    pub y_range: (i16, i16),
+    /// Absolute motion is divided by `divisor` before reporting
+    pub divisor: u8,
+    /// Enable tap-to-click
+    pub tap_to_click: bool,
+    /// Max duration of a tap
+    pub tap_timeout: Duration,
+    /// Max distance of finger moving during a tap
+    pub tap_distance: u16,
+    /// Enable two-finger scroll
+    pub two_finger_scroll: bool,
+    /// Absolute motion per wheel unit in two-finger scroll
+    pub scroll_divisor: u8,
+    /// Enable circular scroll
+    pub circular_scroll: bool,
+    /// Width of the edge ring which starts circular scroll, in percent of the touchpad radius
+    pub circular_scroll_ring: u8,
+    /// Angle per wheel unit in circular scroll, in milliradians
+    pub circular_scroll_step: u16,
+    /// Enable inertial glide
+    pub glide: bool,
+    /// Min speed to start gliding, in reported units per event
+    pub glide_threshold: u8,
+    /// Percent of speed kept after each glide step
+    pub glide_decay: u8,
+    /// Interval of glide steps, the steps elapsed since the last event are reported at once
+    pub glide_interval: Duration,
+    /// Only process events of touchpads attached to this board, `None` processes events of all touchpads
+    pub source: Option<EventSource>,
+}
+
+impl Default for TouchpadConfig {
+    fn default() -> Self {
+        Self {
+            x_range: (128, 1920),
+            y_range: (64, 1472),
+            divisor: 2,
+            tap_to_click: true,
+            tap_timeout: Duration::from_millis(180),
+            tap_distance: 40,
+            two_finger_scroll: true,
+            scroll_divisor: 32,
+            circular_scroll: true,
+            circular_scroll_ring: 25,
+            circular_scroll_step: 250,
+            glide: true,
+            glide_threshold: 6,
+            glide_decay: 85,
+            glide_interval: Duration::from_millis(10),
+            source: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Gesture {
+    None,
+    Cursor,
+    TwoFingerScroll,
+    CircularScroll,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Finger {
+    start: (i16, i16),
+    last: (i16, i16),
+    /// Max distance from the start position
+    moved: u16,
+}
+
+/// Report generated by a gesture
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum GestureOutput {
+    /// Move the cursor
+    Move(i32, i32),
+    /// Scroll the wheel and pan
+    Scroll { wheel: i32, pan: i32 },
+    /// Click the buttons
+    Click(u8),
+}
+
+/// Inertial glide of the cursor after a fast swipe
+#[derive(Clone, Copy, Debug)]
+struct Glide {
+    /// Speed in 1/256 reported units per step
+    velocity: (i32, i32),
+    /// Motion which isn't reported yet, in 1/256 units
+    acc: (i32, i32),
+    /// Time of the last step
+    last_step: Instant,
+}
+
+impl Glide {
+    /// Advance the glide by `steps` with decaying speed, returns the motion
+    fn advance(&mut self, steps: u64, decay: u8) -> (i32, i32) {
+        let decay = decay.min(99) as i32;
+        let mut motion = (0, 0);
+        for _ in 0..steps {
+            self.velocity = (self.velocity.0 * decay / 100, self.velocity.1 * decay / 100);
+            if self.is_over() {
+                break;
+            }
+            self.acc = (self.acc.0 + self.velocity.0, self.acc.1 + self.velocity.1);
+            let (x, y) = (self.acc.0 / 256, self.acc.1 / 256);
+            self.acc = (self.acc.0 - x * 256, self.acc.1 - y * 256);
+            motion = (motion.0 + x, motion.1 + y);
+        }
+        motion
+    }
+
+    /// Whether the speed is too low to keep gliding
+    fn is_over(&self) -> bool {
+        self.velocity.0.abs() < 64 && self.velocity.1.abs() < 64
+    }
+}
+
+/// Gesture recognition of absolute touchpad events
+struct GestureState {
+    fingers: [Option<Finger>; MAX_FINGERS],
+    gesture: Gesture,
+    /// Timestamp of the first finger touching
+    touch_start: Instant,
+    /// Whether more than one fingers touched in current gesture
+    multi_finger: bool,
+    /// Max finger moving distance in current gesture
+    moved: u16,
+    /// Remainders of the scaled down motion
+    remainder: (i32, i32),
+    /// Remainders of the scrolling
+    scroll_remainder: (i32, i32),
+    /// Accumulated angle of circular scroll, in milliradians
+    angle: i32,
+    /// Smoothed cursor speed, in reported units per event
+    velocity: (i32, i32),
+    /// Current glide, the cursor keeps moving until the speed is too low or the touchpad is touched again
+    glide: Option<Glide>,
+}
+
+impl GestureState {
+    fn new() -> Self {
+        Self {
+            fingers: [None; MAX_FINGERS],
+            gesture: Gesture::None,
+            touch_start: Instant::MIN,
+            multi_finger: false,
+            moved: 0,
+            remainder: (0, 0),
+            scroll_remainder: (0, 0),
+            angle: 0,
+            velocity: (0, 0),
+            glide: None,
+        }
+    }
+
+    /// Update the state with the position and the pressure of the finger in `slot`
+    fn update(
+        &mut self,
+        config: &TouchpadConfig,
+        slot: usize,
+        pos: (i16, i16),
+        z: i16,
+        now: Instant,
+    ) -> Option<GestureOutput> {
+        if slot >= MAX_FINGERS {
+            return None;
+        }
+        if z == 0 {
+            if self.fingers[slot].is_some() {
+                return self.on_lift(config, slot, now);
+            }
+            // The finger is still lifted
+            return self.advance_glide(config, now);
+        }
+
+        // Touched again, stop gliding
+        self.glide = None;
+        match self.fingers[slot] {
+            None => {
+                self.on_touch(config, slot, pos, now);
+                None
+            }
+            Some(finger) => self.on_move(config, slot, finger, pos),
+        }
+    }
+
+    fn center(config: &TouchpadConfig) -> (i32, i32) {
+        let (x_min, x_max) = config.x_range;
+        let (y_min, y_max) = config.y_range;
+        (
+            (x_min as i32 + x_max as i32) / 2,
+            (y_min as i32 + y_max as i32) / 2,
+        )
+    }
+
+    /// Whether the position is in the edge ring of circular scroll
+    fn in_scroll_ring(config: &TouchpadConfig, pos: (i16, i16)) -> bool {
+        let (cx, cy) = Self::center(config);
+        let radius = ((config.x_range.1 - config.x_range.0) as i32)
+            .min((config.y_range.1 - config.y_range.0) as i32)
+            / 2;
+        let inner = radius * (100 - config.circular_scroll_ring.min(100) as i32) / 100;
+        let (dx, dy) = (pos.0 as i32 - cx, pos.1 as i32 - cy);
+        dx * dx + dy * dy >= inner * inner
+    }
+
+    fn on_touch(&mut self, config: &TouchpadConfig, slot: usize, pos: (i16, i16), now: Instant) {
+        let other_touching = self.fingers.iter().any(|f| f.is_some());
+        self.fingers[slot] = Some(Finger {
+            start: pos,
+            last: pos,
+            moved: 0,
+        });
+
+        if other_touching {
+            self.multi_finger = true;
+            if config.two_finger_scroll {
+                self.gesture = Gesture::TwoFingerScroll;
+            }
+        } else {
+            // New gesture
+            self.touch_start = now;
+            self.multi_finger = false;
+            self.moved = 0;
+            self.remainder = (0, 0);
+            self.scroll_remainder = (0, 0);
+            self.angle = 0;
+            self.velocity = (0, 0);
+            self.gesture = if config.circular_scroll && Self::in_scroll_ring(config, pos) {
+                Gesture::CircularScroll
+            } else {
+                Gesture::Cursor
+            };
+        }
+    }
+
+    fn on_move(
+        &mut self,
+        config: &TouchpadConfig,
+        slot: usize,
+        mut finger: Finger,
+        pos: (i16, i16),
+    ) -> Option<GestureOutput> {
+        let dx = pos.0 as i32 - finger.last.0 as i32;
+        let dy = pos.1 as i32 - finger.last.1 as i32;
+        let last = finger.last;
+        finger.last = pos;
+        let distance = (pos.0 as i32 - finger.start.0 as i32).unsigned_abs()
+            + (pos.1 as i32 - finger.start.1 as i32).unsigned_abs();
+        finger.moved = finger.moved.max(distance.min(u16::MAX as u32) as u16);
+        self.moved = self.moved.max(finger.moved);
+        self.fingers[slot] = Some(finger);
+
+        // Only the first touching finger moves the cursor or scrolls
+        if self.fingers.iter().position(|f| f.is_some()) != Some(slot) {
+            return None;
+        }
+
+        match self.gesture {
+            Gesture::Cursor => {
+                let divisor = config.divisor.max(1) as i32;
+                let x = self.remainder.0 + dx;
+                let y = self.remainder.1 + dy;
+                self.remainder = (x % divisor, y % divisor);
+                let (x, y) = (x / divisor, y / divisor);
+                self.velocity = ((self.velocity.0 + x) / 2, (self.velocity.1 + y) / 2);
+                (x != 0 || y != 0).then_some(GestureOutput::Move(x, y))
+            }
+            Gesture::TwoFingerScroll => {
+                let divisor = config.scroll_divisor.max(1) as i32;
+                let x = self.scroll_remainder.0 + dx;
+                let y = self.scroll_remainder.1 + dy;
+                self.scroll_remainder = (x % divisor, y % divisor);
+                let (pan, wheel) = (x / divisor, -y / divisor);
+                (pan != 0 || wheel != 0).then_some(GestureOutput::Scroll { wheel, pan })
+            }
+            Gesture::CircularScroll => {
+                let (cx, cy) = Self::center(config);
+                let p1 = (last.0 as i32 - cx, last.1 as i32 - cy);
+                let p2 = (pos.0 as i32 - cx, pos.1 as i32 - cy);
+                // For small angles, sin(angle) ≈ angle = cross(p1, p2) / (|p1| * |p2|)
+                let cross = p1.0 * p2.1 - p1.1 * p2.0;
+                let r2 = (p1.0 * p1.0 + p1.1 * p1.1 + p2.0 * p2.0 + p2.1 * p2.1) / 2;
+                if r2 == 0 {
+                    return None;
+                }
+                self.angle += cross * 1000 / r2;
+                let step = config.circular_scroll_step.max(1) as i32;
+                let steps = self.angle / step;
+                if steps == 0 {
+                    return None;
+                }
+                self.angle -= steps * step;
+                // Clockwise scrolls down
+                Some(GestureOutput::Scroll {
+                    wheel: -steps,
+                    pan: 0,
+                })
+            }
+            Gesture::None => None,
+        }
+    }
+
+    fn on_lift(
+        &mut self,
+        config: &TouchpadConfig,
+        slot: usize,
+        now: Instant,
+    ) -> Option<GestureOutput> {
+        self.fingers[slot] = None;
+        if self.fingers.iter().any(|f| f.is_some()) {
+            // Gesture is still in progress
+            return None;
+        }
+
+        let gesture = self.gesture;
+        self.gesture = Gesture::None;
+
+        if config.tap_to_click
+            && self.moved <= config.tap_distance
+            && now.saturating_duration_since(self.touch_start) <= config.tap_timeout
+        {
+            // Two-finger tap is right click
+            let button = if self.multi_finger { 1 << 1 } else { 1 << 0 };
+            return Some(GestureOutput::Click(button));
+        }
+
+        let threshold = config.glide_threshold as i32;
+        if gesture == Gesture::Cursor
+            && config.glide
+            && (self.velocity.0.abs() >= threshold || self.velocity.1.abs() >= threshold)
+        {
+            self.glide = Some(Glide {
+                velocity: (self.velocity.0 * 256, self.velocity.1 * 256),
+                acc: (0, 0),
+                last_step: now,
+            });
+        }
+        self.velocity = (0, 0);
+        None
+    }
+
+    /// Advance the glide by the steps elapsed since the last step
+    fn advance_glide(&mut self, config: &TouchpadConfig, now: Instant) -> Option<GestureOutput> {
+        let glide = self.glide.as_mut()?;
+        let interval = config.glide_interval.as_ticks().max(1);
+        let steps = now.saturating_duration_since(glide.last_step).as_ticks() / interval;
+        if steps == 0 {
+            return None;
+        }
+        glide.last_step += Duration::from_ticks(steps * interval);
+        let (x, y) = glide.advance(steps, config.glide_decay);
+        if glide.is_over() {
+            self.glide = None;
+        }
+        (x != 0 || y != 0).then_some(GestureOutput::Move(x, y))
+    }
+}
+
+/// Touchpad gesture processor
+pub struct TouchpadProcessor<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize> {
+    keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+    config: TouchpadConfig,
+    state: GestureState,
+}
+
+impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    TouchpadProcessor<'a, ROW, COL, NUM_LAYER>
+{
+    pub fn new(
+        keymap: &'a RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>>,
+        config: TouchpadConfig,
+    ) -> Self {
+        Self {
+            keymap,
+            config,
+            state: GestureState::new(),
+        }
+    }
+
+    async fn send_mouse_report(&mut self, buttons: u8, x: i32, y: i32, wheel: i32, pan: i32) {
+        let report = MouseReport {
+            buttons: MOUSE_BUTTONS.load(Ordering::Relaxed) | buttons,
+            x: clamp_i8(x),
+            y: clamp_i8(y),
+            wheel: clamp_i8(wheel),
+            pan: clamp_i8(pan),
+        };
+        self.send_report(Report::MouseReport(report)).await;
+    }
+
+    async fn process_relative(&mut self, event: TouchpadEvent) {
+        let (mut x, mut y, mut wheel, mut pan) = (0, 0, 0, 0);
+        for a in event.axis.iter() {
+            if let AxisValType::Rel = a.typ {
+                match a.axis {
+                    Axis::X => x += a.value as i32,
+                    Axis::Y => y += a.value as i32,
+                    Axis::V => wheel += a.value as i32,
+                    Axis::H => pan += a.value as i32,
+                    _ => (),
+                }
+            }
+        }
+        self.send_mouse_report(0, x, y, wheel, pan).await;
+    }
+
+    async fn process_absolute(&mut self, event: TouchpadEvent) {
+        let (mut x, mut y, mut z) = (0, 0, 0);
+        for a in event.axis.iter() {
+            match a.axis {
+                Axis::X => x = a.value,
+                Axis::Y => y = a.value,
+                Axis::Z => z = a.value,
+                _ => (),
+            }
+        }
+
+        let output = self.state.update(
+            &self.config,
+            event.finger as usize,
+            (x, y),
+            z,
+            Instant::now(),
+        );
+        GLIDING.store(self.state.glide.is_some(), Ordering::Relaxed);
+        match output {
+            Some(GestureOutput::Move(x, y)) => self.send_mouse_report(0, x, y, 0, 0).await,
+            Some(GestureOutput::Scroll { wheel, pan }) => {
+                self.send_mouse_report(0, 0, 0, wheel, pan).await
+            }
+            Some(GestureOutput::Click(button)) => {
+                debug!("Touchpad tap, button: {}", button);
+                self.send_mouse_report(button, 0, 0, 0, 0).await;
+                Timer::after_millis(10).await;
+                self.send_mouse_report(0, 0, 0, 0, 0).await;
+            }
+            None => (),
+        }
+    }
+}
+
+impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
+    InputProcessor<'a, ROW, COL, NUM_LAYER> for TouchpadProcessor<'a, ROW, COL, NUM_LAYER>
+{
+    async fn process(&mut self, event: Event) -> ProcessResult {
+        match event {
+            Event::Touchpad(touchpad_event)
+                if self
+                    .config
+                    .source
+                    .is_none_or(|source| source == touchpad_event.source) =>
+            {
+                if touchpad_event
+                    .axis
+                    .iter()
+                    .any(|a| matches!(a.typ, AxisValType::Abs))
+                {
+                    self.process_absolute(touchpad_event).await;
+                } else {
+                    self.process_relative(touchpad_event).await;
+                }
+                ProcessResult::Stop
+            }
+            _ => ProcessResult::Continue(event),
+        }
+    }
+
+    fn get_keymap(&self) -> &RefCell<KeyMap<'a, ROW, COL, NUM_LAYER>> {
+        self.keymap
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn test_tap_to_click() {
+        let config = TouchpadConfig::default();
+        let mut state = GestureState::new();
+        assert_eq!(state.update(&config, 0, (1000, 700), 20, at(0)), None);
+        assert_eq!(
+            state.update(&config, 0, (1010, 705), 20, at(50)),
+            Some(GestureOutput::Move(5, 2))
+        );
+        assert_eq!(
+            state.update(&config, 0, (0, 0), 0, at(100)),
+            Some(GestureOutput::Click(1 << 0))
+        );
+
+        // Two-finger tap is right click
+        state.update(&config, 0, (1000, 700), 20, at(1000));
+        state.update(&config, 1, (1200, 700), 20, at(1020));
+        assert_eq!(state.update(&config, 1, (0, 0), 0, at(1080)), None);
+        assert_eq!(
+            state.update(&config, 0, (0, 0), 0, at(1100)),
+            Some(GestureOutput::Click(1 << 1))
+        );
+
+        // Too long to be a tap
+        state.update(&config, 0, (1000, 700), 20, at(2000));
+        assert_eq!(state.update(&config, 0, (0, 0), 0, at(2200)), None);
+        // Moved too far to be a tap
+        state.update(&config, 0, (1000, 700), 20, at(3000));
+        state.update(&config, 0, (1050, 700), 20, at(3010));
+        assert_eq!(state.update(&config, 0, (0, 0), 0, at(3020)), None);
+    }
+
+    #[test]
+    fn test_two_finger_scroll() {
+        let config = TouchpadConfig::default();
+        let mut state = GestureState::new();
+        state.update(&config, 0, (1000, 700), 20, at(0));
+        state.update(&config, 1, (1200, 700), 20, at(10));
+        // Moving up scrolls up
+        assert_eq!(
+            state.update(&config, 0, (1000, 636), 20, at(20)),
+            Some(GestureOutput::Scroll { wheel: 2, pan: 0 })
+        );
+        // Only the first finger scrolls
+        assert_eq!(state.update(&config, 1, (1200, 600), 20, at(30)), None);
+        // Less than a wheel unit is kept for the next motion
+        assert_eq!(state.update(&config, 0, (1020, 636), 20, at(40)), None);
+        assert_eq!(
+            state.update(&config, 0, (1040, 636), 20, at(50)),
+            Some(GestureOutput::Scroll { wheel: 0, pan: 1 })
+        );
+    }
+
+    #[test]
+    fn test_circular_scroll() {
+        let config = TouchpadConfig::default();
+        let mut state = GestureState::new();
+        // Center is (1024, 768), touching at the right edge starts circular scroll
+        state.update(&config, 0, (1624, 768), 20, at(0));
+        assert_eq!(state.gesture, Gesture::CircularScroll);
+        // Moving clockwise by ~0.31 rad scrolls down by one unit
+        assert_eq!(
+            state.update(&config, 0, (1624, 968), 20, at(10)),
+            Some(GestureOutput::Scroll { wheel: -1, pan: 0 })
+        );
+        assert_eq!(state.angle, 65);
+        // Moving back counter-clockwise scrolls up
+        assert_eq!(
+            state.update(&config, 0, (1624, 768), 20, at(20)),
+            Some(GestureOutput::Scroll { wheel: 1, pan: 0 })
+        );
+
+        // Touching in the middle moves the cursor
+        state.update(&config, 0, (0, 0), 0, at(1000));
+        state.update(&config, 0, (1024, 768), 20, at(2000));
+        assert_eq!(state.gesture, Gesture::Cursor);
+    }
+
+    #[test]
+    fn test_glide_decay() {
+        let mut glide = Glide {
+            velocity: (10 * 256, -5 * 256),
+            acc: (0, 0),
+            last_step: at(0),
+        };
+        assert_eq!(glide.advance(2, 50), (7, -3));
+        assert!(!glide.is_over());
+        // The glide stops when the speed is too low
+        assert_eq!(glide.advance(10, 50), (2, -1));
+        assert!(glide.is_over());
+    }
+
+    #[test]
+    fn test_glide_after_swipe() {
+        let config = TouchpadConfig::default();
+        let mut state = GestureState::new();
+        state.update(&config, 0, (900, 768), 20, at(0));
+        for i in 1..=3 {
+            let x = 900 + 40 * i as i16;
+            assert_eq!(
+                state.update(&config, 0, (x, 768), 20, at(10 * i)),
+                Some(GestureOutput::Move(20, 0))
+            );
+        }
+        // Lifting after a fast swipe starts gliding, which is advanced by the following events
+        assert_eq!(state.update(&config, 0, (0, 0), 0, at(40)), None);
+        assert!(state.glide.is_some());
+        assert_eq!(state.update(&config, 0, (0, 0), 0, at(45)), None);
+        assert_eq!(
+            state.update(&config, 0, (0, 0), 0, at(60)),
+            Some(GestureOutput::Move(26, 0))
+        );
+        // Touching again stops gliding
+        state.update(&config, 0, (900, 768), 20, at(70));
+        assert!(state.glide.is_none());
+        assert_eq!(state.update(&config, 0, (0, 0), 0, at(500)), None);
+        assert_eq!(state.update(&config, 0, (0, 0), 0, at(600)), None);
+
+        // The glide stops by itself
+        let mut state = GestureState::new();
+        state.update(&config, 0, (900, 768), 20, at(1000));
+        for i in 1..=3 {
+            state.update(
+                &config,
+                0,
+                (900 + 40 * i as i16, 768),
+                20,
+                at(1000 + 10 * i),
+            );
+        }
+        state.update(&config, 0, (0, 0), 0, at(1040));
+        assert!(state.update(&config, 0, (0, 0), 0, at(2040)).is_some());
+        assert!(state.glide.is_none());
+    }
+}
@@ -0,0 +1,705 @@
+//! Matrix scanning through I2C GPIO expanders
+//!
+//! Some boards, like Dactyl-style split keyboards, connect a half of the matrix to an I2C GPIO expander
+//! instead of a second MCU. [`ExpanderOutputs`] and [`ExpanderInputs`] are [`ScanOutputs`] and [`ScanInputs`]
+//! on an expander, so they can be scanned by [`IoExpanderMatrix`], or mixed with native pins by
+//! [`MixedOutputs`](crate::shift_register::MixedOutputs) and [`MixedInputs`](crate::shift_register::MixedInputs).
+//!
+//! Supported chips:
+//!
+//! - [`Mcp23017`]: MCP23017, inputs use the internal pull-ups if the matrix is low active
+//! - [`Pca9555`]: PCA9555/PCA9535/TCA9555, there are no internal pull-ups, so external pull-up or pull-down resistors are required
+//!
+//! The expander pins are numbered 0~15, 0~7 are the pins of port A(port 0), 8~15 are the pins of port B(port 1).
+//! The outputs and inputs on the same chip can share an I2C bus using
+//! [`I2cDevice`](embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice).
+//! All outputs of a chip should be in the same [`ExpanderOutputs`].
+//!
+//! If the INT pin of the expander is connected, set it by [`ExpanderInputs::with_interrupt`].
+//! Then the matrix waits for the interrupt-on-change instead of polling the expander when the `async_matrix` feature is enabled.
+use core::convert::Infallible;
+use core::marker::PhantomData;
+
+use embassy_time::Timer;
+use embedded_hal::digital::ErrorType;
+use embedded_hal::i2c::Error;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::i2c::I2c;
+
+use crate::shift_register::{ScanInputs, ScanOutputs, ShiftRegisterMatrix};
+
+/// Matrix whose outputs or inputs are connected through I2C GPIO expanders, see [`ShiftRegisterMatrix`].
+pub type IoExpanderMatrix<
+    I,
+    O,
+    D,
+    const ROW_OFFSET: usize,
+    const COL_OFFSET: usize,
+    const INPUT_NUM: usize,
+    const OUTPUT_NUM: usize,
+    const COL2ROW: bool = true,
+> = ShiftRegisterMatrix<I, O, D, ROW_OFFSET, COL_OFFSET, INPUT_NUM, OUTPUT_NUM, COL2ROW>;
+
+/// Register layout of an I2C GPIO expander with two 8-bit ports
+pub trait ExpanderChip {
+    /// Input register of port A, it's followed by the input register of port B
+    const INPUT_REG: u8;
+    /// Output register of port A, it's followed by the output register of port B
+    const OUTPUT_REG: u8;
+
+    /// Configure `pins` as inputs, and enable the interrupt-on-change of them if it's necessary
+    async fn configure_inputs<B: I2c>(
+        i2c: &mut B,
+        address: u8,
+        pins: u16,
+        pull_up: bool,
+    ) -> Result<(), B::Error>;
+
+    /// Configure `pins` as outputs
+    async fn configure_outputs<B: I2c>(i2c: &mut B, address: u8, pins: u16)
+        -> Result<(), B::Error>;
+}
+
+async fn read_u16<B: I2c>(i2c: &mut B, address: u8, reg: u8) -> Result<u16, B::Error> {
+    let mut buf = [0u8; 2];
+    i2c.write_read(address, &[reg], &mut buf).await?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+async fn write_u16<B: I2c>(i2c: &mut B, address: u8, reg: u8, value: u16) -> Result<(), B::Error> {
+    let [port_a, port_b] = value.to_le_bytes();
+    i2c.write(address, &[reg, port_a, port_b]).await
+}
+
+/// Set bits of `pins` in a register pair to `set`, other bits are kept
+async fn update_u16<B: I2c>(
+    i2c: &mut B,
+    address: u8,
+    reg: u8,
+    pins: u16,
+    set: bool,
+) -> Result<(), B::Error> {
+    let value = read_u16(i2c, address, reg).await?;
+    let value = if set { value | pins } else { value & !pins };
+    write_u16(i2c, address, reg, value).await
+}
+
+/// MCP23017, registers are accessed with `IOCON.BANK = 0`, which is the power-on default
+pub struct Mcp23017;
+
+impl Mcp23017 {
+    const IODIR: u8 = 0x00;
+    const GPINTEN: u8 = 0x04;
+    const INTCON: u8 = 0x08;
+    const IOCON: u8 = 0x0A;
+    const GPPU: u8 = 0x0C;
+    /// `IOCON.MIRROR`, INTA and INTB are internally connected
+    const IOCON_MIRROR: u8 = 0x40;
+}
+
+impl ExpanderChip for Mcp23017 {
+    const INPUT_REG: u8 = 0x12;
+    const OUTPUT_REG: u8 = 0x14;
+
+    async fn configure_inputs<B: I2c>(
+        i2c: &mut B,
+        address: u8,
+        pins: u16,
+        pull_up: bool,
+    ) -> Result<(), B::Error> {
+        update_u16(i2c, address, Self::IODIR, pins, true).await?;
+        update_u16(i2c, address, Self::GPPU, pins, pull_up).await?;
+        // Compare against the previous value, so that any change triggers the interrupt
+        update_u16(i2c, address, Self::INTCON, pins, false).await?;
+        // Either of INTA or INTB can be connected
+        i2c.write(address, &[Self::IOCON, Self::IOCON_MIRROR])
+            .await?;
+        update_u16(i2c, address, Self::GPINTEN, pins, true).await
+    }
+
+    async fn configure_outputs<B: I2c>(
+        i2c: &mut B,
+        address: u8,
+        pins: u16,
+    ) -> Result<(), B::Error> {
+        update_u16(i2c, address, Self::IODIR, pins, false).await
+    }
+}
+
+/// PCA9555, also works for the register compatible PCA9535 and TCA9555.
+///
+/// The interrupt of PCA9555 is always enabled for all inputs.
+pub struct Pca9555;
+
+impl Pca9555 {
+    const CONFIG: u8 = 0x06;
+}
+
+impl ExpanderChip for Pca9555 {
+    const INPUT_REG: u8 = 0x00;
+    const OUTPUT_REG: u8 = 0x02;
+
+    async fn configure_inputs<B: I2c>(
+        i2c: &mut B,
+        address: u8,
+        pins: u16,
+        _pull_up: bool,
+    ) -> Result<(), B::Error> {
+        update_u16(i2c, address, Self::CONFIG, pins, true).await
+    }
+
+    async fn configure_outputs<B: I2c>(
+        i2c: &mut B,
+        address: u8,
+        pins: u16,
+    ) -> Result<(), B::Error> {
+        update_u16(i2c, address, Self::CONFIG, pins, false).await
+    }
+}
+
+/// Bit mask of expander pins
+fn pin_mask(pins: &[u8]) -> u16 {
+    pins.iter().fold(0, |mask, p| mask | (1 << p))
+}
+
+/// `N` outputs on an I2C GPIO expander, output `i` is the expander pin `pins[i]`
+pub struct ExpanderOutputs<C: ExpanderChip, B: I2c, const N: usize> {
+    i2c: B,
+    address: u8,
+    pins: [u8; N],
+    /// Current output levels, `None` if the expander is not configured yet
+    levels: Option<u16>,
+    /// The last write failed, the error is logged once until a write succeeds
+    failing: bool,
+    _chip: PhantomData<C>,
+}
+
+impl<C: ExpanderChip, B: I2c, const N: usize> ExpanderOutputs<C, B, N> {
+    /// Create outputs on the expander at 7-bit I2C `address`
+    pub fn new(i2c: B, address: u8, pins: [u8; N]) -> Self {
+        assert!(
+            pins.iter().all(|p| *p < 16),
+            "Expander pin number should be 0~15"
+        );
+        Self {
+            i2c,
+            address,
+            pins,
+            levels: None,
+            failing: false,
+            _chip: PhantomData,
+        }
+    }
+
+    async fn write_levels(&mut self, levels: u16) -> Result<(), B::Error> {
+        write_u16(&mut self.i2c, self.address, C::OUTPUT_REG, levels).await?;
+        if self.levels.is_none() {
+            // Set the output levels before switching pins to outputs, to avoid glitches
+            C::configure_outputs(&mut self.i2c, self.address, pin_mask(&self.pins)).await?;
+        }
+        self.levels = Some(levels);
+        Ok(())
+    }
+}
+
+impl<C: ExpanderChip, B: I2c, const N: usize> ScanOutputs for ExpanderOutputs<C, B, N> {
+    async fn set_active(&mut self, active: u32, low_active: bool) {
+        let levels = self
+            .pins
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| (active & (1 << i) != 0) != low_active)
+            .fold(0u16, |levels, (_, p)| levels | (1 << p));
+        if self.levels == Some(levels) {
+            return;
+        }
+        match self.write_levels(levels).await {
+            Ok(()) => self.failing = false,
+            Err(e) => {
+                // The expander may be reset when it's back, configure it again then
+                self.levels = None;
+                if !self.failing {
+                    error!("Failed to write I/O expander: {:?}", e.kind());
+                    self.failing = true;
+                }
+            }
+        }
+    }
+}
+
+/// Placeholder of the interrupt pin for [`ExpanderInputs`] without the INT pin connected
+pub struct NoInterrupt;
+
+impl ErrorType for NoInterrupt {
+    type Error = Infallible;
+}
+
+impl Wait for NoInterrupt {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        core::future::pending().await
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        core::future::pending().await
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        core::future::pending().await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        core::future::pending().await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        core::future::pending().await
+    }
+}
+
+/// `N` inputs on an I2C GPIO expander, input `i` is the expander pin `pins[i]`
+pub struct ExpanderInputs<C: ExpanderChip, B: I2c, W: Wait, const N: usize> {
+    i2c: B,
+    address: u8,
+    pins: [u8; N],
+    /// INT pin of the expander, active low
+    interrupt: Option<W>,
+    configured: bool,
+    /// The last read failed, the error is logged once until a read succeeds
+    failing: bool,
+    _chip: PhantomData<C>,
+}
+
+impl<C: ExpanderChip, B: I2c, const N: usize> ExpanderInputs<C, B, NoInterrupt, N> {
+    /// Create inputs on the expander at 7-bit I2C `address`
+    pub fn new(i2c: B, address: u8, pins: [u8; N]) -> Self {
+        assert!(
+            pins.iter().all(|p| *p < 16),
+            "Expander pin number should be 0~15"
+        );
+        Self {
+            i2c,
+            address,
+            pins,
+            interrupt: None,
+            configured: false,
+            failing: false,
+            _chip: PhantomData,
+        }
+    }
+
+    /// Use the INT pin of the expander to wait for key presses, instead of polling
+    pub fn with_interrupt<W: Wait>(self, interrupt: W) -> ExpanderInputs<C, B, W, N> {
+        ExpanderInputs {
+            i2c: self.i2c,
+            address: self.address,
+            pins: self.pins,
+            interrupt: Some(interrupt),
+            configured: self.configured,
+            failing: self.failing,
+            _chip: PhantomData,
+        }
+    }
+}
+
+impl<C: ExpanderChip, B: I2c, W: Wait, const N: usize> ExpanderInputs<C, B, W, N> {
+    async fn read_levels(&mut self, low_active: bool) -> Result<u16, B::Error> {
+        if !self.configured {
+            C::configure_inputs(
+                &mut self.i2c,
+                self.address,
+                pin_mask(&self.pins),
+                low_active,
+            )
+            .await?;
+            self.configured = true;
+        }
+        read_u16(&mut self.i2c, self.address, C::INPUT_REG).await
+    }
+}
+
+impl<C: ExpanderChip, B: I2c, W: Wait, const N: usize> ScanInputs for ExpanderInputs<C, B, W, N> {
+    async fn read_active(&mut self, low_active: bool) -> u32 {
+        match self.read_levels(low_active).await {
+            Ok(levels) => {
+                self.failing = false;
+                self.pins
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, p)| (levels & (1 << **p) != 0) != low_active)
+                    .fold(0, |active, (i, _)| active | (1 << i))
+            }
+            Err(e) => {
+                // The expander may be reset when it's back, configure it again then
+                self.configured = false;
+                if !self.failing {
+                    error!("Failed to read I/O expander: {:?}", e.kind());
+                    self.failing = true;
+                }
+                0
+            }
+        }
+    }
+
+    async fn wait_for_active(&mut self, low_active: bool) {
+        // Reading the inputs clears the pending interrupt, so the INT pin is checked after each read
+        while self.read_active(low_active).await == 0 {
+            match self.interrupt.as_mut() {
+                Some(interrupt) => {
+                    interrupt.wait_for_low().await.ok();
+                }
+                None => Timer::after_millis(1).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::cell::RefCell;
+
+    use embassy_futures::block_on;
+    use embassy_time::Duration;
+    use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource};
+    use embedded_hal_async::i2c::{ErrorType as I2cErrorType, Operation};
+
+    use super::*;
+    use crate::config::{DebounceAlgorithm, DebounceConfig};
+    use crate::debounce::per_key_debouncer::PerKeyDebouncer;
+    use crate::matrix::test_utils::read_key;
+    use crate::shift_register::MixedInputs;
+
+    const ADDRESS: u8 = 0x20;
+
+    /// Register model of an expander whose pins are connected by a key matrix
+    struct FakeExpander {
+        regs: [u8; 0x16],
+        /// Direction register, bit is 1 for inputs
+        dir_reg: u8,
+        input_reg: u8,
+        output_reg: u8,
+        /// Pull-up register of MCP23017, or `None` if the inputs are pulled down externally
+        pull_up_reg: Option<u8>,
+        /// Pressed keys, as (output pin, input pin)
+        pressed: Vec<(u8, u8)>,
+        /// Number of I2C transactions
+        transactions: usize,
+        /// The expander doesn't acknowledge any transaction
+        unplugged: bool,
+    }
+
+    impl FakeExpander {
+        fn mcp23017() -> Self {
+            let mut regs = [0; 0x16];
+            // IODIR: all inputs after reset
+            regs[0x00] = 0xFF;
+            regs[0x01] = 0xFF;
+            Self {
+                regs,
+                dir_reg: 0x00,
+                input_reg: Mcp23017::INPUT_REG,
+                output_reg: Mcp23017::OUTPUT_REG,
+                pull_up_reg: Some(0x0C),
+                pressed: Vec::new(),
+                transactions: 0,
+                unplugged: false,
+            }
+        }
+
+        fn pca9555() -> Self {
+            let mut regs = [0; 0x16];
+            // Config: all inputs after reset
+            regs[0x06] = 0xFF;
+            regs[0x07] = 0xFF;
+            Self {
+                regs,
+                dir_reg: 0x06,
+                input_reg: Pca9555::INPUT_REG,
+                output_reg: Pca9555::OUTPUT_REG,
+                pull_up_reg: None,
+                pressed: Vec::new(),
+                transactions: 0,
+                unplugged: false,
+            }
+        }
+
+        fn reg_u16(&self, reg: u8) -> u16 {
+            u16::from_le_bytes([self.regs[reg as usize], self.regs[reg as usize + 1]])
+        }
+
+        fn is_input(&self, pin: u8) -> bool {
+            self.reg_u16(self.dir_reg) & (1 << pin) != 0
+        }
+
+        /// Pin levels seen from the input register
+        fn levels(&self) -> u16 {
+            let outputs = self.reg_u16(self.output_reg);
+            (0..16).fold(0, |levels, pin| {
+                let level = if self.is_input(pin) {
+                    let idle = match self.pull_up_reg {
+                        Some(reg) => self.reg_u16(reg) & (1 << pin) != 0,
+                        None => false,
+                    };
+                    // A pressed key connects the input to an output
+                    self.pressed
+                        .iter()
+                        .filter(|(o, i)| *i == pin && !self.is_input(*o))
+                        .map(|(o, _)| outputs & (1 << o) != 0)
+                        .fold(
+                            idle,
+                            |level, out| if idle { level && out } else { level || out },
+                        )
+                } else {
+                    outputs & (1 << pin) != 0
+                };
+                levels | ((level as u16) << pin)
+            })
+        }
+
+        fn read(&self, reg: u8) -> u8 {
+            if reg == self.input_reg {
+                self.levels() as u8
+            } else if reg == self.input_reg + 1 {
+                (self.levels() >> 8) as u8
+            } else {
+                self.regs[reg as usize]
+            }
+        }
+    }
+
+    /// Fake I2C bus connected to a [`FakeExpander`]
+    struct FakeI2c<'a>(&'a RefCell<FakeExpander>);
+
+    impl I2cErrorType for FakeI2c<'_> {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for FakeI2c<'_> {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            assert_eq!(address, ADDRESS);
+            let mut fake = self.0.borrow_mut();
+            if fake.unplugged {
+                return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+            }
+            fake.transactions += 1;
+            let mut reg = 0;
+            for operation in operations {
+                match operation {
+                    Operation::Write(bytes) => {
+                        reg = bytes[0];
+                        for b in &bytes[1..] {
+                            fake.regs[reg as usize] = *b;
+                            reg += 1;
+                        }
+                    }
+                    Operation::Read(buf) => {
+                        for b in buf.iter_mut() {
+                            *b = fake.read(reg);
+                            reg += 1;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Fake INT pin, which presses a key when it's waited
+    struct FakeInterrupt<'a> {
+        expander: &'a RefCell<FakeExpander>,
+        key: (u8, u8),
+        waits: usize,
+    }
+
+    impl ErrorType for FakeInterrupt<'_> {
+        type Error = Infallible;
+    }
+
+    impl Wait for FakeInterrupt<'_> {
+        async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+
+        async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+            self.waits += 1;
+            self.expander.borrow_mut().pressed.push(self.key);
+            Ok(())
+        }
+
+        async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+
+        async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+
+        async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+    }
+
+    /// Inputs whose active bits are set by the test, like native pins
+    struct FixedInputs(u32);
+
+    impl ScanInputs for FixedInputs {
+        async fn read_active(&mut self, _low_active: bool) -> u32 {
+            self.0
+        }
+    }
+
+    fn debouncer<const IN: usize, const OUT: usize>() -> PerKeyDebouncer<IN, OUT> {
+        PerKeyDebouncer::new(DebounceConfig {
+            algorithm: DebounceAlgorithm::SymDeferPk,
+            press_time: Duration::from_millis(0),
+            release_time: Duration::from_millis(0),
+        })
+    }
+
+    #[test]
+    fn test_mcp23017_configuration() {
+        let fake = RefCell::new(FakeExpander::mcp23017());
+        let mut outputs = ExpanderOutputs::<Mcp23017, _, 2>::new(FakeI2c(&fake), ADDRESS, [0, 1]);
+        let mut inputs =
+            ExpanderInputs::<Mcp23017, _, _, 3>::new(FakeI2c(&fake), ADDRESS, [8, 9, 10]);
+        block_on(outputs.set_active(0b01, true));
+        assert_eq!(block_on(inputs.read_active(true)), 0);
+
+        let fake = fake.borrow();
+        // IODIR
+        assert_eq!(fake.reg_u16(0x00), 0xFFFC);
+        // GPPU
+        assert_eq!(fake.reg_u16(0x0C), 0x0700);
+        // GPINTEN
+        assert_eq!(fake.reg_u16(0x04), 0x0700);
+        // IOCON.MIRROR
+        assert_eq!(fake.regs[0x0A], 0x40);
+        // OLAT, the active output is low
+        assert_eq!(fake.reg_u16(0x14), 0b10);
+    }
+
+    #[test]
+    fn test_mcp23017_matrix_scan() {
+        let fake = RefCell::new(FakeExpander::mcp23017());
+        let outputs = ExpanderOutputs::<Mcp23017, _, 2>::new(FakeI2c(&fake), ADDRESS, [0, 1]);
+        let inputs = ExpanderInputs::<Mcp23017, _, _, 3>::new(FakeI2c(&fake), ADDRESS, [8, 9, 10]);
+        let mut matrix = IoExpanderMatrix::<_, _, _, 0, 0, 3, 2>::new(
+            inputs,
+            outputs,
+            debouncer::<3, 2>(),
+            true,
+        );
+
+        fake.borrow_mut().pressed.push((1, 10));
+        assert_eq!(read_key(&mut matrix), (2, 1, true));
+        fake.borrow_mut().pressed.push((0, 8));
+        assert_eq!(read_key(&mut matrix), (0, 0, true));
+        fake.borrow_mut().pressed.clear();
+        assert_eq!(read_key(&mut matrix), (0, 0, false));
+        assert_eq!(read_key(&mut matrix), (2, 1, false));
+    }
+
+    #[test]
+    fn test_pca9555_row2col_high_active() {
+        let fake = RefCell::new(FakeExpander::pca9555());
+        let outputs = ExpanderOutputs::<Pca9555, _, 3>::new(FakeI2c(&fake), ADDRESS, [8, 9, 15]);
+        let inputs = ExpanderInputs::<Pca9555, _, _, 2>::new(FakeI2c(&fake), ADDRESS, [0, 7]);
+        let mut matrix = IoExpanderMatrix::<_, _, _, 0, 0, 2, 3, false>::new(
+            inputs,
+            outputs,
+            debouncer::<2, 3>(),
+            false,
+        );
+
+        fake.borrow_mut().pressed.push((15, 7));
+        assert_eq!(read_key(&mut matrix), (2, 1, true));
+        // Config
+        assert_eq!(fake.borrow().reg_u16(0x06), 0x7FFF & !0x0300);
+        fake.borrow_mut().pressed.clear();
+        assert_eq!(read_key(&mut matrix), (2, 1, false));
+    }
+
+    #[test]
+    fn test_mixed_native_and_expander_inputs() {
+        let fake = RefCell::new(FakeExpander::mcp23017());
+        let outputs = ExpanderOutputs::<Mcp23017, _, 1>::new(FakeI2c(&fake), ADDRESS, [0]);
+        let expander_inputs =
+            ExpanderInputs::<Mcp23017, _, _, 2>::new(FakeI2c(&fake), ADDRESS, [8, 9]);
+        let inputs = MixedInputs::<_, _, 2>::new(FixedInputs(0b10), expander_inputs);
+        let mut matrix = IoExpanderMatrix::<_, _, _, 0, 0, 4, 1>::new(
+            inputs,
+            outputs,
+            debouncer::<4, 1>(),
+            true,
+        );
+
+        fake.borrow_mut().pressed.push((0, 9));
+        // Native input 1, then expander input 1
+        assert_eq!(read_key(&mut matrix), (1, 0, true));
+        assert_eq!(read_key(&mut matrix), (3, 0, true));
+    }
+
+    #[test]
+    fn test_outputs_skip_unchanged_levels() {
+        let fake = RefCell::new(FakeExpander::mcp23017());
+        let mut outputs = ExpanderOutputs::<Mcp23017, _, 2>::new(FakeI2c(&fake), ADDRESS, [0, 1]);
+        block_on(outputs.set_active(0b01, true));
+        let transactions = fake.borrow().transactions;
+        block_on(outputs.set_active(0b01, true));
+        assert_eq!(fake.borrow().transactions, transactions);
+        block_on(outputs.set_active(0b10, true));
+        assert_eq!(fake.borrow().transactions, transactions + 1);
+        assert_eq!(fake.borrow().reg_u16(0x14), 0b01);
+    }
+
+    #[test]
+    fn test_wait_for_interrupt() {
+        let fake = RefCell::new(FakeExpander::mcp23017());
+        // The output is always active
+        fake.borrow_mut().regs[0x00] = 0xFE;
+        let mut inputs = ExpanderInputs::<Mcp23017, _, _, 2>::new(FakeI2c(&fake), ADDRESS, [8, 9])
+            .with_interrupt(FakeInterrupt {
+                expander: &fake,
+                key: (0, 9),
+                waits: 0,
+            });
+        block_on(inputs.wait_for_active(true));
+        assert_eq!(inputs.interrupt.as_ref().unwrap().waits, 1);
+        assert_eq!(block_on(inputs.read_active(true)), 0b10);
+    }
+
+    #[test]
+    fn test_reconfigure_after_error() {
+        let fake = RefCell::new(FakeExpander::mcp23017());
+        let mut outputs = ExpanderOutputs::<Mcp23017, _, 2>::new(FakeI2c(&fake), ADDRESS, [0, 1]);
+        let mut inputs =
+            ExpanderInputs::<Mcp23017, _, _, 3>::new(FakeI2c(&fake), ADDRESS, [8, 9, 10]);
+        fake.borrow_mut().pressed.push((0, 8));
+        block_on(outputs.set_active(0b01, true));
+        assert_eq!(block_on(inputs.read_active(true)), 0b001);
+
+        // The expander is unplugged
+        fake.borrow_mut().unplugged = true;
+        block_on(outputs.set_active(0b10, true));
+        block_on(outputs.set_active(0b01, true));
+        assert_eq!(block_on(inputs.read_active(true)), 0);
+        assert_eq!(block_on(inputs.read_active(true)), 0);
+        assert!(outputs.failing && inputs.failing);
+
+        // Then it's plugged back with the registers reset
+        let pressed = fake.borrow().pressed.clone();
+        *fake.borrow_mut() = FakeExpander {
+            pressed,
+            ..FakeExpander::mcp23017()
+        };
+        block_on(outputs.set_active(0b01, true));
+        assert_eq!(block_on(inputs.read_active(true)), 0b001);
+        assert!(!outputs.failing && !inputs.failing);
+        // IODIR and GPPU are configured again
+        assert_eq!(fake.borrow().reg_u16(0x00), 0xFFFC);
+        assert_eq!(fake.borrow().reg_u16(0x0C), 0x0700);
+    }
+}
@@ -4,6 +4,11 @@ use crate::combo::{Combo, COMBO_MAX_LENGTH};
 use crate::config::BehaviorConfig;
 use crate::event::KeyEvent;
 use crate::hid::Report;
+use crate::input_device::auto_mouse::{
+    auto_mouse_layer_to_deactivate, wait_for_auto_mouse_timeout,
+};
+use crate::input_device::joystick::{current_joystick_report, JOYSTICK_BUTTONS};
+use crate::input_device::pointing::{update_pointing_mode, MOUSE_BUTTONS};
 use crate::input_device::Runnable;
 use crate::usb::descriptor::KeyboardReport;
 use crate::{
@@ -14,6 +19,7 @@ use crate::{
     usb::descriptor::ViaReport,
 };
 use core::cell::RefCell;
+use core::sync::atomic::Ordering;
 use embassy_futures::{select::select, yield_now};
 use embassy_time::{Instant, Timer};
 use heapless::{Deque, FnvIndexMap, Vec};
@@ -50,7 +56,16 @@ impl<const ROW: usize, const COL: usize, const NUM_LAYER: usize> Runnable
     /// The report is sent using `send_report`.
     async fn run(&mut self) {
         loop {
-            let key_event = KEY_EVENT_CHANNEL.receive().await;
+            let key_event =
+                match select(KEY_EVENT_CHANNEL.receive(), wait_for_auto_mouse_timeout()).await {
+                    embassy_futures::select::Either::First(key_event) => key_event,
+                    embassy_futures::select::Either::Second(layer) => {
+                        // Auto mouse layer timeout
+                        debug!("Deactivate auto mouse layer {}", layer);
+                        self.keymap.borrow_mut().deactivate_layer(layer);
+                        continue;
+                    }
+                };
 
             // Process the key change
             self.process_inner(key_event).await;
@@ -193,6 +208,20 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             .borrow_mut()
             .get_action_with_layer_cache(key_event);
 
+        // Keys are used to type the passkey when pairing
+        #[cfg(feature = "_ble")]
+        if crate::ble::passkey::process_passkey_key(key_event, key_action) {
+            return;
+        }
+
+        // Pressing a non-mouse key deactivates the auto mouse layer
+        if key_event.pressed {
+            if let Some(layer) = auto_mouse_layer_to_deactivate(key_action) {
+                debug!("Deactivate auto mouse layer {}", layer);
+                self.keymap.borrow_mut().deactivate_layer(layer);
+            }
+        }
+
         if self.combo_on {
             if let Some(key_action) = self.process_combo(key_action, key_event).await {
                 self.process_key_action(key_action, key_event).await;
@@ -203,6 +232,8 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
     }
 
     pub(crate) async fn send_keyboard_report(&mut self) {
+        #[cfg(feature = "split")]
+        crate::split::state::update_split_state(|s| s.modifiers = self.report.modifier);
         self.send_report(Report::KeyboardReport(self.report)).await;
         // Yield once after sending the report to channel
         yield_now().await;
@@ -747,10 +778,12 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             self.process_action_system_control(key, key_event).await;
         } else if key.is_mouse_key() {
             self.process_action_mouse(key, key_event).await;
+        } else if key.is_joystick() {
+            self.process_action_joystick(key, key_event).await;
         } else if key.is_user() {
-            #[cfg(feature = "_nrf_ble")]
-            use {crate::ble::nrf::profile::BleProfileAction, crate::channel::BLE_PROFILE_CHANNEL};
-            #[cfg(feature = "_nrf_ble")]
+            #[cfg(feature = "_ble")]
+            use {crate::ble::profile::BleProfileAction, crate::channel::BLE_PROFILE_CHANNEL};
+            #[cfg(feature = "_ble")]
             if !key_event.pressed {
                 // Get user key id
                 let id = key as u8 - KeyCode::User0 as u8;
@@ -789,8 +822,12 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
             self.process_action_macro(key, key_event).await;
         } else if key.is_combo() {
             self.process_action_combo(key, key_event).await;
+        } else if key.is_pointing_mode() {
+            update_pointing_mode(key, key_event.pressed);
         } else if key.is_boot() {
             self.process_boot(key, key_event);
+        } else if key.is_magic() {
+            self.process_action_magic(key, key_event).await;
         } else {
             warn!("Unsupported key: {:?}", key);
         }
@@ -877,6 +914,21 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         }
     }
 
+    /// Process joystick button action. The report contains the latest axes reported by joystick processor.
+    async fn process_action_joystick(&mut self, key: KeyCode, key_event: KeyEvent) {
+        if key.is_joystick() {
+            let bit = 1 << (key as u16 - KeyCode::JoystickButton0 as u16);
+            if key_event.pressed {
+                JOYSTICK_BUTTONS.fetch_or(bit, Ordering::Relaxed);
+            } else {
+                JOYSTICK_BUTTONS.fetch_and(!bit, Ordering::Relaxed);
+            }
+            self.send_report(Report::JoystickReport(current_joystick_report()))
+                .await;
+            yield_now().await;
+        }
+    }
+
     /// Process mouse key action.
     async fn process_action_mouse(&mut self, key: KeyCode, key_event: KeyEvent) {
         if key.is_mouse_key() {
@@ -956,6 +1008,8 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
                     _ => {}
                 }
             }
+            // Share the pressed buttons with pointing processors
+            MOUSE_BUTTONS.store(self.mouse_report.buttons, Ordering::Relaxed);
             self.send_mouse_report().await;
 
             if self
@@ -992,6 +1046,33 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         }
     }
 
+    async fn process_action_magic(&mut self, key: KeyCode, key_event: KeyEvent) {
+        // Magic keys take effect when pressed
+        if !key_event.pressed {
+            return;
+        }
+
+        match key {
+            #[cfg(feature = "split")]
+            KeyCode::MagicEeHandsLeft | KeyCode::MagicEeHandsRight => {
+                use crate::channel::FLASH_CHANNEL;
+                use crate::split::ee_hands::Handedness;
+                use crate::storage::FlashOperationMessage;
+
+                // The handedness is written to the storage of the central, it's used after reboot
+                let handedness = if key == KeyCode::MagicEeHandsLeft {
+                    Handedness::Left
+                } else {
+                    Handedness::Right
+                };
+                FLASH_CHANNEL
+                    .send(FlashOperationMessage::Handedness(handedness))
+                    .await;
+            }
+            _ => warn!("Unsupported magic key: {:?}", key),
+        }
+    }
+
     async fn process_action_macro(&mut self, key: KeyCode, key_event: KeyEvent) {
         // Execute the macro only when releasing the key
         if key_event.pressed {
@@ -813,6 +813,13 @@ pub enum KeyCode {
     TriLayerUpper = 0x778,
     RepeatKey = 0x779,
     AltRepeatKey = 0x77A,
+    // Pointing device mode keycodes, use 0x780 ~ 0x78F
+    DragScroll = 0x780,
+    DragScrollToggle = 0x781,
+    PrecisionMode = 0x782,
+    PrecisionModeToggle = 0x783,
+    SnipingMode = 0x784,
+    SnipingModeToggle = 0x785,
     // Kb keycodes, use 0x800 ~ 0x81F
     Kb0 = 0x800,
     Kb1 = 0x801,
@@ -976,7 +983,12 @@ impl KeyCode {
 
     /// Returns `true` if the keycode is defined by rmk to achieve special functionalities, such as reboot keyboard, goto bootloader, etc.
     pub(crate) fn is_rmk(self) -> bool {
-        KeyCode::Bootloader <= self && self <= KeyCode::AltRepeatKey
+        KeyCode::Bootloader <= self && self <= KeyCode::SnipingModeToggle
+    }
+
+    /// Returns `true` if the keycode switches pointing device mode
+    pub(crate) fn is_pointing_mode(self) -> bool {
+        KeyCode::DragScroll <= self && self <= KeyCode::SnipingModeToggle
     }
 
     /// Returns `true` if the keycode is a combo keycode
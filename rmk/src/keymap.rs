@@ -113,6 +113,7 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
     /// Set the default layer number
     pub(crate) fn set_default_layer(&mut self, layer_num: u8) {
         self.default_layer = layer_num;
+        self.sync_layer_state();
     }
 
     /// Get the next macro operation starting from given index and offset
@@ -256,6 +257,15 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         self.default_layer
     }
 
+    /// Returns `true` if the given layer is activated, default layer is always activated
+    pub(crate) fn is_layer_active(&self, layer_num: u8) -> bool {
+        layer_num == self.default_layer
+            || self
+                .layer_state
+                .get(layer_num as usize)
+                .is_some_and(|activated| *activated)
+    }
+
     fn get_layer_from_cache(&self, row: usize, col: usize) -> u8 {
         self.layer_cache[row][col]
     }
@@ -279,6 +289,21 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         }
     }
 
+    /// Sync the active layers to split peripherals
+    fn sync_layer_state(&self) {
+        #[cfg(feature = "split")]
+        {
+            let layers = (0..NUM_LAYER.min(32))
+                .filter(|l| self.is_layer_active(*l as u8))
+                .fold(0_u32, |bits, l| bits | (1 << l));
+            let default_layer = self.default_layer;
+            crate::split::state::update_split_state(|s| {
+                s.layers = layers;
+                s.default_layer = default_layer;
+            });
+        }
+    }
+
     /// Activate given layer
     pub(crate) fn activate_layer(&mut self, layer_num: u8) {
         if layer_num as usize >= NUM_LAYER {
@@ -290,6 +315,7 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         }
         self.layer_state[layer_num as usize] = true;
         self.update_tri_layer();
+        self.sync_layer_state();
     }
 
     /// Deactivate given layer
@@ -303,6 +329,7 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         }
         self.layer_state[layer_num as usize] = false;
         self.update_tri_layer();
+        self.sync_layer_state();
     }
 
     /// Toggle given layer
@@ -316,5 +343,6 @@ impl<'a, const ROW: usize, const COL: usize, const NUM_LAYER: usize>
         }
 
         self.layer_state[layer_num as usize] = !self.layer_state[layer_num as usize];
+        self.sync_layer_state();
     }
 }
@@ -59,22 +59,29 @@ use {
     crate::via::UsbVialReaderWriter,
 };
 
+pub use embassy_embedded_hal;
+pub use embassy_sync;
 pub use heapless;
 #[cfg(not(feature = "_no_usb"))]
 use usb::{add_usb_reader_writer, register_usb_writer};
 
 pub mod action;
-#[cfg(feature = "_ble")]
+pub mod analog_matrix;
+// The BLE stack is tested on the host with a mock backend
+#[cfg(any(feature = "_ble", test))]
 pub mod ble;
 mod boot;
 pub mod channel;
+pub mod charlieplex;
 pub mod combo;
 pub mod config;
 pub mod debounce;
 pub mod direct_pin;
+pub mod duplex_matrix;
 pub mod event;
 pub mod hid;
 pub mod input_device;
+pub mod io_expander;
 pub mod keyboard;
 mod keyboard_macro;
 pub mod keycode;
@@ -82,6 +89,7 @@ pub mod keymap;
 pub mod layout_macro;
 pub mod light;
 pub mod matrix;
+pub mod shift_register;
 #[cfg(feature = "split")]
 pub mod split;
 pub mod storage;
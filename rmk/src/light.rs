@@ -49,6 +49,10 @@ impl<'d, P: OutputPin, R: HidReaderTrait<ReportType = LedIndicator>> LightServic
                     Ok(indicator) => {
                         // Read led indicator data and send to LED channel
                         debug!("Read keyboard state: {:?}", indicator);
+                        #[cfg(feature = "split")]
+                        crate::split::state::update_split_state(|s| {
+                            s.led_indicator = indicator.into_bits()
+                        });
                         if let Err(e) = self.light_controller.set_leds(indicator) {
                             error!("Send led error {:?}", e.kind());
                             // If there's an error, wait for a while
@@ -67,7 +67,125 @@ impl KeyState {
     }
 }
 
+/// Drive the output pin to the active level or the idle level
+pub(crate) fn set_output_active<Out: OutputPin>(pin: &mut Out, active: bool, low_active: bool) {
+    if active != low_active {
+        pin.set_high().ok();
+    } else {
+        pin.set_low().ok();
+    }
+}
+
+/// Check whether the input pin is at the active level
+pub(crate) fn is_input_active<In: InputPin>(pin: &mut In, low_active: bool) -> bool {
+    if low_active {
+        pin.is_low().ok().unwrap_or_default()
+    } else {
+        pin.is_high().ok().unwrap_or_default()
+    }
+}
+
+/// Check whether the key at (out_idx, in_idx) is at a corner of a ghost rectangle.
+///
+/// On a matrix without diodes, if 3 keys at the corners of a rectangle are pressed, the key at the 4th corner is read as pressed too.
+/// Then all the 4 corners are read as pressed, and there's no way to tell which one is the ghost.
+pub(crate) fn is_ghost_press<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize>(
+    pressed: &[[bool; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+    out_idx: usize,
+    in_idx: usize,
+) -> bool {
+    (0..OUTPUT_PIN_NUM)
+        .filter(|o| *o != out_idx && pressed[*o][in_idx])
+        .any(|o| (0..INPUT_PIN_NUM).any(|i| i != in_idx && pressed[out_idx][i] && pressed[o][i]))
+}
+
+/// Commit the debounced changes of a full matrix scan to `key_states`.
+///
+/// The ghosts are decided against the key states after the whole scan, so the result doesn't depend on the scan order.
+/// With ghost detection, the presses in a ghost rectangle are dropped from `changed`, they're debounced again
+/// in the next scans and reported once the ghost rectangle is cleared.
+pub(crate) fn commit_scan<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize>(
+    key_states: &mut [[KeyState; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+    changed: &mut [[bool; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+    ghost_detection: bool,
+) {
+    let mut pressed = [[false; INPUT_PIN_NUM]; OUTPUT_PIN_NUM];
+    for (out_idx, row) in pressed.iter_mut().enumerate() {
+        for (in_idx, p) in row.iter_mut().enumerate() {
+            *p = key_states[out_idx][in_idx].pressed != changed[out_idx][in_idx];
+        }
+    }
+    for out_idx in 0..OUTPUT_PIN_NUM {
+        for in_idx in 0..INPUT_PIN_NUM {
+            if !changed[out_idx][in_idx] {
+                continue;
+            }
+            if ghost_detection
+                && pressed[out_idx][in_idx]
+                && is_ghost_press(&pressed, out_idx, in_idx)
+            {
+                changed[out_idx][in_idx] = false;
+            } else {
+                key_states[out_idx][in_idx].toggle_pressed();
+            }
+        }
+    }
+}
+
+/// Take the first committed change in scan order, returns its (out_idx, in_idx)
+pub(crate) fn take_changed_key<const INPUT_PIN_NUM: usize, const OUTPUT_PIN_NUM: usize>(
+    changed: &mut [[bool; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+) -> Option<(usize, usize)> {
+    for (out_idx, row) in changed.iter_mut().enumerate() {
+        if let Some(in_idx) = row.iter().position(|c| *c) {
+            row[in_idx] = false;
+            return Some((out_idx, in_idx));
+        }
+    }
+    None
+}
+
+/// A GPIO pin which can be switched between input and output at runtime.
+///
+/// It's used by matrices whose pins are both driven and read, such as [`crate::duplex_matrix::DuplexMatrix`]
+/// and [`crate::charlieplex::CharlieplexMatrix`]. Implement it for the flex pin type of your HAL, such as `Flex` of embassy.
+pub trait FlexPin {
+    /// Switch to input mode, with pull-up if `pull_up` is true, otherwise with pull-down
+    fn set_as_input(&mut self, pull_up: bool);
+
+    /// Switch to output mode, and drive the pin high if `high` is true, otherwise low
+    fn set_as_output(&mut self, high: bool);
+
+    /// Whether the pin is at the high level
+    fn is_high(&mut self) -> bool;
+}
+
+/// Drive the flex pin to the active level
+pub(crate) fn drive_flex_pin<P: FlexPin>(pin: &mut P, low_active: bool) {
+    pin.set_as_output(!low_active);
+}
+
+/// Release the flex pin, it's pulled to the idle level as an input
+pub(crate) fn release_flex_pin<P: FlexPin>(pin: &mut P, low_active: bool) {
+    pin.set_as_input(low_active);
+}
+
+/// Check whether the flex pin is at the active level
+pub(crate) fn is_flex_pin_active<P: FlexPin>(pin: &mut P, low_active: bool) -> bool {
+    pin.is_high() != low_active
+}
+
 /// Matrix is the physical pcb layout of the keyboard matrix.
+///
+/// The diode direction is set by `COL2ROW`:
+/// - `COL2ROW = true`: input pins are rows, output pins are cols
+/// - `COL2ROW = false`: input pins are cols, output pins are rows
+///
+/// The pin polarity is set by `low_active` in [`Matrix::new`]:
+/// - active-high: output pins are pulled high when scanning, input pins should be pulled down
+/// - active-low: output pins are pulled low when scanning, input pins should be pulled up
+///
+/// For matrices without diodes, enable ghost detection by [`Matrix::with_ghost_detection`].
 pub struct Matrix<
     #[cfg(feature = "async_matrix")] In: Wait + InputPin,
     #[cfg(not(feature = "async_matrix"))] In: InputPin,
@@ -75,6 +193,7 @@ pub struct Matrix<
     D: DebouncerTrait,
     const INPUT_PIN_NUM: usize,
     const OUTPUT_PIN_NUM: usize,
+    const COL2ROW: bool = true,
 > {
     /// Input pins of the pcb matrix
     input_pins: [In; INPUT_PIN_NUM],
@@ -86,8 +205,12 @@ pub struct Matrix<
     key_states: [[KeyState; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
     /// Start scanning
     scan_start: Option<Instant>,
-    /// Current scan pos: (out_idx, in_idx)
-    scan_pos: (usize, usize),
+    /// Keys changed in the last scan, which are not reported yet
+    changed: [[bool; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+    /// Pin active level
+    low_active: bool,
+    /// Suppress the press of ghost keys
+    ghost_detection: bool,
 }
 
 impl<
@@ -97,23 +220,40 @@ impl<
         D: DebouncerTrait,
         const INPUT_PIN_NUM: usize,
         const OUTPUT_PIN_NUM: usize,
-    > Matrix<In, Out, D, INPUT_PIN_NUM, OUTPUT_PIN_NUM>
+        const COL2ROW: bool,
+    > Matrix<In, Out, D, INPUT_PIN_NUM, OUTPUT_PIN_NUM, COL2ROW>
 {
     /// Create a matrix from input and output pins.
     pub fn new(
         input_pins: [In; INPUT_PIN_NUM],
-        output_pins: [Out; OUTPUT_PIN_NUM],
+        mut output_pins: [Out; OUTPUT_PIN_NUM],
         debouncer: D,
+        low_active: bool,
     ) -> Self {
+        // All output pins are at the idle level when not scanning
+        for out_pin in output_pins.iter_mut() {
+            set_output_active(out_pin, false, low_active);
+        }
         Matrix {
             input_pins,
             output_pins,
             debouncer,
             key_states: [[KeyState::new(); INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
             scan_start: None,
-            scan_pos: (0, 0),
+            changed: [[false; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+            low_active,
+            ghost_detection: false,
         }
     }
+
+    /// Enable or disable ghost detection.
+    ///
+    /// When it's enabled, the press of a key which completes a rectangle with 3 pressed keys is suppressed,
+    /// until any of the 3 keys is released.
+    pub fn with_ghost_detection(mut self, ghost_detection: bool) -> Self {
+        self.ghost_detection = ghost_detection;
+        self
+    }
 }
 
 impl<
@@ -123,47 +263,46 @@ impl<
         D: DebouncerTrait,
         const INPUT_PIN_NUM: usize,
         const OUTPUT_PIN_NUM: usize,
-    > InputDevice for Matrix<In, Out, D, INPUT_PIN_NUM, OUTPUT_PIN_NUM>
+        const COL2ROW: bool,
+    > InputDevice for Matrix<In, Out, D, INPUT_PIN_NUM, OUTPUT_PIN_NUM, COL2ROW>
 {
     async fn read_event(&mut self) -> crate::event::Event {
         loop {
-            let (out_idx_start, in_idx_start) = self.scan_pos;
+            // Report the changes of the last scan one by one
+            if let Some((out_idx, in_idx)) = take_changed_key(&mut self.changed) {
+                let (row, col) = if COL2ROW {
+                    (in_idx, out_idx)
+                } else {
+                    (out_idx, in_idx)
+                };
+                return Event::Key(KeyEvent {
+                    row: row as u8,
+                    col: col as u8,
+                    pressed: self.key_states[out_idx][in_idx].pressed,
+                });
+            }
+
             #[cfg(feature = "async_matrix")]
             self.wait_for_key().await;
 
-            // Scan matrix and send report
-            for out_idx in out_idx_start..self.output_pins.len() {
-                // Pull up output pin, wait 1us ensuring the change comes into effect
+            // Scan the whole matrix, the debounced changes are committed after the scan
+            let mut changed = [[false; INPUT_PIN_NUM]; OUTPUT_PIN_NUM];
+            for out_idx in 0..self.output_pins.len() {
+                // Set output pin to active level, wait 1us ensuring the change comes into effect
                 if let Some(out_pin) = self.output_pins.get_mut(out_idx) {
-                    out_pin.set_high().ok();
+                    set_output_active(out_pin, true, self.low_active);
                 }
                 Timer::after_micros(1).await;
-                for in_idx in in_idx_start..self.input_pins.len() {
+                for in_idx in 0..self.input_pins.len() {
                     let in_pin = self.input_pins.get_mut(in_idx).unwrap();
                     // Check input pins and debounce
                     let debounce_state = self.debouncer.detect_change_with_debounce(
                         in_idx,
                         out_idx,
-                        in_pin.is_high().ok().unwrap_or_default(),
+                        is_input_active(in_pin, self.low_active),
                         &self.key_states[out_idx][in_idx],
                     );
-
-                    if let DebounceState::Debounced = debounce_state {
-                        self.key_states[out_idx][in_idx].toggle_pressed();
-                        #[cfg(feature = "col2row")]
-                        let (row, col, key_state) =
-                            (in_idx, out_idx, self.key_states[out_idx][in_idx]);
-                        #[cfg(not(feature = "col2row"))]
-                        let (row, col, key_state) =
-                            (out_idx, in_idx, self.key_states[out_idx][in_idx]);
-
-                        self.scan_pos = (out_idx, in_idx);
-                        return Event::Key(KeyEvent {
-                            row: row as u8,
-                            col: col as u8,
-                            pressed: key_state.pressed,
-                        });
-                    }
+                    changed[out_idx][in_idx] = matches!(debounce_state, DebounceState::Debounced);
 
                     // If there's key still pressed, always refresh the self.scan_start
                     #[cfg(feature = "async_matrix")]
@@ -172,12 +311,13 @@ impl<
                     }
                 }
 
-                // Pull it back to low
+                // Set it back to idle level
                 if let Some(out_pin) = self.output_pins.get_mut(out_idx) {
-                    out_pin.set_low().ok();
+                    set_output_active(out_pin, false, self.low_active);
                 }
             }
-            self.scan_pos = (0, 0);
+            commit_scan(&mut self.key_states, &mut changed, self.ghost_detection);
+            self.changed = changed;
         }
     }
 }
@@ -189,16 +329,19 @@ impl<
         D: DebouncerTrait,
         const INPUT_PIN_NUM: usize,
         const OUTPUT_PIN_NUM: usize,
-    > MatrixTrait for Matrix<In, Out, D, INPUT_PIN_NUM, OUTPUT_PIN_NUM>
+        const COL2ROW: bool,
+    > MatrixTrait for Matrix<In, Out, D, INPUT_PIN_NUM, OUTPUT_PIN_NUM, COL2ROW>
 {
-    #[cfg(feature = "col2row")]
-    const ROW: usize = INPUT_PIN_NUM;
-    #[cfg(feature = "col2row")]
-    const COL: usize = OUTPUT_PIN_NUM;
-    #[cfg(not(feature = "col2row"))]
-    const ROW: usize = OUTPUT_PIN_NUM;
-    #[cfg(not(feature = "col2row"))]
-    const COL: usize = INPUT_PIN_NUM;
+    const ROW: usize = if COL2ROW {
+        INPUT_PIN_NUM
+    } else {
+        OUTPUT_PIN_NUM
+    };
+    const COL: usize = if COL2ROW {
+        OUTPUT_PIN_NUM
+    } else {
+        INPUT_PIN_NUM
+    };
 
     #[cfg(feature = "async_matrix")]
     async fn wait_for_key(&mut self) {
@@ -210,27 +353,43 @@ impl<
                 self.scan_start = None;
             }
         }
-        // First, set all output pin to high
+        // First, set all output pin to active level
         for out in self.output_pins.iter_mut() {
-            out.set_high().ok();
+            set_output_active(out, true, self.low_active);
         }
         Timer::after_micros(1).await;
-        let mut futs: Vec<_, INPUT_PIN_NUM> = self
-            .input_pins
-            .iter_mut()
-            .map(|input_pin| input_pin.wait_for_high())
-            .collect();
-        let _ = select_slice(futs.as_mut_slice()).await;
+        wait_for_any_input_active(&mut self.input_pins, self.low_active).await;
 
-        // Set all output pins back to low
+        // Set all output pins back to idle level
         for out in self.output_pins.iter_mut() {
-            out.set_low().ok();
+            set_output_active(out, false, self.low_active);
         }
 
         self.scan_start = Some(Instant::now());
     }
 }
 
+/// Wait until any of the input pins goes to the active level
+#[cfg(feature = "async_matrix")]
+pub(crate) async fn wait_for_any_input_active<In: Wait, const INPUT_PIN_NUM: usize>(
+    input_pins: &mut [In; INPUT_PIN_NUM],
+    low_active: bool,
+) {
+    if low_active {
+        let mut futs: Vec<_, INPUT_PIN_NUM> = input_pins
+            .iter_mut()
+            .map(|input_pin| input_pin.wait_for_low())
+            .collect();
+        let _ = select_slice(futs.as_mut_slice()).await;
+    } else {
+        let mut futs: Vec<_, INPUT_PIN_NUM> = input_pins
+            .iter_mut()
+            .map(|input_pin| input_pin.wait_for_high())
+            .collect();
+        let _ = select_slice(futs.as_mut_slice()).await;
+    }
+}
+
 pub struct TestMatrix<const ROW: usize, const COL: usize> {
     last: bool,
 }
@@ -271,3 +430,306 @@ impl<const ROW: usize, const COL: usize> InputDevice for TestMatrix<ROW, COL> {
         })
     }
 }
+
+/// Fake flex pins connected by keys, for testing matrices of flex pins
+#[cfg(test)]
+pub(crate) mod fake_flex_pin {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::FlexPin;
+
+    #[derive(Clone, Copy)]
+    enum Mode {
+        Input { pull_up: bool },
+        Output { high: bool },
+    }
+
+    /// A net of pins, a pressed key `(from, to)` connects pin `from` to pin `to` when `from` is an output.
+    ///
+    /// Diodes are not simulated, a key of `(from, to)` is never seen when `to` is driven.
+    #[derive(Default)]
+    pub(crate) struct FakeNet {
+        modes: Vec<Mode>,
+        pub(crate) pressed: Vec<(usize, usize)>,
+    }
+
+    impl FakeNet {
+        /// Add `N` pins to the net, their indices start from the number of existing pins
+        pub(crate) fn pins<const N: usize>(net: &Rc<RefCell<FakeNet>>) -> [FakeFlexPin; N] {
+            let start = net.borrow().modes.len();
+            net.borrow_mut()
+                .modes
+                .extend([Mode::Input { pull_up: false }; N]);
+            core::array::from_fn(|i| FakeFlexPin {
+                net: net.clone(),
+                idx: start + i,
+            })
+        }
+    }
+
+    pub(crate) struct FakeFlexPin {
+        net: Rc<RefCell<FakeNet>>,
+        idx: usize,
+    }
+
+    impl FlexPin for FakeFlexPin {
+        fn set_as_input(&mut self, pull_up: bool) {
+            self.net.borrow_mut().modes[self.idx] = Mode::Input { pull_up };
+        }
+
+        fn set_as_output(&mut self, high: bool) {
+            self.net.borrow_mut().modes[self.idx] = Mode::Output { high };
+        }
+
+        fn is_high(&mut self) -> bool {
+            let net = self.net.borrow();
+            match net.modes[self.idx] {
+                Mode::Output { high } => high,
+                Mode::Input { pull_up } => net
+                    .pressed
+                    .iter()
+                    .filter(|(_, to)| *to == self.idx)
+                    .find_map(|(from, _)| match net.modes[*from] {
+                        Mode::Output { high } => Some(high),
+                        Mode::Input { .. } => None,
+                    })
+                    .unwrap_or(pull_up),
+            }
+        }
+    }
+}
+
+/// Helpers for testing matrices
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use embassy_futures::block_on;
+
+    use crate::event::Event;
+    use crate::input_device::InputDevice;
+
+    /// Read a key event from the matrix, as (row, col, pressed)
+    pub(crate) fn read_key(matrix: &mut impl InputDevice) -> (u8, u8, bool) {
+        match block_on(matrix.read_event()) {
+            Event::Key(key) => (key.row, key.col, key.pressed),
+            _ => panic!("Not a key event"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::convert::Infallible;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use embassy_time::Duration;
+    use embedded_hal::digital::ErrorType;
+
+    use super::*;
+    use crate::config::{DebounceAlgorithm, DebounceConfig};
+    use crate::debounce::per_key_debouncer::PerKeyDebouncer;
+    use crate::matrix::test_utils::read_key;
+
+    /// A 2x2 matrix without diodes, an input is high if it's connected to a high output through pressed keys
+    #[derive(Default)]
+    struct DiodelessNet {
+        outputs: [bool; 2],
+        /// Pressed keys, as (out_idx, in_idx)
+        pressed: Vec<(usize, usize)>,
+        /// Number of started scans
+        scans: usize,
+    }
+
+    impl DiodelessNet {
+        fn is_input_high(&self, in_idx: usize) -> bool {
+            let mut outputs = self.outputs;
+            let mut inputs = [false; 2];
+            // Spread the high level through pressed keys, in both directions
+            loop {
+                let mut changed = false;
+                for &(o, i) in &self.pressed {
+                    if outputs[o] != inputs[i] {
+                        outputs[o] = true;
+                        inputs[i] = true;
+                        changed = true;
+                    }
+                }
+                if !changed {
+                    return inputs[in_idx];
+                }
+            }
+        }
+    }
+
+    struct FakeOutput(Rc<RefCell<DiodelessNet>>, usize);
+
+    impl ErrorType for FakeOutput {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for FakeOutput {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.0.borrow_mut().outputs[self.1] = false;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            let mut net = self.0.borrow_mut();
+            net.outputs[self.1] = true;
+            if self.1 == 0 {
+                net.scans += 1;
+            }
+            Ok(())
+        }
+    }
+
+    struct FakeInput(Rc<RefCell<DiodelessNet>>, usize);
+
+    impl ErrorType for FakeInput {
+        type Error = Infallible;
+    }
+
+    impl InputPin for FakeInput {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(self.0.borrow().is_input_high(self.1))
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.0.borrow().is_input_high(self.1))
+        }
+    }
+
+    fn matrix(
+        net: &Rc<RefCell<DiodelessNet>>,
+        ghost_detection: bool,
+    ) -> Matrix<FakeInput, FakeOutput, PerKeyDebouncer<2, 2>, 2, 2> {
+        let debouncer = PerKeyDebouncer::new(DebounceConfig {
+            algorithm: DebounceAlgorithm::SymDeferPk,
+            press_time: Duration::from_millis(0),
+            release_time: Duration::from_millis(0),
+        });
+        Matrix::new(
+            [FakeInput(net.clone(), 0), FakeInput(net.clone(), 1)],
+            [FakeOutput(net.clone(), 0), FakeOutput(net.clone(), 1)],
+            debouncer,
+            false,
+        )
+        .with_ghost_detection(ghost_detection)
+    }
+
+    #[test]
+    fn test_is_ghost_press() {
+        let mut pressed = [[false; 3]; 3];
+        pressed[0][0] = true;
+        pressed[0][2] = true;
+        pressed[1][2] = true;
+        assert!(!is_ghost_press(&pressed, 0, 0));
+        pressed[1][0] = true;
+        // All the 4 corners are in the ghost rectangle
+        assert!(is_ghost_press(&pressed, 1, 0));
+        assert!(is_ghost_press(&pressed, 0, 0));
+        // Not in the same rectangle
+        assert!(!is_ghost_press(&pressed, 2, 0));
+        assert!(!is_ghost_press(&pressed, 1, 1));
+    }
+
+    #[test]
+    fn test_commit_scan() {
+        let mut key_states = [[KeyState::new(); 2]; 2];
+        key_states[0][0].pressed = true;
+        key_states[0][1].pressed = true;
+        // Both presses in the ghost rectangle are dropped
+        let mut changed = [[false, false], [true, true]];
+        commit_scan(&mut key_states, &mut changed, true);
+        assert_eq!(changed, [[false, false], [false, false]]);
+        assert!(!key_states[1][0].pressed && !key_states[1][1].pressed);
+
+        // The rectangle is cleared by the release in the same scan
+        let mut changed = [[true, false], [false, true]];
+        commit_scan(&mut key_states, &mut changed, true);
+        assert!(!key_states[0][0].pressed && key_states[1][1].pressed);
+        assert_eq!(take_changed_key(&mut changed), Some((0, 0)));
+        assert_eq!(take_changed_key(&mut changed), Some((1, 1)));
+        assert_eq!(take_changed_key(&mut changed), None);
+    }
+
+    /// Run the matrix until a full scan is done, returns the key event read during the scan if there's any
+    fn scan_once(
+        net: &Rc<RefCell<DiodelessNet>>,
+        matrix: &mut impl InputDevice,
+    ) -> Option<(u8, u8, bool)> {
+        let scans = net.borrow().scans;
+        // The next scan is started after the current full scan is committed
+        let scanned = core::future::poll_fn(|cx| {
+            if net.borrow().scans > scans + 1 {
+                core::task::Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                core::task::Poll::Pending
+            }
+        });
+        match embassy_futures::block_on(embassy_futures::select::select(
+            matrix.read_event(),
+            scanned,
+        )) {
+            embassy_futures::select::Either::First(Event::Key(key)) => {
+                Some((key.row, key.col, key.pressed))
+            }
+            _ => None,
+        }
+    }
+
+    /// Hold keys at (out 0, in 1) and (out 0, in 0), then press the key at (out 1, in 0)
+    fn press_three_corners(net: &Rc<RefCell<DiodelessNet>>, matrix: &mut impl InputDevice) {
+        // Input pins are rows, output pins are cols
+        net.borrow_mut().pressed.push((0, 1));
+        assert_eq!(read_key(matrix), (1, 0, true));
+        net.borrow_mut().pressed.push((0, 0));
+        assert_eq!(read_key(matrix), (0, 0, true));
+        net.borrow_mut().pressed.push((1, 0));
+    }
+
+    #[test]
+    fn test_ghost_key_without_detection() {
+        let net = Rc::new(RefCell::new(DiodelessNet::default()));
+        let mut matrix = matrix(&net, false);
+        press_three_corners(&net, &mut matrix);
+        assert_eq!(read_key(&mut matrix), (0, 1, true));
+        // The ghost key at out 1, in 1
+        assert_eq!(read_key(&mut matrix), (1, 1, true));
+    }
+
+    #[test]
+    fn test_ghost_key_suppressed() {
+        let net = Rc::new(RefCell::new(DiodelessNet::default()));
+        let mut matrix = matrix(&net, true);
+        press_three_corners(&net, &mut matrix);
+        // Both the third key and the ghost key are suppressed, until the ghost rectangle is cleared
+        assert_eq!(scan_once(&net, &mut matrix), None);
+        net.borrow_mut().pressed.retain(|k| *k != (0, 0));
+        assert_eq!(read_key(&mut matrix), (0, 0, false));
+        assert_eq!(read_key(&mut matrix), (0, 1, true));
+        net.borrow_mut().pressed.retain(|k| *k != (1, 0));
+        assert_eq!(read_key(&mut matrix), (0, 1, false));
+    }
+
+    #[test]
+    fn test_ghost_key_scanned_before_real_key() {
+        let net = Rc::new(RefCell::new(DiodelessNet::default()));
+        let mut matrix = matrix(&net, true);
+        net.borrow_mut().pressed.push((0, 0));
+        assert_eq!(read_key(&mut matrix), (0, 0, true));
+        net.borrow_mut().pressed.push((0, 1));
+        assert_eq!(read_key(&mut matrix), (1, 0, true));
+        // Press the key at (out 1, in 1), the ghost at (out 1, in 0) is scanned first
+        net.borrow_mut().pressed.push((1, 1));
+        assert_eq!(scan_once(&net, &mut matrix), None);
+        net.borrow_mut().pressed.retain(|k| *k != (0, 0));
+        assert_eq!(read_key(&mut matrix), (0, 0, false));
+        // The ghost key is never reported
+        assert_eq!(read_key(&mut matrix), (1, 1, true));
+        net.borrow_mut().pressed.retain(|k| *k != (1, 1));
+        assert_eq!(read_key(&mut matrix), (1, 1, false));
+    }
+}
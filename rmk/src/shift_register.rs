@@ -0,0 +1,555 @@
+//! Matrix scanning through shift registers
+//!
+//! Low-pin-count boards often drive the output side of the matrix through a chain of 74HC595,
+//! or read the input side through a chain of 74HC165, both connected to an SPI bus.
+//! [`ShiftRegisterMatrix`] scans a matrix whose outputs are [`ScanOutputs`] and inputs are [`ScanInputs`],
+//! which can be either native GPIO pins, or a shift register chain:
+//!
+//! - [`Hc595`]: outputs driven by a 74HC595 chain, the latch(RCLK) is connected to the CS pin of the `SpiDevice`
+//! - [`Hc165`]: inputs read from a 74HC165 chain, the clock enable(CE) is connected to the CS pin of the `SpiDevice`,
+//!   the parallel load(PL) is connected to a separate output pin
+//!
+//! Output/input `i` is the `i % 8`th pin of the `i / 8`th chip in the chain, the 0th chip is the one connected to the MCU.
+use embassy_futures::select::select;
+use embassy_time::{Instant, Timer};
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal::spi::Error;
+#[cfg(feature = "async_matrix")]
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::SpiDevice;
+
+use crate::debounce::{DebounceState, DebouncerTrait};
+use crate::event::{Event, KeyEvent};
+use crate::input_device::InputDevice;
+use crate::matrix::{is_input_active, set_output_active, KeyState, MatrixTrait};
+
+/// Output side of a matrix
+pub trait ScanOutputs {
+    /// Set outputs whose bit in `active` is 1 to the active level, and others to the idle level
+    async fn set_active(&mut self, active: u32, low_active: bool);
+}
+
+/// Input side of a matrix
+pub trait ScanInputs {
+    /// Read all inputs, the bit of an input is 1 if it's at the active level
+    async fn read_active(&mut self, low_active: bool) -> u32;
+
+    /// Wait until any of the inputs is at the active level.
+    ///
+    /// The default implementation polls the inputs every 1ms.
+    async fn wait_for_active(&mut self, low_active: bool) {
+        while self.read_active(low_active).await == 0 {
+            Timer::after_millis(1).await;
+        }
+    }
+}
+
+impl<Out: OutputPin, const N: usize> ScanOutputs for [Out; N] {
+    async fn set_active(&mut self, active: u32, low_active: bool) {
+        for (i, pin) in self.iter_mut().enumerate() {
+            set_output_active(pin, active & (1 << i) != 0, low_active);
+        }
+    }
+}
+
+fn read_input_pins<In: InputPin>(pins: &mut [In], low_active: bool) -> u32 {
+    let mut active = 0;
+    for (i, pin) in pins.iter_mut().enumerate() {
+        if is_input_active(pin, low_active) {
+            active |= 1 << i;
+        }
+    }
+    active
+}
+
+#[cfg(not(feature = "async_matrix"))]
+impl<In: InputPin, const N: usize> ScanInputs for [In; N] {
+    async fn read_active(&mut self, low_active: bool) -> u32 {
+        read_input_pins(self, low_active)
+    }
+}
+
+#[cfg(feature = "async_matrix")]
+impl<In: Wait + InputPin, const N: usize> ScanInputs for [In; N] {
+    async fn read_active(&mut self, low_active: bool) -> u32 {
+        read_input_pins(self, low_active)
+    }
+
+    async fn wait_for_active(&mut self, low_active: bool) {
+        crate::matrix::wait_for_any_input_active(self, low_active).await;
+    }
+}
+
+/// Outputs joined from two [`ScanOutputs`], such as native pins and an I/O expander.
+///
+/// The first `FIRST_NUM` outputs are from `first`, the rest are from `second`.
+pub struct MixedOutputs<A: ScanOutputs, B: ScanOutputs, const FIRST_NUM: usize> {
+    first: A,
+    second: B,
+}
+
+impl<A: ScanOutputs, B: ScanOutputs, const FIRST_NUM: usize> MixedOutputs<A, B, FIRST_NUM> {
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+}
+
+impl<A: ScanOutputs, B: ScanOutputs, const FIRST_NUM: usize> ScanOutputs
+    for MixedOutputs<A, B, FIRST_NUM>
+{
+    async fn set_active(&mut self, active: u32, low_active: bool) {
+        let first_mask = u32::MAX.checked_shr(32 - FIRST_NUM as u32).unwrap_or(0);
+        self.first.set_active(active & first_mask, low_active).await;
+        self.second
+            .set_active(
+                active.checked_shr(FIRST_NUM as u32).unwrap_or(0),
+                low_active,
+            )
+            .await;
+    }
+}
+
+/// Inputs joined from two [`ScanInputs`], such as native pins and an I/O expander.
+///
+/// The first `FIRST_NUM` inputs are from `first`, the rest are from `second`.
+pub struct MixedInputs<A: ScanInputs, B: ScanInputs, const FIRST_NUM: usize> {
+    first: A,
+    second: B,
+}
+
+impl<A: ScanInputs, B: ScanInputs, const FIRST_NUM: usize> MixedInputs<A, B, FIRST_NUM> {
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+}
+
+impl<A: ScanInputs, B: ScanInputs, const FIRST_NUM: usize> ScanInputs
+    for MixedInputs<A, B, FIRST_NUM>
+{
+    async fn read_active(&mut self, low_active: bool) -> u32 {
+        let first = self.first.read_active(low_active).await;
+        let second = self.second.read_active(low_active).await;
+        first | second.checked_shl(FIRST_NUM as u32).unwrap_or(0)
+    }
+
+    async fn wait_for_active(&mut self, low_active: bool) {
+        select(
+            self.first.wait_for_active(low_active),
+            self.second.wait_for_active(low_active),
+        )
+        .await;
+    }
+}
+
+/// A chain of `CHIPS` 74HC595 serial-in parallel-out shift registers, at most 4 chips.
+pub struct Hc595<S: SpiDevice, const CHIPS: usize = 1> {
+    spi: S,
+}
+
+impl<S: SpiDevice, const CHIPS: usize> Hc595<S, CHIPS> {
+    pub fn new(spi: S) -> Self {
+        assert!(CHIPS <= 4, "At most 4 shift registers are supported");
+        Self { spi }
+    }
+}
+
+impl<S: SpiDevice, const CHIPS: usize> ScanOutputs for Hc595<S, CHIPS> {
+    async fn set_active(&mut self, active: u32, low_active: bool) {
+        let levels = if low_active { !active } else { active };
+        // The last shifted byte ends up in the 0th chip
+        let mut buf = [0u8; CHIPS];
+        for (i, byte) in buf.iter_mut().rev().enumerate() {
+            *byte = (levels >> (i * 8)) as u8;
+        }
+        if let Err(e) = self.spi.write(&buf).await {
+            error!("Failed to write 74HC595: {:?}", e.kind());
+        }
+    }
+}
+
+/// A chain of `CHIPS` 74HC165 parallel-in serial-out shift registers, at most 4 chips.
+pub struct Hc165<S: SpiDevice, L: OutputPin, const CHIPS: usize = 1> {
+    spi: S,
+    /// Parallel load pin, active low
+    load: L,
+}
+
+impl<S: SpiDevice, L: OutputPin, const CHIPS: usize> Hc165<S, L, CHIPS> {
+    pub fn new(spi: S, mut load: L) -> Self {
+        assert!(CHIPS <= 4, "At most 4 shift registers are supported");
+        load.set_high().ok();
+        Self { spi, load }
+    }
+}
+
+impl<S: SpiDevice, L: OutputPin, const CHIPS: usize> ScanInputs for Hc165<S, L, CHIPS> {
+    async fn read_active(&mut self, low_active: bool) -> u32 {
+        // Latch the inputs
+        self.load.set_low().ok();
+        self.load.set_high().ok();
+        let mut buf = [0u8; CHIPS];
+        if let Err(e) = self.spi.read(&mut buf).await {
+            error!("Failed to read 74HC165: {:?}", e.kind());
+            return 0;
+        }
+        // The 0th chip is shifted out first
+        let levels = buf
+            .iter()
+            .enumerate()
+            .fold(0u32, |levels, (i, b)| levels | ((*b as u32) << (i * 8)));
+        if low_active {
+            // Bits of missing chips are never active
+            let mask = u32::MAX.checked_shr(32 - (CHIPS * 8) as u32).unwrap_or(0);
+            !levels & mask
+        } else {
+            levels
+        }
+    }
+}
+
+/// Matrix whose outputs or inputs are connected through shift registers.
+///
+/// It scans any [`ScanOutputs`] and [`ScanInputs`], so it's also used as [`crate::io_expander::IoExpanderMatrix`].
+/// The diode direction and pin polarity are same as [`crate::matrix::Matrix`].
+/// `ROW_OFFSET` and `COL_OFFSET` are used when the matrix is on the split central, set them to 0 otherwise.
+/// At most 32 inputs and 32 outputs are supported.
+pub struct ShiftRegisterMatrix<
+    I: ScanInputs,
+    O: ScanOutputs,
+    D: DebouncerTrait,
+    const ROW_OFFSET: usize,
+    const COL_OFFSET: usize,
+    const INPUT_NUM: usize,
+    const OUTPUT_NUM: usize,
+    const COL2ROW: bool = true,
+> {
+    /// Input side of the matrix
+    inputs: I,
+    /// Output side of the matrix
+    outputs: O,
+    /// Debouncer
+    debouncer: D,
+    /// Key state matrix
+    key_states: [[KeyState; INPUT_NUM]; OUTPUT_NUM],
+    /// Start scanning
+    scan_start: Option<Instant>,
+    /// Current scan pos: (out_idx, in_idx)
+    scan_pos: (usize, usize),
+    /// Pin active level
+    low_active: bool,
+}
+
+impl<
+        I: ScanInputs,
+        O: ScanOutputs,
+        D: DebouncerTrait,
+        const ROW_OFFSET: usize,
+        const COL_OFFSET: usize,
+        const INPUT_NUM: usize,
+        const OUTPUT_NUM: usize,
+        const COL2ROW: bool,
+    > ShiftRegisterMatrix<I, O, D, ROW_OFFSET, COL_OFFSET, INPUT_NUM, OUTPUT_NUM, COL2ROW>
+{
+    /// Create a matrix from inputs and outputs.
+    pub fn new(inputs: I, outputs: O, debouncer: D, low_active: bool) -> Self {
+        assert!(
+            INPUT_NUM <= 32 && OUTPUT_NUM <= 32,
+            "At most 32 inputs and outputs are supported"
+        );
+        Self {
+            inputs,
+            outputs,
+            debouncer,
+            key_states: [[KeyState::new(); INPUT_NUM]; OUTPUT_NUM],
+            scan_start: None,
+            scan_pos: (0, 0),
+            low_active,
+        }
+    }
+}
+
+impl<
+        I: ScanInputs,
+        O: ScanOutputs,
+        D: DebouncerTrait,
+        const ROW_OFFSET: usize,
+        const COL_OFFSET: usize,
+        const INPUT_NUM: usize,
+        const OUTPUT_NUM: usize,
+        const COL2ROW: bool,
+    > InputDevice
+    for ShiftRegisterMatrix<I, O, D, ROW_OFFSET, COL_OFFSET, INPUT_NUM, OUTPUT_NUM, COL2ROW>
+{
+    async fn read_event(&mut self) -> Event {
+        loop {
+            let (out_idx_start, in_idx_start) = self.scan_pos;
+            #[cfg(feature = "async_matrix")]
+            self.wait_for_key().await;
+
+            for out_idx in out_idx_start..OUTPUT_NUM {
+                // Activate current output, wait 1us ensuring the change comes into effect
+                self.outputs.set_active(1 << out_idx, self.low_active).await;
+                Timer::after_micros(1).await;
+                let active = self.inputs.read_active(self.low_active).await;
+
+                let in_idx_start = if out_idx == out_idx_start {
+                    in_idx_start
+                } else {
+                    0
+                };
+                for in_idx in in_idx_start..INPUT_NUM {
+                    let debounce_state = self.debouncer.detect_change_with_debounce(
+                        in_idx,
+                        out_idx,
+                        active & (1 << in_idx) != 0,
+                        &self.key_states[out_idx][in_idx],
+                    );
+
+                    if let DebounceState::Debounced = debounce_state {
+                        self.key_states[out_idx][in_idx].toggle_pressed();
+                        let (row, col) = if COL2ROW {
+                            (in_idx, out_idx)
+                        } else {
+                            (out_idx, in_idx)
+                        };
+
+                        self.scan_pos = (out_idx, in_idx);
+                        return Event::Key(KeyEvent {
+                            row: (row + ROW_OFFSET) as u8,
+                            col: (col + COL_OFFSET) as u8,
+                            pressed: self.key_states[out_idx][in_idx].pressed,
+                        });
+                    }
+
+                    // If there's key still pressed, always refresh the self.scan_start
+                    #[cfg(feature = "async_matrix")]
+                    if self.key_states[out_idx][in_idx].pressed {
+                        self.scan_start = Some(Instant::now());
+                    }
+                }
+            }
+
+            // Set all outputs back to idle level
+            self.outputs.set_active(0, self.low_active).await;
+            self.scan_pos = (0, 0);
+        }
+    }
+}
+
+impl<
+        I: ScanInputs,
+        O: ScanOutputs,
+        D: DebouncerTrait,
+        const ROW_OFFSET: usize,
+        const COL_OFFSET: usize,
+        const INPUT_NUM: usize,
+        const OUTPUT_NUM: usize,
+        const COL2ROW: bool,
+    > MatrixTrait
+    for ShiftRegisterMatrix<I, O, D, ROW_OFFSET, COL_OFFSET, INPUT_NUM, OUTPUT_NUM, COL2ROW>
+{
+    const ROW: usize = if COL2ROW { INPUT_NUM } else { OUTPUT_NUM };
+    const COL: usize = if COL2ROW { OUTPUT_NUM } else { INPUT_NUM };
+
+    #[cfg(feature = "async_matrix")]
+    async fn wait_for_key(&mut self) {
+        if let Some(start_time) = self.scan_start {
+            // If no key press over 1ms, stop scanning and wait for interupt
+            if start_time.elapsed().as_millis() <= 1 {
+                return;
+            } else {
+                self.scan_start = None;
+            }
+        }
+        // Activate all outputs, then wait for any input
+        let all = u32::MAX >> (32 - OUTPUT_NUM);
+        self.outputs.set_active(all, self.low_active).await;
+        Timer::after_micros(1).await;
+        self.inputs.wait_for_active(self.low_active).await;
+        self.outputs.set_active(0, self.low_active).await;
+
+        self.scan_start = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+
+    use embassy_futures::block_on;
+    use embassy_time::Duration;
+    use embedded_hal::digital::ErrorType as PinErrorType;
+    use embedded_hal_async::spi::{ErrorType as SpiErrorType, Operation};
+
+    use super::*;
+    use crate::config::{DebounceAlgorithm, DebounceConfig};
+    use crate::debounce::per_key_debouncer::PerKeyDebouncer;
+    use crate::matrix::test_utils::read_key;
+
+    /// Fake SPI bus connected to a 74HC165 chain, which shifts out `levels`
+    struct FakeSpi {
+        levels: Vec<u8>,
+    }
+
+    impl SpiErrorType for FakeSpi {
+        type Error = Infallible;
+    }
+
+    impl SpiDevice for FakeSpi {
+        async fn transaction(
+            &mut self,
+            operations: &mut [Operation<'_, u8>],
+        ) -> Result<(), Self::Error> {
+            for operation in operations {
+                if let Operation::Read(buf) = operation {
+                    buf.copy_from_slice(&self.levels[..buf.len()]);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    struct FakePin;
+
+    impl PinErrorType for FakePin {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for FakePin {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// A 74HC595 chain driving the outputs of a key matrix
+    struct FakeHc595Board {
+        /// Latched output levels, bit `i` is the level of output `i`
+        latch: u32,
+        /// Pressed keys, as (output, input)
+        pressed: Vec<(usize, usize)>,
+    }
+
+    /// Fake SPI bus connected to the 74HC595 chain, the last shifted byte is latched by the 0th chip
+    struct FakeHc595Spi<'a>(&'a RefCell<FakeHc595Board>);
+
+    impl SpiErrorType for FakeHc595Spi<'_> {
+        type Error = Infallible;
+    }
+
+    impl SpiDevice for FakeHc595Spi<'_> {
+        async fn transaction(
+            &mut self,
+            operations: &mut [Operation<'_, u8>],
+        ) -> Result<(), Self::Error> {
+            for operation in operations {
+                if let Operation::Write(buf) = operation {
+                    self.0.borrow_mut().latch = buf
+                        .iter()
+                        .fold(0, |latch, byte| (latch << 8) | *byte as u32);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Native inputs of the matrix, which read the latched outputs through the pressed keys
+    struct WiredInputs<'a>(&'a RefCell<FakeHc595Board>);
+
+    impl ScanInputs for WiredInputs<'_> {
+        async fn read_active(&mut self, low_active: bool) -> u32 {
+            let board = self.0.borrow();
+            let driven = if low_active {
+                !board.latch
+            } else {
+                board.latch
+            };
+            board
+                .pressed
+                .iter()
+                .filter(|(output, _)| driven & (1 << output) != 0)
+                .fold(0, |active, (_, input)| active | (1 << input))
+        }
+    }
+
+    fn debouncer<const IN: usize, const OUT: usize>() -> PerKeyDebouncer<IN, OUT> {
+        PerKeyDebouncer::new(DebounceConfig {
+            algorithm: DebounceAlgorithm::SymDeferPk,
+            press_time: Duration::from_millis(0),
+            release_time: Duration::from_millis(0),
+        })
+    }
+
+    fn read_hc165<const CHIPS: usize>(levels: &[u8], low_active: bool) -> u32 {
+        let spi = FakeSpi {
+            levels: levels.to_vec(),
+        };
+        let mut hc165: Hc165<_, _, CHIPS> = Hc165::new(spi, FakePin);
+        block_on(hc165.read_active(low_active))
+    }
+
+    #[test]
+    fn test_hc165_low_active() {
+        // All keys are released, inputs are pulled up
+        assert_eq!(read_hc165::<1>(&[0xFF], true), 0);
+        assert_eq!(read_hc165::<3>(&[0xFF, 0xFF, 0xFF], true), 0);
+        assert_eq!(read_hc165::<4>(&[0xFF, 0xFF, 0xFF, 0xFF], true), 0);
+        // Input 0 and input 15 are pressed
+        assert_eq!(read_hc165::<2>(&[0xFE, 0x7F], true), 0x8001);
+    }
+
+    #[test]
+    fn test_hc165_high_active() {
+        assert_eq!(read_hc165::<1>(&[0x00], false), 0);
+        assert_eq!(read_hc165::<2>(&[0x01, 0x80], false), 0x8001);
+        assert_eq!(read_hc165::<4>(&[0, 0, 0, 0x80], false), 0x8000_0000);
+    }
+
+    #[test]
+    fn test_hc595_matrix_scan() {
+        let board = RefCell::new(FakeHc595Board {
+            latch: 0,
+            pressed: Vec::new(),
+        });
+        // 2 rows on native inputs, 10 columns driven by 2 chained 74HC595
+        let mut matrix = ShiftRegisterMatrix::<_, _, _, 0, 0, 2, 10>::new(
+            WiredInputs(&board),
+            Hc595::<_, 2>::new(FakeHc595Spi(&board)),
+            debouncer::<2, 10>(),
+            true,
+        );
+
+        board.borrow_mut().pressed.push((9, 1));
+        assert_eq!(read_key(&mut matrix), (1, 9, true));
+        // Only output 9, the 1st pin of the 1st chip, is driven low
+        assert_eq!(board.borrow().latch, 0xFFFF & !(1 << 9));
+        board.borrow_mut().pressed.push((0, 0));
+        assert_eq!(read_key(&mut matrix), (0, 0, true));
+        assert_eq!(board.borrow().latch, 0xFFFE);
+        board.borrow_mut().pressed.clear();
+        assert_eq!(read_key(&mut matrix), (0, 0, false));
+        assert_eq!(read_key(&mut matrix), (1, 9, false));
+    }
+
+    #[test]
+    fn test_hc595_row2col_high_active() {
+        let board = RefCell::new(FakeHc595Board {
+            latch: 0,
+            pressed: vec![(2, 0)],
+        });
+        // 3 rows driven by a 74HC595, 1 column on native inputs
+        let mut matrix = ShiftRegisterMatrix::<_, _, _, 1, 2, 1, 3, false>::new(
+            WiredInputs(&board),
+            Hc595::<_, 1>::new(FakeHc595Spi(&board)),
+            debouncer::<1, 3>(),
+            false,
+        );
+
+        assert_eq!(read_key(&mut matrix), (3, 2, true));
+        assert_eq!(board.borrow().latch, 0b100);
+    }
+}
@@ -0,0 +1,117 @@
+//! Battery levels of split peripherals.
+//!
+//! Peripherals set their battery level by [`update_battery_level`], the level is sent to the central
+//! every time it's updated, and when the peripheral connects or reconnects to the central.
+//! The central keeps the latest level of each peripheral, which can be read by [`peripheral_battery_level`]
+//! to show it on LEDs or displays. On nRF BLE centrals, the levels are also reported to the host
+//! by an extra Battery Service for each peripheral.
+//!
+//! ```ignore
+//! // On the central
+//! if let Some(level) = rmk::split::battery::peripheral_battery_level(0) {
+//!     // Show the battery level of peripheral 0
+//! }
+//! ```
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::{Receiver, Watch};
+
+use crate::RawMutex;
+
+/// Maximum number of peripherals whose battery levels are kept on the central
+pub const PERIPHERAL_BATTERY_NUM: usize = 8;
+
+/// The battery level of the peripheral isn't received yet
+const UNKNOWN_BATTERY_LEVEL: u8 = 0xFF;
+
+/// Battery level of this peripheral
+static BATTERY_LEVEL: Watch<RawMutex, u8, 1> = Watch::new();
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNKNOWN: AtomicU8 = AtomicU8::new(UNKNOWN_BATTERY_LEVEL);
+
+/// Battery levels of peripherals, on the central
+static PERIPHERAL_BATTERY_LEVELS: [AtomicU8; PERIPHERAL_BATTERY_NUM] =
+    [UNKNOWN; PERIPHERAL_BATTERY_NUM];
+
+/// Signaled when the battery level of any peripheral is changed, on the central
+pub(crate) static PERIPHERAL_BATTERY_CHANGED: Signal<RawMutex, ()> = Signal::new();
+
+/// Update the battery level of this peripheral, in percent, it's sent to the central.
+///
+/// Levels larger than 100 are treated as 100.
+pub fn update_battery_level(level: u8) {
+    BATTERY_LEVEL.sender().send(level.min(100));
+}
+
+/// Get the latest battery level of this peripheral
+pub(crate) fn battery_level() -> Option<u8> {
+    BATTERY_LEVEL.try_get()
+}
+
+/// Get the receiver of the battery level of this peripheral
+pub(crate) fn battery_level_receiver() -> Option<Receiver<'static, RawMutex, u8, 1>> {
+    BATTERY_LEVEL.receiver()
+}
+
+/// Get the latest battery level of the peripheral with `id`, in percent.
+///
+/// Returns `None` if the peripheral hasn't reported its battery level, or it's disconnected.
+pub fn peripheral_battery_level(id: usize) -> Option<u8> {
+    PERIPHERAL_BATTERY_LEVELS
+        .get(id)
+        .map(|level| level.load(Ordering::Acquire))
+        .filter(|level| *level != UNKNOWN_BATTERY_LEVEL)
+}
+
+/// Set the battery level of the peripheral with `id`, `None` means unknown
+pub(crate) fn set_peripheral_battery_level(id: usize, level: Option<u8>) {
+    if let Some(battery_level) = PERIPHERAL_BATTERY_LEVELS.get(id) {
+        let level = level.map_or(UNKNOWN_BATTERY_LEVEL, |l| l.min(100));
+        if battery_level.swap(level, Ordering::AcqRel) != level {
+            PERIPHERAL_BATTERY_CHANGED.signal(());
+        }
+    }
+}
+
+/// Sample the battery level of this peripheral every 120s, the level is sent to the central.
+///
+/// Run it together with the split peripheral. On nRF, the sampler is the same `BleBatteryConfig` as the one of a
+/// BLE keyboard, other chips can use [`AdcBatterySampler`](crate::ble::battery::AdcBatterySampler).
+#[cfg(feature = "_ble")]
+pub async fn run_peripheral_battery_sampling(
+    mut sampler: impl crate::ble::battery::BatterySampler,
+) -> ! {
+    crate::ble::battery::run_battery_sampling(&mut sampler, |level| {
+        info!("Peripheral battery level: {}", level);
+        update_battery_level(level);
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_peripheral_battery_level() {
+        assert_eq!(peripheral_battery_level(1), None);
+        set_peripheral_battery_level(1, Some(80));
+        assert_eq!(peripheral_battery_level(1), Some(80));
+        assert!(PERIPHERAL_BATTERY_CHANGED.signaled());
+
+        PERIPHERAL_BATTERY_CHANGED.reset();
+        set_peripheral_battery_level(1, Some(80));
+        assert!(!PERIPHERAL_BATTERY_CHANGED.signaled());
+
+        set_peripheral_battery_level(1, Some(120));
+        assert_eq!(peripheral_battery_level(1), Some(100));
+        set_peripheral_battery_level(1, None);
+        assert_eq!(peripheral_battery_level(1), None);
+
+        // Out of range peripherals are ignored
+        set_peripheral_battery_level(PERIPHERAL_BATTERY_NUM, Some(50));
+        assert_eq!(peripheral_battery_level(PERIPHERAL_BATTERY_NUM), None);
+    }
+}
@@ -3,45 +3,112 @@ use crate::debounce::fast_debouncer::RapidDebouncer;
 use crate::debounce::{DebounceState, DebouncerTrait};
 use crate::event::{Event, KeyEvent};
 use crate::input_device::InputDevice;
-use crate::matrix::{KeyState, MatrixTrait};
+use crate::matrix::{
+    commit_scan, is_input_active, set_output_active, take_changed_key, KeyState, MatrixTrait,
+};
 use embassy_time::{Instant, Timer};
 use embedded_hal::digital::{InputPin, OutputPin};
 #[cfg(feature = "async_matrix")]
 use embedded_hal_async::digital::Wait;
 #[cfg(not(feature = "_nrf_ble"))]
+use embedded_hal_async::i2c::I2c;
+#[cfg(not(feature = "_ble"))]
 use embedded_io_async::{Read, Write};
 
 /// Run central's peripheral manager task.
 ///
 /// # Arguments
 /// * `id` - peripheral id
-/// * `addr` - (optional) peripheral's BLE static address. This argument is enabled only for nRF BLE split now
+/// * `addr` - (optional) peripheral's BLE static address. This argument is enabled only for BLE split
 /// * `receiver` - (optional) serial port. This argument is enabled only for serial split now
 pub async fn run_peripheral_manager<
     const ROW: usize,
     const COL: usize,
     const ROW_OFFSET: usize,
     const COL_OFFSET: usize,
-    #[cfg(not(feature = "_nrf_ble"))] S: Read + Write,
+    #[cfg(not(feature = "_ble"))] S: Read + Write,
 >(
     id: usize,
-    #[cfg(feature = "_nrf_ble")] addr: [u8; 6],
-    #[cfg(not(feature = "_nrf_ble"))] receiver: S,
+    #[cfg(feature = "_ble")] addr: [u8; 6],
+    #[cfg(not(feature = "_ble"))] receiver: S,
 ) {
+    #[cfg(feature = "_ble")]
+    run_peripheral_manager_with_offset::<ROW, COL>(id, (ROW_OFFSET, COL_OFFSET), addr).await;
+
+    #[cfg(not(feature = "_ble"))]
+    run_peripheral_manager_with_offset::<ROW, COL, S>(id, (ROW_OFFSET, COL_OFFSET), receiver).await;
+}
+
+/// Run central's peripheral manager task, with the peripheral's matrix offset chosen at runtime.
+///
+/// It's used when the same firmware runs on both halves, see [`crate::split::ee_hands`].
+///
+/// # Arguments
+/// * `id` - peripheral id
+/// * `offset` - (row_offset, col_offset) of the peripheral's matrix in the keyboard's matrix
+/// * `addr` - (optional) peripheral's BLE static address. This argument is enabled only for BLE split
+/// * `receiver` - (optional) serial port. This argument is enabled only for serial split now
+pub async fn run_peripheral_manager_with_offset<
+    const ROW: usize,
+    const COL: usize,
+    #[cfg(not(feature = "_ble"))] S: Read + Write,
+>(
+    id: usize,
+    offset: (usize, usize),
+    #[cfg(feature = "_ble")] addr: [u8; 6],
+    #[cfg(not(feature = "_ble"))] receiver: S,
+) {
+    let (row_offset, col_offset) = offset;
+
     #[cfg(feature = "_nrf_ble")]
     {
         use crate::split::nrf::central::run_ble_peripheral_manager;
-        run_ble_peripheral_manager::<ROW, COL, ROW_OFFSET, COL_OFFSET>(id, addr).await;
+        run_ble_peripheral_manager::<ROW, COL>(id, row_offset, col_offset, addr).await;
     };
 
-    #[cfg(not(feature = "_nrf_ble"))]
+    #[cfg(feature = "_esp_ble")]
+    {
+        use crate::split::esp::central::run_ble_peripheral_manager;
+        run_ble_peripheral_manager::<ROW, COL>(id, row_offset, col_offset, addr).await;
+    };
+
+    #[cfg(not(feature = "_ble"))]
     {
         use crate::split::serial::run_serial_peripheral_manager;
-        run_serial_peripheral_manager::<ROW, COL, ROW_OFFSET, COL_OFFSET, S>(id, receiver).await;
+        run_serial_peripheral_manager::<ROW, COL, S>(id, row_offset, col_offset, receiver).await;
     };
 }
 
+/// Run central's peripheral manager task for a peripheral connected by I2C.
+///
+/// The central is the I2C controller, and polls the peripheral for its messages.
+/// If several peripherals are on the same bus, share the bus by `embassy_embedded_hal::shared_bus`.
+///
+/// # Arguments
+/// * `id` - peripheral id
+/// * `i2c` - I2C bus connected to the peripheral
+/// * `address` - I2C address of the peripheral
+#[cfg(not(feature = "_nrf_ble"))]
+pub async fn run_i2c_peripheral_manager<
+    const ROW: usize,
+    const COL: usize,
+    const ROW_OFFSET: usize,
+    const COL_OFFSET: usize,
+    I: I2c,
+>(
+    id: usize,
+    i2c: I,
+    address: u8,
+) {
+    crate::split::i2c::run_i2c_peripheral_manager::<ROW, COL, I>(
+        id, ROW_OFFSET, COL_OFFSET, i2c, address,
+    )
+    .await;
+}
+
 /// Matrix is the physical pcb layout of the keyboard matrix.
+///
+/// The diode direction and pin polarity are set in the same way as [`crate::matrix::Matrix`].
 pub struct CentralMatrix<
     #[cfg(feature = "async_matrix")] In: Wait + InputPin,
     #[cfg(not(feature = "async_matrix"))] In: InputPin,
@@ -51,6 +118,7 @@ pub struct CentralMatrix<
     const COL_OFFSET: usize,
     const INPUT_PIN_NUM: usize,
     const OUTPUT_PIN_NUM: usize,
+    const COL2ROW: bool = true,
 > {
     /// Input pins of the pcb matrix
     input_pins: [In; INPUT_PIN_NUM],
@@ -62,8 +130,12 @@ pub struct CentralMatrix<
     key_states: [[KeyState; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
     /// Start scanning
     scan_start: Option<Instant>,
-    /// Current scan pos: (out_idx, in_idx)
-    scan_pos: (usize, usize),
+    /// Keys changed in the last scan, which are not reported yet
+    changed: [[bool; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+    /// Pin active level
+    low_active: bool,
+    /// Suppress the press of ghost keys
+    ghost_detection: bool,
 }
 
 impl<
@@ -75,58 +147,47 @@ impl<
         const COL_OFFSET: usize,
         const INPUT_PIN_NUM: usize,
         const OUTPUT_PIN_NUM: usize,
+        const COL2ROW: bool,
     > InputDevice
-    for CentralMatrix<In, Out, D, ROW_OFFSET, COL_OFFSET, INPUT_PIN_NUM, OUTPUT_PIN_NUM>
+    for CentralMatrix<In, Out, D, ROW_OFFSET, COL_OFFSET, INPUT_PIN_NUM, OUTPUT_PIN_NUM, COL2ROW>
 {
     async fn read_event(&mut self) -> Event {
         loop {
-            let (out_idx_start, in_idx_start) = self.scan_pos;
+            // Report the changes of the last scan one by one
+            if let Some((out_idx, in_idx)) = take_changed_key(&mut self.changed) {
+                let (row, col) = if COL2ROW {
+                    (in_idx, out_idx)
+                } else {
+                    (out_idx, in_idx)
+                };
+                return Event::Key(KeyEvent {
+                    row: (row + ROW_OFFSET) as u8,
+                    col: (col + COL_OFFSET) as u8,
+                    pressed: self.key_states[out_idx][in_idx].pressed,
+                });
+            }
 
             #[cfg(feature = "async_matrix")]
             self.wait_for_key().await;
 
-            // Scan matrix and send report
-            for out_idx in out_idx_start..self.output_pins.len() {
-                // Pull up output pin, wait 1us ensuring the change comes into effect
+            // Scan the whole matrix, the debounced changes are committed after the scan
+            let mut changed = [[false; INPUT_PIN_NUM]; OUTPUT_PIN_NUM];
+            for out_idx in 0..self.output_pins.len() {
+                // Set output pin to active level, wait 1us ensuring the change comes into effect
                 if let Some(out_pin) = self.output_pins.get_mut(out_idx) {
-                    out_pin.set_high().ok();
+                    set_output_active(out_pin, true, self.low_active);
                 }
                 Timer::after_micros(1).await;
-                for in_idx in in_idx_start..self.input_pins.len() {
+                for in_idx in 0..self.input_pins.len() {
                     let in_pin = self.input_pins.get_mut(in_idx).unwrap();
                     // Check input pins and debounce
                     let debounce_state = self.debouncer.detect_change_with_debounce(
                         in_idx,
                         out_idx,
-                        in_pin.is_high().ok().unwrap_or_default(),
+                        is_input_active(in_pin, self.low_active),
                         &self.key_states[out_idx][in_idx],
                     );
-
-                    match debounce_state {
-                        DebounceState::Debounced => {
-                            self.key_states[out_idx][in_idx].toggle_pressed();
-                            #[cfg(feature = "col2row")]
-                            let (row, col, key_state) = (
-                                (in_idx + ROW_OFFSET) as u8,
-                                (out_idx + COL_OFFSET) as u8,
-                                self.key_states[out_idx][in_idx],
-                            );
-                            #[cfg(not(feature = "col2row"))]
-                            let (row, col, key_state) = (
-                                (out_idx + ROW_OFFSET) as u8,
-                                (in_idx + COL_OFFSET) as u8,
-                                self.key_states[out_idx][in_idx],
-                            );
-
-                            self.scan_pos = (out_idx, in_idx);
-                            return Event::Key(KeyEvent {
-                                row,
-                                col,
-                                pressed: key_state.pressed,
-                            });
-                        }
-                        _ => (),
-                    }
+                    changed[out_idx][in_idx] = matches!(debounce_state, DebounceState::Debounced);
 
                     // If there's key still pressed, always refresh the self.scan_start
                     #[cfg(feature = "async_matrix")]
@@ -134,14 +195,17 @@ impl<
                         self.scan_start = Some(Instant::now());
                     }
                 }
-                // Pull it back to low
+                // Set it back to idle level
                 if let Some(out_pin) = self.output_pins.get_mut(out_idx) {
-                    out_pin.set_low().ok();
+                    set_output_active(out_pin, false, self.low_active);
                 }
             }
+            commit_scan(&mut self.key_states, &mut changed, self.ghost_detection);
+            self.changed = changed;
 
-            self.scan_pos = (0, 0);
-            embassy_time::Timer::after_micros(100).await;
+            if !self.changed.iter().flatten().any(|c| *c) {
+                embassy_time::Timer::after_micros(100).await;
+            }
         }
     }
 }
@@ -155,22 +219,24 @@ impl<
         const COL_OFFSET: usize,
         const INPUT_PIN_NUM: usize,
         const OUTPUT_PIN_NUM: usize,
+        const COL2ROW: bool,
     > MatrixTrait
-    for CentralMatrix<In, Out, D, ROW_OFFSET, COL_OFFSET, INPUT_PIN_NUM, OUTPUT_PIN_NUM>
+    for CentralMatrix<In, Out, D, ROW_OFFSET, COL_OFFSET, INPUT_PIN_NUM, OUTPUT_PIN_NUM, COL2ROW>
 {
-    #[cfg(feature = "col2row")]
-    const ROW: usize = INPUT_PIN_NUM;
-    #[cfg(feature = "col2row")]
-    const COL: usize = OUTPUT_PIN_NUM;
-    #[cfg(not(feature = "col2row"))]
-    const ROW: usize = OUTPUT_PIN_NUM;
-    #[cfg(not(feature = "col2row"))]
-    const COL: usize = INPUT_PIN_NUM;
+    const ROW: usize = if COL2ROW {
+        INPUT_PIN_NUM
+    } else {
+        OUTPUT_PIN_NUM
+    };
+    const COL: usize = if COL2ROW {
+        OUTPUT_PIN_NUM
+    } else {
+        INPUT_PIN_NUM
+    };
 
     #[cfg(feature = "async_matrix")]
     async fn wait_for_key(&mut self) {
-        use embassy_futures::select::select_slice;
-        use heapless::Vec;
+        use crate::matrix::wait_for_any_input_active;
 
         if let Some(start_time) = self.scan_start {
             // If not key over 2 secs, wait for interupt in next loop
@@ -180,22 +246,17 @@ impl<
                 self.scan_start = None;
             }
         }
-        // First, set all output pin to high
+        // First, set all output pin to active level
         for out in self.output_pins.iter_mut() {
-            out.set_high().ok();
+            set_output_active(out, true, self.low_active);
         }
         Timer::after_micros(1).await;
-        info!("Waiting for high");
-        let mut futs: Vec<_, INPUT_PIN_NUM> = self
-            .input_pins
-            .iter_mut()
-            .map(|input_pin| input_pin.wait_for_high())
-            .collect();
-        let _ = select_slice(futs.as_mut_slice()).await;
-
-        // Set all output pins back to low
+        info!("Waiting for active level");
+        wait_for_any_input_active(&mut self.input_pins, self.low_active).await;
+
+        // Set all output pins back to idle level
         for out in self.output_pins.iter_mut() {
-            out.set_low().ok();
+            set_output_active(out, false, self.low_active);
         }
 
         self.scan_start = Some(Instant::now());
@@ -211,23 +272,37 @@ impl<
         const COL_OFFSET: usize,
         const INPUT_PIN_NUM: usize,
         const OUTPUT_PIN_NUM: usize,
-    > CentralMatrix<In, Out, D, ROW_OFFSET, COL_OFFSET, INPUT_PIN_NUM, OUTPUT_PIN_NUM>
+        const COL2ROW: bool,
+    > CentralMatrix<In, Out, D, ROW_OFFSET, COL_OFFSET, INPUT_PIN_NUM, OUTPUT_PIN_NUM, COL2ROW>
 {
     /// Initialization of central
     pub fn new(
         input_pins: [In; INPUT_PIN_NUM],
-        output_pins: [Out; OUTPUT_PIN_NUM],
+        mut output_pins: [Out; OUTPUT_PIN_NUM],
         debouncer: D,
+        low_active: bool,
     ) -> Self {
+        // All output pins are at the idle level when not scanning
+        for out_pin in output_pins.iter_mut() {
+            set_output_active(out_pin, false, low_active);
+        }
         CentralMatrix {
             input_pins,
             output_pins,
             debouncer,
             key_states: [[KeyState::default(); INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
             scan_start: None,
-            scan_pos: (0, 0),
+            changed: [[false; INPUT_PIN_NUM]; OUTPUT_PIN_NUM],
+            low_active,
+            ghost_detection: false,
         }
     }
+
+    /// Enable or disable ghost detection, see [`crate::matrix::Matrix::with_ghost_detection`]
+    pub fn with_ghost_detection(mut self, ghost_detection: bool) -> Self {
+        self.ghost_detection = ghost_detection;
+        self
+    }
 }
 
 /// DirectPinMartex only has input pins.
@@ -1,16 +1,26 @@
 //! The abstracted driver layer of the split keyboard.
 //!
-use super::SplitMessage;
+use super::battery::set_peripheral_battery_level;
+use super::state::{
+    split_state, split_state_receiver, update_output_state, update_split_state, SplitState,
+    SPLIT_STATE_VERSION,
+};
+use super::stats::{
+    peripheral_link_stats, update_peripheral_link_stats, LinkErrors, SplitLinkStats,
+};
+use super::{SplitMessage, SPLIT_MESSAGE_MAX_SIZE};
 use crate::channel::EVENT_CHANNEL;
 use crate::input_device::InputDevice;
-use crate::CONNECTION_STATE;
 use crate::{
     channel::KEY_EVENT_CHANNEL,
-    event::{Event, KeyEvent},
+    event::{Event, EventSource, KeyEvent},
 };
+use crate::{RawMutex, CONNECTION_STATE};
+use core::future::pending;
 use core::sync::atomic::Ordering;
-use embassy_futures::select::select;
-use embassy_time::{Instant, Timer};
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::channel::Sender;
+use embassy_time::{Duration, Instant, Timer};
 
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -20,11 +30,26 @@ pub(crate) enum SplitDriverError {
     DeserializeError,
     SerializeError,
     BleError(u8),
+    AckTimeout,
+    I2cError,
+    /// The peripheral is disconnected, the message isn't written
+    Disconnected,
 }
 
+/// If nothing is received from a peripheral in this duration, the peripheral is considered as disconnected
+pub(crate) const LINK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Interval of logging the link statistics of a peripheral
+const LINK_STATS_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
 /// Split message reader from other split devices
 pub(crate) trait SplitReader {
     async fn read(&mut self) -> Result<SplitMessage, SplitDriverError>;
+
+    /// Take the errors which are dropped silently by the reader since the last call
+    fn take_link_errors(&mut self) -> LinkErrors {
+        LinkErrors::default()
+    }
 }
 
 /// Split message writer to other split devices
@@ -32,52 +57,266 @@ pub(crate) trait SplitWriter {
     async fn write(&mut self, message: &SplitMessage) -> Result<usize, SplitDriverError>;
 }
 
+/// Split writer which forwards messages to a channel, the channel is drained by the task which owns the link, e.g. the BLE client.
+///
+/// Messages are dropped when the channel is full, which happens when the peripheral is disconnected,
+/// so that the writer never blocks the [`PeripheralManager`].
+pub(crate) struct ChannelSplitWriter<'a> {
+    sender: Sender<'a, RawMutex, SplitMessage, 8>,
+    // Whether messages are being dropped, the warning is logged once until a message is sent again
+    dropping: bool,
+}
+
+impl<'a> ChannelSplitWriter<'a> {
+    pub(crate) fn new(sender: Sender<'a, RawMutex, SplitMessage, 8>) -> Self {
+        Self {
+            sender,
+            dropping: false,
+        }
+    }
+}
+
+impl SplitWriter for ChannelSplitWriter<'_> {
+    async fn write(&mut self, message: &SplitMessage) -> Result<usize, SplitDriverError> {
+        if self.sender.try_send(*message).is_err() {
+            if !self.dropping {
+                warn!(
+                    "Split messages to peripheral are dropped, the peripheral may be disconnected"
+                );
+                self.dropping = true;
+            }
+            return Err(SplitDriverError::Disconnected);
+        }
+        self.dropping = false;
+        Ok(SPLIT_MESSAGE_MAX_SIZE)
+    }
+}
+
 /// PeripheralManager runs in central.
 /// It reads split message from peripheral and updates key matrix cache of the peripheral.
 ///
 /// When the central scans the matrix, the scanning thread sends sync signal and gets key state cache back.
 ///
 /// The `ROW` and `COL` are the number of rows and columns of the corresponding peripheral's keyboard matrix.
-/// The `ROW_OFFSET` and `COL_OFFSET` are the offset of the peripheral's matrix in the keyboard's matrix.
 pub(crate) struct PeripheralManager<
     const ROW: usize,
     const COL: usize,
-    const ROW_OFFSET: usize,
-    const COL_OFFSET: usize,
     R: SplitReader + SplitWriter,
 > {
     /// Receiver
     receiver: R,
     /// Peripheral id
     id: usize,
+    /// Row offset of the peripheral's matrix in the keyboard's matrix
+    row_offset: usize,
+    /// Col offset of the peripheral's matrix in the keyboard's matrix
+    col_offset: usize,
+    /// Keys which are reported as pressed by the peripheral, in the peripheral's matrix
+    pressed: [[bool; COL]; ROW],
+    /// Whether the peripheral is connected
+    connected: bool,
+    /// The time of the last message received from the peripheral
+    last_seen: Instant,
+    /// Whether the peripheral has ever been connected
+    ever_connected: bool,
+    /// Sequence number of the next ping
+    ping_seq: u16,
+    /// Sequence number and the sending time of the ping which isn't replied yet
+    pending_ping: Option<(u16, Instant)>,
+    /// The time of the last log of link statistics
+    last_stats_log: Instant,
 }
 
-impl<
-        const ROW: usize,
-        const COL: usize,
-        const ROW_OFFSET: usize,
-        const COL_OFFSET: usize,
-        R: SplitReader + SplitWriter,
-    > PeripheralManager<ROW, COL, ROW_OFFSET, COL_OFFSET, R>
+impl<const ROW: usize, const COL: usize, R: SplitReader + SplitWriter>
+    PeripheralManager<ROW, COL, R>
 {
-    pub(crate) fn new(receiver: R, id: usize) -> Self {
-        Self { receiver, id }
+    pub(crate) fn new(receiver: R, id: usize, row_offset: usize, col_offset: usize) -> Self {
+        Self {
+            receiver,
+            id,
+            row_offset,
+            col_offset,
+            pressed: [[false; COL]; ROW],
+            connected: false,
+            last_seen: Instant::now(),
+            ever_connected: false,
+            ping_seq: 0,
+            pending_ping: None,
+            last_stats_log: Instant::now(),
+        }
+    }
+
+    /// Update the link state after a message is received from the peripheral
+    fn on_message_received(&mut self) {
+        self.last_seen = Instant::now();
+        if !self.connected {
+            info!("Split peripheral {} connected", self.id);
+            self.connected = true;
+            if self.ever_connected {
+                update_peripheral_link_stats(self.id, |s| {
+                    s.reconnects = s.reconnects.saturating_add(1)
+                });
+            }
+            self.ever_connected = true;
+            self.update_connection_state();
+        }
+    }
+
+    /// Update the link statistics with the result of a read, and the errors dropped by the reader.
+    ///
+    /// Errors are ignored when the peripheral is disconnected, the transport keeps failing then.
+    fn update_link_stats(&mut self, result: Option<&Result<SplitMessage, SplitDriverError>>) {
+        let mut errors = self.receiver.take_link_errors();
+        if !self.connected {
+            return;
+        }
+        let mut received = 0;
+        match result {
+            Some(Ok(_)) => received = 1,
+            Some(Err(SplitDriverError::DeserializeError)) => errors.deserialize += 1,
+            // Corrupted or incomplete transfers
+            Some(Err(SplitDriverError::SerialError | SplitDriverError::I2cError)) => {
+                errors.frame += 1
+            }
+            // Empty reads and write errors aren't link errors
+            Some(Err(_)) | None => (),
+        }
+        update_peripheral_link_stats(self.id, |s| {
+            s.messages_received = s.messages_received.wrapping_add(received);
+            s.add_errors(errors);
+        });
+    }
+
+    /// Send a ping to the peripheral, the previous ping is lost if it isn't replied yet
+    async fn ping(&mut self) {
+        if self.pending_ping.is_some() && self.connected {
+            update_peripheral_link_stats(self.id, |s| {
+                s.pings_lost = s.pings_lost.saturating_add(1)
+            });
+        }
+        let seq = self.ping_seq;
+        self.ping_seq = self.ping_seq.wrapping_add(1);
+        self.pending_ping = Some((seq, Instant::now()));
+        self.write_message(&SplitMessage::Ping(seq)).await;
+    }
+
+    /// Record the round-trip latency if the pong replies the pending ping
+    fn on_pong(&mut self, seq: u16) {
+        match self.pending_ping {
+            Some((ping_seq, sent_at)) if ping_seq == seq => {
+                self.pending_ping = None;
+                let latency = sent_at.elapsed().as_micros().min(u32::MAX as u64) as u32;
+                update_peripheral_link_stats(self.id, |s| s.record_latency(latency));
+            }
+            _ => debug!("Ignored stale pong {} of peripheral {}", seq, self.id),
+        }
+    }
+
+    /// Log the link statistics of the peripheral periodically
+    fn log_link_stats(&mut self) {
+        if self.last_stats_log.elapsed() < LINK_STATS_LOG_INTERVAL {
+            return;
+        }
+        self.last_stats_log = Instant::now();
+        if let Some(stats) = peripheral_link_stats(self.id) {
+            let SplitLinkStats {
+                messages_received,
+                deserialize_errors,
+                frame_errors,
+                reconnects,
+                pings_lost,
+                last_latency_us,
+                average_latency_us,
+                ..
+            } = stats;
+            info!(
+                "Split peripheral {} link: {} messages, {} deserialize errors, {} frame errors, {} reconnects, {} pings lost, latency {:?}us, average {:?}us",
+                self.id,
+                messages_received,
+                deserialize_errors,
+                frame_errors,
+                reconnects,
+                pings_lost,
+                last_latency_us,
+                average_latency_us
+            );
+        }
+    }
+
+    /// Check whether the link is lost, release all keys pressed on the peripheral if so
+    async fn check_link(&mut self) {
+        self.check_link_at(Instant::now()).await
+    }
+
+    async fn check_link_at(&mut self, now: Instant) {
+        if !self.connected || now.saturating_duration_since(self.last_seen) < LINK_TIMEOUT {
+            return;
+        }
+        warn!("Split peripheral {} disconnected", self.id);
+        self.connected = false;
+        self.update_connection_state();
+        set_peripheral_battery_level(self.id, None);
+
+        for (row, cols) in self.pressed.iter_mut().enumerate() {
+            for (col, pressed) in cols.iter_mut().enumerate() {
+                if *pressed {
+                    *pressed = false;
+                    KEY_EVENT_CHANNEL
+                        .send(KeyEvent {
+                            row: (row + self.row_offset) as u8,
+                            col: (col + self.col_offset) as u8,
+                            pressed: false,
+                        })
+                        .await;
+                }
+            }
+        }
+    }
+
+    /// Update the connection state of the peripheral in the keyboard state
+    fn update_connection_state(&self) {
+        if self.id >= 8 {
+            return;
+        }
+        let connected = self.connected;
+        let id = self.id;
+        update_peripheral_link_stats(id, |s| s.connected = connected);
+        update_split_state(|s| {
+            if connected {
+                s.peripherals |= 1 << id;
+            } else {
+                s.peripherals &= !(1 << id);
+            }
+        });
     }
 
     /// Run the manager.
     ///
-    /// The manager receives from the peripheral and forward the message to `KEY_EVENT_CHANNEL`.
-    /// It also sync the `ConnectionState` to the peripheral periodically.
+    /// The manager receives from the peripheral and forward key events to `KEY_EVENT_CHANNEL`,
+    /// other events to `EVENT_CHANNEL` with the peripheral id attached as [`EventSource::Peripheral`].
+    /// It also sync the `ConnectionState` and the keyboard state to the peripheral periodically,
+    /// the keyboard state is synced on change as well.
+    ///
+    /// The peripheral replies each `ConnectionState` with a `Heartbeat`. If nothing is received from the peripheral
+    /// in `LINK_TIMEOUT`, the peripheral is considered as disconnected, and all keys pressed on it are released.
+    ///
+    /// A `Ping` is sent to the peripheral every 1000ms as well, for measuring the round-trip latency.
+    /// The link statistics are logged every `LINK_STATS_LOG_INTERVAL`.
     pub(crate) async fn run(mut self) -> ! {
         let mut conn_state = CONNECTION_STATE.load(Ordering::Acquire);
         // Send connection state once on start
-        if let Err(e) = self
-            .receiver
-            .write(&SplitMessage::ConnectionState(conn_state))
-            .await
-        {
-            error!("SplitDriver write error: {:?}", e);
+        self.write_message(&SplitMessage::ConnectionState(conn_state))
+            .await;
+
+        let mut state_receiver = split_state_receiver();
+        if state_receiver.is_none() {
+            error!(
+                "Too many split state receivers, the state is synced to peripheral {} periodically",
+                self.id
+            );
         }
+        update_output_state();
+        self.write_split_state(split_state()).await;
 
         let mut last_sync_time = Instant::now();
 
@@ -86,58 +325,98 @@ impl<
             let elapsed = last_sync_time.elapsed().as_millis() as u64;
             let wait_time = if elapsed >= 1000 { 1 } else { 1000 - elapsed };
 
-            // Read the message from peripheral, or sync the connection state every 1000ms.
-            match select(self.read_event(), Timer::after_millis(wait_time)).await {
+            let state_changed = async {
+                match state_receiver.as_mut() {
+                    Some(receiver) => receiver.changed().await,
+                    None => pending().await,
+                }
+            };
+
+            // Read the message from peripheral, sync the keyboard state on change,
+            // or sync the connection state and the keyboard state every 1000ms.
+            match select3(
+                self.read_event(),
+                state_changed,
+                Timer::after_millis(wait_time),
+            )
+            .await
+            {
                 // Use built-in channels for split peripherals
-                embassy_futures::select::Either::First(event) => match event {
+                Either3::First(event) => match event {
                     Event::Key(key_event) => KEY_EVENT_CHANNEL.send(key_event).await,
-                    _ => {
+                    mut event => {
+                        // Processors tell events of peripherals apart by the source
+                        event.set_source(EventSource::Peripheral(self.id as u8));
                         if EVENT_CHANNEL.is_full() {
                             let _ = EVENT_CHANNEL.receive().await;
                         }
                         EVENT_CHANNEL.send(event).await;
                     }
                 },
-                embassy_futures::select::Either::Second(_) => {
+                Either3::Second(state) => self.write_split_state(state).await,
+                Either3::Third(_) => {
+                    // Errors are counted even if no valid message is received
+                    self.update_link_stats(None);
+                    self.check_link().await;
+                    self.log_link_stats();
                     // Timer elapsed, sync the connection state
                     conn_state = CONNECTION_STATE.load(Ordering::Acquire);
-                    if let Err(e) = self
-                        .receiver
-                        .write(&SplitMessage::ConnectionState(conn_state))
-                        .await
-                    {
-                        error!("SplitDriver write error: {:?}", e);
+                    self.write_message(&SplitMessage::ConnectionState(conn_state))
+                        .await;
+                    // Sync the keyboard state, the output state is refreshed first
+                    update_output_state();
+                    if let Some(receiver) = state_receiver.as_mut() {
+                        // The latest state is sent below, so the pending change is consumed here
+                        receiver.try_changed();
                     }
+                    self.write_split_state(split_state()).await;
+                    self.ping().await;
                     last_sync_time = Instant::now();
                 }
             }
         }
     }
+
+    /// Send the keyboard state to the peripheral
+    async fn write_split_state(&mut self, state: SplitState) {
+        self.write_message(&SplitMessage::State(SPLIT_STATE_VERSION, state.encode()))
+            .await;
+    }
+
+    /// Write a message to the peripheral, messages to a disconnected peripheral are dropped silently
+    async fn write_message(&mut self, message: &SplitMessage) {
+        match self.receiver.write(message).await {
+            Ok(_) | Err(SplitDriverError::Disconnected) => (),
+            Err(e) => error!("SplitDriver write error: {:?}", e),
+        }
+    }
 }
 
-impl<
-        const ROW: usize,
-        const COL: usize,
-        const ROW_OFFSET: usize,
-        const COL_OFFSET: usize,
-        R: SplitReader + SplitWriter,
-    > InputDevice for PeripheralManager<ROW, COL, ROW_OFFSET, COL_OFFSET, R>
+impl<const ROW: usize, const COL: usize, R: SplitReader + SplitWriter> InputDevice
+    for PeripheralManager<ROW, COL, R>
 {
     async fn read_event(&mut self) -> Event {
         loop {
-            match self.receiver.read().await {
+            let message = self.receiver.read().await;
+            if message.is_ok() {
+                self.on_message_received();
+            }
+            self.update_link_stats(Some(&message));
+            match message {
                 Ok(SplitMessage::Key(e)) => {
                     // Verify the row/col
-                    if e.row as usize > ROW || e.col as usize > COL {
+                    if e.row as usize >= ROW || e.col as usize >= COL {
                         error!("Invalid peripheral row/col: {} {}", e.row, e.col);
                         continue;
                     }
 
                     if CONNECTION_STATE.load(core::sync::atomic::Ordering::Acquire) {
+                        // Track pressed keys, they're released if the peripheral is disconnected
+                        self.pressed[e.row as usize][e.col as usize] = e.pressed;
                         // Only when the connection is established, send the key event.
                         let adjusted_key_event = KeyEvent {
-                            row: e.row + ROW_OFFSET as u8,
-                            col: e.col + COL_OFFSET as u8,
+                            row: e.row + self.row_offset as u8,
+                            col: e.col + self.col_offset as u8,
                             pressed: e.pressed,
                         };
                         return Event::Key(adjusted_key_event);
@@ -152,6 +431,11 @@ impl<
                         warn!("Event from peripheral is ignored because the connection is not established.");
                     }
                 }
+                Ok(SplitMessage::Pong(seq)) => self.on_pong(seq),
+                Ok(SplitMessage::BatteryLevel(level)) => {
+                    debug!("Battery level of peripheral {}: {}", self.id, level);
+                    set_peripheral_battery_level(self.id, Some(level));
+                }
                 Ok(_) => {
                     // Ignore other types of messages
                     debug!("Ignored non-event split message");
@@ -163,3 +447,140 @@ impl<
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use embassy_futures::block_on;
+    use embassy_futures::select::{select, Either};
+    use embassy_sync::channel::Channel;
+    use embassy_time::with_timeout;
+
+    use super::*;
+    use crate::split::state::split_state;
+
+    // Tests which receive from `KEY_EVENT_CHANNEL` can't run in parallel
+    static KEY_EVENT_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    /// A receiver which replies the given messages in order, then waits forever
+    struct MockReceiver(std::vec::Vec<SplitMessage>);
+
+    impl SplitReader for MockReceiver {
+        async fn read(&mut self) -> Result<SplitMessage, SplitDriverError> {
+            if self.0.is_empty() {
+                pending().await
+            } else {
+                Ok(self.0.remove(0))
+            }
+        }
+
+        fn take_link_errors(&mut self) -> LinkErrors {
+            // Each read drops a corrupted frame
+            LinkErrors {
+                frame: 1,
+                deserialize: 0,
+            }
+        }
+    }
+
+    impl SplitWriter for MockReceiver {
+        async fn write(&mut self, _message: &SplitMessage) -> Result<usize, SplitDriverError> {
+            Ok(0)
+        }
+    }
+
+    /// A link which receives nothing, messages written to it are never drained
+    struct StuckLink<'a>(ChannelSplitWriter<'a>);
+
+    impl SplitReader for StuckLink<'_> {
+        async fn read(&mut self) -> Result<SplitMessage, SplitDriverError> {
+            pending().await
+        }
+    }
+
+    impl SplitWriter for StuckLink<'_> {
+        async fn write(&mut self, message: &SplitMessage) -> Result<usize, SplitDriverError> {
+            self.0.write(message).await
+        }
+    }
+
+    #[test]
+    fn test_check_link_releases_pressed_keys() {
+        let _lock = KEY_EVENT_LOCK.lock().unwrap();
+        CONNECTION_STATE.store(true, Ordering::Release);
+        let key = |row, col, pressed| SplitMessage::Key(KeyEvent { row, col, pressed });
+        let receiver = MockReceiver(std::vec![
+            key(0, 1, true),
+            key(1, 0, true),
+            key(1, 0, false)
+        ]);
+        let mut manager = PeripheralManager::<2, 2, _>::new(receiver, 5, 1, 2);
+        for _ in 0..3 {
+            block_on(manager.read_event());
+        }
+        assert!(split_state().is_peripheral_connected(5));
+
+        // The link isn't lost yet
+        block_on(manager.check_link_at(manager.last_seen + LINK_TIMEOUT / 2));
+        assert!(KEY_EVENT_CHANNEL.try_receive().is_err());
+
+        // Only the key which is still pressed is released
+        block_on(manager.check_link_at(manager.last_seen + LINK_TIMEOUT));
+        let released = KEY_EVENT_CHANNEL.try_receive().unwrap();
+        assert_eq!(
+            (released.row, released.col, released.pressed),
+            (1, 3, false)
+        );
+        assert!(KEY_EVENT_CHANNEL.try_receive().is_err());
+        assert!(!split_state().is_peripheral_connected(5));
+    }
+
+    #[test]
+    fn test_link_stats_counted_when_connected() {
+        let mut manager =
+            PeripheralManager::<2, 2, _>::new(MockReceiver(std::vec::Vec::new()), 6, 0, 0);
+
+        // Errors of a disconnected peripheral are ignored, including the ones dropped by the reader
+        manager.update_link_stats(Some(&Err(SplitDriverError::I2cError)));
+        manager.update_link_stats(None);
+        assert_eq!(peripheral_link_stats(6).unwrap().frame_errors, 0);
+
+        manager.on_message_received();
+        manager.update_link_stats(Some(&Ok(SplitMessage::Pong(0))));
+        let stats = peripheral_link_stats(6).unwrap();
+        assert_eq!((stats.messages_received, stats.frame_errors), (1, 1));
+
+        // Empty reads aren't errors, only the frame dropped by the reader is counted
+        manager.update_link_stats(Some(&Err(SplitDriverError::EmptyMessage)));
+        assert_eq!(peripheral_link_stats(6).unwrap().frame_errors, 2);
+        manager.update_link_stats(Some(&Err(SplitDriverError::SerialError)));
+        manager.update_link_stats(Some(&Err(SplitDriverError::DeserializeError)));
+        let stats = peripheral_link_stats(6).unwrap();
+        assert_eq!((stats.frame_errors, stats.deserialize_errors), (5, 1));
+    }
+
+    #[test]
+    fn test_run_not_blocked_by_stuck_link() {
+        let _lock = KEY_EVENT_LOCK.lock().unwrap();
+        let channel: Channel<RawMutex, SplitMessage, 8> = Channel::new();
+        let link = StuckLink(ChannelSplitWriter::new(channel.sender()));
+        let mut manager = PeripheralManager::<2, 2, _>::new(link, 7, 2, 0);
+        manager.pressed[1][1] = true;
+        manager.on_message_received();
+        assert!(split_state().is_peripheral_connected(7));
+
+        // The channel is filled by the periodic messages, the manager still detects the lost link
+        let released = block_on(with_timeout(
+            LINK_TIMEOUT * 3,
+            select(manager.run(), KEY_EVENT_CHANNEL.receive()),
+        ));
+        let Ok(Either::Second(released)) = released else {
+            panic!("Pressed key isn't released");
+        };
+        assert!(channel.is_full());
+        assert_eq!(
+            (released.row, released.col, released.pressed),
+            (3, 1, false)
+        );
+        assert!(!split_state().is_peripheral_connected(7));
+    }
+}
@@ -0,0 +1,263 @@
+//! Single firmware for both halves of a split keyboard.
+//!
+//! Both halves run the same firmware, each half decides at boot whether it's the central or a peripheral,
+//! and whether it's the left or the right half:
+//!
+//! - The role is usually detected by [`SplitRole::from_vbus`]: the half which is powered by USB is the central.
+//! - The handedness can be read from a strap pin by [`Handedness::from_pin`],
+//!   or from the storage by [`read_handedness`]. The handedness in the storage is written by
+//!   `MagicEeHandsLeft`/`MagicEeHandsRight` keys, pressing the key on the central writes the central's storage.
+//!
+//! Peripherals always report keys in their own matrix, so the offsets only matter on the central.
+//! The central wraps its matrix with [`OffsetMatrix`], and runs the peripheral manager by
+//! [`run_peripheral_manager_with_offset`](crate::split::central::run_peripheral_manager_with_offset),
+//! both with the offsets from [`SplitLayout`].
+//!
+//! ```ignore
+//! let layout = SplitLayout::new((0, 0), (0, 7));
+//! let handedness = read_handedness(&mut flash, &storage_config).await.unwrap_or(Handedness::Left);
+//! match SplitRole::from_vbus(&mut vbus_pin) {
+//!     SplitRole::Central => {
+//!         // The matrix is created with 0 offsets
+//!         let matrix = OffsetMatrix::new(matrix, layout.offset(handedness));
+//!         let peripheral_offset = layout.offset(handedness.opposite());
+//!         // Run RMK with `matrix`, and the peripheral manager with `peripheral_offset`
+//!     }
+//!     SplitRole::Peripheral => {
+//!         // Run the split peripheral with the unmodified matrix
+//!     }
+//! }
+//! ```
+use embedded_hal::digital::InputPin;
+use embedded_storage_async::nor_flash::NorFlash;
+
+use crate::config::StorageConfig;
+use crate::event::{Event, KeyEvent};
+use crate::input_device::InputDevice;
+use crate::matrix::MatrixTrait;
+
+/// Which half of the split keyboard this is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Handedness {
+    Left = 0,
+    Right = 1,
+}
+
+impl Handedness {
+    /// Read the handedness from a strap pin.
+    ///
+    /// The half is the left one if the pin level is high and `left_level_high` is true,
+    /// or the pin level is low and `left_level_high` is false.
+    pub fn from_pin<P: InputPin>(pin: &mut P, left_level_high: bool) -> Self {
+        if pin.is_high().ok().unwrap_or_default() == left_level_high {
+            Handedness::Left
+        } else {
+            Handedness::Right
+        }
+    }
+
+    /// The other half
+    pub fn opposite(self) -> Self {
+        match self {
+            Handedness::Left => Handedness::Right,
+            Handedness::Right => Handedness::Left,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Handedness::Left),
+            1 => Some(Handedness::Right),
+            _ => None,
+        }
+    }
+}
+
+/// Role of the half in the split keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SplitRole {
+    Central,
+    Peripheral,
+}
+
+impl SplitRole {
+    /// Detect the role from the USB VBUS presence, the half which is powered by USB is the central.
+    ///
+    /// `vbus` is a pin which is high when VBUS is present, such as a pin connected to VBUS through a voltage divider.
+    pub fn from_vbus<P: InputPin>(vbus: &mut P) -> Self {
+        if vbus.is_high().ok().unwrap_or_default() {
+            SplitRole::Central
+        } else {
+            SplitRole::Peripheral
+        }
+    }
+}
+
+/// Offsets of the left and right halves in the whole keyboard matrix, as `(row_offset, col_offset)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SplitLayout {
+    pub left: (usize, usize),
+    pub right: (usize, usize),
+}
+
+impl SplitLayout {
+    pub const fn new(left: (usize, usize), right: (usize, usize)) -> Self {
+        Self { left, right }
+    }
+
+    /// Offsets of the given half
+    pub fn offset(&self, handedness: Handedness) -> (usize, usize) {
+        match handedness {
+            Handedness::Left => self.left,
+            Handedness::Right => self.right,
+        }
+    }
+}
+
+/// Read the handedness written by `MagicEeHandsLeft`/`MagicEeHandsRight` from the storage.
+///
+/// It's called before RMK starts, with the same flash and storage config which are passed to RMK later.
+/// Returns `None` if the handedness has never been written.
+pub async fn read_handedness<F: NorFlash>(
+    flash: &mut F,
+    storage_config: &StorageConfig,
+) -> Option<Handedness> {
+    crate::storage::read_handedness(flash, storage_config).await
+}
+
+/// Matrix wrapper which adds offsets, chosen at runtime, to the key events of the matrix.
+///
+/// The wrapped matrix should be created with 0 offsets.
+pub struct OffsetMatrix<M: MatrixTrait> {
+    matrix: M,
+    row_offset: usize,
+    col_offset: usize,
+}
+
+impl<M: MatrixTrait> OffsetMatrix<M> {
+    /// Wrap the matrix with `(row_offset, col_offset)`
+    pub fn new(matrix: M, offset: (usize, usize)) -> Self {
+        Self {
+            matrix,
+            row_offset: offset.0,
+            col_offset: offset.1,
+        }
+    }
+}
+
+impl<M: MatrixTrait> InputDevice for OffsetMatrix<M> {
+    async fn read_event(&mut self) -> Event {
+        match self.matrix.read_event().await {
+            Event::Key(key) => Event::Key(KeyEvent {
+                row: key.row + self.row_offset as u8,
+                col: key.col + self.col_offset as u8,
+                pressed: key.pressed,
+            }),
+            event => event,
+        }
+    }
+}
+
+impl<M: MatrixTrait> MatrixTrait for OffsetMatrix<M> {
+    const ROW: usize = M::ROW;
+    const COL: usize = M::COL;
+
+    #[cfg(feature = "async_matrix")]
+    async fn wait_for_key(&mut self) {
+        self.matrix.wait_for_key().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::convert::Infallible;
+
+    use embassy_futures::block_on;
+    use embedded_hal::digital::ErrorType;
+
+    use super::*;
+
+    struct FakePin(bool);
+
+    impl ErrorType for FakePin {
+        type Error = Infallible;
+    }
+
+    impl InputPin for FakePin {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(self.0)
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.0)
+        }
+    }
+
+    /// Matrix which reports a key at (1, 2)
+    struct FakeMatrix;
+
+    impl InputDevice for FakeMatrix {
+        async fn read_event(&mut self) -> Event {
+            Event::Key(KeyEvent {
+                row: 1,
+                col: 2,
+                pressed: true,
+            })
+        }
+    }
+
+    impl MatrixTrait for FakeMatrix {
+        const ROW: usize = 4;
+        const COL: usize = 6;
+
+        #[cfg(feature = "async_matrix")]
+        async fn wait_for_key(&mut self) {}
+    }
+
+    #[test]
+    fn test_handedness_from_pin() {
+        assert_eq!(
+            Handedness::from_pin(&mut FakePin(true), true),
+            Handedness::Left
+        );
+        assert_eq!(
+            Handedness::from_pin(&mut FakePin(false), true),
+            Handedness::Right
+        );
+        assert_eq!(
+            Handedness::from_pin(&mut FakePin(false), false),
+            Handedness::Left
+        );
+        assert_eq!(
+            Handedness::from_pin(&mut FakePin(true), false),
+            Handedness::Right
+        );
+    }
+
+    #[test]
+    fn test_role_from_vbus() {
+        assert_eq!(SplitRole::from_vbus(&mut FakePin(true)), SplitRole::Central);
+        assert_eq!(
+            SplitRole::from_vbus(&mut FakePin(false)),
+            SplitRole::Peripheral
+        );
+    }
+
+    #[test]
+    fn test_offset_matrix() {
+        let layout = SplitLayout::new((0, 0), (0, 6));
+        for (handedness, col) in [(Handedness::Left, 2), (Handedness::Right, 8)] {
+            let mut matrix = OffsetMatrix::new(FakeMatrix, layout.offset(handedness));
+            match block_on(matrix.read_event()) {
+                Event::Key(key) => assert_eq!((key.row, key.col, key.pressed), (1, col, true)),
+                _ => panic!("Not a key event"),
+            }
+        }
+        assert_eq!(OffsetMatrix::<FakeMatrix>::ROW, 4);
+        assert_eq!(OffsetMatrix::<FakeMatrix>::COL, 6);
+    }
+}
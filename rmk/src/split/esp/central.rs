@@ -0,0 +1,178 @@
+extern crate alloc;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_futures::join::join;
+use embassy_futures::select::{select, Either};
+use embassy_sync::channel::{Channel, Receiver};
+use embassy_time::Timer;
+use esp32_nimble::{BLEAddress, BLEAddressType, BLEClient, BLEError};
+
+use super::{MESSAGE_TO_CENTRAL_UUID, MESSAGE_TO_PERIPHERAL_UUID, SPLIT_SERVICE_UUID};
+use crate::split::driver::{
+    ChannelSplitWriter, PeripheralManager, SplitDriverError, SplitReader, SplitWriter,
+};
+use crate::split::{SplitMessage, SPLIT_MESSAGE_MAX_SIZE};
+use crate::RawMutex;
+
+/// Channel of messages received from a peripheral, messages which can't be deserialized are errors
+type ReceiveChannel = Channel<RawMutex, Result<SplitMessage, SplitDriverError>, 8>;
+
+pub(crate) async fn run_ble_peripheral_manager<const ROW: usize, const COL: usize>(
+    id: usize,
+    row_offset: usize,
+    col_offset: usize,
+    addr: [u8; 6],
+) {
+    // Messages are received in the notification callback of NimBLE, so the channel is shared with the callback
+    let receive_channel: Arc<ReceiveChannel> = Arc::new(Channel::new());
+    // Channel is used to write messages to peripheral
+    let notify_channel: Channel<RawMutex, SplitMessage, 8> = Channel::new();
+
+    let split_ble_driver = BleSplitCentralDriver {
+        receiver: receive_channel.receiver(),
+        writer: ChannelSplitWriter::new(notify_channel.sender()),
+    };
+
+    // Create peripheral manager instance
+    let peripheral_manager =
+        PeripheralManager::<ROW, COL, _>::new(split_ble_driver, id, row_offset, col_offset);
+
+    info!("Running peripheral manager {}", id);
+
+    join(
+        peripheral_manager.run(),
+        run_ble_client(receive_channel.clone(), notify_channel.receiver(), addr),
+    )
+    .await;
+}
+
+// If the one peripheral client is connecting, don't try to connect again
+static CONNECTING_CLIENT: AtomicBool = AtomicBool::new(false);
+
+/// Run a single ble client, which connects to the ble peripheral with `addr`.
+///
+/// Messages notified by the peripheral are sent to `receive_channel`,
+/// messages received from `notify_receiver` are written to the peripheral.
+async fn run_ble_client(
+    receive_channel: Arc<ReceiveChannel>,
+    notify_receiver: Receiver<'_, RawMutex, SplitMessage, 8>,
+    addr: [u8; 6],
+) -> ! {
+    let peer = BLEAddress::from_le_bytes(addr, BLEAddressType::Random);
+    let mut client = BLEClient::new();
+    // Same connection parameters as nRF: 7.5ms interval, and 5s supervision timeout
+    client.set_connection_params(6, 6, 99, 500, 16, 16);
+    loop {
+        // NimBLE connects one peripheral at a time
+        while CONNECTING_CLIENT
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            Timer::after_millis(200).await;
+        }
+        info!("Starting connect to split peripheral");
+        let connected = connect(&mut client, &peer, receive_channel.clone()).await;
+        CONNECTING_CLIENT.store(false, Ordering::SeqCst);
+
+        match connected {
+            Ok(()) => {
+                info!("Connected to split peripheral");
+                write_messages(&mut client, &notify_receiver).await;
+                warn!("Split peripheral disconnected");
+            }
+            Err(e) => error!("BLE split peripheral connect error: {}", e.code()),
+        }
+        if client.connected() {
+            client.disconnect().ok();
+        }
+
+        // Wait for 1s before trying to connect (again)
+        Timer::after_secs(1).await;
+    }
+}
+
+/// Connect to the peripheral, and subscribe its messages
+async fn connect(
+    client: &mut BLEClient,
+    peer: &BLEAddress,
+    receive_channel: Arc<ReceiveChannel>,
+) -> Result<(), BLEError> {
+    client.connect(peer).await?;
+    client
+        .get_service(SPLIT_SERVICE_UUID)
+        .await?
+        .get_characteristic(MESSAGE_TO_CENTRAL_UUID)
+        .await?
+        .on_notify(move |data| {
+            let message = postcard::from_bytes(data).map_err(|e| {
+                error!("Postcard deserialize split message error: {}", e);
+                SplitDriverError::DeserializeError
+            });
+            if receive_channel.try_send(message).is_err() {
+                error!("Split message channel is full, message dropped");
+            }
+        })
+        .subscribe_notify(false)
+        .await
+}
+
+/// Write messages to the peripheral, returns when the peripheral is disconnected
+async fn write_messages(
+    client: &mut BLEClient,
+    notify_receiver: &Receiver<'_, RawMutex, SplitMessage, 8>,
+) {
+    loop {
+        match select(notify_receiver.receive(), Timer::after_millis(100)).await {
+            Either::First(message) => {
+                let mut buf = [0_u8; SPLIT_MESSAGE_MAX_SIZE];
+                match postcard::to_slice(&message, &mut buf) {
+                    Ok(bytes) => {
+                        if let Err(e) = write_message(client, bytes).await {
+                            error!("BLE message_to_peripheral write error: {}", e.code());
+                        }
+                    }
+                    Err(e) => error!("Postcard serialize split message error: {}", e),
+                }
+            }
+            // Check the connection every 100ms
+            Either::Second(_) => {
+                if !client.connected() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn write_message(client: &mut BLEClient, bytes: &[u8]) -> Result<(), BLEError> {
+    client
+        .get_service(SPLIT_SERVICE_UUID)
+        .await?
+        .get_characteristic(MESSAGE_TO_PERIPHERAL_UUID)
+        .await?
+        .write_value(bytes, false)
+        .await
+}
+
+/// Ble central driver which reads and writes the split message.
+///
+/// The BLE client runs in a separate task, messages are forwarded by channels, same as nRF.
+pub(crate) struct BleSplitCentralDriver<'a> {
+    // Receiver that receives message from peripheral
+    receiver: Receiver<'a, RawMutex, Result<SplitMessage, SplitDriverError>, 8>,
+    // Writer that sends message to peripherals
+    writer: ChannelSplitWriter<'a>,
+}
+
+impl SplitReader for BleSplitCentralDriver<'_> {
+    async fn read(&mut self) -> Result<SplitMessage, SplitDriverError> {
+        self.receiver.receive().await
+    }
+}
+
+impl SplitWriter for BleSplitCentralDriver<'_> {
+    async fn write(&mut self, message: &SplitMessage) -> Result<usize, SplitDriverError> {
+        self.writer.write(message).await
+    }
+}
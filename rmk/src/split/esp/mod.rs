@@ -0,0 +1,32 @@
+//! BLE split on ESP32 chips, using NimBLE.
+//!
+//! The peripheral runs a GATT server, the central connects to each peripheral as a GATT client.
+//! The GATT service is the same as nRF BLE split.
+use esp32_nimble::enums::OwnAddrType;
+use esp32_nimble::utilities::BleUuid;
+use esp32_nimble::{uuid128, BLEDevice};
+
+pub mod central;
+pub mod peripheral;
+
+/// UUID of the split service
+pub(crate) const SPLIT_SERVICE_UUID: BleUuid = uuid128!("4dd5fbaa-18e5-4b07-bf0a-353698659946");
+/// UUID of the characteristic which notifies messages to the central
+pub(crate) const MESSAGE_TO_CENTRAL_UUID: BleUuid =
+    uuid128!("0e6313e3-bd0b-45c2-8d2e-37a2e8128bc3");
+/// UUID of the characteristic which messages to the peripheral are written to
+pub(crate) const MESSAGE_TO_PERIPHERAL_UUID: BleUuid =
+    uuid128!("4b3514fb-cae4-4d38-a097-3a2a3d1c3b9c");
+
+/// Use `addr` as the random static address of this device.
+///
+/// The address is in the same byte order as nRF, which is the `ble_addr` in `keyboard.toml`.
+/// On the central, call it before running RMK, so that peripherals can recognize the central.
+pub fn set_ble_addr(addr: [u8; 6]) {
+    let device = BLEDevice::take();
+    device.set_own_addr_type(OwnAddrType::Random);
+    let rc = unsafe { esp_idf_svc::sys::ble_hs_id_set_rnd(addr.as_ptr()) };
+    if rc != 0 {
+        error!("Set BLE address error, error code: {}", rc);
+    }
+}
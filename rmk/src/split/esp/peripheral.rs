@@ -0,0 +1,131 @@
+extern crate alloc;
+use alloc::sync::Arc;
+
+use embassy_futures::select::select;
+use embassy_sync::channel::Channel;
+use embassy_time::Timer;
+use esp32_nimble::utilities::mutex::Mutex;
+use esp32_nimble::{
+    BLEAddress, BLEAddressType, BLEAdvertisementData, BLECharacteristic, BLEDevice, BLEServer,
+    NimbleProperties,
+};
+
+use super::{
+    set_ble_addr, MESSAGE_TO_CENTRAL_UUID, MESSAGE_TO_PERIPHERAL_UUID, SPLIT_SERVICE_UUID,
+};
+use crate::split::driver::{SplitDriverError, SplitReader, SplitWriter};
+use crate::split::peripheral::SplitPeripheral;
+use crate::split::{SplitMessage, SPLIT_MESSAGE_MAX_SIZE};
+use crate::{RawMutex, CONNECTION_STATE};
+
+/// Messages written by the central, they're received in the write callback of NimBLE
+static RECEIVE_CHANNEL: Channel<RawMutex, Result<SplitMessage, SplitDriverError>, 4> =
+    Channel::new();
+
+/// BLE driver for split peripheral
+pub(crate) struct BleSplitPeripheralDriver {
+    message_to_central: Arc<Mutex<BLECharacteristic>>,
+}
+
+impl SplitReader for BleSplitPeripheralDriver {
+    async fn read(&mut self) -> Result<SplitMessage, SplitDriverError> {
+        RECEIVE_CHANNEL.receive().await
+    }
+}
+
+impl SplitWriter for BleSplitPeripheralDriver {
+    async fn write(&mut self, message: &SplitMessage) -> Result<usize, SplitDriverError> {
+        let mut buf = [0_u8; SPLIT_MESSAGE_MAX_SIZE];
+        let bytes = postcard::to_slice(message, &mut buf).map_err(|e| {
+            error!("Postcard serialize split message error: {}", e);
+            SplitDriverError::SerializeError
+        })?;
+        debug!("Writing split message to central: {:?}", message);
+        self.message_to_central.lock().set_value(bytes).notify();
+        Ok(bytes.len())
+    }
+}
+
+/// Initialize and run the ESP32 peripheral keyboard service via BLE.
+///
+/// Only the central with `central_addr` is accepted, other connections are disconnected.
+pub(crate) async fn initialize_esp_ble_split_peripheral_and_run(
+    central_addr: [u8; 6],
+    peripheral_addr: [u8; 6],
+) -> ! {
+    set_ble_addr(peripheral_addr);
+    let device = BLEDevice::take();
+    let server = device.get_server();
+
+    let central = BLEAddress::from_le_bytes(central_addr, BLEAddressType::Random);
+    server.on_connect(move |server, desc| {
+        if desc.address() != central {
+            warn!("Rejected split connection from an unknown device");
+            if let Err(e) = server.disconnect(desc.conn_handle()) {
+                error!("BLE disconnect error, error code: {}", e.code());
+            }
+        }
+    });
+    server.advertise_on_disconnect(true);
+
+    let service = server.create_service(SPLIT_SERVICE_UUID);
+    let message_to_central = service.lock().create_characteristic(
+        MESSAGE_TO_CENTRAL_UUID,
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+    );
+    service
+        .lock()
+        .create_characteristic(
+            MESSAGE_TO_PERIPHERAL_UUID,
+            NimbleProperties::WRITE | NimbleProperties::WRITE_NO_RSP,
+        )
+        .lock()
+        .on_write(|args| {
+            let message = postcard::from_bytes(args.recv_data()).map_err(|e| {
+                error!("Postcard deserialize split message error: {}", e);
+                SplitDriverError::DeserializeError
+            });
+            if RECEIVE_CHANNEL.try_send(message).is_err() {
+                error!("Split message channel is full, message dropped");
+            }
+        });
+
+    let ble_advertising = device.get_advertising();
+    if let Err(e) = ble_advertising.lock().set_data(
+        BLEAdvertisementData::new()
+            .name("rmk_split_peri")
+            .add_service_uuid(SPLIT_SERVICE_UUID),
+    ) {
+        error!("BLE advertising error, error code: {}", e.code());
+    }
+    if let Err(e) = ble_advertising.lock().start() {
+        error!("BLE advertising start error: {}", e.code());
+    }
+
+    loop {
+        CONNECTION_STATE.store(false, core::sync::atomic::Ordering::Release);
+        wait_for_connection(server).await;
+        info!("Connected to split central");
+        RECEIVE_CHANNEL.clear();
+
+        let mut peripheral = SplitPeripheral::new(BleSplitPeripheralDriver {
+            message_to_central: message_to_central.clone(),
+        });
+        select(peripheral.run(), wait_for_disconnection(server)).await;
+        warn!("Split central disconnected");
+    }
+}
+
+async fn wait_for_connection(server: &BLEServer) {
+    // Check connection status every 100 ms
+    while server.connected_count() == 0 {
+        Timer::after_millis(100).await;
+    }
+}
+
+async fn wait_for_disconnection(server: &BLEServer) {
+    // Check connection status every 500 ms
+    while server.connected_count() > 0 {
+        Timer::after_millis(500).await;
+    }
+}
@@ -0,0 +1,700 @@
+//! I2C split transport.
+//!
+//! The central is the I2C controller, and each peripheral is an I2C target with its own address.
+//! I2C targets can't start a transfer, so the central polls the peripheral for its messages:
+//!
+//! - Poll: the central writes `[CMD_POLL, ack]` and reads `[seq, len]` back after a repeated start.
+//!   `ack` is the `seq` of the last message received by the central, the peripheral removes the acked message
+//!   from its queue before replying. If `len` isn't 0, the central reads the `len` bytes of the message then.
+//! - Message to the peripheral: the central writes `[CMD_MESSAGE, message..]`.
+//!
+//! Messages are postcard serialized [`SplitMessage`]s. Messages of the peripheral are kept in its queue until
+//! they're acked, so a failed transfer is retried by the next poll.
+//!
+//! The sequence number restarts when the peripheral restarts, so the `ack` of the central might be a message
+//! sent before the restart. The peripheral replies nothing until it's polled with `ack` 0, which the central
+//! sends after a poll without message.
+use embassy_sync::channel::{Channel, Receiver, Sender};
+use embassy_time::{Duration, Timer};
+use embedded_hal_async::i2c::I2c;
+use heapless::{Deque, Vec};
+
+use crate::split::{
+    driver::{PeripheralManager, SplitDriverError, SplitReader, SplitWriter},
+    stats::LinkErrors,
+    SplitMessage, SPLIT_MESSAGE_MAX_SIZE,
+};
+use crate::RawMutex;
+
+#[cfg(feature = "rp2040_i2c")]
+pub mod rp;
+
+/// Poll the message of the peripheral
+const CMD_POLL: u8 = 0x01;
+/// Send a message to the peripheral
+const CMD_MESSAGE: u8 = 0x02;
+
+/// Interval of polling the peripheral
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Time to wait before polling again after a bus error, such as the peripheral isn't connected
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Maximum number of messages which are waiting to be polled by the central
+const QUEUE_SIZE: usize = 16;
+
+/// Maximum number of received messages which are not read yet
+const PENDING_MESSAGE_NUM: usize = 4;
+
+/// Serialized split message
+type Payload = Vec<u8, SPLIT_MESSAGE_MAX_SIZE>;
+
+/// Command received by an I2C target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum I2cTargetCommand {
+    /// The controller reads from the target
+    Read,
+    /// The controller wrote `n` bytes to the target
+    Write(usize),
+    /// The controller wrote `n` bytes to the target, then reads from it after a repeated start
+    WriteRead(usize),
+}
+
+/// I2C target(slave) driver, which is used by split peripherals.
+///
+/// embedded-hal doesn't have a trait for I2C targets, implement this trait for the I2C target driver of your chip.
+/// It's implemented for `embassy_rp::i2c_slave::I2cSlave` when `rp2040_i2c` feature is enabled.
+///
+/// The target is served by its own loop, which is never cancelled, so a command is always responded.
+pub trait I2cTarget {
+    type Error;
+
+    /// Wait for the next command from the controller, bytes written by the controller are saved in `buf`.
+    async fn listen(&mut self, buf: &mut [u8]) -> Result<I2cTargetCommand, Self::Error>;
+
+    /// Respond to the read of the controller with `buf`.
+    ///
+    /// If the controller reads more bytes than `buf`, the rest bytes should be 0.
+    async fn respond_to_read(&mut self, buf: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Poll split messages from peripheral via I2C and process them
+///
+/// Generic parameters:
+/// - `const ROW`: row number of the peripheral's matrix
+/// - `const COL`: column number of the peripheral's matrix
+/// - `I`: an I2C bus that implements `I2c` trait in embedded-hal-async
+///
+/// `row_offset` and `col_offset` are the offsets of the peripheral's matrix in the whole matrix,
+/// `address` is the I2C address of the peripheral
+pub(crate) async fn run_i2c_peripheral_manager<const ROW: usize, const COL: usize, I: I2c>(
+    id: usize,
+    row_offset: usize,
+    col_offset: usize,
+    i2c: I,
+    address: u8,
+) {
+    let split_i2c_driver = I2cCentralDriver::new(i2c, address);
+    let peripheral_manager =
+        PeripheralManager::<ROW, COL, _>::new(split_i2c_driver, id, row_offset, col_offset);
+    info!("Running I2C peripheral manager {}", id);
+
+    peripheral_manager.run().await;
+}
+
+/// I2C driver for split central, which is the I2C controller
+pub(crate) struct I2cCentralDriver<I: I2c> {
+    i2c: I,
+    /// I2C address of the peripheral
+    address: u8,
+    /// Sequence number of the last received message, 0 if there's no message to ack
+    ack: u8,
+    /// Bus errors of polling, which are not taken yet
+    errors: LinkErrors,
+    /// Whether the peripheral isn't responding, the warning is logged once until it responds again
+    failing: bool,
+}
+
+impl<I: I2c> I2cCentralDriver<I> {
+    pub(crate) fn new(i2c: I, address: u8) -> Self {
+        Self {
+            i2c,
+            address,
+            ack: 0,
+            errors: LinkErrors::default(),
+            failing: false,
+        }
+    }
+
+    /// Poll the peripheral once, returns the message of the peripheral if there's any
+    async fn poll(&mut self) -> Result<Option<SplitMessage>, SplitDriverError> {
+        let mut header = [0_u8; 2];
+        self.i2c
+            .write_read(self.address, &[CMD_POLL, self.ack], &mut header)
+            .await
+            .map_err(|_e| SplitDriverError::I2cError)?;
+        let [seq, len] = header;
+        if len == 0 {
+            // The acked message is removed, so there's nothing to ack
+            self.ack = 0;
+            return Ok(None);
+        }
+        if seq == self.ack {
+            debug!("Dropped duplicated split message, seq: {}", seq);
+            return Ok(None);
+        }
+
+        let mut buf = [0_u8; SPLIT_MESSAGE_MAX_SIZE];
+        let payload = buf
+            .get_mut(..len as usize)
+            .ok_or(SplitDriverError::DeserializeError)?;
+        self.i2c
+            .read(self.address, payload)
+            .await
+            .map_err(|_e| SplitDriverError::I2cError)?;
+        // Ack the message even if it can't be deserialized, otherwise it would be polled forever
+        self.ack = seq;
+        postcard::from_bytes(payload)
+            .map(Some)
+            .map_err(|_e| SplitDriverError::DeserializeError)
+    }
+
+    /// Update the failing state by the result of a transfer
+    fn set_failing(&mut self, failing: bool) {
+        if failing && !self.failing {
+            warn!("I2C split peripheral {} isn't responding", self.address);
+        } else if !failing && self.failing {
+            info!("I2C split peripheral {} is responding again", self.address);
+        }
+        self.failing = failing;
+    }
+}
+
+impl<I: I2c> SplitReader for I2cCentralDriver<I> {
+    async fn read(&mut self) -> Result<SplitMessage, SplitDriverError> {
+        loop {
+            let result = self.poll().await;
+            self.set_failing(matches!(result, Err(SplitDriverError::I2cError)));
+            match result {
+                Ok(Some(message)) => return Ok(message),
+                Ok(None) => Timer::after(POLL_INTERVAL).await,
+                // Bus errors keep happening while the peripheral is unplugged, they're counted and retried here
+                Err(SplitDriverError::I2cError) => {
+                    self.errors.frame += 1;
+                    Timer::after(RETRY_INTERVAL).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn take_link_errors(&mut self) -> LinkErrors {
+        core::mem::take(&mut self.errors)
+    }
+}
+
+impl<I: I2c> SplitWriter for I2cCentralDriver<I> {
+    async fn write(&mut self, message: &SplitMessage) -> Result<usize, SplitDriverError> {
+        let mut buf = [0_u8; SPLIT_MESSAGE_MAX_SIZE + 1];
+        buf[0] = CMD_MESSAGE;
+        let n_bytes = postcard::to_slice(message, &mut buf[1..])
+            .map_err(|_e| SplitDriverError::SerializeError)?
+            .len();
+        let result = self.i2c.write(self.address, &buf[..n_bytes + 1]).await;
+        self.set_failing(result.is_err());
+        // The message isn't written because the peripheral isn't responding, which has been logged
+        result.map_err(|_e| SplitDriverError::Disconnected)?;
+        Ok(n_bytes)
+    }
+}
+
+/// Channels between the split driver of an I2C peripheral and the server of its I2C target
+pub(crate) struct I2cPeripheralChannels {
+    /// Serialized messages to the central
+    outgoing: Channel<RawMutex, Payload, QUEUE_SIZE>,
+    /// Messages received from the central
+    incoming: Channel<RawMutex, Result<SplitMessage, SplitDriverError>, PENDING_MESSAGE_NUM>,
+}
+
+impl I2cPeripheralChannels {
+    pub(crate) const fn new() -> Self {
+        Self {
+            outgoing: Channel::new(),
+            incoming: Channel::new(),
+        }
+    }
+
+    /// Create the split driver of the peripheral, and the server of `target`, which should run together
+    pub(crate) fn split<T: I2cTarget>(
+        &self,
+        target: T,
+    ) -> (I2cPeripheralDriver<'_>, I2cTargetServer<'_, T>) {
+        (
+            I2cPeripheralDriver {
+                outgoing: self.outgoing.sender(),
+                incoming: self.incoming.receiver(),
+            },
+            I2cTargetServer {
+                target,
+                queue: Deque::new(),
+                tx_seq: 1,
+                synced: false,
+                outgoing: self.outgoing.receiver(),
+                incoming: self.incoming.sender(),
+                failing: false,
+            },
+        )
+    }
+}
+
+/// I2C driver for split peripheral, the messages are exchanged with the [`I2cTargetServer`] by channels,
+/// so reads and writes can be cancelled at any time.
+pub(crate) struct I2cPeripheralDriver<'a> {
+    outgoing: Sender<'a, RawMutex, Payload, QUEUE_SIZE>,
+    incoming: Receiver<'a, RawMutex, Result<SplitMessage, SplitDriverError>, PENDING_MESSAGE_NUM>,
+}
+
+impl SplitReader for I2cPeripheralDriver<'_> {
+    async fn read(&mut self) -> Result<SplitMessage, SplitDriverError> {
+        self.incoming.receive().await
+    }
+}
+
+impl SplitWriter for I2cPeripheralDriver<'_> {
+    async fn write(&mut self, message: &SplitMessage) -> Result<usize, SplitDriverError> {
+        let mut buf = [0_u8; SPLIT_MESSAGE_MAX_SIZE];
+        let bytes =
+            postcard::to_slice(message, &mut buf).map_err(|_e| SplitDriverError::SerializeError)?;
+        let payload = Payload::from_slice(bytes).map_err(|_e| SplitDriverError::SerializeError)?;
+        let n_bytes = payload.len();
+        // Wait until the central polls a message out of the full queue
+        self.outgoing.send(payload).await;
+        Ok(n_bytes)
+    }
+}
+
+/// Server of the I2C target of a split peripheral.
+///
+/// It responds to the transfers of the central, messages to the central are queued until they're polled and acked.
+pub(crate) struct I2cTargetServer<'a, T: I2cTarget> {
+    target: T,
+    /// Serialized messages to the central with their sequence numbers, they're removed when acked
+    queue: Deque<(u8, Payload), QUEUE_SIZE>,
+    /// Sequence number of the next message to the central, 0 is skipped because it means "nothing to ack"
+    tx_seq: u8,
+    /// Whether the central has been polled with `ack` 0 since the peripheral started,
+    /// acks before that might be of the messages sent before the peripheral restarted
+    synced: bool,
+    outgoing: Receiver<'a, RawMutex, Payload, QUEUE_SIZE>,
+    incoming: Sender<'a, RawMutex, Result<SplitMessage, SplitDriverError>, PENDING_MESSAGE_NUM>,
+    /// Whether the target is failing, the error is logged once until a transfer succeeds
+    failing: bool,
+}
+
+impl<T: I2cTarget> I2cTargetServer<'_, T> {
+    /// Serve the transfers of the central forever
+    pub(crate) async fn run(&mut self) -> ! {
+        loop {
+            match self.serve().await {
+                Ok(()) => self.failing = false,
+                Err(e) => {
+                    if !self.failing {
+                        error!("I2C split target error: {:?}", e);
+                        self.failing = true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Serve a transfer of the central
+    async fn serve(&mut self) -> Result<(), SplitDriverError> {
+        let mut buf = [0_u8; SPLIT_MESSAGE_MAX_SIZE + 1];
+        let command = self
+            .target
+            .listen(&mut buf)
+            .await
+            .map_err(|_e| SplitDriverError::I2cError)?;
+        match command {
+            I2cTargetCommand::WriteRead(n) if n >= 2 && buf[0] == CMD_POLL => {
+                let ack = buf[1];
+                self.synced |= ack == 0;
+                if self.synced && self.queue.front().is_some_and(|(seq, _)| *seq == ack) {
+                    self.queue.pop_front();
+                }
+                self.fill_queue();
+                // Reply nothing until synced, then the central resets its ack to 0
+                let header = match self.queue.front() {
+                    Some((seq, payload)) if self.synced => [*seq, payload.len() as u8],
+                    _ => [0, 0],
+                };
+                self.respond(&header).await?;
+            }
+            I2cTargetCommand::Read => {
+                // The central reads the message in the queue front after polling its header
+                let payload = match self.queue.front() {
+                    Some((_, payload)) => payload.as_slice(),
+                    None => &[],
+                };
+                self.target
+                    .respond_to_read(payload)
+                    .await
+                    .map_err(|_e| SplitDriverError::I2cError)?;
+            }
+            I2cTargetCommand::Write(n) if n >= 1 && buf[0] == CMD_MESSAGE => {
+                let message = match buf.get(1..n) {
+                    Some(payload) => postcard::from_bytes(payload)
+                        .map_err(|_e| SplitDriverError::DeserializeError),
+                    None => Err(SplitDriverError::EmptyMessage),
+                };
+                if self.incoming.try_send(message).is_err() {
+                    warn!("Too many pending split messages, dropped");
+                }
+            }
+            I2cTargetCommand::WriteRead(_) => {
+                warn!("Unknown I2C split command: {}", buf[0]);
+                // Always respond, or the central waits for the response until timeout
+                self.respond(&[]).await?;
+            }
+            I2cTargetCommand::Write(_) => warn!("Unknown I2C split command: {}", buf[0]),
+        }
+        Ok(())
+    }
+
+    /// Move the messages written by the split driver to the queue, until the queue is full
+    fn fill_queue(&mut self) {
+        while !self.queue.is_full() {
+            let Ok(payload) = self.outgoing.try_receive() else {
+                return;
+            };
+            let seq = self.tx_seq;
+            self.tx_seq = self.tx_seq.checked_add(1).unwrap_or(1);
+            // The queue isn't full here
+            let _ = self.queue.push_back((seq, payload));
+        }
+    }
+
+    async fn respond(&mut self, buf: &[u8]) -> Result<(), SplitDriverError> {
+        self.target
+            .respond_to_read(buf)
+            .await
+            .map_err(|_e| SplitDriverError::I2cError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use core::future::pending;
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::{select, select3, Either, Either3};
+    use embassy_futures::yield_now;
+    use embassy_time::with_timeout;
+    use embedded_hal_async::i2c::{ErrorKind, ErrorType, NoAcknowledgeSource, Operation};
+
+    use super::*;
+    use crate::event::KeyEvent;
+
+    const ADDRESS: u8 = 0x42;
+
+    /// A transfer on the in-memory I2C bus
+    enum Transfer {
+        /// Bytes written by the controller, and the number of bytes read by the controller after a repeated start
+        WriteRead(std::vec::Vec<u8>, usize),
+        /// Bytes written by the controller
+        Write(std::vec::Vec<u8>),
+        /// Number of bytes read by the controller
+        Read(usize),
+    }
+
+    /// In-memory I2C bus with a single target
+    #[derive(Default)]
+    struct Bus {
+        /// Transfer waiting for the target
+        transfer: Option<Transfer>,
+        /// Response of the target to the read of the controller
+        response: Option<std::vec::Vec<u8>>,
+        /// The target is disconnected, transfers are not acknowledged
+        disconnected: bool,
+    }
+
+    /// The controller end of the in-memory I2C bus
+    struct Controller(Rc<RefCell<Bus>>);
+
+    /// The target end of the in-memory I2C bus
+    struct Target {
+        bus: Rc<RefCell<Bus>>,
+        /// Number of bytes the controller is reading
+        reading: usize,
+    }
+
+    fn bus() -> (Controller, Target) {
+        let bus = Rc::new(RefCell::new(Bus::default()));
+        (Controller(bus.clone()), Target { bus, reading: 0 })
+    }
+
+    impl Controller {
+        /// Start a transfer and wait for the response of the target
+        async fn transfer(
+            &mut self,
+            transfer: Transfer,
+        ) -> Result<Option<std::vec::Vec<u8>>, ErrorKind> {
+            if self.0.borrow().disconnected {
+                return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+            }
+            let has_response = !matches!(transfer, Transfer::Write(_));
+            self.0.borrow_mut().transfer = Some(transfer);
+            if !has_response {
+                // Wait for the target to receive the written bytes
+                while self.0.borrow().transfer.is_some() {
+                    yield_now().await;
+                }
+                return Ok(None);
+            }
+            loop {
+                if let Some(response) = self.0.borrow_mut().response.take() {
+                    return Ok(Some(response));
+                }
+                yield_now().await;
+            }
+        }
+    }
+
+    impl ErrorType for Controller {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for Controller {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            assert_eq!(address, ADDRESS);
+            let transfer = match operations {
+                [Operation::Write(w), Operation::Read(r)] => {
+                    Transfer::WriteRead(w.to_vec(), r.len())
+                }
+                [Operation::Write(w)] => Transfer::Write(w.to_vec()),
+                [Operation::Read(r)] => Transfer::Read(r.len()),
+                _ => unimplemented!(),
+            };
+            if let Some(response) = self.transfer(transfer).await? {
+                if let Some(Operation::Read(r)) = operations.last_mut() {
+                    r.copy_from_slice(&response[..r.len()]);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl I2cTarget for Target {
+        type Error = ();
+
+        async fn listen(&mut self, buf: &mut [u8]) -> Result<I2cTargetCommand, Self::Error> {
+            loop {
+                if let Some(transfer) = self.bus.borrow_mut().transfer.take() {
+                    return Ok(match transfer {
+                        Transfer::WriteRead(w, r) => {
+                            buf[..w.len()].copy_from_slice(&w);
+                            self.reading = r;
+                            I2cTargetCommand::WriteRead(w.len())
+                        }
+                        Transfer::Write(w) => {
+                            buf[..w.len()].copy_from_slice(&w);
+                            I2cTargetCommand::Write(w.len())
+                        }
+                        Transfer::Read(r) => {
+                            self.reading = r;
+                            I2cTargetCommand::Read
+                        }
+                    });
+                }
+                yield_now().await;
+            }
+        }
+
+        async fn respond_to_read(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+            let mut response = buf.to_vec();
+            response.resize(self.reading, 0);
+            self.bus.borrow_mut().response = Some(response);
+            Ok(())
+        }
+    }
+
+    fn key(i: usize) -> SplitMessage {
+        SplitMessage::Key(KeyEvent {
+            row: i as u8,
+            col: (i >> 8) as u8,
+            pressed: i & 1 == 0,
+        })
+    }
+
+    #[test]
+    fn test_i2c_split_messages() {
+        let (controller, target) = bus();
+        let mut central = I2cCentralDriver::new(controller, ADDRESS);
+        let channels = I2cPeripheralChannels::new();
+        let (mut peripheral, mut server) = channels.split(target);
+        // More than the queue size, and more than 255 to wrap the sequence number
+        let key_num = 300;
+
+        let peripheral_task = async {
+            let mut received = std::vec::Vec::new();
+            for i in 0..key_num {
+                peripheral.write(&key(i)).await.unwrap();
+            }
+            while received.len() < 2 {
+                if let Ok(SplitMessage::ConnectionState(state)) = peripheral.read().await {
+                    received.push(state);
+                }
+            }
+            received
+        };
+        let central_task = async {
+            for i in 0..key_num {
+                match central.read().await.unwrap() {
+                    SplitMessage::Key(k) => {
+                        let SplitMessage::Key(expected) = key(i) else {
+                            unreachable!()
+                        };
+                        assert_eq!(
+                            (k.row, k.col, k.pressed),
+                            (expected.row, expected.col, expected.pressed)
+                        );
+                    }
+                    m => panic!("Unexpected message {:?}", m),
+                }
+            }
+            central
+                .write(&SplitMessage::ConnectionState(true))
+                .await
+                .unwrap();
+            central
+                .write(&SplitMessage::ConnectionState(false))
+                .await
+                .unwrap();
+            // Nothing is left in the peripheral's queue
+            assert!(central.poll().await.unwrap().is_none());
+            pending::<()>().await
+        };
+        match block_on(select3(server.run(), peripheral_task, central_task)) {
+            Either3::Second(received) => assert_eq!(received, [true, false]),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_i2c_split_cancelled_reads() {
+        let (controller, target) = bus();
+        let mut central = I2cCentralDriver::new(controller, ADDRESS);
+        let channels = I2cPeripheralChannels::new();
+        let (mut peripheral, mut server) = channels.split(target);
+        let key_num = 50;
+
+        // Reads of the peripheral are cancelled all the time, like the ones in `SplitPeripheral::run`
+        let peripheral_task = async {
+            for i in 0..key_num {
+                peripheral.write(&key(i)).await.unwrap();
+                for _ in 0..3 {
+                    let _ = select(peripheral.read(), yield_now()).await;
+                }
+            }
+            pending::<()>().await
+        };
+        let central_task = async {
+            for i in 0..key_num {
+                match central.read().await.unwrap() {
+                    SplitMessage::Key(k) => assert_eq!(k.row, i as u8),
+                    m => panic!("Unexpected message {:?}", m),
+                }
+            }
+        };
+        match block_on(select3(server.run(), peripheral_task, central_task)) {
+            Either3::Third(()) => (),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Poll the peripheral until a message is received, returns `None` if nothing is received in `polls` polls
+    fn poll_message<T: I2cTarget>(
+        central: &mut I2cCentralDriver<Controller>,
+        server: &mut I2cTargetServer<'_, T>,
+        polls: usize,
+    ) -> Option<SplitMessage> {
+        let poll = async {
+            for _ in 0..polls {
+                if let Some(message) = central.poll().await.unwrap() {
+                    return Some(message);
+                }
+            }
+            None
+        };
+        match block_on(select(server.run(), poll)) {
+            Either::First(_) => unreachable!(),
+            Either::Second(message) => message,
+        }
+    }
+
+    #[test]
+    fn test_i2c_split_peripheral_restart() {
+        let (controller, target) = bus();
+        let bus = controller.0.clone();
+        let mut central = I2cCentralDriver::new(controller, ADDRESS);
+        let channels = I2cPeripheralChannels::new();
+        let (mut peripheral, mut server) = channels.split(target);
+        block_on(peripheral.write(&key(1))).unwrap();
+        assert!(matches!(
+            poll_message(&mut central, &mut server, 3),
+            Some(SplitMessage::Key(k)) if k.row == 1
+        ));
+
+        // The peripheral restarts before the message is acked,
+        // the first message after the restart has the same sequence number as the unacked one
+        let channels = I2cPeripheralChannels::new();
+        let (mut peripheral, mut server) = channels.split(Target { bus, reading: 0 });
+        block_on(peripheral.write(&key(2))).unwrap();
+        assert!(matches!(
+            poll_message(&mut central, &mut server, 3),
+            Some(SplitMessage::Key(k)) if k.row == 2
+        ));
+        assert!(poll_message(&mut central, &mut server, 3).is_none());
+    }
+
+    #[test]
+    fn test_i2c_split_retry_after_bus_error() {
+        let (controller, target) = bus();
+        let bus = controller.0.clone();
+        let mut central = I2cCentralDriver::new(controller, ADDRESS);
+        let channels = I2cPeripheralChannels::new();
+        let (mut peripheral, mut server) = channels.split(target);
+
+        block_on(peripheral.write(&key(1))).unwrap();
+        bus.borrow_mut().disconnected = true;
+        assert!(matches!(
+            block_on(central.poll()),
+            Err(SplitDriverError::I2cError)
+        ));
+        // Reads keep retrying while the peripheral is unplugged, the errors are counted as link errors
+        assert!(block_on(with_timeout(Duration::from_millis(250), central.read())).is_err());
+        assert!(central.failing);
+        assert!(central.take_link_errors().frame >= 2);
+        // Writes to the unplugged peripheral are dropped
+        assert!(matches!(
+            block_on(central.write(&SplitMessage::ConnectionState(true))),
+            Err(SplitDriverError::Disconnected)
+        ));
+        bus.borrow_mut().disconnected = false;
+
+        // The message is kept in the queue until it's received by the central
+        match block_on(select(server.run(), central.read())) {
+            Either::Second(Ok(SplitMessage::Key(k))) => assert_eq!((k.row, k.pressed), (1, false)),
+            _ => panic!("Key is not received"),
+        }
+        assert!(!central.failing);
+    }
+}
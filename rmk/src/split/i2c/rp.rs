@@ -0,0 +1,25 @@
+//! [`I2cTarget`] implementation for the I2C target driver of RP2040
+use embassy_rp::i2c::Instance;
+use embassy_rp::i2c_slave::{Command, Error, I2cSlave};
+
+use super::{I2cTarget, I2cTargetCommand};
+
+impl<T: Instance> I2cTarget for I2cSlave<'_, T> {
+    type Error = Error;
+
+    async fn listen(&mut self, buf: &mut [u8]) -> Result<I2cTargetCommand, Self::Error> {
+        loop {
+            match I2cSlave::listen(self, buf).await? {
+                Command::Read => return Ok(I2cTargetCommand::Read),
+                Command::Write(n) => return Ok(I2cTargetCommand::Write(n)),
+                Command::WriteRead(n) => return Ok(I2cTargetCommand::WriteRead(n)),
+                // General calls are sent to all targets on the bus, they're not used by split
+                Command::GeneralCall(_) => (),
+            }
+        }
+    }
+
+    async fn respond_to_read(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.respond_and_fill(buf, 0).await.map(|_| ())
+    }
+}
@@ -3,16 +3,24 @@ use serde::{Deserialize, Serialize};
 
 use crate::event::{Event, KeyEvent};
 
+pub mod battery;
 pub mod central;
 /// Common abstraction layer of split driver
 pub(crate) mod driver;
+pub mod ee_hands;
+#[cfg(feature = "_esp_ble")]
+pub mod esp;
+#[cfg(not(feature = "_nrf_ble"))]
+pub mod i2c;
 #[cfg(feature = "_nrf_ble")]
 pub mod nrf;
 pub mod peripheral;
 #[cfg(feature = "rp2040_pio")]
 pub mod rp;
-#[cfg(not(feature = "_nrf_ble"))]
+#[cfg(not(feature = "_ble"))]
 pub mod serial;
+pub mod state;
+pub mod stats;
 
 /// Maximum size of a split message
 pub const SPLIT_MESSAGE_MAX_SIZE: usize = SplitMessage::POSTCARD_MAX_SIZE + 4;
@@ -30,4 +38,16 @@ pub(crate) enum SplitMessage {
     /// The central connection state, true if central has been connected to host.
     /// This message is sync from central to peripheral
     ConnectionState(bool),
+    /// Keyboard state with its version, from central to peripheral.
+    ///
+    /// The state is encoded separately, so that it's decoded only if the version matches
+    State(u8, state::EncodedSplitState),
+    /// Heartbeat from peripheral to central, it's the reply of `ConnectionState`
+    Heartbeat,
+    /// Battery level of the peripheral in percent, from peripheral to central
+    BatteryLevel(u8),
+    /// Ping with a sequence number from central to peripheral, for measuring the round-trip latency
+    Ping(u16),
+    /// Reply of `Ping` with the same sequence number, from peripheral to central
+    Pong(u16),
 }
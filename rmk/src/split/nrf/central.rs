@@ -6,7 +6,9 @@ use nrf_softdevice::ble::{central, gatt_client, Address, AddressType};
 
 use crate::{
     split::{
-        driver::{PeripheralManager, SplitDriverError, SplitReader, SplitWriter},
+        driver::{
+            ChannelSplitWriter, PeripheralManager, SplitDriverError, SplitReader, SplitWriter,
+        },
         SplitMessage, SPLIT_MESSAGE_MAX_SIZE,
     },
     RawMutex, CONNECTION_STATE,
@@ -22,13 +24,10 @@ pub(crate) struct BleSplitCentralClient {
     pub(crate) message_to_peripheral: [u8; SPLIT_MESSAGE_MAX_SIZE],
 }
 
-pub(crate) async fn run_ble_peripheral_manager<
-    const ROW: usize,
-    const COL: usize,
-    const ROW_OFFSET: usize,
-    const COL_OFFSET: usize,
->(
+pub(crate) async fn run_ble_peripheral_manager<const ROW: usize, const COL: usize>(
     id: usize,
+    row_offset: usize,
+    col_offset: usize,
     addr: [u8; 6],
 ) {
     // Channel is used to receive messages from peripheral
@@ -44,13 +43,13 @@ pub(crate) async fn run_ble_peripheral_manager<
 
     let split_ble_driver = BleSplitCentralDriver {
         receiver: receive_receiver,
-        sender: notify_sender,
+        writer: ChannelSplitWriter::new(notify_sender),
         connection_state: CONNECTION_STATE.load(Ordering::Acquire),
     };
 
     // Create peripheral manager instance
     let peripheral_manager =
-        PeripheralManager::<ROW, COL, ROW_OFFSET, COL_OFFSET, _>::new(split_ble_driver, id);
+        PeripheralManager::<ROW, COL, _>::new(split_ble_driver, id, row_offset, col_offset);
 
     info!("Running peripheral manager {}", id);
 
@@ -172,8 +171,8 @@ pub(crate) async fn run_ble_client(
 pub(crate) struct BleSplitCentralDriver<'a> {
     // Receiver that receives message from peripheral
     pub(crate) receiver: Receiver<'a, RawMutex, SplitMessage, 8>,
-    // Sender that send message to peripherals
-    pub(crate) sender: Sender<'a, RawMutex, SplitMessage, 8>,
+    // Writer that sends message to peripherals, messages are dropped when the peripheral is disconnected
+    writer: ChannelSplitWriter<'a>,
     // Cached connection state
     connection_state: bool,
 }
@@ -193,7 +192,6 @@ impl SplitWriter for BleSplitCentralDriver<'_> {
             }
         }
         // Always sync the connection state to peripheral since central doesn't know the CONNECTION_STATE of the peripheral.
-        self.sender.send(message.clone()).await;
-        Ok(SPLIT_MESSAGE_MAX_SIZE)
+        self.writer.write(message).await
     }
 }
@@ -1,13 +1,21 @@
-use super::driver::{SplitReader, SplitWriter};
+use super::battery::{battery_level, battery_level_receiver};
+use super::driver::{SplitReader, SplitWriter, LINK_TIMEOUT};
+use super::state::receive_split_state;
 use super::SplitMessage;
 use crate::channel::{EVENT_CHANNEL, KEY_EVENT_CHANNEL};
 #[cfg(not(feature = "_nrf_ble"))]
+use crate::split::i2c::{I2cPeripheralChannels, I2cTarget};
+#[cfg(not(feature = "_ble"))]
 use crate::split::serial::SerialSplitDriver;
 use crate::CONNECTION_STATE;
+use core::future::pending;
 #[cfg(feature = "_nrf_ble")]
 use embassy_executor::Spawner;
-use embassy_futures::select::select3;
 #[cfg(not(feature = "_nrf_ble"))]
+use embassy_futures::join::join;
+use embassy_futures::select::{select4, Either4};
+use embassy_time::Instant;
+#[cfg(not(feature = "_ble"))]
 use embedded_io_async::{Read, Write};
 
 /// Run the split peripheral service.
@@ -15,17 +23,17 @@ use embedded_io_async::{Read, Write};
 /// # Arguments
 ///
 /// * `matrix` - the matrix scanning implementation to use.
-/// * `central_addr` - (optional) central's BLE static address. This argument is enabled only for nRF BLE split now
-/// * `peripheral_addr` - (optional) peripheral's BLE static address. This argument is enabled only for nRF BLE split now
+/// * `central_addr` - (optional) central's BLE static address. This argument is enabled only for BLE split
+/// * `peripheral_addr` - (optional) peripheral's BLE static address. This argument is enabled only for BLE split
 /// * `serial` - (optional) serial port used to send peripheral split message. This argument is enabled only for serial split now
-/// * `spawner`: (optional) embassy spawner used to spawn async tasks. This argument is enabled for non-esp microcontrollers
-pub async fn run_rmk_split_peripheral<#[cfg(not(feature = "_nrf_ble"))] S: Write + Read>(
-    #[cfg(feature = "_nrf_ble")] central_addr: [u8; 6],
-    #[cfg(feature = "_nrf_ble")] peripheral_addr: [u8; 6],
-    #[cfg(not(feature = "_nrf_ble"))] serial: S,
+/// * `spawner`: (optional) embassy spawner used to spawn async tasks. This argument is enabled only for nRF BLE split
+pub async fn run_rmk_split_peripheral<#[cfg(not(feature = "_ble"))] S: Write + Read>(
+    #[cfg(feature = "_ble")] central_addr: [u8; 6],
+    #[cfg(feature = "_ble")] peripheral_addr: [u8; 6],
+    #[cfg(not(feature = "_ble"))] serial: S,
     #[cfg(feature = "_nrf_ble")] spawner: Spawner,
 ) {
-    #[cfg(not(feature = "_nrf_ble"))]
+    #[cfg(not(feature = "_ble"))]
     {
         let mut peripheral = SplitPeripheral::new(SerialSplitDriver::new(serial));
         loop {
@@ -40,45 +48,116 @@ pub async fn run_rmk_split_peripheral<#[cfg(not(feature = "_nrf_ble"))] S: Write
         spawner,
     )
     .await;
+
+    #[cfg(feature = "_esp_ble")]
+    crate::split::esp::peripheral::initialize_esp_ble_split_peripheral_and_run(
+        central_addr,
+        peripheral_addr,
+    )
+    .await;
+}
+
+/// Run the split peripheral service, connected to the central by I2C.
+///
+/// The peripheral is an I2C target, its messages are queued until the central polls them.
+/// The target is served in its own loop, so that a transfer is never interrupted by the peripheral service.
+///
+/// # Arguments
+///
+/// * `target` - I2C target driver, which listens on the peripheral's address
+#[cfg(not(feature = "_nrf_ble"))]
+pub async fn run_rmk_split_i2c_peripheral<T: I2cTarget>(target: T) {
+    let channels = I2cPeripheralChannels::new();
+    let (driver, mut server) = channels.split(target);
+    let mut peripheral = SplitPeripheral::new(driver);
+    join(server.run(), peripheral.run()).await;
 }
 
 /// The split peripheral instance.
 pub(crate) struct SplitPeripheral<S: SplitWriter + SplitReader> {
     split_driver: S,
+    /// The time of the last message received from the central
+    last_received: Option<Instant>,
 }
 
 impl<S: SplitWriter + SplitReader> SplitPeripheral<S> {
     pub(crate) fn new(split_driver: S) -> Self {
-        Self { split_driver }
+        Self {
+            split_driver,
+            last_received: None,
+        }
+    }
+
+    /// Update the time of the last message from the central, returns true if the central is connected again.
+    ///
+    /// The central considers the peripheral as disconnected if nothing is received from it in `LINK_TIMEOUT`,
+    /// the peripheral uses the same timeout, since the central sends messages to it every 1s.
+    fn on_message_received(&mut self, now: Instant) -> bool {
+        let reconnected = self
+            .last_received
+            .is_none_or(|t| now.saturating_duration_since(t) >= LINK_TIMEOUT);
+        self.last_received = Some(now);
+        reconnected
     }
 
     /// Run the peripheral keyboard service.
     ///
     /// The peripheral uses the general matrix, does scanning and send the key events through `SplitWriter`.
     /// If also receives split messages from the central through `SplitReader`.
+    /// The battery level is sent to the central when it's updated, and when the central is connected again,
+    /// since the central forgets the level of a disconnected peripheral.
     pub(crate) async fn run(&mut self) -> ! {
+        let mut level_receiver = battery_level_receiver();
         loop {
-            match select3(
+            let battery_level_changed = async {
+                match level_receiver.as_mut() {
+                    Some(receiver) => receiver.changed().await,
+                    None => pending().await,
+                }
+            };
+
+            match select4(
                 self.split_driver.read(),
                 KEY_EVENT_CHANNEL.receive(),
                 EVENT_CHANNEL.receive(),
+                battery_level_changed,
             )
             .await
             {
-                embassy_futures::select::Either3::First(m) => match m {
+                Either4::First(m) => match m {
                     // Currently only handle the central state message
-                    Ok(split_message) => match split_message {
-                        SplitMessage::ConnectionState(state) => {
-                            info!("Received connection state update: {}", state);
-                            CONNECTION_STATE.store(state, core::sync::atomic::Ordering::Release);
+                    Ok(split_message) => {
+                        if self.on_message_received(Instant::now()) {
+                            if let Some(level) = battery_level() {
+                                debug!("Writing battery level to reconnected central: {}", level);
+                                self.split_driver
+                                    .write(&SplitMessage::BatteryLevel(level))
+                                    .await
+                                    .ok();
+                            }
                         }
-                        _ => (),
-                    },
+                        match split_message {
+                            SplitMessage::ConnectionState(state) => {
+                                info!("Received connection state update: {}", state);
+                                CONNECTION_STATE
+                                    .store(state, core::sync::atomic::Ordering::Release);
+                                // Reply a heartbeat, so that the central knows the peripheral is alive
+                                self.split_driver.write(&SplitMessage::Heartbeat).await.ok();
+                            }
+                            SplitMessage::Ping(seq) => {
+                                self.split_driver.write(&SplitMessage::Pong(seq)).await.ok();
+                            }
+                            SplitMessage::State(version, state) => {
+                                receive_split_state(version, &state);
+                            }
+                            _ => (),
+                        }
+                    }
                     Err(e) => {
                         error!("Split message read error: {:?}", e);
                     }
                 },
-                embassy_futures::select::Either3::Second(e) => {
+                Either4::Second(e) => {
                     // Only send the key event if the connection is established
                     if CONNECTION_STATE.load(core::sync::atomic::Ordering::Acquire) {
                         debug!("Writing split key event to central");
@@ -87,7 +166,7 @@ impl<S: SplitWriter + SplitReader> SplitPeripheral<S> {
                         debug!("Connection not established, skipping key event");
                     }
                 }
-                embassy_futures::select::Either3::Third(e) => {
+                Either4::Third(e) => {
                     if CONNECTION_STATE.load(core::sync::atomic::Ordering::Acquire) {
                         debug!("Writing split event to central: {:?}", e);
                         self.split_driver.write(&SplitMessage::Event(e)).await.ok();
@@ -95,7 +174,46 @@ impl<S: SplitWriter + SplitReader> SplitPeripheral<S> {
                         debug!("Connection not established, skipping event");
                     }
                 }
+                Either4::Fourth(level) => {
+                    debug!("Writing battery level to central: {}", level);
+                    self.split_driver
+                        .write(&SplitMessage::BatteryLevel(level))
+                        .await
+                        .ok();
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::split::driver::SplitDriverError;
+
+    struct MockDriver;
+
+    impl SplitReader for MockDriver {
+        async fn read(&mut self) -> Result<SplitMessage, SplitDriverError> {
+            pending().await
+        }
+    }
+
+    impl SplitWriter for MockDriver {
+        async fn write(&mut self, _message: &SplitMessage) -> Result<usize, SplitDriverError> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_central_reconnected() {
+        let mut peripheral = SplitPeripheral::new(MockDriver);
+        let start = Instant::from_secs(10);
+        // The first message from the central
+        assert!(peripheral.on_message_received(start));
+        assert!(!peripheral.on_message_received(start + LINK_TIMEOUT / 2));
+        // The central has considered the peripheral as disconnected
+        assert!(peripheral.on_message_received(start + LINK_TIMEOUT * 2));
+        assert!(!peripheral.on_message_received(start + LINK_TIMEOUT * 2));
+    }
+}
@@ -0,0 +1,241 @@
+//! Frames of the serial split transport.
+//!
+//! A frame is `[flags, seq, payload.., crc32]` before encoding. The payload is a postcard serialized [`SplitMessage`],
+//! ack frames have no payload. The CRC-32 is little endian, calculated over all preceding bytes.
+//!
+//! The frame is COBS encoded and terminated by `0x00`, so the receiver can always resync at the next `0x00`
+//! after corrupted or lost bytes.
+use postcard::experimental::max_size::MaxSize;
+
+use crate::split::SplitMessage;
+
+/// The frame is an ack of the frame with the same seq
+pub(crate) const FLAG_ACK: u8 = 0x01;
+/// The frame should be acked by the receiver
+pub(crate) const FLAG_RELIABLE: u8 = 0x02;
+/// The frame is sent before any ack is received since the sender started,
+/// the receiver resets its sequence tracking on it
+pub(crate) const FLAG_SYNC: u8 = 0x04;
+
+/// Frame delimiter
+pub(crate) const DELIMITER: u8 = 0x00;
+
+/// Maximum size of a frame before encoding
+pub(crate) const FRAME_MAX_SIZE: usize = SplitMessage::POSTCARD_MAX_SIZE + 6;
+
+/// Maximum size of an encoded frame, including the delimiter
+pub(crate) const ENCODED_FRAME_MAX_SIZE: usize = FRAME_MAX_SIZE + FRAME_MAX_SIZE / 254 + 2;
+
+/// Decoded frame
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Frame {
+    /// Ack of the reliable frame with `seq`
+    Ack(u8),
+    /// Split message
+    Data {
+        flags: u8,
+        seq: u8,
+        message: SplitMessage,
+    },
+}
+
+/// Frame error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum FrameError {
+    /// The frame can't be COBS decoded
+    Cobs,
+    /// The frame is too short, or its CRC doesn't match
+    Crc,
+    /// The payload can't be deserialized
+    Payload,
+    /// The message can't be serialized
+    Serialize,
+}
+
+/// CRC-32 (IEEE 802.3)
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// COBS encode `src` into `dst`, returns the encoded length, without the delimiter.
+///
+/// `dst` should be at least `src.len() + src.len() / 254 + 1` bytes.
+fn cobs_encode(src: &[u8], dst: &mut [u8]) -> usize {
+    let mut code_idx = 0;
+    let mut dst_idx = 1;
+    let mut code = 1_u8;
+    for byte in src {
+        if *byte == 0 {
+            dst[code_idx] = code;
+            code_idx = dst_idx;
+            dst_idx += 1;
+            code = 1;
+        } else {
+            dst[dst_idx] = *byte;
+            dst_idx += 1;
+            code += 1;
+            if code == 0xFF {
+                dst[code_idx] = code;
+                code_idx = dst_idx;
+                dst_idx += 1;
+                code = 1;
+            }
+        }
+    }
+    dst[code_idx] = code;
+    dst_idx
+}
+
+/// COBS decode `buf` in place, `buf` doesn't contain the delimiter. Returns the decoded length.
+fn cobs_decode(buf: &mut [u8]) -> Result<usize, FrameError> {
+    let mut read_idx = 0;
+    let mut write_idx = 0;
+    while read_idx < buf.len() {
+        let code = buf[read_idx] as usize;
+        if code == 0 || read_idx + code > buf.len() {
+            return Err(FrameError::Cobs);
+        }
+        read_idx += 1;
+        for _ in 1..code {
+            buf[write_idx] = buf[read_idx];
+            write_idx += 1;
+            read_idx += 1;
+        }
+        if code != 0xFF && read_idx < buf.len() {
+            buf[write_idx] = 0;
+            write_idx += 1;
+        }
+    }
+    Ok(write_idx)
+}
+
+/// Encode a frame into `buf`, returns the encoded bytes, including the delimiter
+pub(crate) fn encode_frame<'a>(
+    flags: u8,
+    seq: u8,
+    message: Option<&SplitMessage>,
+    buf: &'a mut [u8; ENCODED_FRAME_MAX_SIZE],
+) -> Result<&'a [u8], FrameError> {
+    let mut frame = [0_u8; FRAME_MAX_SIZE];
+    frame[0] = flags;
+    frame[1] = seq;
+    let mut len = 2;
+    if let Some(message) = message {
+        len += postcard::to_slice(message, &mut frame[2..FRAME_MAX_SIZE - 4])
+            .map_err(|_| FrameError::Serialize)?
+            .len();
+    }
+    let crc = crc32(&frame[..len]);
+    frame[len..len + 4].copy_from_slice(&crc.to_le_bytes());
+    len += 4;
+
+    let encoded_len = cobs_encode(&frame[..len], buf);
+    buf[encoded_len] = DELIMITER;
+    Ok(&buf[..encoded_len + 1])
+}
+
+/// Decode a frame in place, `buf` is the encoded frame without the delimiter
+pub(crate) fn decode_frame(buf: &mut [u8]) -> Result<Frame, FrameError> {
+    let len = cobs_decode(buf)?;
+    if len < 6 {
+        return Err(FrameError::Crc);
+    }
+    let (frame, crc) = buf[..len].split_at(len - 4);
+    if crc32(frame).to_le_bytes() != crc {
+        return Err(FrameError::Crc);
+    }
+    let (flags, seq) = (frame[0], frame[1]);
+    if flags & FLAG_ACK != 0 {
+        return Ok(Frame::Ack(seq));
+    }
+    let message = postcard::from_bytes(&frame[2..]).map_err(|_| FrameError::Payload)?;
+    Ok(Frame::Data {
+        flags,
+        seq,
+        message,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::event::KeyEvent;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_encoded_frame_has_no_zero() {
+        let message = SplitMessage::Key(KeyEvent {
+            row: 0,
+            col: 0,
+            pressed: false,
+        });
+        let mut buf = [0_u8; ENCODED_FRAME_MAX_SIZE];
+        let encoded = encode_frame(0, 0, Some(&message), &mut buf).unwrap();
+        let (delimiter, body) = encoded.split_last().unwrap();
+        assert_eq!(*delimiter, DELIMITER);
+        assert!(!body.contains(&DELIMITER));
+    }
+
+    proptest! {
+        #[test]
+        fn cobs_roundtrip(data in prop::collection::vec(any::<u8>(), 0..600)) {
+            let mut encoded = vec![0_u8; data.len() + data.len() / 254 + 1];
+            let len = cobs_encode(&data, &mut encoded);
+            prop_assert!(!encoded[..len].contains(&0));
+            let decoded_len = cobs_decode(&mut encoded[..len]).unwrap();
+            prop_assert_eq!(&encoded[..decoded_len], &data[..]);
+        }
+
+        #[test]
+        fn frame_roundtrip(row: u8, col: u8, pressed: bool, flags in 0_u8..8, seq: u8) {
+            let flags = flags & !FLAG_ACK;
+            let message = SplitMessage::Key(KeyEvent { row, col, pressed });
+            let mut buf = [0_u8; ENCODED_FRAME_MAX_SIZE];
+            let encoded = encode_frame(flags, seq, Some(&message), &mut buf).unwrap();
+            let mut encoded = encoded[..encoded.len() - 1].to_vec();
+            match decode_frame(&mut encoded).unwrap() {
+                Frame::Data { flags: f, seq: s, message: SplitMessage::Key(key) } => {
+                    prop_assert_eq!((f, s), (flags, seq));
+                    prop_assert_eq!((key.row, key.col, key.pressed), (row, col, pressed));
+                }
+                frame => prop_assert!(false, "Unexpected frame {:?}", frame),
+            }
+        }
+
+        #[test]
+        fn corrupted_frame_is_rejected(
+            row: u8,
+            col: u8,
+            seq: u8,
+            flips in prop::collection::vec((any::<prop::sample::Index>(), 0_u8..8), 1..4),
+        ) {
+            let message = SplitMessage::Key(KeyEvent { row, col, pressed: true });
+            let mut buf = [0_u8; ENCODED_FRAME_MAX_SIZE];
+            let encoded = encode_frame(FLAG_RELIABLE, seq, Some(&message), &mut buf).unwrap();
+            let mut encoded = encoded[..encoded.len() - 1].to_vec();
+            let original = encoded.clone();
+            for (idx, bit) in flips {
+                let idx = idx.index(encoded.len());
+                encoded[idx] ^= 1 << bit;
+            }
+            // Flips may cancel each other out
+            prop_assume!(encoded != original);
+            prop_assert!(decode_frame(&mut encoded).is_err());
+        }
+    }
+}
@@ -1,33 +1,56 @@
+//! Serial split transport.
+//!
+//! Split messages are sent in frames with CRC, see [`frame`] for the frame format.
+//! Key events are reliable: the receiver acks them, and the sender retransmits them if no ack is received in time.
+//! Other messages are sent periodically or are less important, so they're not acked.
+use embassy_time::{with_timeout, Duration};
 use embedded_io_async::{Read, Write};
+use heapless::Deque;
 
 use crate::split::{
     driver::{PeripheralManager, SplitReader, SplitWriter},
-    SplitMessage, SPLIT_MESSAGE_MAX_SIZE,
+    stats::LinkErrors,
+    SplitMessage,
 };
 
+use self::frame::{
+    decode_frame, encode_frame, Frame, FrameError, DELIMITER, ENCODED_FRAME_MAX_SIZE, FLAG_ACK,
+    FLAG_RELIABLE, FLAG_SYNC,
+};
 use super::driver::SplitDriverError;
 
+pub(crate) mod frame;
+
+/// Time to wait for the ack of a reliable frame
+const ACK_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// Maximum number of retransmissions of a reliable frame
+const MAX_RETRANSMISSION: usize = 3;
+
+/// Maximum number of received messages which are not read yet
+const PENDING_MESSAGE_NUM: usize = 4;
+
 // Receive split message from peripheral via serial and process it
 ///
 /// Generic parameters:
 /// - `const ROW`: row number of the peripheral's matrix
 /// - `const COL`: column number of the peripheral's matrix
-/// - `const ROW_OFFSET`: row offset of the peripheral's matrix in the whole matrix
-/// - `const COL_OFFSET`: column offset of the peripheral's matrix in the whole matrix
 /// - `S`: a serial port that implements `Read` and `Write` trait in embedded-io-async
+///
+/// `row_offset` and `col_offset` are the offsets of the peripheral's matrix in the whole matrix
 pub(crate) async fn run_serial_peripheral_manager<
     const ROW: usize,
     const COL: usize,
-    const ROW_OFFSET: usize,
-    const COL_OFFSET: usize,
     S: Read + Write,
 >(
     id: usize,
+    row_offset: usize,
+    col_offset: usize,
     receiver: S,
 ) {
     let split_serial_driver: SerialSplitDriver<S> = SerialSplitDriver::new(receiver);
     let peripheral_manager =
-        PeripheralManager::<ROW, COL, ROW_OFFSET, COL_OFFSET, _>::new(split_serial_driver, id);
+        PeripheralManager::<ROW, COL, _>::new(split_serial_driver, id, row_offset, col_offset);
     info!("Running peripheral manager {}", id);
 
     peripheral_manager.run().await;
@@ -36,80 +59,452 @@ pub(crate) async fn run_serial_peripheral_manager<
 /// Serial driver for BOTH split central and peripheral
 pub(crate) struct SerialSplitDriver<S: Read + Write> {
     serial: S,
-    buffer: [u8; SPLIT_MESSAGE_MAX_SIZE],
+    /// Received bytes, COBS encoded
+    buffer: [u8; ENCODED_FRAME_MAX_SIZE],
     n_bytes_part: usize,
+    /// The buffer overflowed, received bytes are dropped until the next delimiter
+    discarding: bool,
+    /// Sequence number of the next reliable frame
+    tx_seq: u8,
+    /// Whether any ack has been received
+    acked: bool,
+    /// Sequence number and sync flag of the last accepted reliable frame, used to drop retransmitted duplicates
+    last_received: Option<(u8, bool)>,
+    /// Messages which are received but not read, such as the ones received when waiting for an ack
+    pending: Deque<SplitMessage, PENDING_MESSAGE_NUM>,
+    /// Dropped frames, which are not taken yet
+    errors: LinkErrors,
 }
 
 impl<S: Read + Write> SerialSplitDriver<S> {
     pub(crate) fn new(serial: S) -> Self {
         Self {
             serial,
-            buffer: [0_u8; SPLIT_MESSAGE_MAX_SIZE],
+            buffer: [0_u8; ENCODED_FRAME_MAX_SIZE],
             n_bytes_part: 0,
+            discarding: false,
+            tx_seq: 0,
+            acked: false,
+            last_received: None,
+            pending: Deque::new(),
+            errors: LinkErrors::default(),
         }
     }
-}
 
-impl<S: Read + Write> SplitReader for SerialSplitDriver<S> {
-    async fn read(&mut self) -> Result<SplitMessage, SplitDriverError> {
-        const SENTINEL: u8 = 0x00;
-        while self.n_bytes_part < self.buffer.len() {
+    /// Read the next valid frame, corrupted frames are dropped
+    async fn read_frame(&mut self) -> Result<Frame, SplitDriverError> {
+        loop {
+            // Process the received frame first
+            if let Some(pos) = self.buffer[..self.n_bytes_part]
+                .iter()
+                .position(|b| *b == DELIMITER)
+            {
+                let result = if self.discarding || pos == 0 {
+                    self.discarding = false;
+                    None
+                } else {
+                    Some(decode_frame(&mut self.buffer[..pos]))
+                };
+                self.buffer.copy_within(pos + 1..self.n_bytes_part, 0);
+                self.n_bytes_part -= pos + 1;
+                match result {
+                    Some(Ok(frame)) => return Ok(frame),
+                    Some(Err(e)) => {
+                        warn!("Dropped corrupted split frame: {:?}", e);
+                        if e == FrameError::Payload {
+                            self.errors.deserialize = self.errors.deserialize.saturating_add(1);
+                        } else {
+                            self.errors.frame = self.errors.frame.saturating_add(1);
+                        }
+                    }
+                    None => (),
+                }
+                continue;
+            }
+
+            if self.n_bytes_part == self.buffer.len() {
+                // No delimiter in the whole buffer, resync at the next delimiter
+                warn!("Split frame is too long, dropped");
+                self.errors.frame = self.errors.frame.saturating_add(1);
+                self.n_bytes_part = 0;
+                self.discarding = true;
+            }
+
             let n_bytes = self
                 .serial
                 .read(&mut self.buffer[self.n_bytes_part..])
                 .await
-                .map_err(|_e| {
-                    self.n_bytes_part = 0;
-                    SplitDriverError::SerialError
-                })?;
+                .map_err(|_e| SplitDriverError::SerialError)?;
             if n_bytes == 0 {
                 return Err(SplitDriverError::EmptyMessage);
             }
+            self.n_bytes_part += n_bytes;
+        }
+    }
+
+    /// Write a frame
+    async fn write_frame(
+        &mut self,
+        flags: u8,
+        seq: u8,
+        message: Option<&SplitMessage>,
+    ) -> Result<usize, SplitDriverError> {
+        let mut buf = [0_u8; ENCODED_FRAME_MAX_SIZE];
+        let bytes = encode_frame(flags, seq, message, &mut buf).map_err(|e| {
+            error!("Split frame encode error: {:?}", e);
+            SplitDriverError::SerializeError
+        })?;
+        self.serial
+            .write_all(bytes)
+            .await
+            .map_err(|_e| SplitDriverError::SerialError)?;
+        Ok(bytes.len())
+    }
 
-            self.n_bytes_part = (self.n_bytes_part + n_bytes).min(self.buffer.len());
-            if self.buffer[..self.n_bytes_part].contains(&SENTINEL) {
-                break;
+    /// Save the received message to the pending queue, and ack it if it's reliable
+    async fn accept(
+        &mut self,
+        flags: u8,
+        seq: u8,
+        message: SplitMessage,
+    ) -> Result<(), SplitDriverError> {
+        if flags & FLAG_RELIABLE == 0 {
+            if self.pending.push_back(message).is_err() {
+                warn!("Too many pending split messages, dropped");
             }
+            return Ok(());
         }
 
-        let (result, n_bytes_unused) = match postcard::take_from_bytes_cobs::<SplitMessage>(
-            &mut self.buffer.clone()[..self.n_bytes_part],
-        ) {
-            Ok((message, unused_bytes)) => (Ok(message), unused_bytes.len()),
-            Err(e) => {
-                error!("Postcard deserialize split message error: {}", e);
-                let n_bytes_unused = self.buffer[..self.n_bytes_part]
-                    .iter()
-                    .position(|&x| x == SENTINEL)
-                    .map_or(0, |index| self.n_bytes_part - index - 1);
-                (Err(SplitDriverError::SerializeError), n_bytes_unused)
+        let id = (seq, flags & FLAG_SYNC != 0);
+        if self.last_received == Some(id) {
+            debug!("Dropped duplicated split message, seq: {}", seq);
+        } else if self.pending.push_back(message).is_ok() {
+            self.last_received = Some(id);
+        } else {
+            // Not acked, the sender will retransmit it
+            warn!("Too many pending split messages, dropped");
+            return Ok(());
+        }
+        self.write_frame(FLAG_ACK, seq, None).await?;
+        Ok(())
+    }
+
+    /// Wait for the ack of the reliable frame with `seq`, messages received meanwhile are saved
+    async fn wait_for_ack(&mut self, seq: u8) -> Result<(), SplitDriverError> {
+        loop {
+            match self.read_frame().await? {
+                Frame::Ack(s) if s == seq => return Ok(()),
+                // Stale ack of a retransmitted frame
+                Frame::Ack(_) => (),
+                Frame::Data {
+                    flags,
+                    seq: s,
+                    message,
+                } => self.accept(flags, s, message).await?,
             }
-        };
+        }
+    }
+}
 
-        self.buffer
-            .copy_within(self.n_bytes_part - n_bytes_unused..self.n_bytes_part, 0);
-        self.n_bytes_part = n_bytes_unused;
+impl<S: Read + Write> SplitReader for SerialSplitDriver<S> {
+    async fn read(&mut self) -> Result<SplitMessage, SplitDriverError> {
+        loop {
+            if let Some(message) = self.pending.pop_front() {
+                return Ok(message);
+            }
+            match self.read_frame().await? {
+                Frame::Data {
+                    flags,
+                    seq,
+                    message,
+                } => self.accept(flags, seq, message).await?,
+                // No frame is waiting for the ack
+                Frame::Ack(_) => (),
+            }
+        }
+    }
 
-        result
+    fn take_link_errors(&mut self) -> LinkErrors {
+        core::mem::take(&mut self.errors)
     }
 }
 
 impl<S: Read + Write> SplitWriter for SerialSplitDriver<S> {
     async fn write(&mut self, message: &SplitMessage) -> Result<usize, SplitDriverError> {
-        let mut buf = [0_u8; SPLIT_MESSAGE_MAX_SIZE];
-        let bytes = postcard::to_slice_cobs(message, &mut buf).map_err(|e| {
-            error!("Postcard serialize split message error: {}", e);
-            SplitDriverError::SerializeError
-        })?;
-        let mut remaining_bytes = bytes.len();
-        while remaining_bytes > 0 {
-            let sent_bytes = self
-                .serial
-                .write(&bytes[bytes.len() - remaining_bytes..])
-                .await
-                .map_err(|_e| SplitDriverError::SerialError)?;
-            remaining_bytes -= sent_bytes;
+        if !matches!(message, SplitMessage::Key(_)) {
+            return self.write_frame(0, 0, Some(message)).await;
+        }
+
+        let seq = self.tx_seq;
+        self.tx_seq = self.tx_seq.wrapping_add(1);
+        // Mark the frame before the first ack, so that the receiver knows that the sequence is restarted
+        let flags = if self.acked {
+            FLAG_RELIABLE
+        } else {
+            FLAG_RELIABLE | FLAG_SYNC
+        };
+        for _ in 0..=MAX_RETRANSMISSION {
+            let n_bytes = self.write_frame(flags, seq, Some(message)).await?;
+            if let Ok(result) = with_timeout(ACK_TIMEOUT, self.wait_for_ack(seq)).await {
+                result?;
+                self.acked = true;
+                return Ok(n_bytes);
+            }
+            warn!("No ack of split message, seq: {}", seq);
+        }
+        Err(SplitDriverError::AckTimeout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::convert::Infallible;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::{select, Either};
+    use embassy_futures::yield_now;
+    use embedded_io_async::ErrorType;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::event::KeyEvent;
+
+    /// One direction of an in-memory serial connection, which corrupts and drops bytes randomly
+    #[derive(Default)]
+    struct Pipe {
+        data: VecDeque<u8>,
+        /// Xorshift random state
+        rng: u64,
+        /// Probability of flipping a random bit of each byte, in 1/1000
+        flip_per_mille: u64,
+        /// Probability of dropping each byte, in 1/1000
+        drop_per_mille: u64,
+    }
+
+    impl Pipe {
+        fn random(&mut self) -> u64 {
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+            self.rng
+        }
+
+        fn push(&mut self, byte: u8) {
+            if self.random() % 1000 < self.drop_per_mille {
+                return;
+            }
+            let byte = if self.random() % 1000 < self.flip_per_mille {
+                byte ^ (1 << (self.random() % 8))
+            } else {
+                byte
+            };
+            self.data.push_back(byte);
+        }
+    }
+
+    /// An end of the in-memory serial connection
+    struct PipeEnd {
+        rx: Rc<RefCell<Pipe>>,
+        tx: Rc<RefCell<Pipe>>,
+    }
+
+    impl ErrorType for PipeEnd {
+        type Error = Infallible;
+    }
+
+    impl Read for PipeEnd {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            loop {
+                {
+                    let mut rx = self.rx.borrow_mut();
+                    if !rx.data.is_empty() {
+                        let n = buf.len().min(rx.data.len());
+                        for b in buf[..n].iter_mut() {
+                            *b = rx.data.pop_front().unwrap();
+                        }
+                        return Ok(n);
+                    }
+                }
+                yield_now().await;
+            }
+        }
+    }
+
+    impl Write for PipeEnd {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            let mut tx = self.tx.borrow_mut();
+            for b in buf {
+                tx.push(*b);
+            }
+            Ok(buf.len())
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// Create a pair of connected pipe ends, bytes in both directions are corrupted with the same probabilities
+    fn pipe_pair(seed: u64, flip_per_mille: u64, drop_per_mille: u64) -> (PipeEnd, PipeEnd) {
+        let forward = Rc::new(RefCell::new(Pipe {
+            rng: seed | 1,
+            flip_per_mille,
+            drop_per_mille,
+            ..Default::default()
+        }));
+        let backward = Rc::new(RefCell::new(Pipe {
+            rng: seed.rotate_left(32) | 1,
+            flip_per_mille,
+            drop_per_mille,
+            ..Default::default()
+        }));
+        (
+            PipeEnd {
+                rx: backward.clone(),
+                tx: forward.clone(),
+            },
+            PipeEnd {
+                rx: forward,
+                tx: backward,
+            },
+        )
+    }
+
+    /// Key event as (row, col, pressed)
+    type Key = (u8, u8, bool);
+
+    fn key(i: usize) -> Key {
+        (i as u8, (i >> 8) as u8, i.is_multiple_of(2))
+    }
+
+    /// Send key events from the peripheral to the central.
+    ///
+    /// Returns keys which are acked, and keys which are received by the central.
+    fn send_keys(peripheral: PipeEnd, central: PipeEnd, key_num: usize) -> (Vec<Key>, Vec<Key>) {
+        let mut peripheral = SerialSplitDriver::new(peripheral);
+        let mut central = SerialSplitDriver::new(central);
+        let mut acked = Vec::new();
+        let mut received = Vec::new();
+
+        let send = async {
+            for i in 0..key_num {
+                let (row, col, pressed) = key(i);
+                let message = SplitMessage::Key(KeyEvent { row, col, pressed });
+                if peripheral.write(&message).await.is_ok() {
+                    acked.push(key(i));
+                }
+            }
+        };
+        let receive = async {
+            loop {
+                if let Ok(SplitMessage::Key(k)) = central.read().await {
+                    received.push((k.row, k.col, k.pressed));
+                }
+            }
+        };
+        match block_on(select(send, receive)) {
+            Either::First(_) => (),
+            Either::Second(_) => unreachable!(),
+        }
+        (acked, received)
+    }
+
+    #[test]
+    fn test_send_keys() {
+        let (peripheral, central) = pipe_pair(1, 0, 0);
+        let (acked, received) = send_keys(peripheral, central, 20);
+        let keys: Vec<_> = (0..20).map(key).collect();
+        assert_eq!(acked, keys);
+        assert_eq!(received, keys);
+    }
+
+    #[test]
+    fn test_resync_after_garbage() {
+        let (mut peripheral, central) = pipe_pair(1, 0, 0);
+        // Garbage without delimiter, which is longer than the receiving buffer
+        block_on(peripheral.write(&[0xAA; 3 * ENCODED_FRAME_MAX_SIZE])).unwrap();
+        let (acked, received) = send_keys(peripheral, central, 5);
+        let keys: Vec<_> = (0..5).map(key).collect();
+        assert_eq!(acked, keys);
+        assert_eq!(received, keys);
+    }
+
+    #[test]
+    fn test_corrupted_frames_are_counted() {
+        let (mut peripheral, central) = pipe_pair(1, 0, 0);
+        // A frame which is too short to have a CRC
+        block_on(peripheral.write(&[0x03, 0x11, 0x22, DELIMITER])).unwrap();
+        let mut peripheral = SerialSplitDriver::new(peripheral);
+        let mut central = SerialSplitDriver::new(central);
+        block_on(peripheral.write(&SplitMessage::Heartbeat)).unwrap();
+
+        assert!(matches!(
+            block_on(central.read()),
+            Ok(SplitMessage::Heartbeat)
+        ));
+        assert_eq!(
+            central.take_link_errors(),
+            LinkErrors {
+                frame: 1,
+                deserialize: 0
+            }
+        );
+        assert_eq!(central.take_link_errors(), LinkErrors::default());
+    }
+
+    #[test]
+    fn test_messages_received_when_waiting_for_ack() {
+        let (peripheral, central) = pipe_pair(1, 0, 0);
+        let mut peripheral = SerialSplitDriver::new(peripheral);
+        let mut central = SerialSplitDriver::new(central);
+
+        block_on(central.write(&SplitMessage::ConnectionState(true))).unwrap();
+        let key = SplitMessage::Key(KeyEvent {
+            row: 1,
+            col: 2,
+            pressed: true,
+        });
+        let send = async {
+            peripheral.write(&key).await.unwrap();
+            peripheral.read().await.unwrap()
+        };
+        let receive = async {
+            loop {
+                central.read().await.ok();
+            }
+        };
+        match block_on(select(send, receive)) {
+            Either::First(SplitMessage::ConnectionState(state)) => assert!(state),
+            _ => panic!("Connection state is not received"),
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        /// Corrupted or lost keys are never received, and acked keys are received exactly once, in order
+        #[test]
+        fn reliable_keys_with_bit_errors(
+            seed: u64,
+            flip_per_mille in 0_u64..10,
+            drop_per_mille in 0_u64..10,
+        ) {
+            let (peripheral, central) = pipe_pair(seed, flip_per_mille, drop_per_mille);
+            let (acked, received) = send_keys(peripheral, central, 30);
+
+            // Received keys are sent keys, without duplicates
+            let mut sent = (0..30).map(key);
+            for k in received.iter() {
+                prop_assert!(sent.any(|s| s == *k), "Unexpected key {:?}", k);
+            }
+            // Keys which are not acked might be received too, if only the ack is lost
+            let mut received_iter = received.iter();
+            for k in acked.iter() {
+                prop_assert!(received_iter.any(|r| r == k), "Acked key {:?} is not received", k);
+            }
         }
-        Ok(bytes.len())
     }
 }
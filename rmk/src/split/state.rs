@@ -0,0 +1,216 @@
+//! Keyboard state synchronized from the central to peripherals.
+//!
+//! The central keeps the state of the whole keyboard, such as active layers, LED indicator and lighting mode,
+//! and sends it to peripherals when it's changed, and every 1s.
+//! Peripherals can read the latest state by [`split_state`], or wait for state changes by [`split_state_receiver`],
+//! to show the state on their own LEDs or displays.
+//!
+//! ```ignore
+//! let mut receiver = rmk::split::state::split_state_receiver().unwrap();
+//! loop {
+//!     let state = receiver.changed().await;
+//!     if state.layers & (1 << 1) != 0 {
+//!         // Layer 1 is active
+//!     }
+//! }
+//! ```
+use embassy_sync::watch::{Receiver, Watch};
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+use crate::RawMutex;
+
+/// Version of [`SplitState`], it's sent together with the state.
+///
+/// Bump it when the fields of [`SplitState`] are changed.
+/// Peripherals ignore the state whose version is different from theirs.
+pub const SPLIT_STATE_VERSION: u8 = 2;
+
+/// Size of the encoded [`SplitState`] in split messages.
+///
+/// Don't change it, so that the message can always be decoded and the state is decoded only if the version matches.
+pub(crate) const ENCODED_SPLIT_STATE_SIZE: usize = 32;
+
+const _: () = assert!(SplitState::POSTCARD_MAX_SIZE <= ENCODED_SPLIT_STATE_SIZE);
+
+/// [`SplitState`] encoded by postcard, padded with zeros
+pub(crate) type EncodedSplitState = [u8; ENCODED_SPLIT_STATE_SIZE];
+
+/// Maximum number of [`split_state_receiver`]s.
+///
+/// On the central, each peripheral takes one receiver.
+pub const SPLIT_STATE_RECEIVER_NUM: usize = 4;
+
+/// Latest keyboard state
+static SPLIT_STATE: Watch<RawMutex, SplitState, SPLIT_STATE_RECEIVER_NUM> = Watch::new();
+
+/// Lighting state of the keyboard.
+///
+/// RMK doesn't interpret it, set it on the central by [`update_light_state`] if your keyboard has lighting effects.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, MaxSize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LightState {
+    /// Lighting mode, 0 means off
+    pub mode: u8,
+    /// Brightness, range: 0 ~ 255
+    pub brightness: u8,
+    /// Speed of the lighting effect
+    pub speed: u8,
+    /// Hue of the lighting color
+    pub hue: u8,
+    /// Saturation of the lighting color
+    pub saturation: u8,
+}
+
+/// Keyboard state synchronized from the central to peripherals
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, MaxSize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SplitState {
+    /// Active layers, bit `n` is set if layer `n` is active. The default layer is always active
+    pub layers: u32,
+    /// Default layer
+    pub default_layer: u8,
+    /// Modifiers in the latest keyboard report, in HID modifier byte format
+    pub modifiers: u8,
+    /// HID LED indicator from the host.
+    ///
+    /// Bit 0: num lock, bit 1: caps lock, bit 2: scroll lock, bit 3: compose, bit 4: kana
+    pub led_indicator: u8,
+    /// Lighting state
+    pub light: LightState,
+    /// Current output of the central: 0 for USB, 1 for BLE
+    pub connection_type: u8,
+    /// Active BLE profile of the central
+    pub ble_profile: u8,
+    /// Connected peripherals, bit `n` is set if peripheral `n` is connected to the central.
+    ///
+    /// Only the first 8 peripherals are tracked.
+    pub peripherals: u8,
+}
+
+impl SplitState {
+    /// Whether the peripheral with `id` is connected to the central
+    pub fn is_peripheral_connected(&self, id: usize) -> bool {
+        id < 8 && self.peripherals & (1 << id) != 0
+    }
+
+    /// Encode the state, it's sent together with [`SPLIT_STATE_VERSION`]
+    pub(crate) fn encode(&self) -> EncodedSplitState {
+        let mut encoded = [0; ENCODED_SPLIT_STATE_SIZE];
+        // The buffer is always large enough, which is checked at compile time
+        let _ = postcard::to_slice(self, &mut encoded);
+        encoded
+    }
+}
+
+/// Get the latest keyboard state.
+///
+/// On peripherals, it's the default state before the first state is received from the central.
+pub fn split_state() -> SplitState {
+    SPLIT_STATE.try_get().unwrap_or_default()
+}
+
+/// Get a receiver which waits for the changes of keyboard state.
+///
+/// Returns `None` if there are already [`SPLIT_STATE_RECEIVER_NUM`] receivers.
+pub fn split_state_receiver(
+) -> Option<Receiver<'static, RawMutex, SplitState, SPLIT_STATE_RECEIVER_NUM>> {
+    SPLIT_STATE.receiver()
+}
+
+/// Update the lighting state on the central, the change is sent to peripherals
+pub fn update_light_state(light: LightState) {
+    update_split_state(|s| s.light = light);
+}
+
+/// Update the keyboard state, receivers are notified only if the state is changed
+pub(crate) fn update_split_state(f: impl Fn(&mut SplitState)) {
+    SPLIT_STATE.sender().send_if_modified(|state| {
+        let old = *state;
+        let mut new = old.unwrap_or_default();
+        f(&mut new);
+        *state = Some(new);
+        old != Some(new)
+    });
+}
+
+/// Update the keyboard state with the state received from the central.
+///
+/// The state is decoded only if `version` matches [`SPLIT_STATE_VERSION`], returns whether the state is accepted.
+pub(crate) fn receive_split_state(version: u8, encoded: &EncodedSplitState) -> bool {
+    if version != SPLIT_STATE_VERSION {
+        warn!(
+            "Ignored keyboard state of version {}, expected {}",
+            version, SPLIT_STATE_VERSION
+        );
+        return false;
+    }
+    match postcard::from_bytes::<SplitState>(encoded) {
+        Ok(state) => {
+            debug!("Received keyboard state update: {:?}", state);
+            update_split_state(|s| *s = state);
+            true
+        }
+        Err(e) => {
+            error!("Keyboard state decode error: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Update the output state from the current connection type and BLE profile
+pub(crate) fn update_output_state() {
+    let connection_type = crate::CONNECTION_TYPE.load(core::sync::atomic::Ordering::Acquire);
+    #[cfg(feature = "_ble")]
+    let ble_profile =
+        crate::ble::profile::ACTIVE_PROFILE.load(core::sync::atomic::Ordering::Acquire);
+    #[cfg(not(feature = "_ble"))]
+    let ble_profile = 0;
+    update_split_state(|s| {
+        s.connection_type = connection_type;
+        s.ble_profile = ble_profile;
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_encode_split_state() {
+        let state = SplitState {
+            layers: u32::MAX,
+            default_layer: 1,
+            ble_profile: 2,
+            ..Default::default()
+        };
+        let encoded = state.encode();
+        assert_eq!(postcard::from_bytes::<SplitState>(&encoded).unwrap(), state);
+    }
+
+    #[test]
+    fn test_update_split_state() {
+        let mut receiver = split_state_receiver().unwrap();
+        update_split_state(|s| s.ble_profile = 3);
+        assert_eq!(block_on(receiver.changed()).ble_profile, 3);
+        // Receivers are not notified if the state isn't changed
+        update_split_state(|s| s.ble_profile = 3);
+        assert!(receiver.try_changed().is_none());
+
+        // The state of another version is ignored
+        let state = SplitState {
+            ble_profile: 4,
+            ..split_state()
+        };
+        assert!(!receive_split_state(
+            SPLIT_STATE_VERSION + 1,
+            &state.encode()
+        ));
+        assert!(receiver.try_changed().is_none());
+        assert!(receive_split_state(SPLIT_STATE_VERSION, &state.encode()));
+        assert_eq!(receiver.try_changed().map(|s| s.ble_profile), Some(4));
+        assert_eq!(split_state().ble_profile, 4);
+    }
+}
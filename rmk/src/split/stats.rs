@@ -0,0 +1,160 @@
+//! Link statistics of split peripherals, on the central.
+//!
+//! The central counts messages and errors received from each peripheral, and measures the round-trip latency
+//! by sending a ping to the peripheral every second. The statistics can be read by [`peripheral_link_stats`],
+//! they're also logged periodically and can be queried by via, see the split keyboard documentation.
+//!
+//! ```ignore
+//! if let Some(stats) = rmk::split::stats::peripheral_link_stats(0) {
+//!     info!("Frame errors of peripheral 0: {}", stats.frame_errors);
+//! }
+//! ```
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::RawMutex;
+
+/// Maximum number of peripherals whose link statistics are kept on the central
+pub const PERIPHERAL_LINK_STATS_NUM: usize = 8;
+
+/// Link statistics of a split peripheral
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SplitLinkStats {
+    /// Whether the peripheral is connected
+    pub connected: bool,
+    /// Number of messages received from the peripheral
+    pub messages_received: u32,
+    /// Number of received messages which can't be deserialized
+    pub deserialize_errors: u32,
+    /// Number of CRC, framing and I2C transfer errors, which are counted only when the peripheral is connected
+    pub frame_errors: u32,
+    /// Number of times the peripheral is connected again after a disconnection
+    pub reconnects: u16,
+    /// Number of pings which aren't replied before the next ping
+    pub pings_lost: u16,
+    /// The latest round-trip latency in microseconds
+    pub last_latency_us: Option<u32>,
+    /// Moving average of the round-trip latency in microseconds
+    pub average_latency_us: Option<u32>,
+}
+
+impl SplitLinkStats {
+    const fn new() -> Self {
+        Self {
+            connected: false,
+            messages_received: 0,
+            deserialize_errors: 0,
+            frame_errors: 0,
+            reconnects: 0,
+            pings_lost: 0,
+            last_latency_us: None,
+            average_latency_us: None,
+        }
+    }
+
+    /// Record a round-trip latency, the average moves 1/8 towards it
+    pub(crate) fn record_latency(&mut self, latency_us: u32) {
+        self.last_latency_us = Some(latency_us);
+        self.average_latency_us = Some(match self.average_latency_us {
+            Some(average) => average - average / 8 + latency_us / 8,
+            None => latency_us,
+        });
+    }
+
+    /// Add errors counted by the transport
+    pub(crate) fn add_errors(&mut self, errors: LinkErrors) {
+        self.frame_errors = self.frame_errors.saturating_add(errors.frame);
+        self.deserialize_errors = self.deserialize_errors.saturating_add(errors.deserialize);
+    }
+}
+
+/// Errors counted by a split transport, which are dropped silently by the transport
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct LinkErrors {
+    /// Corrupted frames
+    pub(crate) frame: u32,
+    /// Frames whose payload can't be deserialized
+    pub(crate) deserialize: u32,
+}
+
+/// Link statistics of peripherals, on the central
+static PERIPHERAL_LINK_STATS: Mutex<
+    RawMutex,
+    RefCell<[SplitLinkStats; PERIPHERAL_LINK_STATS_NUM]>,
+> = Mutex::new(RefCell::new(
+    [SplitLinkStats::new(); PERIPHERAL_LINK_STATS_NUM],
+));
+
+/// Get the link statistics of the peripheral with `id`.
+///
+/// Returns `None` if the id is out of range.
+pub fn peripheral_link_stats(id: usize) -> Option<SplitLinkStats> {
+    PERIPHERAL_LINK_STATS.lock(|stats| stats.borrow().get(id).copied())
+}
+
+/// Reset the counters of the peripheral with `id`, the connection status is kept
+pub fn reset_peripheral_link_stats(id: usize) {
+    update_peripheral_link_stats(id, |s| {
+        *s = SplitLinkStats {
+            connected: s.connected,
+            ..SplitLinkStats::new()
+        }
+    });
+}
+
+/// Update the link statistics of the peripheral with `id`, out of range peripherals are ignored
+pub(crate) fn update_peripheral_link_stats(id: usize, f: impl FnOnce(&mut SplitLinkStats)) {
+    PERIPHERAL_LINK_STATS.lock(|stats| {
+        if let Some(s) = stats.borrow_mut().get_mut(id) {
+            f(s);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_latency() {
+        let mut stats = SplitLinkStats::default();
+        stats.record_latency(800);
+        assert_eq!(stats.last_latency_us, Some(800));
+        assert_eq!(stats.average_latency_us, Some(800));
+
+        stats.record_latency(1600);
+        assert_eq!(stats.last_latency_us, Some(1600));
+        assert_eq!(stats.average_latency_us, Some(900));
+    }
+
+    #[test]
+    fn test_peripheral_link_stats() {
+        update_peripheral_link_stats(2, |s| {
+            s.connected = true;
+            s.messages_received += 3;
+            s.add_errors(LinkErrors {
+                frame: 2,
+                deserialize: 1,
+            });
+        });
+        let stats = peripheral_link_stats(2).unwrap();
+        assert_eq!(stats.messages_received, 3);
+        assert_eq!(stats.frame_errors, 2);
+        assert_eq!(stats.deserialize_errors, 1);
+
+        reset_peripheral_link_stats(2);
+        assert_eq!(
+            peripheral_link_stats(2),
+            Some(SplitLinkStats {
+                connected: true,
+                ..Default::default()
+            })
+        );
+
+        // Out of range peripherals are ignored
+        update_peripheral_link_stats(PERIPHERAL_LINK_STATS_NUM, |s| s.reconnects += 1);
+        assert_eq!(peripheral_link_stats(PERIPHERAL_LINK_STATS_NUM), None);
+    }
+}
@@ -1,7 +1,12 @@
 pub mod dummy_flash;
 mod eeconfig;
 
+#[cfg(feature = "_ble")]
+use crate::ble::profile::BondInfo;
+#[cfg(feature = "split")]
+use crate::split::ee_hands::Handedness;
 use crate::{
+    analog_matrix::KeyCalibration,
     channel::FLASH_CHANNEL,
     combo::{Combo, COMBO_MAX_LENGTH},
     config::StorageConfig,
@@ -9,6 +14,8 @@ use crate::{
 };
 use byteorder::{BigEndian, ByteOrder};
 use core::fmt::Debug;
+#[cfg(feature = "_nrf_ble")]
+use core::mem;
 use core::ops::Range;
 use embassy_embedded_hal::adapter::BlockingAsync;
 use embedded_storage::nor_flash::NorFlash;
@@ -19,8 +26,6 @@ use sequential_storage::{
     map::{fetch_all_items, fetch_item, store_item, SerializationError, Value},
     Error as SSError,
 };
-#[cfg(feature = "_nrf_ble")]
-use {crate::ble::nrf::bonder::BondInfo, core::mem};
 
 use crate::keyboard_macro::MACRO_SPACE_SIZE;
 use crate::{
@@ -35,10 +40,10 @@ use self::eeconfig::EeKeymapConfig;
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub(crate) enum FlashOperationMessage {
     // Bond info to be saved
-    #[cfg(feature = "_nrf_ble")]
+    #[cfg(feature = "_ble")]
     BondInfo(BondInfo),
     // Current active BLE profile number
-    #[cfg(feature = "_nrf_ble")]
+    #[cfg(feature = "_ble")]
     ActiveBleProfile(u8),
     // Clear the storage
     Reset,
@@ -60,6 +65,11 @@ pub(crate) enum FlashOperationMessage {
     ConnectionType(u8),
     // Write combo
     WriteCombo(ComboData),
+    // Calibration of an analog key
+    AnalogCalibration(AnalogCalibrationData),
+    // Handedness of the split half
+    #[cfg(feature = "split")]
+    Handedness(Handedness),
 }
 
 #[repr(u32)]
@@ -73,9 +83,12 @@ pub(crate) enum StorageKeys {
     MacroData,
     ComboData,
     ConnectionType,
-    #[cfg(feature = "_nrf_ble")]
+    AnalogCalibration,
+    #[cfg(feature = "split")]
+    Handedness,
+    #[cfg(any(feature = "_ble", test))]
     ActiveBleProfile = 0xEE,
-    #[cfg(feature = "_nrf_ble")]
+    #[cfg(feature = "_ble")]
     BleBondInfo = 0xEF,
 }
 
@@ -91,7 +104,12 @@ impl StorageKeys {
             6 => Some(StorageKeys::MacroData),
             7 => Some(StorageKeys::ComboData),
             8 => Some(StorageKeys::ConnectionType),
-            #[cfg(feature = "_nrf_ble")]
+            9 => Some(StorageKeys::AnalogCalibration),
+            #[cfg(feature = "split")]
+            10 => Some(StorageKeys::Handedness),
+            #[cfg(any(feature = "_ble", test))]
+            0xEE => Some(StorageKeys::ActiveBleProfile),
+            #[cfg(feature = "_ble")]
             0xEF => Some(StorageKeys::BleBondInfo),
             _ => None,
         }
@@ -107,12 +125,21 @@ pub(crate) enum StorageData {
     MacroData([u8; MACRO_SPACE_SIZE]),
     ComboData(ComboData),
     ConnectionType(u8),
-    #[cfg(feature = "_nrf_ble")]
+    AnalogCalibration(AnalogCalibrationData),
+    #[cfg(feature = "split")]
+    Handedness(Handedness),
+    #[cfg(feature = "_ble")]
     BondInfo(BondInfo),
-    #[cfg(feature = "_nrf_ble")]
+    #[cfg(any(feature = "_ble", test))]
     ActiveBleProfile(u8),
 }
 
+/// Size of the bond info saved in the storage
+#[cfg(feature = "_nrf_ble")]
+const BOND_INFO_SIZE: usize = mem::size_of::<BondInfo>();
+#[cfg(feature = "_esp_ble")]
+const BOND_INFO_SIZE: usize = BondInfo::SIZE;
+
 pub(crate) fn get_bond_info_key(slot_num: u8) -> u32 {
     0x2000 + slot_num as u32
 }
@@ -129,6 +156,10 @@ pub(crate) fn get_combo_key(idx: usize) -> u32 {
     (0x3000 + idx) as u32
 }
 
+pub(crate) fn get_analog_calibration_key(row: u8, col: u8) -> u32 {
+    0x10000 + ((row as u32) << 8) + col as u32
+}
+
 impl Value<'_> for StorageData {
     fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
         if buffer.len() < 6 {
@@ -197,20 +228,39 @@ impl Value<'_> for StorageData {
                 buffer[1] = *ty;
                 Ok(2)
             }
-            #[cfg(feature = "_nrf_ble")]
+            StorageData::AnalogCalibration(c) => {
+                if buffer.len() < 7 {
+                    return Err(SerializationError::BufferTooSmall);
+                }
+                buffer[0] = StorageKeys::AnalogCalibration as u8;
+                buffer[1] = c.row;
+                buffer[2] = c.col;
+                BigEndian::write_u16(&mut buffer[3..5], c.rest);
+                BigEndian::write_u16(&mut buffer[5..7], c.bottom);
+                Ok(7)
+            }
+            #[cfg(feature = "split")]
+            StorageData::Handedness(h) => {
+                buffer[0] = StorageKeys::Handedness as u8;
+                buffer[1] = *h as u8;
+                Ok(2)
+            }
+            #[cfg(feature = "_ble")]
             StorageData::BondInfo(b) => {
-                if buffer.len() < 121 {
+                if buffer.len() < BOND_INFO_SIZE + 1 {
                     return Err(SerializationError::BufferTooSmall);
                 }
 
-                // Must be 120
-                // info!("size of BondInfo: {}", size_of_val(self));
+                // 120 bytes for nRF, 9 bytes for ESP32
                 buffer[0] = StorageKeys::BleBondInfo as u8;
-                let buf: [u8; 120] = unsafe { mem::transmute_copy(b) };
-                buffer[1..121].copy_from_slice(&buf);
-                Ok(121)
+                #[cfg(feature = "_nrf_ble")]
+                let buf: [u8; BOND_INFO_SIZE] = unsafe { mem::transmute_copy(b) };
+                #[cfg(feature = "_esp_ble")]
+                let buf = b.to_bytes();
+                buffer[1..BOND_INFO_SIZE + 1].copy_from_slice(&buf);
+                Ok(BOND_INFO_SIZE + 1)
             }
-            #[cfg(feature = "_nrf_ble")]
+            #[cfg(any(feature = "_ble", test))]
             StorageData::ActiveBleProfile(slot_num) => {
                 buffer[0] = StorageKeys::ActiveBleProfile as u8;
                 buffer[1] = *slot_num;
@@ -301,16 +351,35 @@ impl Value<'_> for StorageData {
                     }))
                 }
                 StorageKeys::ConnectionType => Ok(StorageData::ConnectionType(buffer[1])),
-                #[cfg(feature = "_nrf_ble")]
+                StorageKeys::AnalogCalibration => {
+                    if buffer.len() < 7 {
+                        return Err(SerializationError::InvalidData);
+                    }
+                    Ok(StorageData::AnalogCalibration(AnalogCalibrationData {
+                        row: buffer[1],
+                        col: buffer[2],
+                        rest: BigEndian::read_u16(&buffer[3..5]),
+                        bottom: BigEndian::read_u16(&buffer[5..7]),
+                    }))
+                }
+                #[cfg(feature = "split")]
+                StorageKeys::Handedness => Handedness::from_u8(buffer[1])
+                    .map(StorageData::Handedness)
+                    .ok_or(SerializationError::InvalidData),
+                #[cfg(feature = "_ble")]
                 StorageKeys::BleBondInfo => {
                     // Make `transmute_copy` happy, because the compiler doesn't know the size of buffer
-                    let mut buf = [0_u8; 120];
-                    buf.copy_from_slice(&buffer[1..121]);
+                    let mut buf = [0_u8; BOND_INFO_SIZE];
+                    buf.copy_from_slice(&buffer[1..BOND_INFO_SIZE + 1]);
+                    #[cfg(feature = "_nrf_ble")]
                     let info: BondInfo = unsafe { mem::transmute_copy(&buf) };
+                    // The fields are decoded explicitly, a `bool` can't be transmuted from arbitrary bytes
+                    #[cfg(feature = "_esp_ble")]
+                    let info = BondInfo::from_bytes(&buf);
 
                     Ok(StorageData::BondInfo(info))
                 }
-                #[cfg(feature = "_nrf_ble")]
+                #[cfg(any(feature = "_ble", test))]
                 StorageKeys::ActiveBleProfile => Ok(StorageData::ActiveBleProfile(buffer[1])),
             }
         } else {
@@ -333,9 +402,12 @@ impl StorageData {
                 panic!("To get combo key for ComboData, use `get_combo_key` instead");
             }
             StorageData::ConnectionType(_) => StorageKeys::ConnectionType as u32,
-            #[cfg(feature = "_nrf_ble")]
+            StorageData::AnalogCalibration(c) => get_analog_calibration_key(c.row, c.col),
+            #[cfg(feature = "split")]
+            StorageData::Handedness(_) => StorageKeys::Handedness as u32,
+            #[cfg(feature = "_ble")]
             StorageData::BondInfo(b) => get_bond_info_key(b.slot_num),
-            #[cfg(feature = "_nrf_ble")]
+            #[cfg(any(feature = "_ble", test))]
             StorageData::ActiveBleProfile(_) => StorageKeys::ActiveBleProfile as u32,
         }
     }
@@ -371,6 +443,15 @@ pub(crate) struct ComboData {
     pub(crate) output: KeyAction,
 }
 
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) struct AnalogCalibrationData {
+    pub(crate) row: u8,
+    pub(crate) col: u8,
+    pub(crate) rest: u16,
+    pub(crate) bottom: u16,
+}
+
 pub fn async_flash_wrapper<F: NorFlash>(flash: F) -> BlockingAsync<F> {
     embassy_embedded_hal::adapter::BlockingAsync::new(flash)
 }
@@ -419,18 +500,6 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
             "Number of used sector for storage must larger than 1"
         );
 
-        // If config.start_addr == 0, use last `num_sectors` sectors or sectors begin at 0x0006_0000 for nRF52
-        // Other wise, use storage config setting
-        #[cfg(feature = "_nrf_ble")]
-        let start_addr = if config.start_addr == 0 {
-            0x0006_0000
-        } else {
-            config.start_addr
-        };
-
-        #[cfg(not(feature = "_nrf_ble"))]
-        let start_addr = config.start_addr;
-
         // Check storage setting
         info!(
             "Flash capacity {} KB, RMK use {} KB({} sectors) starting from 0x{:X} as storage",
@@ -439,16 +508,7 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
             config.num_sectors,
             config.start_addr,
         );
-        let storage_range = if start_addr == 0 {
-            (flash.capacity() - config.num_sectors as usize * F::ERASE_SIZE) as u32
-                ..flash.capacity() as u32
-        } else {
-            assert!(
-                start_addr % F::ERASE_SIZE == 0,
-                "Storage's start addr MUST BE a multiplier of sector size"
-            );
-            start_addr as u32..(start_addr + config.num_sectors as usize * F::ERASE_SIZE) as u32
-        };
+        let storage_range = get_storage_range(&flash, &config);
 
         let mut storage = Self {
             flash,
@@ -572,6 +632,18 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
                     )
                     .await
                 }
+                FlashOperationMessage::AnalogCalibration(c) => {
+                    let data = StorageData::AnalogCalibration(c);
+                    store_item::<u32, StorageData, _>(
+                        &mut self.flash,
+                        self.storage_range.clone(),
+                        &mut storage_cache,
+                        &mut self.buffer,
+                        &data.key(),
+                        &data,
+                    )
+                    .await
+                }
                 FlashOperationMessage::ConnectionType(ty) => {
                     store_item(
                         &mut self.flash,
@@ -583,7 +655,21 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
                     )
                     .await
                 }
-                #[cfg(feature = "_nrf_ble")]
+                #[cfg(feature = "split")]
+                FlashOperationMessage::Handedness(handedness) => {
+                    info!("Saving handedness: {:?}", handedness);
+                    let data = StorageData::Handedness(handedness);
+                    store_item::<u32, StorageData, _>(
+                        &mut self.flash,
+                        self.storage_range.clone(),
+                        &mut storage_cache,
+                        &mut self.buffer,
+                        &data.key(),
+                        &data,
+                    )
+                    .await
+                }
+                #[cfg(feature = "_ble")]
                 FlashOperationMessage::ActiveBleProfile(profile) => {
                     let data = StorageData::ActiveBleProfile(profile);
                     store_item::<u32, StorageData, _>(
@@ -596,11 +682,12 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
                     )
                     .await
                 }
-                #[cfg(feature = "_nrf_ble")]
+                #[cfg(feature = "_ble")]
                 FlashOperationMessage::ClearSlot(key) => {
                     info!("Clearing bond info slot_num: {}", key);
                     // Remove item in `sequential-storage` is quite expensive, so just override the item with `removed = true`
                     let mut empty = BondInfo::default();
+                    empty.slot_num = key;
                     empty.removed = true;
                     let data = StorageData::BondInfo(empty);
                     store_item::<u32, StorageData, _>(
@@ -613,7 +700,7 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
                     )
                     .await
                 }
-                #[cfg(feature = "_nrf_ble")]
+                #[cfg(feature = "_ble")]
                 FlashOperationMessage::BondInfo(b) => {
                     info!("Saving bond info: {:?}", b);
                     let data = StorageData::BondInfo(b);
@@ -627,7 +714,7 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
                     )
                     .await
                 }
-                #[cfg(not(feature = "_nrf_ble"))]
+                #[cfg(not(feature = "_ble"))]
                 _ => Ok(()),
             } {
                 print_storage_error::<F>(e);
@@ -667,6 +754,37 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
         Ok(())
     }
 
+    pub(crate) async fn read_analog_calibration(
+        &mut self,
+        calibration: &mut [[Option<KeyCalibration>; COL]; ROW],
+    ) -> Result<(), ()> {
+        let mut storage_cache = NoCache::new();
+        let mut key_iterator = fetch_all_items::<u32, _, _>(
+            &mut self.flash,
+            self.storage_range.clone(),
+            &mut storage_cache,
+            &mut self.buffer,
+        )
+        .await
+        .map_err(|e| print_storage_error::<F>(e))?;
+
+        while let Ok(Some((_key, item))) = key_iterator
+            .next::<u32, StorageData>(&mut self.buffer)
+            .await
+        {
+            if let StorageData::AnalogCalibration(c) = item {
+                if (c.row as usize) < ROW && (c.col as usize) < COL {
+                    calibration[c.row as usize][c.col as usize] = Some(KeyCalibration {
+                        rest: c.rest,
+                        bottom: c.bottom,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub(crate) async fn read_macro_cache(&mut self, macro_cache: &mut [u8]) -> Result<(), ()> {
         // Read storage and send back from send_channel
         let read_data = fetch_item::<u32, StorageData, _>(
@@ -799,6 +917,54 @@ impl<F: AsyncNorFlash, const ROW: usize, const COL: usize, const NUM_LAYER: usiz
     }
 }
 
+/// Get the flash range used by the storage
+fn get_storage_range<F: AsyncNorFlash>(flash: &F, config: &StorageConfig) -> Range<u32> {
+    // If config.start_addr == 0, use last `num_sectors` sectors or sectors begin at 0x0006_0000 for nRF52
+    // Other wise, use storage config setting
+    #[cfg(feature = "_nrf_ble")]
+    let start_addr = if config.start_addr == 0 {
+        0x0006_0000
+    } else {
+        config.start_addr
+    };
+
+    #[cfg(not(feature = "_nrf_ble"))]
+    let start_addr = config.start_addr;
+
+    if start_addr == 0 {
+        (flash.capacity() - config.num_sectors as usize * F::ERASE_SIZE) as u32
+            ..flash.capacity() as u32
+    } else {
+        assert!(
+            start_addr % F::ERASE_SIZE == 0,
+            "Storage's start addr MUST BE a multiplier of sector size"
+        );
+        start_addr as u32..(start_addr + config.num_sectors as usize * F::ERASE_SIZE) as u32
+    }
+}
+
+/// Read the handedness of the split half, before the storage is created
+#[cfg(feature = "split")]
+pub(crate) async fn read_handedness<F: AsyncNorFlash>(
+    flash: &mut F,
+    config: &StorageConfig,
+) -> Option<Handedness> {
+    let range = get_storage_range(flash, config);
+    let mut buffer = [0_u8; get_buffer_size()];
+    match fetch_item::<u32, StorageData, _>(
+        flash,
+        range,
+        &mut NoCache::new(),
+        &mut buffer,
+        &(StorageKeys::Handedness as u32),
+    )
+    .await
+    {
+        Ok(Some(StorageData::Handedness(handedness))) => Some(handedness),
+        _ => None,
+    }
+}
+
 fn print_storage_error<F: AsyncNorFlash>(e: SSError<F::Error>) {
     match e {
         SSError::Storage { value: _ } => error!("Flash error"),
@@ -824,3 +990,29 @@ const fn get_buffer_size() -> usize {
     // Efficiently round up to the nearest multiple of 32 using bit manipulation.
     (buffer_size + 31) & !31
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_active_ble_profile_round_trip() {
+        let mut buffer = [0_u8; 16];
+        let data = StorageData::ActiveBleProfile(3);
+        let len = data.serialize_into(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], &[0xEE, 3]);
+        assert_eq!(data.key(), StorageKeys::ActiveBleProfile as u32);
+
+        let decoded = StorageData::deserialize_from(&buffer[..len]).unwrap();
+        assert!(matches!(decoded, StorageData::ActiveBleProfile(3)));
+    }
+
+    #[test]
+    fn test_storage_key_from_u8() {
+        assert!(matches!(
+            StorageKeys::from_u8(0xEE),
+            Some(StorageKeys::ActiveBleProfile)
+        ));
+        assert!(StorageKeys::from_u8(0xED).is_none());
+    }
+}
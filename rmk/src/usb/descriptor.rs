@@ -30,6 +30,36 @@ pub struct KeyboardReport {
     pub keycodes: [u8; 6],
 }
 
+/// JoystickReport describes a gamepad/joystick report with 3 absolute axes and 32 buttons.
+#[gen_hid_descriptor(
+    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = 0x05) = {
+        (usage_page = BUTTON, usage_min = BUTTON_1, usage_max = 0x20) = {
+            #[packed_bits 32] #[item_settings data,variable,absolute] buttons=input;
+        };
+        (usage_page = GENERIC_DESKTOP,) = {
+            (usage = X,) = {
+                #[item_settings data,variable,absolute] x=input;
+            };
+            (usage = Y,) = {
+                #[item_settings data,variable,absolute] y=input;
+            };
+            (usage = Z,) = {
+                #[item_settings data,variable,absolute] z=input;
+            };
+        };
+    }
+)]
+#[allow(dead_code)]
+#[derive(Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct JoystickReport {
+    /// Bitmap of pressed buttons, bit 0 is the first button
+    pub buttons: u32,
+    pub x: i8,
+    pub y: i8,
+    pub z: i8,
+}
+
 #[gen_hid_descriptor(
     (collection = APPLICATION, usage_page = 0xFF60, usage = 0x61) = {
         (usage = 0x62, logical_min = 0x0) = {
@@ -58,6 +88,7 @@ pub enum CompositeReportType {
     Mouse = 0x01,
     Media = 0x02,
     System = 0x03,
+    Joystick = 0x04,
 }
 
 impl CompositeReportType {
@@ -66,12 +97,13 @@ impl CompositeReportType {
             0x01 => Self::Mouse,
             0x02 => Self::Media,
             0x03 => Self::System,
+            0x04 => Self::Joystick,
             _ => Self::None,
         }
     }
 }
 
-/// A composite hid report which contains mouse, consumer, system reports.
+/// A composite hid report which contains mouse, consumer, system and joystick reports.
 /// Report id is used to distinguish from them.
 #[gen_hid_descriptor(
     (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = MOUSE) = {
@@ -112,6 +144,24 @@ impl CompositeReportType {
                 #[item_settings data,array,absolute,not_null] system_usage_id=input;
             };
         };
+    },
+    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = 0x05) = {
+        (report_id = 0x04,) = {
+            (usage_page = BUTTON, usage_min = BUTTON_1, usage_max = 0x20) = {
+                #[packed_bits 32] #[item_settings data,variable,absolute] joystick_buttons=input;
+            };
+            (usage_page = GENERIC_DESKTOP,) = {
+                (usage = X,) = {
+                    #[item_settings data,variable,absolute] joystick_x=input;
+                };
+                (usage = Y,) = {
+                    #[item_settings data,variable,absolute] joystick_y=input;
+                };
+                (usage = Z,) = {
+                    #[item_settings data,variable,absolute] joystick_z=input;
+                };
+            };
+        };
     }
 )]
 #[derive(Default, Serialize)]
@@ -123,4 +173,8 @@ pub struct CompositeReport {
     pub(crate) pan: i8,   // Scroll left (negative) or right (positive) this many units
     pub(crate) media_usage_id: u16,
     pub(crate) system_usage_id: u8,
+    pub(crate) joystick_buttons: u32,
+    pub(crate) joystick_x: i8,
+    pub(crate) joystick_y: i8,
+    pub(crate) joystick_z: i8,
 }
@@ -189,10 +189,18 @@ impl<
                 // backlight/rgblight/rgb matrix/led matrix/audio settings here
                 warn!("Custom set value -- not supported")
             }
-            ViaCommand::CustomGetValue => {
+            ViaCommand::CustomGetValue => match (report.output_data[1], report.output_data[2]) {
+                #[cfg(feature = "split")]
+                (protocol::VIA_CUSTOM_CHANNEL, protocol::VIA_SPLIT_LINK_STATS) => {
+                    write_split_link_stats(report)
+                }
+                #[cfg(feature = "split")]
+                (protocol::VIA_CUSTOM_CHANNEL, protocol::VIA_SPLIT_BATTERY_LEVEL) => {
+                    write_split_battery_level(report)
+                }
                 // backlight/rgblight/rgb matrix/led matrix/audio settings here
-                warn!("Custom get value -- not supported")
-            }
+                _ => warn!("Custom get value -- not supported"),
+            },
             ViaCommand::CustomSave => {
                 // backlight/rgblight/rgb matrix/led matrix/audio settings here
                 warn!("Custom get value -- not supported")
@@ -360,6 +368,44 @@ fn get_position_from_offset(
     (row, col, layer)
 }
 
+/// Write the link statistics of the split peripheral whose id is in `output_data[3]`.
+///
+/// The statistics are written from `input_data[4]`, in big endian:
+/// connected(u8), messages received(u32), deserialize errors(u32), frame errors(u32), reconnects(u16),
+/// pings lost(u16), last latency(u32, us) and average latency(u32, us). The latency is 0 if it's not measured yet.
+#[cfg(feature = "split")]
+fn write_split_link_stats(report: &mut ViaReport) {
+    let Some(stats) = crate::split::stats::peripheral_link_stats(report.output_data[3] as usize)
+    else {
+        report.input_data[0] = ViaCommand::Unhandled as u8;
+        return;
+    };
+    let data = &mut report.input_data;
+    data[4] = stats.connected as u8;
+    BigEndian::write_u32(&mut data[5..9], stats.messages_received);
+    BigEndian::write_u32(&mut data[9..13], stats.deserialize_errors);
+    BigEndian::write_u32(&mut data[13..17], stats.frame_errors);
+    BigEndian::write_u16(&mut data[17..19], stats.reconnects);
+    BigEndian::write_u16(&mut data[19..21], stats.pings_lost);
+    BigEndian::write_u32(&mut data[21..25], stats.last_latency_us.unwrap_or(0));
+    BigEndian::write_u32(&mut data[25..29], stats.average_latency_us.unwrap_or(0));
+}
+
+/// Write the battery level of the split peripheral whose id is in `output_data[3]` to `input_data[4]`, in percent.
+///
+/// The level is 0xFF if the peripheral hasn't reported its battery level, or it's disconnected.
+#[cfg(feature = "split")]
+fn write_split_battery_level(report: &mut ViaReport) {
+    use crate::split::battery::{peripheral_battery_level, PERIPHERAL_BATTERY_NUM};
+
+    let id = report.output_data[3] as usize;
+    if id >= PERIPHERAL_BATTERY_NUM {
+        report.input_data[0] = ViaCommand::Unhandled as u8;
+        return;
+    }
+    report.input_data[4] = peripheral_battery_level(id).unwrap_or(0xFF);
+}
+
 fn count_zeros(data: &[u8]) -> usize {
     data.iter().filter(|&&x| x == 0).count()
 }
@@ -43,3 +43,15 @@ pub(crate) enum ViaKeyboardInfo {
     FirmwareVersion = 0x04,
     DeviceIndication = 0x05,
 }
+
+/// Custom channel of via, whose values are defined by the keyboard
+#[cfg(feature = "split")]
+pub(crate) const VIA_CUSTOM_CHANNEL: u8 = 0x00;
+
+/// Value id of split link statistics in the custom channel, the peripheral id follows the value id
+#[cfg(feature = "split")]
+pub(crate) const VIA_SPLIT_LINK_STATS: u8 = 0x01;
+
+/// Value id of split peripheral battery level in the custom channel, the peripheral id follows the value id
+#[cfg(feature = "split")]
+pub(crate) const VIA_SPLIT_BATTERY_LEVEL: u8 = 0x02;
@@ -3,6 +3,7 @@ $initialDir = Get-Location
 $dirs = @(
     "rmk",
     "rmk-macro",
+    "tests/mock_time",
     "examples/use_rust/esp32c3_ble",
     "examples/use_rust/esp32c6_ble",
     "examples/use_rust/esp32s3_ble",
@@ -1,5 +1,6 @@
 cd rmk && cargo fmt && cd ..
 cd rmk-macro && cargo fmt && cd ..
+cd tests/mock_time && cargo fmt && cd ../..
 cd examples/use_rust/esp32c3_ble && cargo +esp fmt && cd ../../..
 cd examples/use_rust/esp32c6_ble && cargo +esp fmt && cd ../../..
 cd examples/use_rust/esp32s3_ble && cargo +esp fmt && cd ../../..
@@ -0,0 +1,27 @@
+//! Tests of RMK which run with the mock time driver of embassy-time.
+//!
+//! The time is advanced manually by [`embassy_time::MockDriver`], so time-dependent code can be tested
+//! through its public interface. The mock driver is global, tests which use it are serialized by [`lock_time`].
+#![cfg(test)]
+
+mod per_key_debouncer;
+
+use std::sync::{Mutex, MutexGuard};
+
+use embassy_time::{Duration, Instant, MockDriver};
+
+static TIME_LOCK: Mutex<()> = Mutex::new(());
+
+/// Take the mock time driver, the time is reset to 0
+fn lock_time() -> MutexGuard<'static, ()> {
+    let guard = TIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    MockDriver::get().reset();
+    guard
+}
+
+/// Advance the mock time to `ms` since the reset
+fn advance_to(ms: u64) {
+    let now = Instant::now().as_millis();
+    assert!(ms >= now, "Time can't go back");
+    MockDriver::get().advance(Duration::from_millis(ms - now));
+}
@@ -0,0 +1,116 @@
+use rmk::config::{DebounceAlgorithm, DebounceConfig};
+use rmk::debounce::per_key_debouncer::PerKeyDebouncer;
+use rmk::debounce::{DebounceState, DebouncerTrait};
+use rmk::matrix::KeyState;
+
+use super::*;
+
+fn debouncer(algorithm: DebounceAlgorithm, press: u64, release: u64) -> PerKeyDebouncer<1, 1> {
+    PerKeyDebouncer::new(DebounceConfig {
+        algorithm,
+        press_time: Duration::from_millis(press),
+        release_time: Duration::from_millis(release),
+    })
+}
+
+/// Feed a sequence of (time in ms, pin state) to the debouncer, like a matrix scanning a single key.
+///
+/// Returns the time and the state of every reported change.
+fn feed(debouncer: &mut PerKeyDebouncer<1, 1>, samples: &[(u64, bool)]) -> Vec<(u64, bool)> {
+    let _time = lock_time();
+    let mut key_state = KeyState::new();
+    let mut changes = Vec::new();
+    for &(ms, pin_state) in samples {
+        advance_to(ms);
+        if let DebounceState::Debounced =
+            debouncer.detect_change_with_debounce(0, 0, pin_state, &key_state)
+        {
+            key_state.toggle_pressed();
+            changes.push((ms, key_state.pressed));
+        }
+    }
+    changes
+}
+
+/// Scan every 1ms from `from` to `to`, the pin state is given by `f`
+fn scan(from: u64, to: u64, f: impl Fn(u64) -> bool) -> Vec<(u64, bool)> {
+    (from..to).map(|ms| (ms, f(ms))).collect()
+}
+
+/// A press which bounces for 3ms at 10ms, is held until 50ms, then bounces for 3ms when released
+fn bouncing_press(ms: u64) -> bool {
+    // The pin is high at 10ms, low at 11ms, then high from 12ms. When releasing, it's high again at 51ms.
+    matches!(ms, 10 | 12..=49 | 51)
+}
+
+#[test]
+fn test_sym_defer_pk() {
+    let mut d = debouncer(DebounceAlgorithm::SymDeferPk, 5, 5);
+    let changes = feed(&mut d, &scan(0, 100, bouncing_press));
+    // The press is stable from 12ms, the release is stable from 52ms
+    assert_eq!(changes, vec![(17, true), (57, false)]);
+}
+
+#[test]
+fn test_sym_defer_pk_filters_noise() {
+    let mut d = debouncer(DebounceAlgorithm::SymDeferPk, 5, 5);
+    // Short glitches never last for the debounce time
+    let changes = feed(&mut d, &scan(0, 100, |ms| ms % 10 < 3));
+    assert!(changes.is_empty());
+}
+
+#[test]
+fn test_sym_eager_pk() {
+    let mut d = debouncer(DebounceAlgorithm::SymEagerPk, 5, 5);
+    let changes = feed(&mut d, &scan(0, 100, bouncing_press));
+    // Changes are reported at the first edge, following bounces are ignored
+    assert_eq!(changes, vec![(10, true), (50, false)]);
+}
+
+#[test]
+fn test_sym_eager_pk_reports_missed_change_after_cooldown() {
+    let mut d = debouncer(DebounceAlgorithm::SymEagerPk, 5, 5);
+    // A 2ms tap, the release happens during the cooldown
+    let changes = feed(&mut d, &scan(0, 30, |ms| (10..12).contains(&ms)));
+    assert_eq!(changes, vec![(10, true), (15, false)]);
+}
+
+#[test]
+fn test_asym_eager_defer_pk() {
+    let mut d = debouncer(DebounceAlgorithm::AsymEagerDeferPk, 5, 8);
+    let changes = feed(&mut d, &scan(0, 100, bouncing_press));
+    // Press is eager, release is deferred until it's stable for 8ms
+    assert_eq!(changes, vec![(10, true), (60, false)]);
+}
+
+#[test]
+fn test_separate_press_release_time() {
+    let mut d = debouncer(DebounceAlgorithm::SymDeferPk, 2, 10);
+    let changes = feed(&mut d, &scan(0, 100, |ms| (10..50).contains(&ms)));
+    assert_eq!(changes, vec![(12, true), (60, false)]);
+}
+
+#[test]
+fn test_slow_scan() {
+    let mut d = debouncer(DebounceAlgorithm::SymDeferPk, 5, 5);
+    // The matrix is scanned every 3ms
+    let samples: Vec<_> = (0..100)
+        .step_by(3)
+        .map(|ms| (ms, (10..50).contains(&ms)))
+        .collect();
+    let changes = feed(&mut d, &samples);
+    assert_eq!(changes, vec![(18, true), (57, false)]);
+}
+
+#[test]
+fn test_zero_debounce_time() {
+    for algorithm in [
+        DebounceAlgorithm::SymDeferPk,
+        DebounceAlgorithm::SymEagerPk,
+        DebounceAlgorithm::AsymEagerDeferPk,
+    ] {
+        let mut d = debouncer(algorithm, 0, 0);
+        let changes = feed(&mut d, &scan(0, 20, |ms| (5..8).contains(&ms)));
+        assert_eq!(changes, vec![(5, true), (8, false)]);
+    }
+}